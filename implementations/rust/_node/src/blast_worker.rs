@@ -54,6 +54,7 @@ impl BlastWorker {
                 return_route: m.return_route.clone(),
                 message_type: MessageType::Payload,
                 message_body: m.message_body,
+                extensions: m.extensions,
             };
             self.pending_message = Some(pending_message);
             Ok(())
@@ -132,6 +133,7 @@ impl BlastWorker {
             },
             message_type: MessageType::Payload,
             message_body: p.into(),
+            extensions: vec![],
         };
         self.router_tx
             .send(Router(RouterCommand::SendMessage(m)))
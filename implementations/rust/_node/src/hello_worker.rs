@@ -54,6 +54,7 @@ impl HelloWorker {
                 return_route: m.return_route.clone(),
                 message_type: MessageType::Payload,
                 message_body: m.message_body,
+                extensions: m.extensions,
             };
             self.pending_message = Some(pending_message);
             Ok(())
@@ -121,6 +122,7 @@ impl HelloWorker {
             },
             message_type: MessageType::Payload,
             message_body: s.as_bytes().to_vec(),
+            extensions: vec![],
         };
         match self
             .router_tx
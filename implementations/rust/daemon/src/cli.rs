@@ -3,10 +3,12 @@ use std::path::PathBuf;
 use std::str::FromStr;
 
 use ockam::message::{Route, RouterAddress};
+use ockam::secure_channel::DEFAULT_REKEY_MESSAGES;
 
 use ockam_vault_file::FILENAME_KEY_SUFFIX;
-use structopt::{clap::ArgSettings::Hidden, StructOpt};
+use structopt::{clap::ArgSettings::Hidden, clap::ErrorKind::ArgumentConflict, StructOpt};
 use url::Url;
+use zeroize::Zeroizing;
 
 /// The port on which the config updater runs and accepts Config messages.
 pub const DEFAULT_CONFIG_PORT: u16 = 11199;
@@ -43,7 +45,7 @@ pub struct Args {
     #[structopt(
         long,
         default_value = "stdout",
-        help = r#"Route to responder (sink), e.g. udp://host:port[,udp://host:port] (note comma-separation) or "stdout""#
+        help = r#"Route to responder (sink), e.g. udp://host:port[,udp://host:port] (note comma-separation, IPv6 hosts in brackets as in udp://[::1]:4000) or "stdout""#
     )]
     route_sink: OutputKind,
 
@@ -69,6 +71,22 @@ pub struct Args {
     )]
     vault_path: PathBuf,
 
+    /// Name of an environment variable holding the passphrase of an encrypted FILESYSTEM vault.
+    #[structopt(
+        long,
+        conflicts_with("vault-passphrase-fd"),
+        help = "Environment variable from which to read the passphrase encrypting the filesystem vault"
+    )]
+    vault_passphrase_env: Option<String>,
+
+    /// File descriptor from which to read the passphrase of an encrypted FILESYSTEM vault.
+    /// It is read to the end and closed, so it can't be 0: stdin carries the messages.
+    #[structopt(
+        long,
+        help = "File descriptor from which to read the passphrase encrypting the filesystem vault, other than 0 (stdin)"
+    )]
+    vault_passphrase_fd: Option<i32>,
+
     /// Start the `ockamd` process as the initiator or responder of a secure channel.
     #[structopt(
         long,
@@ -81,10 +99,101 @@ pub struct Args {
     #[structopt(
         long,
         default_value = FILENAME_KEY_DEFAULT,
-        help = "Name of the private key to use for the identity of the channel initiator"
+        help = "Key file name or label of the private key to use for the identity of the channel initiator"
     )]
     identity_name: String,
 
+    /// Rotate the identity key once it is older than this many days.
+    #[structopt(
+        long,
+        help = "Rotate the labeled identity key once it is older than this many days"
+    )]
+    identity_rotation_days: Option<u64>,
+
+    /// Keep previous identity keys for this many days after a rotation.
+    #[structopt(
+        long,
+        default_value = "7",
+        help = "Number of days previous identity keys are kept after a rotation"
+    )]
+    identity_grace_days: u64,
+
+    /// Rekey a secure channel after sending this many messages with one key.
+    #[structopt(
+        long,
+        default_value = "65536",
+        help = "Number of messages sent with one channel key before rekeying"
+    )]
+    rekey_messages: u64,
+
+    /// Rekey a secure channel once its key is older than this many seconds.
+    #[structopt(
+        long,
+        help = "Rekey a secure channel once its key is older than this many seconds"
+    )]
+    rekey_seconds: Option<u64>,
+
+    /// Close a secure channel that carried no messages for this many seconds.
+    #[structopt(
+        long,
+        help = "Close a secure channel that carried no messages for this many seconds"
+    )]
+    channel_idle_seconds: Option<u64>,
+
+    /// Noise handshake pattern used to establish secure channels.
+    #[structopt(
+        long,
+        default_value = "xx",
+        help = r#"Handshake pattern of secure channels: "xx", "ik" and "nk" which finish in one round trip but need the responder's public key, or "x3dh" with prekey bundles"#
+    )]
+    handshake: HandshakeKind,
+
+    /// Mix an ML-KEM-768 encapsulation into secure channel handshakes along with X25519.
+    #[structopt(
+        long,
+        help = "Mix an ML-KEM-768 (post-quantum) encapsulation into xx, ik and nk handshakes, every ockamd along a route must set it"
+    )]
+    post_quantum: bool,
+
+    /// Host a prekey service keeping this many one-time prekeys available.
+    #[structopt(
+        long,
+        help = "Host a prekey service keeping this many one-time prekeys available for enrollments"
+    )]
+    prekeys: Option<usize>,
+
+    /// Rotate the signed prekey once it is older than this many days.
+    #[structopt(
+        long,
+        default_value = "7",
+        help = "Rotate the signed prekey once it is older than this many days"
+    )]
+    signed_prekey_rotation_days: u64,
+
+    /// Accept enrollments made with previous signed prekeys and issued one-time prekeys for this
+    /// many days.
+    #[structopt(
+        long,
+        default_value = "30",
+        help = "Number of days previous signed prekeys and issued one-time prekeys can be used to enroll"
+    )]
+    prekey_grace_days: u64,
+
+    /// Enroll the identity key with the sink's prekey service instead of opening a channel.
+    #[structopt(
+        long,
+        help = "Enroll the identity key with the sink's prekey service instead of opening a channel"
+    )]
+    enroll: bool,
+
+    /// Name of an environment variable holding the enrollment token. A sink trusts the keys
+    /// enrolled with it, a source presents it when enrolling.
+    #[structopt(
+        long,
+        help = "Environment variable holding the enrollment token: a sink with --prekeys trusts the keys enrolled with it and holds the others for approval in the <trusted-keys>.pending file, a source with --enroll presents it"
+    )]
+    enrollment_token_env: Option<String>,
+
     /// Define the public key provided by the remote (sink) service.
     #[structopt(
         long,
@@ -93,6 +202,15 @@ pub struct Args {
     )]
     public_key_sink: Option<String>,
 
+    /// Only accept secure channels from the public keys listed in this file.
+    /// NK initiators have no static key, so sinks and routers can't use it with NK.
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "File with one hex encoded public key per line; only these keys may open a secure channel. Not for sinks and routers with --handshake nk, whose initiators have no key"
+    )]
+    trusted_keys: Option<PathBuf>,
+
     /// Define the public key provided by the hub service.
     #[structopt(long, help = "The public key provided by the hub service")]
     public_key_hub: Option<String>,
@@ -141,10 +259,25 @@ impl Default for Args {
             local_socket: SocketAddr::from_str(DEFAULT_LOCAL_SOCKET).expect("bad socket addr"),
             vault: VaultKind::Filesystem,
             vault_path: PathBuf::from("ockamd_vault"),
+            vault_passphrase_env: None,
+            vault_passphrase_fd: None,
             role: ChannelRole::Sink,
             service_address: None,
             identity_name: format!("1{}", FILENAME_KEY_SUFFIX),
+            identity_rotation_days: None,
+            identity_grace_days: 7,
+            rekey_messages: DEFAULT_REKEY_MESSAGES,
+            rekey_seconds: None,
+            channel_idle_seconds: None,
+            handshake: HandshakeKind::XX,
+            post_quantum: false,
+            prekeys: None,
+            signed_prekey_rotation_days: 7,
+            prekey_grace_days: 30,
+            enroll: false,
+            enrollment_token_env: None,
             public_key_sink: None,
+            trusted_keys: None,
             public_key_hub: Some("default_key_vaule".into()),
             addon: None,
         }
@@ -155,8 +288,26 @@ impl Args {
     /// Parse the command line options into the Args struct.
     pub fn parse() -> Args {
         // validate provided arguments & override possibly fallible options
-        // TODO: what should be disallowed that the CLI validation wont handle?
-        Args::from_args()
+        let args = Args::from_args();
+        if let Err(e) = args.validate() {
+            structopt::clap::Error::with_description(&e, ArgumentConflict).exit();
+        }
+        args
+    }
+
+    /// Reject the combinations of options that the CLI validation won't
+    pub fn validate(&self) -> Result<(), String> {
+        // an NK initiator never reveals a static key, every one of them would be refused
+        if matches!(self.handshake, HandshakeKind::NK)
+            && self.trusted_keys.is_some()
+            && !matches!(self.role, ChannelRole::Source)
+        {
+            return Err(
+                "--trusted-keys can't be used by a sink or router with --handshake nk, NK initiators have no static key"
+                    .to_string(),
+            );
+        }
+        Ok(())
     }
 
     /// Checks which mode the executable was run in: Control or Server.
@@ -199,10 +350,50 @@ impl Args {
         self.vault_path.clone()
     }
 
+    pub fn vault_passphrase(&self) -> Option<VaultPassphrase> {
+        match (&self.vault_passphrase_env, self.vault_passphrase_fd) {
+            (Some(var), _) => Some(VaultPassphrase::Env(var.clone())),
+            (None, Some(fd)) => Some(VaultPassphrase::Fd(fd)),
+            (None, None) => None,
+        }
+    }
+
+    pub fn handshake(&self) -> HandshakeKind {
+        self.handshake
+    }
+
+    pub fn post_quantum(&self) -> bool {
+        self.post_quantum
+    }
+
+    pub fn prekeys(&self) -> Option<usize> {
+        self.prekeys
+    }
+
+    pub fn signed_prekey_rotation_days(&self) -> u64 {
+        self.signed_prekey_rotation_days
+    }
+
+    pub fn prekey_grace_days(&self) -> u64 {
+        self.prekey_grace_days
+    }
+
+    pub fn enroll(&self) -> bool {
+        self.enroll
+    }
+
+    pub fn enrollment_token_env(&self) -> Option<String> {
+        self.enrollment_token_env.clone()
+    }
+
     pub fn public_key_sink(&self) -> Option<String> {
         self.public_key_sink.clone()
     }
 
+    pub fn trusted_keys(&self) -> Option<PathBuf> {
+        self.trusted_keys.clone()
+    }
+
     pub fn public_key_hub(&self) -> Option<String> {
         self.public_key_hub.clone()
     }
@@ -215,6 +406,26 @@ impl Args {
         self.identity_name.clone()
     }
 
+    pub fn identity_rotation_days(&self) -> Option<u64> {
+        self.identity_rotation_days
+    }
+
+    pub fn identity_grace_days(&self) -> u64 {
+        self.identity_grace_days
+    }
+
+    pub fn rekey_messages(&self) -> u64 {
+        self.rekey_messages
+    }
+
+    pub fn rekey_seconds(&self) -> Option<u64> {
+        self.rekey_seconds
+    }
+
+    pub fn channel_idle_seconds(&self) -> Option<u64> {
+        self.channel_idle_seconds
+    }
+
     pub fn addon(&self) -> Option<Addon> {
         self.addon.clone()
     }
@@ -262,6 +473,57 @@ impl FromStr for VaultKind {
     }
 }
 
+/// Specifies where the passphrase of an encrypted vault is read from.
+#[derive(Clone, Debug)]
+pub enum VaultPassphrase {
+    Env(String),
+    /// A descriptor that is read to the end and closed. Stdin is refused, the
+    /// stdin worker reads messages from it
+    Fd(i32),
+}
+
+impl VaultPassphrase {
+    /// Read the passphrase, without a trailing newline.
+    pub fn read(&self) -> Result<Zeroizing<String>, String> {
+        let mut passphrase = Zeroizing::new(String::new());
+        match self {
+            VaultPassphrase::Env(var) => {
+                *passphrase = std::env::var(var)
+                    .map_err(|_| format!("vault passphrase variable '{}' is not set", var))?;
+            }
+            VaultPassphrase::Fd(fd) if *fd <= 0 => {
+                return Err(format!(
+                    "can't read the vault passphrase from fd {}, it must be a descriptor other than stdin",
+                    fd
+                ));
+            }
+            #[cfg(unix)]
+            VaultPassphrase::Fd(fd) => {
+                use std::io::Read;
+                use std::os::unix::io::FromRawFd;
+
+                // the descriptor is handed over by the parent process and owned from here on
+                let mut file = unsafe { std::fs::File::from_raw_fd(*fd) };
+                file.read_to_string(&mut passphrase).map_err(|e| {
+                    format!("failed to read vault passphrase from fd {}: {}", fd, e)
+                })?;
+            }
+            #[cfg(not(unix))]
+            VaultPassphrase::Fd(_) => {
+                return Err(
+                    "reading the vault passphrase from a file descriptor requires unix".into(),
+                )
+            }
+        }
+
+        let len = passphrase
+            .trim_end_matches(|c| c == '\n' || c == '\r')
+            .len();
+        passphrase.truncate(len);
+        Ok(passphrase)
+    }
+}
+
 /// Specifies which end of the secure channel the instance of `ockamd` is prepared to run in.
 #[derive(Clone, Copy, Debug, StructOpt)]
 pub enum ChannelRole {
@@ -290,6 +552,34 @@ impl FromStr for ChannelRole {
     }
 }
 
+/// Specifies the Noise handshake pattern used for secure channels. Every `ockamd` along a
+/// route must use the same one.
+#[derive(Clone, Copy, Debug)]
+pub enum HandshakeKind {
+    /// Three messages, the responder's public key is learned during the handshake.
+    XX,
+    /// One round trip, the initiator already knows the responder's public key.
+    IK,
+    /// One round trip like IK, but the initiator stays anonymous.
+    NK,
+    /// X3DH enrollment, the responder hands out a prekey bundle.
+    X3DH,
+}
+
+impl FromStr for HandshakeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xx" => Ok(HandshakeKind::XX),
+            "ik" => Ok(HandshakeKind::IK),
+            "nk" => Ok(HandshakeKind::NK),
+            "x3dh" => Ok(HandshakeKind::X3DH),
+            _ => Err("handshake must be set to either 'xx', 'ik', 'nk', or 'x3dh'".into()),
+        }
+    }
+}
+
 /// The mode in which `ockamd` is to be run.
 #[derive(Clone, Copy, Debug, StructOpt)]
 pub enum Mode {
@@ -338,27 +628,10 @@ impl FromStr for OutputKind {
         let mut route = Route { addresses: vec![] };
 
         s.split(',').for_each(|part| match Url::parse(part) {
-            Ok(u) => {
-                if !u.has_host() {
-                    ret = Err(format!("invalid URI: {}", part));
-                }
-
-                match u.scheme() {
-                    "udp" => {
-                        let addr = u.as_str().trim().trim_start_matches("udp://");
-                        if let Ok(router_addr) = RouterAddress::udp_router_address_from_str(addr) {
-                            route.addresses.push(router_addr);
-                        }
-                    }
-                    "tcp" => {
-                        let addr = u.as_str().trim().trim_start_matches("tcp://");
-                        if let Ok(router_addr) = RouterAddress::tcp_router_address_from_str(addr) {
-                            route.addresses.push(router_addr);
-                        }
-                    }
-                    _ => ret = Err(format!("unsupported URL scheme for: {}", u.as_str())),
-                }
-            }
+            Ok(u) => match router_address_from_url(&u) {
+                Ok(addr) => route.addresses.push(addr),
+                Err(e) => ret = Err(e),
+            },
             Err(e) => {
                 if let Ok(chan_addr) = RouterAddress::channel_router_address_from_str(part) {
                     route.addresses.push(chan_addr);
@@ -376,9 +649,25 @@ impl FromStr for OutputKind {
     }
 }
 
+/// The address of a `udp://` or `tcp://` route part, IPv6 hosts go in brackets and
+/// host names are left for the transport to resolve when it sends
+fn router_address_from_url(u: &Url) -> Result<RouterAddress, String> {
+    let address = match (u.host_str(), u.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(_), None) => return Err(format!("missing port in: {}", u.as_str())),
+        _ => return Err(format!("invalid URI: {}", u.as_str())),
+    };
+    match u.scheme() {
+        "udp" => RouterAddress::udp_router_address_from_str(&address),
+        "tcp" => RouterAddress::tcp_router_address_from_str(&address),
+        _ => return Err(format!("unsupported URL scheme for: {}", u.as_str())),
+    }
+    .map_err(|_| format!("invalid host in: {}", u.as_str()))
+}
+
 #[test]
 fn test_cli_args_output() {
-    use ockam::message::AddressType;
+    use ockam::message::{Address, AddressType, HostName};
 
     if let Ok(output_kind) = OutputKind::from_str("udp://127.0.0.1:12345".into()) {
         match output_kind {
@@ -434,6 +723,44 @@ fn test_cli_args_output() {
         }
     }
 
+    // IPv6 route test cases
+    let route =
+        match OutputKind::from_str("udp://[::1]:4000,tcp://[2001:db8::1]:4001,87c4dd31").unwrap() {
+            OutputKind::Channel(r) => r,
+            _ => panic!("bad output kind, expected channel"),
+        };
+    assert_eq!(route.addresses.len(), 3);
+    assert_eq!(
+        route.addresses[0].address,
+        Address::UdpAddress(SocketAddr::from_str("[::1]:4000").unwrap())
+    );
+    assert_eq!(route.addresses[0].length, 19);
+    assert_eq!(
+        route.addresses[1].address,
+        Address::TcpAddress(SocketAddr::from_str("[2001:db8::1]:4001").unwrap())
+    );
+    assert!(OutputKind::from_str("udp://[::1]").is_err());
+
+    // host name route test cases
+    let route = match OutputKind::from_str("udp://sink.example.internal:4000,87c4dd31").unwrap() {
+        OutputKind::Channel(r) => r,
+        _ => panic!("bad output kind, expected channel"),
+    };
+    assert_eq!(route.addresses.len(), 2);
+    assert_eq!(route.addresses[0].a_type, AddressType::Udp);
+    assert_eq!(
+        route.addresses[0].address,
+        Address::UdpHostAddress(HostName::new("sink.example.internal", 4000).unwrap())
+    );
+    let route = match OutputKind::from_str("tcp://localhost:4001").unwrap() {
+        OutputKind::Channel(r) => r,
+        _ => panic!("bad output kind, expected channel"),
+    };
+    assert_eq!(route.addresses[0].a_type, AddressType::Tcp);
+    assert_eq!(route.addresses[0].address.as_string(), "localhost:4001");
+    assert!(OutputKind::from_str("udp://sink.example.internal").is_err());
+    assert!(OutputKind::from_str("udp://sink_example:4000").is_err());
+
     // TCP-only route test cases
     [
         "tcp://127.0.0.1:12345,tcp://10.1.20.34:11111",
@@ -456,3 +783,31 @@ fn test_cli_args_output() {
         }
     });
 }
+
+#[test]
+fn vault_passphrase_not_from_stdin() {
+    assert!(VaultPassphrase::Fd(0).read().is_err());
+    assert!(VaultPassphrase::Fd(-1).read().is_err());
+}
+
+#[test]
+fn trusted_keys_need_initiator_keys() {
+    let args = |role, handshake| Args {
+        role,
+        handshake,
+        trusted_keys: Some(PathBuf::from("trusted")),
+        ..Args::default()
+    };
+    assert!(args(ChannelRole::Sink, HandshakeKind::NK)
+        .validate()
+        .is_err());
+    assert!(args(ChannelRole::Router, HandshakeKind::NK)
+        .validate()
+        .is_err());
+    assert!(args(ChannelRole::Source, HandshakeKind::NK)
+        .validate()
+        .is_ok());
+    assert!(args(ChannelRole::Sink, HandshakeKind::IK)
+        .validate()
+        .is_ok());
+}
@@ -1,10 +1,15 @@
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use crate::cli;
 
+use zeroize::Zeroizing;
+
 use ockam::message::Route;
 
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Role {
     Source,
@@ -12,6 +17,14 @@ pub enum Role {
     Router,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum Handshake {
+    XX,
+    IK,
+    NK,
+    X3DH,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Input {
     Stdin,
@@ -32,11 +45,25 @@ pub struct Config {
     // channel_to_sink: Option<String>,
     role: Role,
     vault_path: PathBuf,
+    vault_passphrase: Option<cli::VaultPassphrase>,
     input_kind: Input,
+    handshake: Handshake,
+    post_quantum: bool,
+    prekeys: Option<usize>,
+    signed_prekey_lifetime: Duration,
+    prekey_grace: Duration,
+    enroll: bool,
+    enrollment_token_env: Option<String>,
     public_key_sink: Option<String>,
     public_key_hub: Option<String>,
+    trusted_keys: Option<PathBuf>,
     service_address: Option<String>,
     identity_name: String,
+    identity_rotation: Option<Duration>,
+    identity_grace: Duration,
+    rekey_messages: u64,
+    rekey_interval: Option<Duration>,
+    channel_idle_timeout: Option<Duration>,
     addon: Option<AddonKind>,
 }
 
@@ -51,6 +78,10 @@ impl Config {
         self.vault_path.clone()
     }
 
+    pub fn vault_passphrase(&self) -> Option<cli::VaultPassphrase> {
+        self.vault_passphrase.clone()
+    }
+
     pub fn onward_route(&self) -> Option<Route> {
         self.onward_route.clone()
     }
@@ -75,6 +106,41 @@ impl Config {
     //     self.channel_to_sink.clone()
     // }
 
+    pub fn handshake(&self) -> Handshake {
+        self.handshake
+    }
+
+    pub fn post_quantum(&self) -> bool {
+        self.post_quantum
+    }
+
+    pub fn prekeys(&self) -> Option<usize> {
+        self.prekeys
+    }
+
+    pub fn signed_prekey_lifetime(&self) -> Duration {
+        self.signed_prekey_lifetime
+    }
+
+    pub fn prekey_grace(&self) -> Duration {
+        self.prekey_grace
+    }
+
+    pub fn enroll(&self) -> bool {
+        self.enroll
+    }
+
+    /// The enrollment token, read from the environment variable named on the command line
+    pub fn enrollment_token(&self) -> Result<Option<Zeroizing<Vec<u8>>>, String> {
+        match &self.enrollment_token_env {
+            Some(var) => match std::env::var(var) {
+                Ok(token) if !token.is_empty() => Ok(Some(Zeroizing::new(token.into_bytes()))),
+                _ => Err(format!("enrollment token variable '{}' is not set", var)),
+            },
+            None => Ok(None),
+        }
+    }
+
     pub fn public_key_sink(&self) -> Option<String> {
         self.public_key_sink.clone()
     }
@@ -83,6 +149,10 @@ impl Config {
         self.public_key_hub.clone()
     }
 
+    pub fn trusted_keys(&self) -> Option<PathBuf> {
+        self.trusted_keys.clone()
+    }
+
     pub fn role(&self) -> Role {
         self.role
     }
@@ -95,6 +165,26 @@ impl Config {
         self.identity_name.clone()
     }
 
+    pub fn identity_rotation(&self) -> Option<Duration> {
+        self.identity_rotation
+    }
+
+    pub fn identity_grace(&self) -> Duration {
+        self.identity_grace
+    }
+
+    pub fn rekey_messages(&self) -> u64 {
+        self.rekey_messages
+    }
+
+    pub fn rekey_interval(&self) -> Option<Duration> {
+        self.rekey_interval
+    }
+
+    pub fn channel_idle_timeout(&self) -> Option<Duration> {
+        self.channel_idle_timeout
+    }
+
     pub fn addon(&self) -> Option<AddonKind> {
         self.addon.clone()
     }
@@ -111,11 +201,29 @@ impl From<cli::Args> for Config {
             // router_socket: args.router_socket(),
             role: Role::Source,
             vault_path: args.vault_path(),
+            vault_passphrase: args.vault_passphrase(),
             input_kind: Input::Stdin,
+            handshake: Handshake::XX,
+            post_quantum: args.post_quantum(),
+            prekeys: args.prekeys(),
+            signed_prekey_lifetime: Duration::from_secs(
+                args.signed_prekey_rotation_days() * SECONDS_PER_DAY,
+            ),
+            prekey_grace: Duration::from_secs(args.prekey_grace_days() * SECONDS_PER_DAY),
+            enroll: args.enroll(),
+            enrollment_token_env: args.enrollment_token_env(),
             public_key_sink: args.public_key_sink(),
             public_key_hub: args.public_key_hub(),
+            trusted_keys: args.trusted_keys(),
             service_address: args.service_address(),
             identity_name: args.identity_name(),
+            identity_rotation: args
+                .identity_rotation_days()
+                .map(|days| Duration::from_secs(days * SECONDS_PER_DAY)),
+            identity_grace: Duration::from_secs(args.identity_grace_days() * SECONDS_PER_DAY),
+            rekey_messages: args.rekey_messages(),
+            rekey_interval: args.rekey_seconds().map(Duration::from_secs),
+            channel_idle_timeout: args.channel_idle_seconds().map(Duration::from_secs),
             addon: if let Some(a) = args.addon() {
                 match a {
                     cli::Addon::InfluxDb(u, db) => Some(AddonKind::InfluxDb(u, db)),
@@ -140,6 +248,13 @@ impl From<cli::Args> for Config {
             cli::ChannelRole::Router => Role::Router,
         };
 
+        cfg.handshake = match args.handshake() {
+            cli::HandshakeKind::XX => Handshake::XX,
+            cli::HandshakeKind::IK => Handshake::IK,
+            cli::HandshakeKind::NK => Handshake::NK,
+            cli::HandshakeKind::X3DH => Handshake::X3DH,
+        };
+
         cfg.input_kind = match args.input_kind() {
             cli::InputKind::Stdin => Input::Stdin,
         };
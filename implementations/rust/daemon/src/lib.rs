@@ -1,5 +1,6 @@
 pub mod cli;
 pub mod config;
 pub mod node;
+pub mod prekey;
 pub mod sink;
 pub mod source;
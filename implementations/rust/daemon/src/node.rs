@@ -4,7 +4,8 @@ use std::thread;
 use std::time;
 
 use crate::cli;
-use crate::config::{Config, Role};
+use crate::config::{Config, Handshake, Role};
+use crate::prekey::{EnrolleeWorker, PreKeyServer};
 use crate::sink::SinkWorker;
 use crate::source::StdinWorker;
 
@@ -12,29 +13,64 @@ use crate::source::StdinWorker;
 //     ockam_daemon::initiator::StdinWorker
 // }
 
+use ockam::common::error::OckamResult;
 use ockam::kex::CipherSuite;
+use ockam::kex::{KeyExchanger, NewKeyExchanger};
 use ockam::message::{Address, RouterAddress};
+use ockam::secure_channel::authorization::{AllowList, ChannelAuthorizer};
 use ockam::secure_channel::*;
 use ockam::system::commands::{OckamCommand, WorkerCommand};
-use ockam_kex_xx::{XXInitiator, XXNewKeyExchanger, XXResponder};
+use ockam_kex_x3dh::{PreKeyStore, X3dhInitiator, X3dhNewKeyExchanger, X3dhResponder};
+use ockam_kex_xx::{
+    IKInitiator, IKNewKeyExchanger, IKResponder, NKInitiator, NKNewKeyExchanger, NKResponder,
+    XXInitiator, XXNewKeyExchanger, XXResponder,
+};
 use ockam_router::router::Router;
 use ockam_transport::tcp::TcpManager;
 use ockam_vault_file::ockam_vault::types::*;
 use ockam_vault_file::ockam_vault::*;
-use ockam_vault_file::FilesystemVault;
+use ockam_vault_file::{FilesystemVault, FILENAME_KEY_SUFFIX};
 use std::net::SocketAddr;
 use std::ops::Deref;
 use std::str::FromStr;
 
+/// How often the age of the identity key is checked
+const ROTATION_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(60 * 60);
+
 pub enum OckamdWorker {
     StdinWorker(StdinWorker),
     Sink(SinkWorker),
+    Enrollee(EnrolleeWorker),
+}
+
+/// A channel manager running the configured handshake pattern
+pub enum NodeChannelManager {
+    XX(ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>),
+    IK(ChannelManager<IKInitiator, IKResponder, IKNewKeyExchanger>),
+    NK(ChannelManager<NKInitiator, NKResponder, NKNewKeyExchanger>),
+    X3DH(ChannelManager<X3dhInitiator, X3dhResponder, X3dhNewKeyExchanger>),
+}
+
+impl NodeChannelManager {
+    pub fn poll(&mut self) -> OckamResult<bool> {
+        match self {
+            NodeChannelManager::XX(m) => m.poll(),
+            NodeChannelManager::IK(m) => m.poll(),
+            NodeChannelManager::NK(m) => m.poll(),
+            NodeChannelManager::X3DH(m) => m.poll(),
+        }
+    }
 }
 
 #[allow(dead_code)]
 pub struct Node<'a> {
     config: &'a Config,
-    chan_manager: ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>,
+    vault: Arc<Mutex<FilesystemVault>>,
+    identity_label: Option<String>,
+    last_rotation_check: Option<time::Instant>,
+    prekey_store: Option<Arc<Mutex<PreKeyStore>>>,
+    last_prekey_check: Option<time::Instant>,
+    chan_manager: NodeChannelManager,
     worker: Option<OckamdWorker>,
     router: Router,
     router_tx: Sender<OckamCommand>,
@@ -97,8 +133,13 @@ impl<'a> Node<'a> {
                 let a = Address::TcpAddress(config.route_hub().unwrap());
                 RouterAddress::from_address(a).unwrap()
             };
-            let sock_addr = SocketAddr::from_str(&hop.address.as_string()).unwrap();
-            match transport.connect(sock_addr) {
+            let connected = match &hop.address {
+                Address::TcpHostAddress(host) | Address::UdpHostAddress(host) => {
+                    transport.connect_host(host)
+                }
+                _ => transport.connect(SocketAddr::from_str(&hop.address.as_string()).unwrap()),
+            };
+            match connected {
                 Ok(h) => h,
                 Err(_) => {
                     panic!("failed to connect, is server running?");
@@ -113,28 +154,62 @@ impl<'a> Node<'a> {
         let (router_tx, router_rx) = std::sync::mpsc::channel();
         let router = Router::new(router_rx);
 
-        // create the vault, using the FILESYSTEM implementation
-        let mut vault =
-            FilesystemVault::new(config.vault_path()).expect("failed to initialize vault");
+        // create the vault, using the FILESYSTEM implementation, encrypted if a passphrase is given
+        let mut vault = match config.vault_passphrase() {
+            Some(source) => {
+                let passphrase = source.read()?;
+                let path = config.vault_path();
+                if path.exists() && !FilesystemVault::is_encrypted(&path) {
+                    println!("Encrypting vault at {:?}", path);
+                    FilesystemVault::migrate_to_encrypted(path, passphrase.as_bytes())
+                } else {
+                    FilesystemVault::new_encrypted(path, passphrase.as_bytes())
+                }
+            }
+            None => FilesystemVault::new(config.vault_path()),
+        }
+        .expect("failed to initialize vault");
 
         // check for re-use of provided identity name from CLI args, if not in on-disk in vault
         // generate a new one to be used
 
-        let resp_key_ctx = match vault.get_persistent_secret(&config.identity_name()) {
+        // the identity name is either a key file name (e.g. "1.key") or a key label
+        let identity_name = config.identity_name();
+        let identity = vault
+            .get_persistent_secret(&identity_name)
+            .or_else(|_| vault.get_persistent_secret_by_label(&identity_name));
+        let resp_key_ctx = match identity {
             Ok(secret) => Some(Arc::new(secret)),
             Err(_) => {
-                // if responder, generate keypair and display static public key
-                if matches!(config.role(), Role::Sink) || matches!(config.role(), Role::Router) {
+                // if responder or enrollee, generate keypair and display static public key
+                if matches!(config.role(), Role::Sink)
+                    || matches!(config.role(), Role::Router)
+                    || config.enroll()
+                {
                     let attributes = SecretAttributes {
                         stype: SecretType::Curve25519,
                         persistence: SecretPersistence::Persistent,
                         length: CURVE25519_SECRET_LENGTH,
                     };
-                    Some(Arc::new(
+                    let secret = vault
+                        .secret_generate(attributes)
+                        .expect("failed to generate secret");
+
+                    // label the new identity so it can be found by name on the next start
+                    if !identity_name.ends_with(FILENAME_KEY_SUFFIX) {
+                        let mut metadata = SecretMetadata::default();
+                        metadata.label = Some(identity_name.clone());
+                        if let Ok(now) = time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+                            metadata
+                                .entries
+                                .insert(METADATA_CREATED_AT.into(), now.as_secs().to_string());
+                        }
                         vault
-                            .secret_generate(attributes)
-                            .expect("failed to generate secret"),
-                    ))
+                            .set_secret_metadata(&secret, metadata)
+                            .expect("failed to label secret");
+                    }
+
+                    Some(Arc::new(secret))
                 } else {
                     None
                 }
@@ -153,33 +228,147 @@ impl<'a> Node<'a> {
             }
         }
 
+        if matches!(config.role(), Role::Source) {
+            if let Some(key) = &resp_key_ctx {
+                if let Ok(init_key) = vault.secret_public_key_get(key) {
+                    println!("Initiator public key: {}", hex::encode(init_key.as_ref()));
+                }
+            }
+        }
+
+        // a labeled identity is looked up again for each new channel so that it can be rotated
+        let identity_label = if resp_key_ctx.is_some()
+            && !identity_name.ends_with(FILENAME_KEY_SUFFIX)
+        {
+            Some(identity_name)
+        } else {
+            if config.identity_rotation().is_some() {
+                println!("Identity key rotation requires a labeled identity, rotation disabled");
+            }
+            None
+        };
+
         // prepare the vault for use in key exchanger and channel manager
         let vault = Arc::new(Mutex::new(vault));
 
-        // create the channel manager
-        type XXChannelManager = ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>;
-        let (channel_tx, channel_rx) = mpsc::channel();
-        let new_key_exchanger = XXNewKeyExchanger::new(
-            CipherSuite::Curve25519AesGcmSha256,
-            vault.clone(),
-            vault.clone(),
-        );
+        // responders keep their prekeys in the vault, for X3DH channels and for enrollments
+        let prekey_store = match (config.role(), &resp_key_ctx) {
+            (Role::Sink, Some(identity)) | (Role::Router, Some(identity))
+                if matches!(config.handshake(), Handshake::X3DH) || config.prekeys().is_some() =>
+            {
+                let mut store = PreKeyStore::new(vault.clone(), identity.clone())
+                    .map_err(|e| format!("failed to create prekey store: {}", e))?;
+                store.set_lifetime(config.signed_prekey_lifetime(), config.prekey_grace());
+                Some(Arc::new(Mutex::new(store)))
+            }
+            _ => None,
+        };
 
-        let chan_manager = XXChannelManager::new(
-            channel_rx,
-            channel_tx.clone(),
-            router_tx.clone(),
-            vault,
-            new_key_exchanger,
-            resp_key_ctx,
-            None,
-        )
-        .unwrap();
+        // only let trusted keys open channels: the sink's key for a source,
+        // the keys listed in the trusted keys file and the keys enrolled since
+        let mut allow_list = match config.trusted_keys() {
+            Some(path) => Some(
+                AllowList::from_file(path)
+                    .map_err(|e| format!("failed to read trusted keys: {}", e))?,
+            ),
+            None => None,
+        };
+        if let (Role::Source, Some(key)) = (config.role(), config.public_key_sink()) {
+            allow_list
+                .get_or_insert_with(AllowList::default)
+                .add_hex(&key)
+                .map_err(|e| format!("invalid sink public key: {}", e))?;
+        }
+        let allow_list = allow_list.map(|allow_list| Arc::new(Mutex::new(allow_list)));
+
+        // create the channel manager, IK and NK initiators need the key of the responder
+        // they open channels to: the sink for a source, the hub otherwise
+        let (channel_tx, channel_rx) = mpsc::channel();
+        let cipher_suite = if config.post_quantum() {
+            CipherSuite::Curve25519MlKem768AesGcmSha256
+        } else {
+            CipherSuite::Curve25519AesGcmSha256
+        };
+        let responder_public_key = match config.role() {
+            Role::Source => config.public_key_sink(),
+            _ => config.public_key_hub(),
+        }
+        .and_then(|key| hex::decode(key).ok())
+        .map(PublicKey::new);
+        let chan_manager = match config.handshake() {
+            Handshake::XX => NodeChannelManager::XX(Node::create_channel_manager(
+                config,
+                vault.clone(),
+                XXNewKeyExchanger::new(cipher_suite, vault.clone(), vault.clone()),
+                resp_key_ctx.clone(),
+                &identity_label,
+                allow_list.clone(),
+                channel_rx,
+                channel_tx.clone(),
+                router_tx.clone(),
+            )?),
+            Handshake::IK => NodeChannelManager::IK(Node::create_channel_manager(
+                config,
+                vault.clone(),
+                IKNewKeyExchanger::new(
+                    cipher_suite,
+                    vault.clone(),
+                    vault.clone(),
+                    responder_public_key,
+                ),
+                resp_key_ctx.clone(),
+                &identity_label,
+                allow_list.clone(),
+                channel_rx,
+                channel_tx.clone(),
+                router_tx.clone(),
+            )?),
+            Handshake::NK => NodeChannelManager::NK(Node::create_channel_manager(
+                config,
+                vault.clone(),
+                NKNewKeyExchanger::new(
+                    cipher_suite,
+                    vault.clone(),
+                    vault.clone(),
+                    responder_public_key,
+                ),
+                resp_key_ctx.clone(),
+                &identity_label,
+                allow_list.clone(),
+                channel_rx,
+                channel_tx.clone(),
+                router_tx.clone(),
+            )?),
+            Handshake::X3DH => {
+                let mut new_key_exchanger = X3dhNewKeyExchanger::new(vault.clone(), vault.clone());
+                if let Some(store) = &prekey_store {
+                    new_key_exchanger.set_prekey_store(store.clone());
+                }
+                NodeChannelManager::X3DH(Node::create_channel_manager(
+                    config,
+                    vault.clone(),
+                    new_key_exchanger,
+                    resp_key_ctx.clone(),
+                    &identity_label,
+                    allow_list.clone(),
+                    channel_rx,
+                    channel_tx.clone(),
+                    router_tx.clone(),
+                )?)
+            }
+        };
 
         if let Ok((transport, transport_tx)) = Node::create_transport(&config, router_tx.clone()) {
             // create the worker
             let mut worker: Option<OckamdWorker> = None;
-            if matches!(config.role(), Role::Source) {
+            if matches!(config.role(), Role::Source) && config.enroll() {
+                worker = Some(OckamdWorker::Enrollee(EnrolleeWorker::initialize(
+                    config,
+                    vault.clone(),
+                    resp_key_ctx,
+                    router_tx.clone(),
+                )?));
+            } else if matches!(config.role(), Role::Source) {
                 worker = Some(OckamdWorker::StdinWorker(
                     StdinWorker::initialize(config, router_tx.clone(), channel_tx.clone()).unwrap(),
                 ));
@@ -187,10 +376,20 @@ impl<'a> Node<'a> {
             if matches!(config.role(), Role::Sink) {
                 let worker_addr =
                     RouterAddress::worker_router_address_from_str("01242020").unwrap();
+                let prekey_server = match (config.prekeys(), &prekey_store) {
+                    (Some(_), Some(store)) => Some(PreKeyServer::new(
+                        config,
+                        store.clone(),
+                        allow_list,
+                        router_tx.clone(),
+                    )?),
+                    _ => None,
+                };
                 worker = Some(OckamdWorker::Sink(
                     SinkWorker::initialize(
                         &config,
                         worker_addr,
+                        prekey_server,
                         router_tx.clone(),
                         channel_tx.clone(),
                     )
@@ -199,6 +398,11 @@ impl<'a> Node<'a> {
             }
             Ok(Self {
                 config,
+                vault,
+                identity_label,
+                last_rotation_check: None,
+                prekey_store,
+                last_prekey_check: None,
                 worker,
                 router,
                 router_tx,
@@ -212,10 +416,130 @@ impl<'a> Node<'a> {
         }
     }
 
+    /// Create a channel manager for the given key exchange and apply the channel settings
+    #[allow(clippy::too_many_arguments)]
+    fn create_channel_manager<I, R, E>(
+        config: &Config,
+        vault: Arc<Mutex<FilesystemVault>>,
+        new_key_exchanger: E,
+        identity: Option<Arc<Box<dyn Secret>>>,
+        identity_label: &Option<String>,
+        allow_list: Option<Arc<Mutex<AllowList>>>,
+        channel_rx: mpsc::Receiver<OckamCommand>,
+        channel_tx: Sender<OckamCommand>,
+        router_tx: Sender<OckamCommand>,
+    ) -> Result<ChannelManager<I, R, E>, String>
+    where
+        I: KeyExchanger + 'static,
+        R: KeyExchanger + 'static,
+        E: NewKeyExchanger<I, R>,
+    {
+        // a source opens channels with its identity, so that enrolled keys are recognized
+        let (resp_key_ctx, init_key_ctx) = match config.role() {
+            Role::Source => (None, identity),
+            _ => (identity, None),
+        };
+        let mut chan_manager = ChannelManager::new(
+            channel_rx,
+            channel_tx,
+            router_tx,
+            vault.clone(),
+            new_key_exchanger,
+            resp_key_ctx,
+            init_key_ctx,
+        )
+        .unwrap();
+        if let Some(label) = identity_label {
+            match config.role() {
+                Role::Source => chan_manager.set_initiator_key_label(vault, label.clone()),
+                _ => chan_manager.set_responder_key_label(vault, label.clone()),
+            }
+        }
+        chan_manager.set_rekey_interval(Some(config.rekey_messages()), config.rekey_interval());
+        chan_manager.set_idle_timeout(config.channel_idle_timeout());
+        if let Some(allow_list) = allow_list {
+            chan_manager.set_authorizer(Box::new(move |key: &PublicKey| {
+                allow_list.lock().unwrap().authorize(key)
+            }));
+        }
+        Ok(chan_manager)
+    }
+
+    /// Rotate the labeled identity key once it is due, new channels pick the new key up
+    fn rotate_identity(&mut self) {
+        let (label, max_age) = match (&self.identity_label, self.config.identity_rotation()) {
+            (Some(label), Some(max_age)) => (label, max_age),
+            _ => return,
+        };
+        if let Some(last) = self.last_rotation_check {
+            if last.elapsed() < ROTATION_CHECK_INTERVAL {
+                return;
+            }
+        }
+        self.last_rotation_check = Some(time::Instant::now());
+
+        let mut vault = self.vault.lock().unwrap();
+        match vault.secret_needs_rotation(label, max_age) {
+            Ok(true) => match vault.secret_rotate(label, self.config.identity_grace()) {
+                Ok(secret) => {
+                    if let Ok(public_key) = vault.secret_public_key_get(&secret) {
+                        println!(
+                            "Rotated identity key, new public key: {}",
+                            hex::encode(public_key.as_ref())
+                        );
+                    }
+                }
+                Err(e) => eprintln!("failed to rotate identity key: {}", e),
+            },
+            Ok(false) => {}
+            Err(e) => eprintln!("failed to check identity key rotation: {}", e),
+        }
+    }
+
+    /// Rotate the signed prekey once it is due and top the one-time prekeys up
+    fn maintain_prekeys(&mut self) {
+        let store = match &self.prekey_store {
+            Some(store) => store,
+            None => return,
+        };
+        if let Some(last) = self.last_prekey_check {
+            if last.elapsed() < ROTATION_CHECK_INTERVAL {
+                return;
+            }
+        }
+        self.last_prekey_check = Some(time::Instant::now());
+
+        let mut store = store.lock().unwrap();
+        match store.rotate() {
+            Ok(true) => println!("Rotated signed prekey"),
+            Ok(false) => {}
+            Err(e) => eprintln!("failed to rotate signed prekey: {}", e),
+        }
+        if let Some(count) = self.config.prekeys() {
+            if let Err(e) = store.replenish(count) {
+                eprintln!("failed to replenish one-time prekeys: {}", e);
+            }
+        }
+    }
+
     pub fn run(mut self) {
-        match self.worker {
+        match self.worker.take() {
             Some(worker) => match worker {
                 OckamdWorker::Sink(mut w) => {
+                    while self.router.poll()
+                        && self.transport.poll()
+                        && w.poll()
+                        && self
+                            .chan_manager
+                            .poll()
+                            .expect("channel manager poll failure")
+                    {
+                        self.rotate_identity();
+                        self.maintain_prekeys();
+                        thread::sleep(time::Duration::from_millis(1));
+                    }
+                }
+                OckamdWorker::Enrollee(mut w) => {
                     while self.router.poll()
                         && self.transport.poll()
                         && w.poll()
@@ -238,6 +562,8 @@ impl<'a> Node<'a> {
                             .poll()
                             .expect("channel manager poll failure")
                     {
+                        self.rotate_identity();
+                        self.maintain_prekeys();
                         thread::sleep(time::Duration::from_millis(1));
                     }
                 }
@@ -250,6 +576,8 @@ impl<'a> Node<'a> {
                         .poll()
                         .expect("channel manager poll failure")
                 {
+                    self.rotate_identity();
+                    self.maintain_prekeys();
                     thread::sleep(time::Duration::from_millis(1));
                 }
             }
@@ -0,0 +1,757 @@
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+use hmac::{Hmac, Mac, NewMac};
+use ockam::message::{AddressType, Codec, Message, MessageType, Route, RouterAddress};
+use ockam::secure_channel::authorization::{AllowList, ChannelAuthorizer};
+use ockam::system::commands::{OckamCommand, RouterCommand, WorkerCommand};
+use ockam_kex_x3dh::{EnrollmentRequest, PreKeyBundle, PreKeyStore, X3dhVault};
+use ockam_vault_file::ockam_vault::types::PublicKey;
+use ockam_vault_file::ockam_vault::Secret;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// Worker address of the prekey service hosted by a sink
+pub const PREKEY_SERVICE_ADDRESS: &str = "58334448";
+
+/// Worker address the enrollee receives prekey service answers on
+pub const ENROLLEE_ADDRESS: &str = "58334445";
+
+/// The first byte of a prekey service message tells what it carries
+const BUNDLE_REQUEST: u8 = 0;
+const BUNDLE: u8 = 1;
+const ENROLLMENT: u8 = 2;
+const ENROLLED: u8 = 3;
+const REJECTED: u8 = 4;
+const PENDING: u8 = 5;
+const STATUS: u8 = 6;
+
+/// An enrollment made with the enrollment token starts with an HMAC-SHA256 of the request
+/// keyed with the token, so the token itself never crosses the network
+const ENROLLMENT_TAG_SIZE: usize = 32;
+
+/// Bundle requests and enrollments a single source may send within `REQUEST_WINDOW`
+const MAX_REQUESTS_PER_SOURCE: usize = 4;
+const REQUEST_WINDOW: Duration = Duration::from_secs(60);
+
+/// Sources whose requests are counted at once, requests from further sources are dropped
+const MAX_SOURCES: usize = 1024;
+
+/// Enrolled keys held for the operator's approval at once
+const MAX_PENDING_ENROLLMENTS: usize = 64;
+
+/// How long the enrollee waits for the prekey service to answer before asking again,
+/// doubled after every try up to `MAX_RETRY_INTERVAL`
+const FIRST_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the enrollee keeps asking before it gives up on the enrollment
+const ENROLLMENT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often an enrollment pending approval is checked on, and for how long
+const APPROVAL_POLL_INTERVAL: Duration = Duration::from_secs(60);
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What became of a verified enrollment
+enum Enrollment {
+    /// The key may open channels from now on
+    Trusted(String),
+    /// The key waits in the pending keys file for the operator to trust it
+    Pending(String),
+}
+
+/// Hands out prekey bundles and accepts the enrollments made with them, so that devices
+/// can enroll with bundles they fetched while the sink was reachable.
+///
+/// Only enrollments authenticated with the enrollment token are trusted right away. The keys
+/// of the others are appended to `<trusted keys>.pending`, the operator approves one by moving
+/// it to the trusted keys file and restarting ockamd.
+pub struct PreKeyServer {
+    store: Arc<Mutex<PreKeyStore>>,
+    allow_list: Option<Arc<Mutex<AllowList>>>,
+    trusted_keys: Option<PathBuf>,
+    enrollment_token: Option<Zeroizing<Vec<u8>>>,
+    pending: BTreeSet<String>,
+    sources: HashMap<Vec<u8>, (Instant, usize)>,
+    router_tx: Sender<OckamCommand>,
+}
+
+impl PreKeyServer {
+    pub fn new(
+        config: &Config,
+        store: Arc<Mutex<PreKeyStore>>,
+        allow_list: Option<Arc<Mutex<AllowList>>>,
+        router_tx: Sender<OckamCommand>,
+    ) -> Result<Self, String> {
+        println!("Prekey service address: {}", PREKEY_SERVICE_ADDRESS);
+        let trusted_keys = config.trusted_keys();
+        let pending = match trusted_keys.as_ref().map(|path| pending_keys(path)) {
+            Some(path) if path.exists() => fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read pending keys: {}", e))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from)
+                .collect(),
+            _ => BTreeSet::new(),
+        };
+        Ok(PreKeyServer {
+            store,
+            allow_list,
+            trusted_keys,
+            enrollment_token: config.enrollment_token()?,
+            pending,
+            sources: HashMap::new(),
+            router_tx,
+        })
+    }
+
+    pub fn address() -> RouterAddress {
+        RouterAddress::worker_router_address_from_str(PREKEY_SERVICE_ADDRESS).unwrap()
+    }
+
+    /// Answer a bundle request or an enrollment request
+    pub fn handle(&mut self, msg: Message) {
+        if !self.may_answer(&msg.return_route) {
+            eprintln!("dropped prekey service request, its source sent too many");
+            return;
+        }
+        let reply = match msg.message_body.split_first() {
+            Some((&BUNDLE_REQUEST, _)) => match self.store.lock().unwrap().issue_bundle() {
+                Ok(bundle) => {
+                    let mut body = vec![BUNDLE];
+                    body.append(&mut bundle.to_bytes());
+                    body
+                }
+                Err(e) => {
+                    eprintln!("failed to issue prekey bundle: {}", e);
+                    return;
+                }
+            },
+            Some((&STATUS, public_key)) => vec![self.status(public_key)],
+            Some((&ENROLLMENT, request)) => match self.enroll(request) {
+                Ok(Enrollment::Trusted(public_key)) => {
+                    println!("Enrolled public key: {}", public_key);
+                    vec![ENROLLED]
+                }
+                Ok(Enrollment::Pending(public_key)) => {
+                    println!(
+                        "Enrollment of public key {} is pending approval, move it to the trusted keys file to approve it",
+                        public_key
+                    );
+                    vec![PENDING]
+                }
+                Err(e) => {
+                    eprintln!("rejected enrollment: {}", e);
+                    vec![REJECTED]
+                }
+            },
+            _ => {
+                eprintln!("unrecognized prekey service message");
+                return;
+            }
+        };
+
+        self.router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(Message {
+                onward_route: msg.return_route,
+                return_route: Route {
+                    addresses: vec![PreKeyServer::address()],
+                },
+                message_type: MessageType::Payload,
+                message_body: reply,
+                extensions: vec![],
+            })))
+            .expect("failed to send prekey service reply");
+    }
+
+    /// Count a request against the transport address it came from,
+    /// returns false once that source sent too many of them lately
+    fn may_answer(&mut self, return_route: &Route) -> bool {
+        let mut source = vec![];
+        if let Some(address) = return_route.addresses.first() {
+            if address.encode(&mut source).is_err() {
+                return false;
+            }
+        }
+        let now = Instant::now();
+        if self.sources.len() >= MAX_SOURCES && !self.sources.contains_key(&source) {
+            self.sources
+                .retain(|_, (since, _)| now.duration_since(*since) < REQUEST_WINDOW);
+            if self.sources.len() >= MAX_SOURCES {
+                return false;
+            }
+        }
+        let (since, count) = self.sources.entry(source).or_insert((now, 0));
+        if now.duration_since(*since) >= REQUEST_WINDOW {
+            *since = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= MAX_REQUESTS_PER_SOURCE
+    }
+
+    /// Whether the key enrolled, waits for the operator's approval or is unknown
+    fn status(&self, public_key: &[u8]) -> u8 {
+        let trusted = match &self.allow_list {
+            Some(allow_list) => allow_list
+                .lock()
+                .unwrap()
+                .authorize(&PublicKey::new(public_key.to_vec())),
+            None => true,
+        };
+        if trusted {
+            ENROLLED
+        } else if self.pending.contains(&hex::encode(public_key)) {
+            PENDING
+        } else {
+            REJECTED
+        }
+    }
+
+    /// Verify the enrollment, then trust the enrollee's key if the enrollment token
+    /// vouches for it and hold it for the operator's approval otherwise
+    fn enroll(&mut self, data: &[u8]) -> Result<Enrollment, String> {
+        let (tag, request) = if data.len() == ENROLLMENT_TAG_SIZE + EnrollmentRequest::SIZE {
+            let (tag, request) = data.split_at(ENROLLMENT_TAG_SIZE);
+            (Some(tag), request)
+        } else {
+            (None, data)
+        };
+        let authorized = match (&self.enrollment_token, tag) {
+            (Some(token), Some(tag)) => {
+                enrollment_tag(token, request)
+                    .verify(tag)
+                    .map_err(|_| "enrollment token mismatch".to_string())?;
+                true
+            }
+            _ => false,
+        };
+
+        let request = EnrollmentRequest::try_from(request).map_err(|e| e.to_string())?;
+        let completed_key_exchange = self
+            .store
+            .lock()
+            .unwrap()
+            .accept_enrollment(&request)
+            .map_err(|e| e.to_string())?;
+        let public_key = completed_key_exchange.remote_static_public_key;
+        let hex_key = hex::encode(public_key.as_ref());
+
+        // without an allow list every key may open channels already
+        let (allow_list, path) = match (&self.allow_list, &self.trusted_keys) {
+            (Some(allow_list), Some(path)) => (allow_list, path),
+            _ => return Ok(Enrollment::Trusted(hex_key)),
+        };
+        if !authorized {
+            if !self.pending.contains(&hex_key) {
+                if self.pending.len() >= MAX_PENDING_ENROLLMENTS {
+                    return Err("too many enrollments are pending approval".to_string());
+                }
+                append_key(&pending_keys(path), &hex_key)
+                    .map_err(|e| format!("failed to update pending keys: {}", e))?;
+                self.pending.insert(hex_key.clone());
+            }
+            return Ok(Enrollment::Pending(hex_key));
+        }
+
+        allow_list.lock().unwrap().add(public_key.as_ref());
+        append_key(path, &hex_key).map_err(|e| format!("failed to update trusted keys: {}", e))?;
+        Ok(Enrollment::Trusted(hex_key))
+    }
+}
+
+/// The file enrollments wait in for the operator's approval, next to the trusted keys file
+fn pending_keys(trusted_keys: &Path) -> PathBuf {
+    let mut path = trusted_keys.as_os_str().to_owned();
+    path.push(".pending");
+    PathBuf::from(path)
+}
+
+fn append_key(path: &Path, public_key: &str) -> std::io::Result<()> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", public_key))
+}
+
+/// HMAC-SHA256 of an enrollment request keyed with the enrollment token
+fn enrollment_tag(token: &[u8], request: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(token).expect("HMAC takes keys of any length");
+    mac.update(request);
+    mac
+}
+
+/// Enrolls this device's identity key with a sink's prekey service: it fetches a bundle,
+/// checks it is signed by the sink's key and sends back the enrollment.
+///
+/// Unanswered requests are sent again with backoff until `ENROLLMENT_TIMEOUT`. An enrollment
+/// pending the operator's approval is checked on every `APPROVAL_POLL_INTERVAL`.
+pub struct EnrolleeWorker {
+    vault: Arc<Mutex<dyn X3dhVault>>,
+    identity_key: Option<Arc<Box<dyn Secret>>>,
+    public_key_sink: Option<PublicKey>,
+    enrollment_token: Option<Zeroizing<Vec<u8>>>,
+    route: Route,
+    router_tx: Sender<OckamCommand>,
+    rx: Receiver<OckamCommand>,
+    /// The enrolled key, once the enrollment is sent
+    public_key: Option<PublicKey>,
+    /// The request waiting for an answer, how often it was sent again
+    /// and when it is sent next
+    outstanding: Option<Vec<u8>>,
+    retries: u32,
+    retry_interval: Duration,
+    retry_at: Instant,
+    deadline: Instant,
+}
+
+impl EnrolleeWorker {
+    pub fn initialize(
+        config: &Config,
+        vault: Arc<Mutex<dyn X3dhVault>>,
+        identity_key: Option<Arc<Box<dyn Secret>>>,
+        router_tx: Sender<OckamCommand>,
+    ) -> Result<EnrolleeWorker, String> {
+        let (tx, rx) = mpsc::channel();
+
+        // register the worker with the router
+        router_tx
+            .send(OckamCommand::Router(RouterCommand::Register(
+                AddressType::Worker,
+                tx,
+            )))
+            .expect("enrollee worker registration failed");
+
+        let public_key_sink = match config.public_key_sink() {
+            Some(key) => Some(PublicKey::new(
+                hex::decode(key).map_err(|_| "invalid sink public key".to_string())?,
+            )),
+            None => None,
+        };
+        let mut route = config.onward_route().unwrap_or(Route { addresses: vec![] });
+        route.addresses.push(PreKeyServer::address());
+
+        let now = Instant::now();
+        let mut worker = EnrolleeWorker {
+            vault,
+            identity_key,
+            public_key_sink,
+            enrollment_token: config.enrollment_token()?,
+            route,
+            router_tx,
+            rx,
+            public_key: None,
+            outstanding: None,
+            retries: 0,
+            retry_interval: FIRST_RETRY_INTERVAL,
+            retry_at: now,
+            deadline: now,
+        };
+        worker.ask(vec![BUNDLE_REQUEST]);
+        Ok(worker)
+    }
+
+    /// Send a request and wait for its answer
+    fn ask(&mut self, body: Vec<u8>) {
+        self.send(body.clone());
+        self.wait(body, FIRST_RETRY_INTERVAL, ENROLLMENT_TIMEOUT);
+    }
+
+    /// Send `body` after `interval` unless an answer arrives first,
+    /// and keep sending it until `timeout`
+    fn wait(&mut self, body: Vec<u8>, interval: Duration, timeout: Duration) {
+        let now = Instant::now();
+        self.outstanding = Some(body);
+        self.retries = 0;
+        self.retry_interval = interval;
+        self.retry_at = now + interval;
+        self.deadline = now + timeout;
+    }
+
+    /// Send the outstanding request again when it is due,
+    /// returns false once it is given up on
+    fn retry(&mut self) -> bool {
+        let body = match &self.outstanding {
+            Some(body) => body.clone(),
+            None => return true,
+        };
+        let now = Instant::now();
+        if now >= self.deadline {
+            eprintln!("gave up waiting on the prekey service, the enrollment is not complete");
+            return false;
+        }
+        if now >= self.retry_at {
+            self.send(body);
+            self.retries += 1;
+            if self.retry_interval < MAX_RETRY_INTERVAL {
+                self.retry_interval = std::cmp::min(self.retry_interval * 2, MAX_RETRY_INTERVAL);
+            }
+            self.retry_at = now + self.retry_interval;
+        }
+        true
+    }
+
+    fn status_request(&self) -> Vec<u8> {
+        let mut body = vec![STATUS];
+        if let Some(public_key) = &self.public_key {
+            body.extend_from_slice(public_key.as_ref());
+        }
+        body
+    }
+
+    fn outstanding(&self) -> Option<u8> {
+        self.outstanding
+            .as_ref()
+            .and_then(|body| body.first().copied())
+    }
+
+    fn send(&self, body: Vec<u8>) {
+        self.router_tx
+            .send(OckamCommand::Router(RouterCommand::SendMessage(Message {
+                onward_route: self.route.clone(),
+                return_route: Route {
+                    addresses: vec![RouterAddress::worker_router_address_from_str(
+                        ENROLLEE_ADDRESS,
+                    )
+                    .unwrap()],
+                },
+                message_type: MessageType::Payload,
+                message_body: body,
+                extensions: vec![],
+            })))
+            .expect("failed to send to prekey service");
+    }
+
+    fn enroll(&mut self, bundle: &[u8]) -> Result<(), String> {
+        let bundle = PreKeyBundle::try_from(bundle).map_err(|e| e.to_string())?;
+        let sink_key = hex::encode(bundle.identity_key().as_ref());
+        match &self.public_key_sink {
+            Some(expected) if expected.as_ref() != bundle.identity_key().as_ref() => {
+                return Err(format!(
+                    "prekey bundle is signed by unexpected key {}",
+                    sink_key
+                ));
+            }
+            Some(_) => {}
+            None => println!("Sink public key: {}", sink_key),
+        }
+
+        let (request, completed_key_exchange) =
+            EnrollmentRequest::create(self.vault.clone(), self.identity_key.clone(), &bundle)
+                .map_err(|e| e.to_string())?;
+        self.public_key = Some(
+            self.vault
+                .lock()
+                .unwrap()
+                .secret_public_key_get(&completed_key_exchange.local_static_secret)
+                .map_err(|e| e.to_string())?,
+        );
+        let request = request.to_bytes();
+        let mut body = vec![ENROLLMENT];
+        if let Some(token) = &self.enrollment_token {
+            body.extend_from_slice(&enrollment_tag(token, &request).finalize().into_bytes());
+        }
+        body.extend_from_slice(&request);
+        self.ask(body);
+        Ok(())
+    }
+
+    /// Returns false once the enrollment is over
+    pub fn poll(&mut self) -> bool {
+        if !self.retry() {
+            return false;
+        }
+        match self.rx.try_recv() {
+            Ok(OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg))) => {
+                match msg.message_body.split_first() {
+                    // a bundle answering a request sent again is not used
+                    Some((&BUNDLE, _)) if self.outstanding() != Some(BUNDLE_REQUEST) => true,
+                    Some((&BUNDLE, bundle)) => match self.enroll(bundle) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            eprintln!("failed to enroll: {}", e);
+                            false
+                        }
+                    },
+                    Some((&ENROLLED, _)) => {
+                        println!("Enrolled with sink");
+                        false
+                    }
+                    // the enrollment sent again may be rejected because the sink already
+                    // accepted it, ask how it went
+                    Some((&REJECTED, _))
+                        if self.outstanding() == Some(ENROLLMENT) && self.retries > 0 =>
+                    {
+                        let status = self.status_request();
+                        self.ask(status);
+                        true
+                    }
+                    Some((&REJECTED, _)) => {
+                        eprintln!("sink rejected the enrollment");
+                        false
+                    }
+                    Some((&PENDING, _)) if self.outstanding() == Some(STATUS) => true,
+                    Some((&PENDING, _)) => {
+                        println!("Enrollment is pending the sink operator's approval");
+                        let status = self.status_request();
+                        self.wait(status, APPROVAL_POLL_INTERVAL, APPROVAL_TIMEOUT);
+                        true
+                    }
+                    _ => {
+                        eprintln!("unrecognized prekey service message");
+                        true
+                    }
+                }
+            }
+            Ok(cmd) => {
+                eprintln!("unrecognized worker command: {:?}", cmd);
+                true
+            }
+            Err(TryRecvError::Empty) => true,
+            Err(e) => {
+                eprintln!("failed to recv worker rx: {:?}", e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_vault_file::ockam_vault::types::{
+        SecretAttributes, SecretPersistence, SecretType, CURVE25519_SECRET_LENGTH,
+    };
+    use ockam_vault_file::ockam_vault::SecretVault;
+    use ockam_vault_file::FilesystemVault;
+
+    fn vault(name: &str) -> Arc<Mutex<FilesystemVault>> {
+        let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        Arc::new(Mutex::new(FilesystemVault::new(path).unwrap()))
+    }
+
+    fn server(name: &str, token: &[u8]) -> (PreKeyServer, Receiver<OckamCommand>) {
+        let vault = vault(name);
+        let identity_key = vault
+            .lock()
+            .unwrap()
+            .secret_generate(SecretAttributes {
+                persistence: SecretPersistence::Persistent,
+                stype: SecretType::Curve25519,
+                length: CURVE25519_SECRET_LENGTH,
+            })
+            .unwrap();
+        let store = PreKeyStore::new(vault, Arc::new(identity_key)).unwrap();
+        let trusted_keys =
+            std::env::temp_dir().join(format!("{}_{}.keys", name, std::process::id()));
+        fs::write(&trusted_keys, "").unwrap();
+        let _ = fs::remove_file(pending_keys(&trusted_keys));
+
+        let (router_tx, router_rx) = mpsc::channel();
+        let server = PreKeyServer {
+            store: Arc::new(Mutex::new(store)),
+            allow_list: Some(Arc::new(Mutex::new(AllowList::default()))),
+            trusted_keys: Some(trusted_keys),
+            enrollment_token: Some(Zeroizing::new(token.to_vec())),
+            pending: BTreeSet::new(),
+            sources: HashMap::new(),
+            router_tx,
+        };
+        (server, router_rx)
+    }
+
+    fn message(source: &str, body: Vec<u8>) -> Message {
+        Message {
+            onward_route: Route {
+                addresses: vec![PreKeyServer::address()],
+            },
+            return_route: Route {
+                addresses: vec![RouterAddress::udp_router_address_from_str(source).unwrap()],
+            },
+            message_type: MessageType::Payload,
+            message_body: body,
+            extensions: vec![],
+        }
+    }
+
+    /// Enroll a fresh key, authenticated with `token` if any, and return the server's answer
+    /// along with the enrolled key
+    fn enroll(
+        server: &mut PreKeyServer,
+        router_rx: &Receiver<OckamCommand>,
+        token: Option<&[u8]>,
+    ) -> (u8, PublicKey) {
+        let bundle = server.store.lock().unwrap().issue_bundle().unwrap();
+        let vault_e: Arc<Mutex<dyn X3dhVault>> = vault("ockamd_prekey_enrollee");
+        let (request, enrollee) =
+            EnrollmentRequest::create(vault_e.clone(), None, &bundle).unwrap();
+        let enrollee_key = vault_e
+            .lock()
+            .unwrap()
+            .secret_public_key_get(&enrollee.local_static_secret)
+            .unwrap();
+
+        let request = request.to_bytes();
+        let mut body = vec![ENROLLMENT];
+        if let Some(token) = token {
+            body.extend_from_slice(&enrollment_tag(token, &request).finalize().into_bytes());
+        }
+        body.extend_from_slice(&request);
+        server.handle(message("127.0.0.1:4051", body));
+        match router_rx.try_recv() {
+            Ok(OckamCommand::Router(RouterCommand::SendMessage(reply))) => {
+                (reply.message_body[0], enrollee_key)
+            }
+            _ => panic!("no reply from the prekey service"),
+        }
+    }
+
+    #[test]
+    fn enrollments_need_the_token() {
+        let (mut server, router_rx) = server("ockamd_prekey_token", b"enrollment token");
+        let trusted_keys = server.trusted_keys.clone().unwrap();
+        let allow_list = server.allow_list.clone().unwrap();
+
+        // without the token the key waits for the operator
+        let (reply, key) = enroll(&mut server, &router_rx, None);
+        assert_eq!(reply, PENDING);
+        assert!(!allow_list.lock().unwrap().authorize(&key));
+        assert_eq!(fs::read_to_string(&trusted_keys).unwrap(), "");
+        assert_eq!(
+            fs::read_to_string(pending_keys(&trusted_keys)).unwrap(),
+            format!("{}\n", hex::encode(key.as_ref()))
+        );
+
+        let (reply, key) = enroll(&mut server, &router_rx, Some(b"guessed token"));
+        assert_eq!(reply, REJECTED);
+        assert!(!allow_list.lock().unwrap().authorize(&key));
+
+        let (reply, key) = enroll(&mut server, &router_rx, Some(b"enrollment token"));
+        assert_eq!(reply, ENROLLED);
+        assert!(allow_list.lock().unwrap().authorize(&key));
+        assert_eq!(
+            fs::read_to_string(&trusted_keys).unwrap(),
+            format!("{}\n", hex::encode(key.as_ref()))
+        );
+        fs::remove_file(pending_keys(&trusted_keys)).unwrap();
+        fs::remove_file(trusted_keys).unwrap();
+    }
+
+    #[test]
+    fn status_of_enrollments() {
+        let (mut server, router_rx) = server("ockamd_prekey_status", b"token");
+        let allow_list = server.allow_list.clone().unwrap();
+        let (reply, key) = enroll(&mut server, &router_rx, None);
+        assert_eq!(reply, PENDING);
+
+        let mut status = vec![STATUS];
+        status.extend_from_slice(key.as_ref());
+        assert_eq!(server.status(key.as_ref()), PENDING);
+        allow_list.lock().unwrap().add(key.as_ref());
+        assert_eq!(server.status(key.as_ref()), ENROLLED);
+        assert_eq!(server.status(&[7u8; 32]), REJECTED);
+
+        server.handle(message("127.0.0.1:4054", status));
+        match router_rx.try_recv() {
+            Ok(OckamCommand::Router(RouterCommand::SendMessage(reply))) => {
+                assert_eq!(reply.message_body, vec![ENROLLED])
+            }
+            _ => panic!("no reply from the prekey service"),
+        }
+        let trusted_keys = server.trusted_keys.unwrap();
+        fs::remove_file(pending_keys(&trusted_keys)).unwrap();
+        fs::remove_file(trusted_keys).unwrap();
+    }
+
+    #[test]
+    fn enrollee_asks_again_and_waits_for_approval() {
+        let (router_tx, router_rx) = mpsc::channel();
+        let (tx, rx) = mpsc::channel();
+        let now = Instant::now();
+        let mut worker = EnrolleeWorker {
+            vault: vault("ockamd_prekey_retry"),
+            identity_key: None,
+            public_key_sink: None,
+            enrollment_token: None,
+            route: Route { addresses: vec![] },
+            router_tx,
+            rx,
+            public_key: Some(PublicKey::new(vec![7u8; 32])),
+            outstanding: None,
+            retries: 0,
+            retry_interval: FIRST_RETRY_INTERVAL,
+            retry_at: now,
+            deadline: now,
+        };
+        let sent = || match router_rx.try_recv() {
+            Ok(OckamCommand::Router(RouterCommand::SendMessage(msg))) => Some(msg.message_body),
+            _ => None,
+        };
+        let reply = |body: Vec<u8>| {
+            tx.send(OckamCommand::Worker(WorkerCommand::ReceiveMessage(
+                message("127.0.0.1:4055", body),
+            )))
+            .unwrap()
+        };
+
+        worker.ask(vec![BUNDLE_REQUEST]);
+        assert_eq!(sent(), Some(vec![BUNDLE_REQUEST]));
+        assert!(worker.poll());
+        assert_eq!(sent(), None);
+
+        // unanswered requests are sent again with backoff
+        worker.retry_at = Instant::now();
+        assert!(worker.poll());
+        assert_eq!(sent(), Some(vec![BUNDLE_REQUEST]));
+        assert_eq!(worker.retry_interval, FIRST_RETRY_INTERVAL * 2);
+
+        // a pending enrollment is checked on instead of given up
+        worker.outstanding = Some(vec![ENROLLMENT]);
+        reply(vec![PENDING]);
+        assert!(worker.poll());
+        assert_eq!(sent(), None);
+        worker.retry_at = Instant::now();
+        assert!(worker.poll());
+        let mut status = vec![STATUS];
+        status.extend_from_slice(&[7u8; 32]);
+        assert_eq!(sent(), Some(status));
+        reply(vec![PENDING]);
+        assert!(worker.poll());
+        reply(vec![ENROLLED]);
+        assert!(!worker.poll());
+
+        // and given up on past the deadline
+        worker.ask(vec![BUNDLE_REQUEST]);
+        worker.deadline = Instant::now();
+        assert!(!worker.poll());
+    }
+
+    #[test]
+    fn requests_are_limited_per_source() {
+        let (mut server, router_rx) = server("ockamd_prekey_flood", b"token");
+        for _ in 0..MAX_REQUESTS_PER_SOURCE {
+            server.handle(message("127.0.0.1:4052", vec![BUNDLE_REQUEST]));
+            assert!(router_rx.try_recv().is_ok());
+        }
+        server.handle(message("127.0.0.1:4052", vec![BUNDLE_REQUEST]));
+        assert!(router_rx.try_recv().is_err());
+
+        // other sources are still answered
+        server.handle(message("127.0.0.1:4053", vec![BUNDLE_REQUEST]));
+        assert!(router_rx.try_recv().is_ok());
+        fs::remove_file(server.trusted_keys.unwrap()).unwrap();
+    }
+}
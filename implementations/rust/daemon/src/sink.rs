@@ -1,6 +1,7 @@
 use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 
 use crate::config::{AddonKind, Config};
+use crate::prekey::PreKeyServer;
 use attohttpc::post;
 use ockam::message::{
     Address, AddressType, Codec, Message as OckamMessage, Message, MessageType, Route,
@@ -23,17 +24,20 @@ pub struct SinkWorker {
     work_fn: WorkFn,
     config: Config,
     route: Option<Route>,
+    prekey_server: Option<PreKeyServer>,
 }
 
 impl SinkWorker {
     pub fn initialize(
         config: &Config,
         worker_addr: RouterAddress,
+        prekey_server: Option<PreKeyServer>,
         router_tx: Sender<OckamCommand>,
         channel_tx: Sender<OckamCommand>,
     ) -> Result<SinkWorker, String> {
         let worker = SinkWorker::new(
             worker_addr,
+            prekey_server,
             router_tx,
             channel_tx.clone(),
             config.clone(),
@@ -70,6 +74,7 @@ impl SinkWorker {
 
     pub fn new(
         addr: RouterAddress,
+        prekey_server: Option<PreKeyServer>,
         router_tx: Sender<OckamCommand>,
         channel_tx: Sender<OckamCommand>,
         config: Config,
@@ -115,6 +120,7 @@ impl SinkWorker {
             config,
             work_fn,
             route: None,
+            prekey_server,
         }
     }
 
@@ -145,6 +151,13 @@ impl SinkWorker {
                 OckamCommand::Worker(WorkerCommand::ReceiveMessage(msg)) => {
                     match msg.message_type {
                         MessageType::Payload => {
+                            // the prekey service shares the sink's worker registration
+                            if let Some(prekey_server) = &mut self.prekey_server {
+                                if msg.onward_route.addresses[0] == PreKeyServer::address() {
+                                    prekey_server.handle(msg);
+                                    return true;
+                                }
+                            }
                             // Confirm address
                             if self.addr != msg.onward_route.addresses[0] {
                                 println!("Received bad worker address");
@@ -161,6 +174,16 @@ impl SinkWorker {
                                 true
                             }
                         }
+                        MessageType::ChannelClose => {
+                            if let Some(closed) = msg.return_route.addresses.first() {
+                                println!("Channel closed: {}", closed.address.as_string());
+                                if matches!(&self.route, Some(r) if r.addresses.first() == Some(closed))
+                                {
+                                    self.route = None;
+                                }
+                            }
+                            true
+                        }
                         _ => unimplemented!(),
                     }
                 }
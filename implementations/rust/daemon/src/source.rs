@@ -2,7 +2,6 @@ use std::sync::mpsc::{self, Receiver, Sender};
 
 use crate::config::Config;
 
-use hex::encode;
 use ockam::message::{
     Address, AddressType, Codec, Message as OckamMessage, Message, MessageType, Route,
     RouterAddress,
@@ -93,23 +92,11 @@ impl StdinWorker {
                 .unwrap();
         self.route.addresses.push(service_address);
 
-        return match RouterAddress::decode(&m.message_body) {
-            Ok((rcc, mb)) => {
-                if let Some(rpk) = self.config.public_key_sink() {
-                    if rpk == encode(mb) {
-                        println!("keys agree");
-                        return Ok(());
-                    } else {
-                        println!("keys conflict");
-                        return Err(
-                            "remote public key doesn't match expected, possible spoofing".into(),
-                        );
-                    }
-                }
-                Ok(())
-            }
+        // the sink's public key was checked by the channel manager during the key agreement
+        match RouterAddress::decode(&m.message_body) {
+            Ok(_) => Ok(()),
             _ => Err("receive channel: expected channel address in message body".into()),
-        };
+        }
     }
 
     pub fn poll(&mut self) -> bool {
@@ -122,6 +109,10 @@ impl StdinWorker {
                             Ok(()) => {}
                             Err(s) => panic!(s),
                         },
+                        MessageType::ChannelClose => {
+                            println!("secure channel to sink closed");
+                            return false;
+                        }
                         _ => unimplemented!(),
                     }
                 }
@@ -141,6 +132,7 @@ impl StdinWorker {
                         return_route: Route { addresses: vec![] },
                         message_type: MessageType::Payload,
                         message_body: s.as_bytes().to_vec(),
+                        extensions: vec![],
                     },
                 )))
                 .expect("failed to send input data to node");
@@ -28,12 +28,16 @@ pub const AES128_KEYSIZE: usize = 16;
 pub const AES256_KEYSIZE: usize = 32;
 /// The number of bytes in AES-GCM tag
 pub const AES_GCM_TAGSIZE: usize = 16;
+/// The number of bytes in ChaCha20-Poly1305 key
+pub const CHACHA20POLY1305_KEYSIZE: usize = 32;
+/// The number of bytes in ChaCha20-Poly1305 tag
+pub const CHACHA20POLY1305_TAGSIZE: usize = 16;
 
 /// A KeyExchange implements these methods
 /// A KeyExchange implementation should wrap a vault instance
 pub trait KeyExchange {
     /// Returns Noise protocol name
-    fn get_protocol_name(&self) -> &'static [u8];
+    fn get_protocol_name(&self) -> Vec<u8>;
 
     /// Create a new `HandshakeState` starting with the prologue
     fn prologue(&mut self) -> OckamResult<()>;
@@ -72,6 +76,11 @@ pub enum CipherSuite {
     Curve25519AesGcmSha256,
     /// P256 Aes128-GCM Sha256
     P256Aes128GcmSha256,
+    /// Curve25519 ChaCha20-Poly1305 Sha256
+    Curve25519ChaChaPolySha256,
+    /// Curve25519 hybrid with ML-KEM-768 Aes256-GCM Sha256, the shared secret of an
+    /// ML-KEM encapsulation is mixed into the keys along with the DH results
+    Curve25519MlKem768AesGcmSha256,
 }
 
 /// Instantiate a stateful key exchange vault instance
@@ -95,6 +104,8 @@ pub struct CompletedKeyExchange {
     pub local_static_secret: Arc<Box<dyn Secret>>,
     /// The long term static public key from remote party
     pub remote_static_public_key: PublicKey,
+    /// The payloads the remote party attached to its handshake messages, in order
+    pub remote_payloads: Vec<Vec<u8>>,
 }
 
 /// Errors thrown by Key exchange
@@ -9,6 +9,7 @@ pub enum Error {
     InvalidState,
     MessageLenMismatch,
     InvalidHash,
+    UnknownPreKey,
 }
 
 impl Error {
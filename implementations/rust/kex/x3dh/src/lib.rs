@@ -18,6 +18,12 @@ use subtle::ConstantTimeEq;
 extern crate arrayref;
 
 pub mod error;
+mod prekey;
+pub use prekey::{
+    EnrollmentRequest, PreKeyStore, PreKeyVault, DEFAULT_MAX_ISSUED_ONE_TIME_PREKEYS,
+    DEFAULT_PREKEY_GRACE_PERIOD, DEFAULT_SIGNED_PREKEY_LIFETIME, ONE_TIME_PREKEY_LABEL_PREFIX,
+    SIGNED_PREKEY_LABEL,
+};
 
 /// Represents and (X)EdDSA or ECDSA signature
 /// from Ed25519 or P-256
@@ -42,30 +48,53 @@ impl From<&[u8; 64]> for Signature {
     }
 }
 
+impl TryFrom<&[u8]> for Signature {
+    type Error = OckamError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() != 64 {
+            return Err(Error::MessageLenMismatch.into());
+        }
+        Ok(Signature(*array_ref![data, 0, 64]))
+    }
+}
+
 impl std::fmt::Debug for Signature {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "Signature {{ {} }}", hex::encode(self.0.as_ref()))
     }
 }
 
-/// Represents all the keys and signature to send to an enrollee
+/// Represents all the keys and signature to send to an enrollee. A bundle without a
+/// one-time prekey is sent as all zeros in its place, and the key agreement skips DH4
 #[derive(Clone, Debug)]
 pub struct PreKeyBundle {
     identity_key: PublicKey,
     signed_prekey: PublicKey,
     signature_prekey: Signature,
-    one_time_prekey: PublicKey,
+    one_time_prekey: Option<PublicKey>,
 }
 
 impl PreKeyBundle {
     const SIZE: usize = 32 + 32 + 64 + 32;
+
+    /// The identity key of the bundle's owner, which signed the signed prekey
+    pub fn identity_key(&self) -> &PublicKey {
+        &self.identity_key
+    }
+
+    /// The one-time prekey, if the owner had one left to hand out
+    pub fn one_time_prekey(&self) -> Option<&PublicKey> {
+        self.one_time_prekey.as_ref()
+    }
+
     /// Convert the prekey bundle to a byte array
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut output = Vec::new();
         output.extend_from_slice(self.identity_key.as_ref());
         output.extend_from_slice(self.signed_prekey.as_ref());
         output.extend_from_slice(self.signature_prekey.0.as_ref());
-        output.extend_from_slice(self.one_time_prekey.as_ref());
+        encode_one_time_prekey(&self.one_time_prekey, &mut output);
         output
     }
 }
@@ -80,7 +109,7 @@ impl TryFrom<&[u8]> for PreKeyBundle {
         let identity_key = PublicKey::new(array_ref![data, 0, 32].to_vec());
         let signed_prekey = PublicKey::new(array_ref![data, 32, 32].to_vec());
         let signature_prekey = Signature(*array_ref![data, 64, 64]);
-        let one_time_prekey = PublicKey::new(array_ref![data, 128, 32].to_vec());
+        let one_time_prekey = decode_one_time_prekey(array_ref![data, 128, 32]);
         Ok(Self {
             identity_key,
             signed_prekey,
@@ -90,12 +119,29 @@ impl TryFrom<&[u8]> for PreKeyBundle {
     }
 }
 
+/// Append a one-time prekey, or zeros when there is none
+pub(crate) fn encode_one_time_prekey(one_time_prekey: &Option<PublicKey>, output: &mut Vec<u8>) {
+    match one_time_prekey {
+        Some(key) => output.extend_from_slice(key.as_ref()),
+        None => output.extend_from_slice(&[0u8; 32]),
+    }
+}
+
+/// Read a one-time prekey written by `encode_one_time_prekey`
+pub(crate) fn decode_one_time_prekey(data: &[u8; 32]) -> Option<PublicKey> {
+    if data.iter().all(|b| *b == 0) {
+        None
+    } else {
+        Some(PublicKey::new(data.to_vec()))
+    }
+}
+
 #[derive(Debug)]
 enum ResponderState {
-    /// Create a PreKey Bundle
-    GenerateBundle,
     /// Expect an enrollment message from this EIK
     SetEnrollmentKey,
+    /// Create a PreKey Bundle
+    GenerateBundle,
     /// Verify an enrollment message
     VerifyEnrollment,
     /// Done
@@ -125,12 +171,17 @@ impl<D> X3dhVault for D where
 
 /// The responder of X3DH creates a prekey bundle that can be used to establish a shared
 /// secret key with another party that can use
+///
+/// Messages follow the secure channel key agreement: the initiator's ephemeral identity key,
+/// then the prekey bundle followed by the responder's payload, then the enrollment message.
 pub struct X3dhResponder {
     // Identity key and signer prekey are wrapped in Arc because they are possible shared
     // among threads/modules
     identity_key: Option<Arc<Box<dyn Secret>>>,
     signed_prekey: Option<Arc<Box<dyn Secret>>>,
     one_time_prekey: Option<Box<dyn Secret>>,
+    prekey_store: Option<Arc<Mutex<PreKeyStore>>>,
+    bundle: Option<PreKeyBundle>,
     expected_enrollment_key: Option<PublicKey>,
     state: ResponderState,
     vault: Arc<Mutex<dyn X3dhVault>>,
@@ -138,19 +189,32 @@ pub struct X3dhResponder {
 }
 
 impl X3dhResponder {
-    fn new(v: Arc<Mutex<dyn X3dhVault>>, identity_key: Option<Arc<Box<dyn Secret>>>) -> Self {
+    fn new(
+        v: Arc<Mutex<dyn X3dhVault>>,
+        identity_key: Option<Arc<Box<dyn Secret>>>,
+        prekey_store: Option<Arc<Mutex<PreKeyStore>>>,
+    ) -> Self {
         Self {
             identity_key,
             signed_prekey: None,
             one_time_prekey: None,
+            prekey_store,
+            bundle: None,
             expected_enrollment_key: None,
             completed_key_exchange: None,
-            state: ResponderState::GenerateBundle,
+            state: ResponderState::SetEnrollmentKey,
             vault: v,
         }
     }
 
     fn prologue(&mut self) -> OckamResult<()> {
+        self.bundle = None;
+        self.expected_enrollment_key = None;
+        self.completed_key_exchange = None;
+        // bundles come from the prekey store when there is one
+        if self.prekey_store.is_some() {
+            return Ok(());
+        }
         let mut vault = self.vault.lock().unwrap();
         let p_atts = SecretAttributes {
             persistence: SecretPersistence::Persistent,
@@ -167,8 +231,6 @@ impl X3dhResponder {
         }
         self.signed_prekey = Some(Arc::new(vault.secret_generate(p_atts)?));
         self.one_time_prekey = Some(vault.secret_generate(e_atts)?);
-        self.expected_enrollment_key = None;
-        self.completed_key_exchange = None;
         Ok(())
     }
 }
@@ -180,6 +242,8 @@ impl std::fmt::Debug for X3dhResponder {
             r#"X3dhResponder {{ identity_key: {:?},
                                       signed_prekey: {:?},
                                       one_time_prekey: {:?},
+                                      prekey_store: {:?},
+                                      bundle: {:?},
                                       expected_enrollment_key: {:?},
                                       state: {:?},
                                       vault,
@@ -187,6 +251,8 @@ impl std::fmt::Debug for X3dhResponder {
             self.identity_key,
             self.signed_prekey,
             self.one_time_prekey,
+            self.prekey_store,
+            self.bundle,
             self.expected_enrollment_key,
             self.state,
             self.completed_key_exchange
@@ -198,6 +264,7 @@ impl std::fmt::Debug for X3dhResponder {
 enum InitiatorState {
     GenerateEphemeralIdentityKey,
     ProcessPreKeyBundle,
+    SendEnrollment,
     Done,
 }
 
@@ -206,6 +273,7 @@ enum InitiatorState {
 pub struct X3dhInitiator {
     ephemeral_identity_key: Option<Box<dyn Secret>>,
     prekey_bundle: Option<PreKeyBundle>,
+    enrollment_message: Option<Vec<u8>>,
     state: InitiatorState,
     vault: Arc<Mutex<dyn X3dhVault>>,
     completed_key_exchange: Option<CompletedKeyExchange>,
@@ -217,6 +285,7 @@ impl X3dhInitiator {
         Self {
             ephemeral_identity_key: None,
             prekey_bundle: None,
+            enrollment_message: None,
             state: InitiatorState::GenerateEphemeralIdentityKey,
             vault: v,
             completed_key_exchange: None,
@@ -233,6 +302,7 @@ impl X3dhInitiator {
         };
         self.ephemeral_identity_key = Some(vault.secret_generate(p_atts)?);
         self.prekey_bundle = None;
+        self.enrollment_message = None;
         self.completed_key_exchange = None;
         Ok(())
     }
@@ -255,126 +325,93 @@ impl std::fmt::Debug for X3dhInitiator {
 impl KeyExchanger for X3dhResponder {
     fn process(&mut self, data: &[u8]) -> OckamResult<Vec<u8>> {
         match self.state {
-            ResponderState::GenerateBundle => {
-                self.prologue()?;
-                let mut vault = self.vault.lock().unwrap();
-                let identity_secret_key = self
-                    .identity_key
-                    .as_ref()
-                    .ok_or(Error::InvalidState.into())?;
-                let signed_prekey = self
-                    .signed_prekey
-                    .as_ref()
-                    .ok_or(Error::InvalidState.into())?;
-                let one_time_prekey = self
-                    .one_time_prekey
-                    .as_ref()
-                    .ok_or(Error::InvalidState.into())?;
-                let signed_prekey_pub = vault.secret_public_key_get(signed_prekey)?;
-                let signature = vault.sign(identity_secret_key, signed_prekey_pub.as_ref())?;
-                let identity_key = vault.secret_public_key_get(identity_secret_key)?;
-                let one_time_prekey_pub = vault.secret_public_key_get(one_time_prekey)?;
-                let bundle = PreKeyBundle {
-                    identity_key,
-                    signed_prekey: signed_prekey_pub,
-                    signature_prekey: Signature(signature),
-                    one_time_prekey: one_time_prekey_pub,
-                };
-                self.state = ResponderState::SetEnrollmentKey;
-                Ok(bundle.to_bytes())
-            }
             ResponderState::SetEnrollmentKey => {
+                self.prologue()?;
                 if data.len() != 32 {
                     return Err(Error::MessageLenMismatch.into());
                 }
                 self.expected_enrollment_key =
                     Some(PublicKey::new(array_ref![data, 0, 32].to_vec()));
-                self.state = ResponderState::VerifyEnrollment;
+                self.state = ResponderState::GenerateBundle;
                 Ok(vec![])
             }
+            ResponderState::GenerateBundle => {
+                let bundle = match &self.prekey_store {
+                    Some(prekey_store) => prekey_store.lock().unwrap().issue_bundle()?,
+                    None => {
+                        let mut vault = self.vault.lock().unwrap();
+                        let identity_secret_key = self
+                            .identity_key
+                            .as_ref()
+                            .ok_or(Error::InvalidState.into())?;
+                        let signed_prekey = self
+                            .signed_prekey
+                            .as_ref()
+                            .ok_or(Error::InvalidState.into())?;
+                        let one_time_prekey = self
+                            .one_time_prekey
+                            .as_ref()
+                            .ok_or(Error::InvalidState.into())?;
+                        let signed_prekey_pub = vault.secret_public_key_get(signed_prekey)?;
+                        let signature =
+                            vault.sign(identity_secret_key, signed_prekey_pub.as_ref())?;
+                        let identity_key = vault.secret_public_key_get(identity_secret_key)?;
+                        let one_time_prekey_pub = vault.secret_public_key_get(one_time_prekey)?;
+                        PreKeyBundle {
+                            identity_key,
+                            signed_prekey: signed_prekey_pub,
+                            signature_prekey: Signature::try_from(signature.as_ref())?,
+                            one_time_prekey: Some(one_time_prekey_pub),
+                        }
+                    }
+                };
+                let mut output = bundle.to_bytes();
+                output.extend_from_slice(data);
+                self.bundle = Some(bundle);
+                self.state = ResponderState::VerifyEnrollment;
+                Ok(output)
+            }
             ResponderState::VerifyEnrollment => {
-                debug_assert!(self.expected_enrollment_key.is_some());
-                if data.len() != ENROLLMENT_MSG_SIZE {
-                    return Err(Error::MessageLenMismatch.into());
-                }
-                let mut vault = self.vault.lock().unwrap();
-                let signed_prekey = self
-                    .signed_prekey
-                    .as_ref()
-                    .ok_or(Error::InvalidState.into())?;
-                let one_time_prekey = self
-                    .one_time_prekey
-                    .as_ref()
+                let eik = self
+                    .expected_enrollment_key
+                    .take()
                     .ok_or(Error::InvalidState.into())?;
-                let eik = self.expected_enrollment_key.as_ref().unwrap();
-                let id = vault.sha256(eik.as_ref())?;
-                if id.ct_eq(&data[32..64]).unwrap_u8() != 1 {
-                    return Err(Error::InvalidHash.into());
-                }
-                let ek = PublicKey::new(array_ref![data, 0, 32].to_vec());
-                let local_static_secret =
-                    self.identity_key.take().ok_or(Error::InvalidState.into())?;
-
-                let dh1 = vault.ec_diffie_hellman(signed_prekey, eik.as_ref())?;
-                let dh2 = vault.ec_diffie_hellman(&local_static_secret, ek.as_ref())?;
-                let dh3 = vault.ec_diffie_hellman(signed_prekey, ek.as_ref())?;
-                let dh4 = vault.ec_diffie_hellman(one_time_prekey, ek.as_ref())?;
-                let mut ikm_bytes = vec![0xFFu8; 32];
-                ikm_bytes.extend_from_slice(vault.secret_export(&dh1)?.as_ref());
-                ikm_bytes.extend_from_slice(vault.secret_export(&dh2)?.as_ref());
-                ikm_bytes.extend_from_slice(vault.secret_export(&dh3)?.as_ref());
-                ikm_bytes.extend_from_slice(vault.secret_export(&dh4)?.as_ref());
-
-                let ikm = vault.secret_import(
-                    &ikm_bytes,
-                    SecretAttributes {
-                        persistence: SecretPersistence::Ephemeral,
-                        stype: SecretType::Buffer,
-                        length: ikm_bytes.len(),
-                    },
-                )?;
-                let salt = vault.secret_import(
-                    &[0u8; 32],
-                    SecretAttributes {
-                        persistence: SecretPersistence::Ephemeral,
-                        stype: SecretType::Buffer,
-                        length: 32,
-                    },
-                )?;
-                let atts = SecretAttributes {
-                    persistence: SecretPersistence::Persistent,
-                    stype: SecretType::Aes,
-                    length: AES256_SECRET_LENGTH,
+                let completed_key_exchange = match &self.prekey_store {
+                    Some(prekey_store) => {
+                        let bundle = self.bundle.as_ref().ok_or(Error::InvalidState.into())?;
+                        prekey_store
+                            .lock()
+                            .unwrap()
+                            .accept_enrollment(&EnrollmentRequest {
+                                ephemeral_identity_key: eik,
+                                signed_prekey: bundle.signed_prekey.clone(),
+                                one_time_prekey: bundle.one_time_prekey.clone(),
+                                message: data.to_vec(),
+                            })?
+                    }
+                    None => {
+                        let mut vault = self.vault.lock().unwrap();
+                        let signed_prekey = self
+                            .signed_prekey
+                            .as_ref()
+                            .ok_or(Error::InvalidState.into())?;
+                        let one_time_prekey = self
+                            .one_time_prekey
+                            .as_ref()
+                            .ok_or(Error::InvalidState.into())?;
+                        let local_static_secret =
+                            self.identity_key.take().ok_or(Error::InvalidState.into())?;
+                        verify_enrollment(
+                            &mut *vault,
+                            local_static_secret,
+                            signed_prekey,
+                            Some(one_time_prekey),
+                            &eik,
+                            data,
+                        )?
+                    }
                 };
-
-                let mut keyrefs = vault.hkdf_sha256(&salt, CSUITE, Some(&ikm), vec![atts, atts])?;
-                let decrypt_key = keyrefs.pop().unwrap();
-                let encrypt_key = keyrefs.pop().unwrap();
-                let mut state_hash = vault.sha256(CSUITE)?.to_vec();
-                state_hash.append(&mut ikm_bytes);
-                let state_hash = vault.sha256(state_hash.as_slice())?;
-
-                let mut aad = data[..64].to_vec();
-                aad.extend_from_slice(CSUITE);
-                aad.extend_from_slice(&state_hash);
-                //TODO: get the channel address from the message somehow if needed
-                let plaintext = vault.aead_aes_gcm_decrypt(
-                    &decrypt_key,
-                    &data[64..],
-                    &data[..12],
-                    aad.as_slice(),
-                )?;
-                let ikb = PublicKey::new(array_ref![plaintext, 0, 32].to_vec());
-                let signature = array_ref![plaintext, 32, 64];
-                vault.verify(signature, eik.as_ref(), &plaintext[..32])?;
-
-                self.completed_key_exchange = Some(CompletedKeyExchange {
-                    h: state_hash,
-                    encrypt_key,
-                    decrypt_key,
-                    local_static_secret,
-                    remote_static_public_key: ikb,
-                });
+                self.completed_key_exchange = Some(completed_key_exchange);
                 self.state = ResponderState::Done;
                 Ok(vec![])
             }
@@ -391,6 +428,94 @@ impl KeyExchanger for X3dhResponder {
     }
 }
 
+/// Verify the enrollment message of the owner of `eik`, made with a bundle holding the public
+/// keys of `signed_prekey` and `one_time_prekey` if any, and return the completed key exchange
+pub(crate) fn verify_enrollment<V>(
+    vault: &mut V,
+    local_static_secret: Arc<Box<dyn Secret>>,
+    signed_prekey: &Box<dyn Secret>,
+    one_time_prekey: Option<&Box<dyn Secret>>,
+    eik: &PublicKey,
+    data: &[u8],
+) -> OckamResult<CompletedKeyExchange>
+where
+    V: SecretVault + AsymmetricVault + SymmetricVault + HashVault + VerifierVault + ?Sized,
+{
+    if data.len() != ENROLLMENT_MSG_SIZE {
+        return Err(Error::MessageLenMismatch.into());
+    }
+    let id = vault.sha256(eik.as_ref())?;
+    if id.ct_eq(&data[32..64]).unwrap_u8() != 1 {
+        return Err(Error::InvalidHash.into());
+    }
+    let ek = PublicKey::new(array_ref![data, 0, 32].to_vec());
+
+    let dh1 = vault.ec_diffie_hellman(signed_prekey, eik.as_ref())?;
+    let dh2 = vault.ec_diffie_hellman(&local_static_secret, ek.as_ref())?;
+    let dh3 = vault.ec_diffie_hellman(signed_prekey, ek.as_ref())?;
+    let mut ikm_bytes = vec![0xFFu8; 32];
+    ikm_bytes.extend_from_slice(vault.secret_export(&dh1)?.as_ref());
+    ikm_bytes.extend_from_slice(vault.secret_export(&dh2)?.as_ref());
+    ikm_bytes.extend_from_slice(vault.secret_export(&dh3)?.as_ref());
+    if let Some(one_time_prekey) = one_time_prekey {
+        let dh4 = vault.ec_diffie_hellman(one_time_prekey, ek.as_ref())?;
+        ikm_bytes.extend_from_slice(vault.secret_export(&dh4)?.as_ref());
+    }
+
+    let ikm = vault.secret_import(
+        &ikm_bytes,
+        SecretAttributes {
+            persistence: SecretPersistence::Ephemeral,
+            stype: SecretType::Buffer,
+            length: ikm_bytes.len(),
+        },
+    )?;
+    let salt = vault.secret_import(
+        &[0u8; 32],
+        SecretAttributes {
+            persistence: SecretPersistence::Ephemeral,
+            stype: SecretType::Buffer,
+            length: 32,
+        },
+    )?;
+    let atts = SecretAttributes {
+        persistence: SecretPersistence::Persistent,
+        stype: SecretType::Aes,
+        length: AES256_SECRET_LENGTH,
+    };
+
+    let mut keyrefs = vault.hkdf_sha256(&salt, CSUITE, Some(&ikm), vec![atts, atts])?;
+    let decrypt_key = keyrefs.pop().unwrap();
+    let encrypt_key = keyrefs.pop().unwrap();
+    let mut state_hash = vault.sha256(CSUITE)?.to_vec();
+    state_hash.append(&mut ikm_bytes);
+    let state_hash = vault.sha256(state_hash.as_slice())?;
+
+    let mut aad = data[..64].to_vec();
+    aad.extend_from_slice(CSUITE);
+    aad.extend_from_slice(&state_hash);
+    //TODO: get the channel address from the message somehow if needed
+    let plaintext =
+        vault.aead_aes_gcm_decrypt(&decrypt_key, &data[64..], &data[..12], aad.as_slice())?;
+    let ikb = PublicKey::new(array_ref![plaintext, 0, 32].to_vec());
+    let signature = array_ref![plaintext, 32, 64];
+    vault.verify(
+        signature,
+        eik.as_ref(),
+        SecretType::Curve25519,
+        &plaintext[..32],
+    )?;
+
+    Ok(CompletedKeyExchange {
+        h: state_hash,
+        encrypt_key,
+        decrypt_key,
+        local_static_secret,
+        remote_static_public_key: ikb,
+        remote_payloads: vec![],
+    })
+}
+
 impl KeyExchanger for X3dhInitiator {
     fn process(&mut self, data: &[u8]) -> OckamResult<Vec<u8>> {
         match self.state {
@@ -408,7 +533,11 @@ impl KeyExchanger for X3dhInitiator {
                 Ok(pubkey.as_ref().to_vec())
             }
             InitiatorState::ProcessPreKeyBundle => {
-                let prekey_bundle = PreKeyBundle::try_from(data)?;
+                // the bundle is followed by the responder's payload
+                if data.len() < PreKeyBundle::SIZE {
+                    return Err(Error::MessageLenMismatch.into());
+                }
+                let prekey_bundle = PreKeyBundle::try_from(&data[..PreKeyBundle::SIZE])?;
 
                 let mut vault = self.vault.lock().unwrap();
 
@@ -421,6 +550,7 @@ impl KeyExchanger for X3dhInitiator {
                 vault.verify(
                     prekey_bundle.signature_prekey.as_ref(),
                     prekey_bundle.identity_key.as_ref(),
+                    SecretType::Curve25519,
                     prekey_bundle.signed_prekey.as_ref(),
                 )?;
                 let atts = SecretAttributes {
@@ -435,12 +565,14 @@ impl KeyExchanger for X3dhInitiator {
                 )?;
                 let dh2 = vault.ec_diffie_hellman(&esk, prekey_bundle.identity_key.as_ref())?;
                 let dh3 = vault.ec_diffie_hellman(&esk, prekey_bundle.signed_prekey.as_ref())?;
-                let dh4 = vault.ec_diffie_hellman(&esk, prekey_bundle.one_time_prekey.as_ref())?;
                 let mut ikm_bytes = vec![0xFFu8; 32];
                 ikm_bytes.extend_from_slice(vault.secret_export(&dh1)?.as_ref());
                 ikm_bytes.extend_from_slice(vault.secret_export(&dh2)?.as_ref());
                 ikm_bytes.extend_from_slice(vault.secret_export(&dh3)?.as_ref());
-                ikm_bytes.extend_from_slice(vault.secret_export(&dh4)?.as_ref());
+                if let Some(one_time_prekey) = &prekey_bundle.one_time_prekey {
+                    let dh4 = vault.ec_diffie_hellman(&esk, one_time_prekey.as_ref())?;
+                    ikm_bytes.extend_from_slice(vault.secret_export(&dh4)?.as_ref());
+                }
 
                 let ikm = vault.secret_import(
                     &ikm_bytes,
@@ -490,7 +622,8 @@ impl KeyExchanger for X3dhInitiator {
                 let ikb = vault.secret_public_key_get(&skb)?;
 
                 let mut plaintext = ikb.as_ref().to_vec();
-                plaintext.extend_from_slice(&vault.sign(ephemeral_identity_key, ikb.as_ref())?);
+                plaintext
+                    .extend_from_slice(vault.sign(ephemeral_identity_key, ikb.as_ref())?.as_ref());
 
                 let mut ciphertext_and_tag = vault.aead_aes_gcm_encrypt(
                     &encrypt_key,
@@ -506,9 +639,17 @@ impl KeyExchanger for X3dhInitiator {
                     decrypt_key,
                     local_static_secret: skb,
                     remote_static_public_key: prekey_bundle.identity_key,
+                    remote_payloads: vec![],
                 });
+                self.enrollment_message = Some(output);
+                self.state = InitiatorState::SendEnrollment;
+                Ok(data[PreKeyBundle::SIZE..].to_vec())
+            }
+            InitiatorState::SendEnrollment => {
                 self.state = InitiatorState::Done;
-                Ok(output)
+                self.enrollment_message
+                    .take()
+                    .ok_or(Error::InvalidState.into())
             }
             InitiatorState::Done => Ok(vec![]),
         }
@@ -527,13 +668,15 @@ impl KeyExchanger for X3dhInitiator {
 pub struct X3dhNewKeyExchanger {
     vault_initiator: Arc<Mutex<dyn X3dhVault>>,
     vault_responder: Arc<Mutex<dyn X3dhVault>>,
+    prekey_store: Option<Arc<Mutex<PreKeyStore>>>,
 }
 
 impl std::fmt::Debug for X3dhNewKeyExchanger {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "X3dhNewKeyExchanger {{ vault_initiator, vault_responder }}"
+            "X3dhNewKeyExchanger {{ vault_initiator, vault_responder, prekey_store: {:?} }}",
+            self.prekey_store
         )
     }
 }
@@ -547,8 +690,15 @@ impl X3dhNewKeyExchanger {
         Self {
             vault_initiator,
             vault_responder,
+            prekey_store: None,
         }
     }
+
+    /// Let responders hand out bundles from `prekey_store` and verify enrollments against it,
+    /// instead of using throwaway prekeys. The store's identity key replaces the responder's
+    pub fn set_prekey_store(&mut self, prekey_store: Arc<Mutex<PreKeyStore>>) {
+        self.prekey_store = Some(prekey_store);
+    }
 }
 
 impl NewKeyExchanger<X3dhInitiator, X3dhResponder> for X3dhNewKeyExchanger {
@@ -557,7 +707,11 @@ impl NewKeyExchanger<X3dhInitiator, X3dhResponder> for X3dhNewKeyExchanger {
     }
 
     fn responder(&self, identity_key: Option<Arc<Box<dyn Secret>>>) -> X3dhResponder {
-        X3dhResponder::new(self.vault_responder.clone(), identity_key)
+        X3dhResponder::new(
+            self.vault_responder.clone(),
+            identity_key,
+            self.prekey_store.clone(),
+        )
     }
 }
 
@@ -571,7 +725,7 @@ mod tests {
         let vault_i = Arc::new(Mutex::new(DefaultVault::default()));
         let vault_r = Arc::new(Mutex::new(DefaultVault::default()));
         let mut initiator = X3dhInitiator::new(vault_i.clone(), None);
-        let mut responder = X3dhResponder::new(vault_r.clone(), None);
+        let mut responder = X3dhResponder::new(vault_r.clone(), None, None);
 
         assert!(initiator.prologue().is_ok());
         assert!(responder.prologue().is_ok());
@@ -580,18 +734,24 @@ mod tests {
         assert!(res.is_ok());
         let eik_bytes = res.unwrap();
         assert_eq!(eik_bytes.len(), 32);
-        let res = responder.process(&[]);
+        let res = responder.process(eik_bytes.as_slice());
+        assert!(res.is_ok(), "{:?}", res);
+        let res = responder.process(b"payload");
         assert!(res.is_ok());
         let prekey_bundle_bytes = res.unwrap();
 
         let res = initiator.process(prekey_bundle_bytes.as_slice());
         assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(res.unwrap(), b"payload");
+        assert!(!initiator.is_complete());
+        let res = initiator.process(&[]);
+        assert!(res.is_ok(), "{:?}", res);
         let final_message = res.unwrap();
+        assert!(initiator.is_complete());
 
-        let res = responder.process(eik_bytes.as_slice());
-        assert!(res.is_ok(), "{:?}", res);
         let res = responder.process(final_message.as_slice());
         assert!(res.is_ok(), res);
+        assert!(responder.is_complete());
 
         let init = initiator.completed_key_exchange.as_ref().unwrap();
         let resp = responder.completed_key_exchange.as_ref().unwrap();
@@ -0,0 +1,461 @@
+use crate::error::Error;
+use crate::{
+    decode_one_time_prekey, encode_one_time_prekey, verify_enrollment, PreKeyBundle, Signature,
+    X3dhInitiator, X3dhVault, ENROLLMENT_MSG_SIZE,
+};
+use ockam_common::error::{OckamError, OckamResult};
+use ockam_kex::{CompletedKeyExchange, KeyExchanger};
+use ockam_vault::types::{
+    PublicKey, SecretAttributes, SecretMetadata, SecretPersistence, SecretType,
+    CURVE25519_SECRET_LENGTH, METADATA_CREATED_AT, METADATA_EXPIRES_AT,
+};
+use ockam_vault::{RotationVault, Secret};
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Label of the current signed prekey, previous ones are labeled `<label>@<version>`
+pub const SIGNED_PREKEY_LABEL: &str = "x3dh_signed_prekey";
+
+/// One-time prekeys are labeled with this prefix followed by their hex encoded public key
+pub const ONE_TIME_PREKEY_LABEL_PREFIX: &str = "x3dh_one_time_prekey_";
+
+/// How long a signed prekey is used before it is rotated, unless configured otherwise
+pub const DEFAULT_SIGNED_PREKEY_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How long a rotated out signed prekey and an issued one-time prekey can still be
+/// used to enroll, unless configured otherwise
+pub const DEFAULT_PREKEY_GRACE_PERIOD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How many issued one-time prekeys may wait for their enrollment, unless configured otherwise.
+/// Bundles are handed out to anyone who asks, past this many they only hold the signed prekey
+pub const DEFAULT_MAX_ISSUED_ONE_TIME_PREKEYS: usize = 100;
+
+/// Vault able to keep prekeys across restarts and rotate them
+pub trait PreKeyVault: X3dhVault + RotationVault {}
+
+impl<D> PreKeyVault for D where D: X3dhVault + RotationVault {}
+
+/// Everything the owner of a prekey bundle needs to verify an enrollment later on:
+/// the enrollee's ephemeral identity key, the prekeys it used and its enrollment message
+#[derive(Clone, Debug)]
+pub struct EnrollmentRequest {
+    pub(crate) ephemeral_identity_key: PublicKey,
+    pub(crate) signed_prekey: PublicKey,
+    pub(crate) one_time_prekey: Option<PublicKey>,
+    pub(crate) message: Vec<u8>,
+}
+
+impl EnrollmentRequest {
+    /// Length of an encoded enrollment request
+    pub const SIZE: usize = 32 + 32 + 32 + ENROLLMENT_MSG_SIZE;
+
+    /// Enroll with the owner of `bundle` while it is offline. The key exchange completes right
+    /// away, the request is delivered to the owner's prekey store whenever it can be reached
+    pub fn create(
+        vault: Arc<Mutex<dyn X3dhVault>>,
+        identity_key: Option<Arc<Box<dyn Secret>>>,
+        bundle: &PreKeyBundle,
+    ) -> OckamResult<(Self, CompletedKeyExchange)> {
+        let mut initiator = X3dhInitiator::new(vault, identity_key);
+        let ephemeral_identity_key = PublicKey::new(initiator.process(&[])?);
+        initiator.process(&bundle.to_bytes())?;
+        let message = initiator.process(&[])?;
+        let request = Self {
+            ephemeral_identity_key,
+            signed_prekey: bundle.signed_prekey.clone(),
+            one_time_prekey: bundle.one_time_prekey.clone(),
+            message,
+        };
+        Ok((request, Box::new(initiator).finalize()?))
+    }
+
+    /// The ephemeral identity key that signed the enrollee's identity key
+    pub fn ephemeral_identity_key(&self) -> &PublicKey {
+        &self.ephemeral_identity_key
+    }
+
+    /// Convert the enrollment request to a byte array
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        output.extend_from_slice(self.ephemeral_identity_key.as_ref());
+        output.extend_from_slice(self.signed_prekey.as_ref());
+        encode_one_time_prekey(&self.one_time_prekey, &mut output);
+        output.extend_from_slice(&self.message);
+        output
+    }
+}
+
+impl TryFrom<&[u8]> for EnrollmentRequest {
+    type Error = OckamError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() != Self::SIZE {
+            return Err(Error::MessageLenMismatch.into());
+        }
+        Ok(Self {
+            ephemeral_identity_key: PublicKey::new(array_ref![data, 0, 32].to_vec()),
+            signed_prekey: PublicKey::new(array_ref![data, 32, 32].to_vec()),
+            one_time_prekey: decode_one_time_prekey(array_ref![data, 64, 32]),
+            message: data[96..].to_vec(),
+        })
+    }
+}
+
+/// Hands out prekey bundles for an identity key and verifies the enrollments made with them.
+///
+/// Prekeys live in the vault under their labels, so bundles handed out before a restart can
+/// still be used to enroll afterwards. The signed prekey is rotated with the vault's key
+/// rotation, one-time prekeys are destroyed once an enrollment used them.
+///
+/// Bundles are never a reason to generate prekeys: once no one-time prekey is available, or
+/// too many issued ones are still waiting for their enrollment, bundles only hold the signed
+/// prekey until `replenish` or `rotate` makes room again.
+pub struct PreKeyStore {
+    vault: Arc<Mutex<dyn PreKeyVault>>,
+    identity_key: Arc<Box<dyn Secret>>,
+    signed_prekey_lifetime: Duration,
+    grace_period: Duration,
+    max_issued: usize,
+}
+
+impl std::fmt::Debug for PreKeyStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "PreKeyStore {{ vault, identity_key: {:?}, signed_prekey_lifetime: {:?}, grace_period: {:?}, max_issued: {} }}",
+            self.identity_key, self.signed_prekey_lifetime, self.grace_period, self.max_issued
+        )
+    }
+}
+
+impl PreKeyStore {
+    /// Create a prekey store for `identity_key`, generating the signed prekey
+    /// if the vault does not hold one yet
+    pub fn new(
+        vault: Arc<Mutex<dyn PreKeyVault>>,
+        identity_key: Arc<Box<dyn Secret>>,
+    ) -> OckamResult<Self> {
+        {
+            let mut v = vault.lock().unwrap();
+            if v.get_persistent_secret_by_label(SIGNED_PREKEY_LABEL)
+                .is_err()
+            {
+                let secret = v.secret_generate(prekey_attributes())?;
+                let mut metadata = SecretMetadata {
+                    label: Some(SIGNED_PREKEY_LABEL.into()),
+                    ..Default::default()
+                };
+                metadata
+                    .entries
+                    .insert(METADATA_CREATED_AT.into(), unix_time()?.to_string());
+                v.set_secret_metadata(&secret, metadata)?;
+            }
+        }
+        Ok(Self {
+            vault,
+            identity_key,
+            signed_prekey_lifetime: DEFAULT_SIGNED_PREKEY_LIFETIME,
+            grace_period: DEFAULT_PREKEY_GRACE_PERIOD,
+            max_issued: DEFAULT_MAX_ISSUED_ONE_TIME_PREKEYS,
+        })
+    }
+
+    /// Set how long a signed prekey is used, and how long rotated out signed prekeys
+    /// and issued one-time prekeys can still be used to enroll
+    pub fn set_lifetime(&mut self, signed_prekey_lifetime: Duration, grace_period: Duration) {
+        self.signed_prekey_lifetime = signed_prekey_lifetime;
+        self.grace_period = grace_period;
+    }
+
+    /// Set how many issued one-time prekeys may wait for their enrollment
+    pub fn set_max_issued(&mut self, max_issued: usize) {
+        self.max_issued = max_issued;
+    }
+
+    /// The identity key bundles are signed with
+    pub fn identity_public_key(&self) -> OckamResult<PublicKey> {
+        self.vault
+            .lock()
+            .unwrap()
+            .secret_public_key_get(&self.identity_key)
+    }
+
+    /// Rotate the signed prekey once it is older than its lifetime and destroy the prekeys
+    /// whose grace period has elapsed. Returns true if the signed prekey was rotated
+    pub fn rotate(&mut self) -> OckamResult<bool> {
+        let mut vault = self.vault.lock().unwrap();
+        if vault.secret_needs_rotation(SIGNED_PREKEY_LABEL, self.signed_prekey_lifetime)? {
+            vault.secret_rotate(SIGNED_PREKEY_LABEL, self.grace_period)?;
+            return Ok(true);
+        }
+        vault.purge_expired_secrets()?;
+        Ok(false)
+    }
+
+    /// Number of one-time prekeys that were not handed out yet
+    pub fn available(&self) -> OckamResult<usize> {
+        Ok(self.one_time_prekeys(false)?.len())
+    }
+
+    /// Number of one-time prekeys that were handed out and not used to enroll yet
+    pub fn issued(&self) -> OckamResult<usize> {
+        Ok(self.one_time_prekeys(true)?.len())
+    }
+
+    /// Generate one-time prekeys until `count` of them are available,
+    /// returns how many were generated
+    pub fn replenish(&mut self, count: usize) -> OckamResult<usize> {
+        let available = self.available()?;
+        let mut vault = self.vault.lock().unwrap();
+        for _ in available..count {
+            let secret = vault.secret_generate(prekey_attributes())?;
+            let public_key = vault.secret_public_key_get(&secret)?;
+            let metadata = SecretMetadata {
+                label: Some(one_time_prekey_label(&public_key)),
+                ..Default::default()
+            };
+            vault.set_secret_metadata(&secret, metadata)?;
+        }
+        Ok(count.saturating_sub(available))
+    }
+
+    /// Hand out a bundle with the current signed prekey and an unused one-time prekey,
+    /// or the signed prekey alone when none can be issued
+    pub fn issue_bundle(&mut self) -> OckamResult<PreKeyBundle> {
+        let label = if self.issued()? < self.max_issued {
+            self.one_time_prekeys(false)?.pop()
+        } else {
+            None
+        };
+
+        let mut vault = self.vault.lock().unwrap();
+        let one_time_prekey = match label {
+            Some(label) => {
+                let one_time_prekey = vault.get_persistent_secret_by_label(&label)?;
+                let mut metadata = vault.get_secret_metadata(&one_time_prekey)?;
+                metadata.entries.insert(
+                    METADATA_EXPIRES_AT.into(),
+                    (unix_time()? + self.grace_period.as_secs()).to_string(),
+                );
+                vault.set_secret_metadata(&one_time_prekey, metadata)?;
+                Some(vault.secret_public_key_get(&one_time_prekey)?)
+            }
+            None => None,
+        };
+
+        let signed_prekey = vault.get_persistent_secret_by_label(SIGNED_PREKEY_LABEL)?;
+        let signed_prekey = vault.secret_public_key_get(&signed_prekey)?;
+        let signature = vault.sign(&self.identity_key, signed_prekey.as_ref())?;
+        Ok(PreKeyBundle {
+            identity_key: vault.secret_public_key_get(&self.identity_key)?,
+            signed_prekey,
+            signature_prekey: Signature::try_from(signature.as_ref())?,
+            one_time_prekey,
+        })
+    }
+
+    /// Verify an enrollment made with a bundle from this store and consume its one-time
+    /// prekey, so the same bundle cannot be used to enroll twice. Enrollments made with a
+    /// bundle holding only the signed prekey can be replayed while that prekey is kept
+    pub fn accept_enrollment(
+        &mut self,
+        request: &EnrollmentRequest,
+    ) -> OckamResult<CompletedKeyExchange> {
+        let mut vault = self.vault.lock().unwrap();
+        let one_time_prekey = match &request.one_time_prekey {
+            Some(public_key) => {
+                let one_time_prekey = vault
+                    .get_persistent_secret_by_label(&one_time_prekey_label(public_key))
+                    .map_err(|_| Error::UnknownPreKey.into())?;
+                if !vault
+                    .get_secret_metadata(&one_time_prekey)?
+                    .entries
+                    .contains_key(METADATA_EXPIRES_AT)
+                {
+                    // never handed out
+                    return Err(Error::UnknownPreKey.into());
+                }
+                Some(one_time_prekey)
+            }
+            None => None,
+        };
+
+        let mut signed_prekey = None;
+        for info in vault.list_persistent_secrets()? {
+            let is_signed_prekey = match &info.metadata.label {
+                Some(label) => {
+                    label == SIGNED_PREKEY_LABEL
+                        || label.starts_with(&format!("{}@", SIGNED_PREKEY_LABEL))
+                }
+                None => false,
+            };
+            if !is_signed_prekey {
+                continue;
+            }
+            let secret = vault.get_persistent_secret(&info.persistence_id)?;
+            if vault.secret_public_key_get(&secret)?.as_ref() == request.signed_prekey.as_ref() {
+                signed_prekey = Some(secret);
+                break;
+            }
+        }
+        let signed_prekey = signed_prekey.ok_or(Error::UnknownPreKey.into())?;
+
+        let completed_key_exchange = verify_enrollment(
+            &mut *vault,
+            self.identity_key.clone(),
+            &signed_prekey,
+            one_time_prekey.as_ref(),
+            &request.ephemeral_identity_key,
+            &request.message,
+        )?;
+        if let Some(one_time_prekey) = one_time_prekey {
+            vault.secret_destroy(one_time_prekey)?;
+        }
+        Ok(completed_key_exchange)
+    }
+
+    /// Labels of the one-time prekeys that were handed out, or of those that were not
+    fn one_time_prekeys(&self, issued: bool) -> OckamResult<Vec<String>> {
+        let mut vault = self.vault.lock().unwrap();
+        Ok(vault
+            .list_persistent_secrets()?
+            .into_iter()
+            .filter(|info| info.metadata.entries.contains_key(METADATA_EXPIRES_AT) == issued)
+            .filter_map(|info| info.metadata.label)
+            .filter(|label| label.starts_with(ONE_TIME_PREKEY_LABEL_PREFIX))
+            .collect())
+    }
+}
+
+fn prekey_attributes() -> SecretAttributes {
+    SecretAttributes {
+        persistence: SecretPersistence::Persistent,
+        stype: SecretType::Curve25519,
+        length: CURVE25519_SECRET_LENGTH,
+    }
+}
+
+fn one_time_prekey_label(public_key: &PublicKey) -> String {
+    format!(
+        "{}{}",
+        ONE_TIME_PREKEY_LABEL_PREFIX,
+        hex::encode(public_key.as_ref())
+    )
+}
+
+fn unix_time() -> OckamResult<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .map_err(|_| Error::InvalidState.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_vault::SecretVault;
+    use ockam_vault_file::FilesystemVault;
+
+    fn vault(path: &str) -> Arc<Mutex<FilesystemVault>> {
+        let path = std::path::PathBuf::from(path);
+        if path.exists() {
+            std::fs::remove_dir_all(path.clone()).unwrap();
+        }
+        Arc::new(Mutex::new(FilesystemVault::new(path).unwrap()))
+    }
+
+    fn store(path: &str) -> PreKeyStore {
+        let vault = vault(path);
+        let identity_key = vault
+            .lock()
+            .unwrap()
+            .secret_generate(prekey_attributes())
+            .unwrap();
+        PreKeyStore::new(vault, Arc::new(identity_key)).unwrap()
+    }
+
+    #[test]
+    fn enrollment() {
+        let mut store = store("__x3dh_enrollment_test");
+        assert_eq!(store.replenish(3).unwrap(), 3);
+        assert_eq!(store.replenish(3).unwrap(), 0);
+
+        let bundle = store.issue_bundle().unwrap();
+        assert_eq!(store.available().unwrap(), 2);
+        assert_eq!(
+            bundle.identity_key().as_ref(),
+            store.identity_public_key().unwrap().as_ref()
+        );
+
+        let vault_e = vault("__x3dh_enrollee_test");
+        let (request, enrollee) =
+            EnrollmentRequest::create(vault_e.clone(), None, &bundle).unwrap();
+        let request = EnrollmentRequest::try_from(request.to_bytes().as_slice()).unwrap();
+
+        let owner = store.accept_enrollment(&request).unwrap();
+        assert_eq!(owner.h, enrollee.h);
+        let enrollee_key = vault_e
+            .lock()
+            .unwrap()
+            .secret_public_key_get(&enrollee.local_static_secret)
+            .unwrap();
+        assert_eq!(
+            owner.remote_static_public_key.as_ref(),
+            enrollee_key.as_ref()
+        );
+
+        // the one-time prekey is gone once used
+        assert!(store.accept_enrollment(&request).is_err());
+    }
+
+    #[test]
+    fn rotated_signed_prekey() {
+        let mut store = store("__x3dh_rotation_test");
+        let bundle = store.issue_bundle().unwrap();
+        assert!(!store.rotate().unwrap());
+
+        store.set_lifetime(Duration::from_secs(0), DEFAULT_PREKEY_GRACE_PERIOD);
+        assert!(store.rotate().unwrap());
+        let rotated = store.issue_bundle().unwrap();
+        assert_ne!(
+            bundle.signed_prekey.as_ref(),
+            rotated.signed_prekey.as_ref()
+        );
+
+        // bundles issued before the rotation are still good during the grace period
+        let vault_e = vault("__x3dh_rotation_enrollee_test");
+        let (request, _) = EnrollmentRequest::create(vault_e, None, &bundle).unwrap();
+        assert!(store.accept_enrollment(&request).is_ok());
+    }
+
+    #[test]
+    fn issued_prekeys_are_capped() {
+        let mut store = store("__x3dh_issued_cap_test");
+        store.set_max_issued(2);
+        store.replenish(3).unwrap();
+        let first = store.issue_bundle().unwrap();
+        assert!(first.one_time_prekey().is_some());
+        assert!(store.issue_bundle().unwrap().one_time_prekey().is_some());
+
+        // no more one-time prekeys go out, and none are generated for the bundle
+        let signed_only = store.issue_bundle().unwrap();
+        assert!(signed_only.one_time_prekey().is_none());
+        assert_eq!(store.issued().unwrap(), 2);
+        assert_eq!(store.available().unwrap(), 1);
+
+        let vault_e = vault("__x3dh_issued_cap_enrollee_test");
+        let (request, enrollee) =
+            EnrollmentRequest::create(vault_e.clone(), None, &signed_only).unwrap();
+        let request = EnrollmentRequest::try_from(request.to_bytes().as_slice()).unwrap();
+        assert_eq!(store.accept_enrollment(&request).unwrap().h, enrollee.h);
+
+        // using an issued one-time prekey makes room for the next one
+        let (request, _) = EnrollmentRequest::create(vault_e, None, &first).unwrap();
+        assert!(store.accept_enrollment(&request).is_ok());
+        assert!(store.issue_bundle().unwrap().one_time_prekey().is_some());
+        assert_eq!(store.available().unwrap(), 0);
+    }
+}
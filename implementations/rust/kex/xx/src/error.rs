@@ -9,6 +9,8 @@ pub enum Error {
     InvalidState,
     InternalVaultError,
     MessageLenMismatch,
+    UnknownResponderKey,
+    InvalidPsk,
 }
 
 impl Error {
@@ -5,16 +5,23 @@ use ockam_kex::{
     SHA256_SIZE,
 };
 use ockam_vault::types::{
-    AES128_SECRET_LENGTH, AES256_SECRET_LENGTH, CURVE25519_SECRET_LENGTH, P256_SECRET_LENGTH,
+    AES128_SECRET_LENGTH, AES256_SECRET_LENGTH, CHACHA20POLY1305_SECRET_LENGTH,
+    CURVE25519_SECRET_LENGTH, ML_KEM_768_CIPHERTEXT_LENGTH, ML_KEM_768_PUBLIC_LENGTH,
+    ML_KEM_768_SECRET_LENGTH, P256_SECRET_LENGTH,
 };
 use ockam_vault::{
     types::{PublicKey, SecretAttributes, SecretPersistence, SecretType},
-    AsymmetricVault, HashVault, Secret, SecretVault, SymmetricVault,
+    AsymmetricVault, HashVault, KemVault, RandomVault, Secret, SecretVault, SymmetricVault,
 };
 use std::sync::{Arc, Mutex};
 use zeroize::Zeroize;
 
 pub mod error;
+mod ik;
+mod nk;
+
+pub use ik::{IKInitiator, IKNewKeyExchanger, IKResponder};
+pub use nk::{NKInitiator, NKNewKeyExchanger, NKResponder};
 
 #[derive(Debug)]
 struct KeyPair {
@@ -23,18 +30,46 @@ struct KeyPair {
 }
 
 /// Vault with XX required functionality
-pub trait XXVault: SecretVault + HashVault + AsymmetricVault + SymmetricVault + Send {}
+pub trait XXVault:
+    SecretVault + HashVault + AsymmetricVault + KemVault + SymmetricVault + RandomVault + Send
+{
+}
+
+impl<D> XXVault for D where
+    D: SecretVault + HashVault + AsymmetricVault + KemVault + SymmetricVault + RandomVault + Send
+{
+}
+
+/// The Noise handshake patterns built on `SymmetricState`
+#[derive(Clone, Copy, Debug)]
+enum HandshakePattern {
+    XX,
+    IK,
+    NK,
+}
 
-impl<D> XXVault for D where D: SecretVault + HashVault + AsymmetricVault + SymmetricVault + Send {}
+/// Where a Noise pre-shared key is mixed into a handshake
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PskModifier {
+    /// At the start of the first message
+    Psk0,
+    /// At the end of the second message
+    Psk2,
+}
 
 /// Represents the XX Handshake
 struct SymmetricState {
     cipher_suite: CipherSuite,
+    pattern: HandshakePattern,
+    psk: Option<(PskModifier, Arc<Box<dyn Secret>>)>,
     identity_key: Option<Arc<Box<dyn Secret>>>,
     identity_public_key: Option<PublicKey>,
     ephemeral_key_pair: Option<KeyPair>,
     remote_static_public_key: Option<PublicKey>,
     remote_ephemeral_public_key: Option<PublicKey>,
+    kem_key_pair: Option<KeyPair>,
+    remote_kem_public_key: Option<PublicKey>,
+    remote_payloads: Vec<Vec<u8>>,
     key: Option<Box<dyn Secret>>,
     nonce: u16,
     h: Option<[u8; SHA256_SIZE]>,
@@ -62,7 +97,9 @@ impl std::fmt::Debug for SymmetricState {
 impl SymmetricState {
     fn get_secret_key_type_and_length(&self) -> (SecretType, usize) {
         match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => {
+            CipherSuite::Curve25519AesGcmSha256
+            | CipherSuite::Curve25519ChaChaPolySha256
+            | CipherSuite::Curve25519MlKem768AesGcmSha256 => {
                 (SecretType::Curve25519, CURVE25519_SECRET_LENGTH)
             }
             CipherSuite::P256Aes128GcmSha256 => (SecretType::P256, P256_SECRET_LENGTH),
@@ -71,18 +108,56 @@ impl SymmetricState {
 
     fn get_symmetric_key_type_and_length(&self) -> (SecretType, usize) {
         match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => (SecretType::Aes, AES256_SECRET_LENGTH),
+            CipherSuite::Curve25519AesGcmSha256 | CipherSuite::Curve25519MlKem768AesGcmSha256 => {
+                (SecretType::Aes, AES256_SECRET_LENGTH)
+            }
             CipherSuite::P256Aes128GcmSha256 => (SecretType::Aes, AES128_SECRET_LENGTH),
+            CipherSuite::Curve25519ChaChaPolySha256 => {
+                (SecretType::ChaCha20Poly1305, CHACHA20POLY1305_SECRET_LENGTH)
+            }
         }
     }
 
     fn get_public_key_size(&self) -> usize {
         match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => 32,
+            CipherSuite::Curve25519AesGcmSha256
+            | CipherSuite::Curve25519ChaChaPolySha256
+            | CipherSuite::Curve25519MlKem768AesGcmSha256 => 32,
             CipherSuite::P256Aes128GcmSha256 => 65,
         }
     }
 
+    /// Is an ML-KEM encapsulation mixed in along with the DH results
+    fn is_hybrid(&self) -> bool {
+        matches!(
+            self.cipher_suite,
+            CipherSuite::Curve25519MlKem768AesGcmSha256
+        )
+    }
+
+    /// The size of a KEM public key or ciphertext of `length` bytes on the wire
+    fn get_kem_token_size(&self, length: usize) -> usize {
+        if self.key.is_some() {
+            length + AES_GCM_TAGSIZE
+        } else {
+            length
+        }
+    }
+
+    /// Encode the current nonce as required by the cipher suite's AEAD:
+    /// 32 bits of zeros followed by the big-endian (AES-GCM) or
+    /// little-endian (ChaChaPoly) encoding of the counter
+    fn get_nonce(&self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        match self.cipher_suite {
+            CipherSuite::Curve25519ChaChaPolySha256 => {
+                nonce[4..].copy_from_slice(&(self.nonce as u64).to_le_bytes())
+            }
+            _ => nonce[4..].copy_from_slice(&(self.nonce as u64).to_be_bytes()),
+        }
+        nonce
+    }
+
     pub fn new(
         cipher_suite: CipherSuite,
         vault: Arc<Mutex<dyn XXVault>>,
@@ -90,11 +165,16 @@ impl SymmetricState {
     ) -> Self {
         Self {
             cipher_suite,
+            pattern: HandshakePattern::XX,
+            psk: None,
             identity_key,
             identity_public_key: None,
             ephemeral_key_pair: None,
             remote_static_public_key: None,
             remote_ephemeral_public_key: None,
+            kem_key_pair: None,
+            remote_kem_public_key: None,
+            remote_payloads: vec![],
             key: None,
             nonce: 0,
             h: None,
@@ -102,14 +182,280 @@ impl SymmetricState {
             vault,
         }
     }
+
+    /// Run `pattern` instead of XX
+    fn with_pattern(mut self, pattern: HandshakePattern) -> Self {
+        self.pattern = pattern;
+        self
+    }
+
+    /// Mix the pre-shared key `psk` into the handshake at `modifier`
+    fn with_psk(mut self, psk: Option<(PskModifier, Arc<Box<dyn Secret>>)>) -> Self {
+        self.psk = psk;
+        self
+    }
+
+    /// The initial value of h and ck: the protocol name padded with zeros,
+    /// or its hash if the name is longer than a hash
+    fn initial_hash<V: HashVault + ?Sized>(&self, vault: &V) -> OckamResult<[u8; SHA256_SIZE]> {
+        let protocol_name = self.get_protocol_name();
+        if protocol_name.len() > SHA256_SIZE {
+            return vault.sha256(&protocol_name);
+        }
+        let mut h = [0u8; SHA256_SIZE];
+        h[..protocol_name.len()].copy_from_slice(&protocol_name);
+        Ok(h)
+    }
+
+    /// MixKey step in Noise protocol with `ikm` as input key material
+    fn mix_key(&mut self, vault: &mut dyn XXVault, ikm: &Box<dyn Secret>) -> OckamResult<()> {
+        let ck = self.ck.take().ok_or_else(|| Error::InvalidState.into())?;
+
+        let attributes_ck = SecretAttributes {
+            stype: SecretType::Buffer,
+            persistence: SecretPersistence::Ephemeral,
+            length: SHA256_SIZE,
+        };
+
+        let symmetric_secret_info = self.get_symmetric_key_type_and_length();
+
+        let attributes_k = SecretAttributes {
+            stype: symmetric_secret_info.0,
+            persistence: SecretPersistence::Ephemeral,
+            length: symmetric_secret_info.1,
+        };
+
+        let mut hkdf_output =
+            vault.hkdf_sha256(&ck, b"", Some(ikm), vec![attributes_ck, attributes_k])?;
+
+        if hkdf_output.len() != 2 {
+            return Err(Error::InternalVaultError.into());
+        }
+
+        let key = self.key.take();
+        if key.is_some() {
+            vault.secret_destroy(key.unwrap())?;
+        }
+
+        self.key = Some(hkdf_output.pop().unwrap());
+
+        vault.secret_destroy(ck)?;
+        self.ck = Some(hkdf_output.pop().unwrap());
+
+        self.nonce = 0;
+
+        Ok(())
+    }
+
+    /// Process an `e` token: mix the ephemeral public key into h,
+    /// and into the keys too when a pre-shared key is in use
+    fn mix_ephemeral_public_key(&mut self, public_key: &[u8]) -> OckamResult<()> {
+        self.mix_hash(public_key)?;
+        if self.psk.is_none() {
+            return Ok(());
+        }
+        let vault = self.vault.clone();
+        let mut vault = vault.lock().unwrap();
+        let attributes = SecretAttributes {
+            stype: SecretType::Buffer,
+            persistence: SecretPersistence::Ephemeral,
+            length: public_key.len(),
+        };
+        let ikm = vault.secret_import(public_key, attributes)?;
+        self.mix_key(&mut *vault, &ikm)?;
+        vault.secret_destroy(ikm)
+    }
+
+    /// Process a `psk` token: MixKeyAndHash the pre-shared key if it
+    /// goes in at `modifier`
+    fn mix_psk(&mut self, modifier: PskModifier) -> OckamResult<()> {
+        let psk = match &self.psk {
+            Some((m, psk)) if *m == modifier => psk.clone(),
+            _ => return Ok(()),
+        };
+        let ck = self.ck.take().ok_or_else(|| Error::InvalidState.into())?;
+
+        let vault = self.vault.clone();
+        let mut vault = vault.lock().unwrap();
+        let psk_attributes = vault.secret_attributes_get(&psk)?;
+        if psk_attributes.stype != SecretType::Buffer || psk_attributes.length != SHA256_SIZE {
+            return Err(Error::InvalidPsk.into());
+        }
+
+        let attributes_buffer = SecretAttributes {
+            stype: SecretType::Buffer,
+            persistence: SecretPersistence::Ephemeral,
+            length: SHA256_SIZE,
+        };
+
+        let symmetric_secret_info = self.get_symmetric_key_type_and_length();
+
+        let attributes_k = SecretAttributes {
+            stype: symmetric_secret_info.0,
+            persistence: SecretPersistence::Ephemeral,
+            length: symmetric_secret_info.1,
+        };
+
+        let mut hkdf_output = vault.hkdf_sha256(
+            &ck,
+            b"",
+            Some(&psk),
+            vec![attributes_buffer, attributes_buffer, attributes_k],
+        )?;
+
+        if hkdf_output.len() != 3 {
+            return Err(Error::InternalVaultError.into());
+        }
+
+        if let Some(key) = self.key.take() {
+            vault.secret_destroy(key)?;
+        }
+        self.key = Some(hkdf_output.pop().unwrap());
+        self.nonce = 0;
+
+        let temp_h = hkdf_output.pop().unwrap();
+        let h = vault.secret_export(&temp_h)?;
+        vault.secret_destroy(temp_h)?;
+
+        vault.secret_destroy(ck)?;
+        self.ck = Some(hkdf_output.pop().unwrap());
+        std::mem::drop(vault);
+
+        self.mix_hash(h.as_ref())
+    }
+
+    /// Process an `e1` token when sending: generate an ephemeral KEM key pair and
+    /// send its public key. Nothing is sent unless the cipher suite is hybrid
+    fn write_kem_public_key(&mut self) -> OckamResult<Vec<u8>> {
+        if !self.is_hybrid() {
+            return Ok(vec![]);
+        }
+        let attributes = SecretAttributes {
+            stype: SecretType::MlKem768,
+            persistence: SecretPersistence::Ephemeral,
+            length: ML_KEM_768_SECRET_LENGTH,
+        };
+        let (secret_handle, public_key) = {
+            let mut vault = self.vault.lock().unwrap();
+            let secret_handle = vault.secret_generate(attributes)?;
+            let public_key = vault.secret_public_key_get(&secret_handle)?;
+            (secret_handle, public_key)
+        };
+        let output = self.encrypt_and_mix_hash(public_key.as_ref())?;
+        self.kem_key_pair = Some(KeyPair {
+            public_key,
+            secret_handle,
+        });
+        Ok(output)
+    }
+
+    /// Process an `e1` token when receiving, returns the rest of the message
+    fn read_kem_public_key<'a>(&mut self, message: &'a [u8]) -> OckamResult<&'a [u8]> {
+        if !self.is_hybrid() {
+            return Ok(message);
+        }
+        let size = self.get_kem_token_size(ML_KEM_768_PUBLIC_LENGTH);
+        if message.len() < size {
+            return Err(Error::MessageLenMismatch.into());
+        }
+        let public_key = self.decrypt_and_mix_hash(&message[..size])?;
+        self.remote_kem_public_key = Some(PublicKey::new(public_key));
+        Ok(&message[size..])
+    }
+
+    /// Process an `ekem1` token when sending: encapsulate a secret to the remote KEM
+    /// public key, send the ciphertext and mix the secret into the keys
+    fn write_kem_ciphertext(&mut self) -> OckamResult<Vec<u8>> {
+        if !self.is_hybrid() {
+            return Ok(vec![]);
+        }
+        let remote_kem_public_key = self
+            .remote_kem_public_key
+            .take()
+            .ok_or_else(|| Error::InvalidState.into())?;
+        let vault = self.vault.clone();
+        let (ciphertext, shared_secret) = vault
+            .lock()
+            .unwrap()
+            .kem_encapsulate(remote_kem_public_key.as_ref())?;
+        let output = self.encrypt_and_mix_hash(&ciphertext)?;
+
+        let mut vault = vault.lock().unwrap();
+        self.mix_key(&mut *vault, &shared_secret)?;
+        vault.secret_destroy(shared_secret)?;
+        Ok(output)
+    }
+
+    /// Process an `ekem1` token when receiving: decapsulate the secret and mix it into
+    /// the keys, returns the rest of the message
+    fn read_kem_ciphertext<'a>(&mut self, message: &'a [u8]) -> OckamResult<&'a [u8]> {
+        if !self.is_hybrid() {
+            return Ok(message);
+        }
+        let size = self.get_kem_token_size(ML_KEM_768_CIPHERTEXT_LENGTH);
+        if message.len() < size {
+            return Err(Error::MessageLenMismatch.into());
+        }
+        let ciphertext = self.decrypt_and_mix_hash(&message[..size])?;
+        let kem_key_pair = self
+            .kem_key_pair
+            .take()
+            .ok_or_else(|| Error::InvalidState.into())?;
+
+        let vault = self.vault.clone();
+        let mut vault = vault.lock().unwrap();
+        let shared_secret = vault.kem_decapsulate(&kem_key_pair.secret_handle, &ciphertext)?;
+        vault.secret_destroy(kem_key_pair.secret_handle)?;
+        self.mix_key(&mut *vault, &shared_secret)?;
+        vault.secret_destroy(shared_secret)?;
+        Ok(&message[size..])
+    }
+
+    /// Decrypt the payload of a handshake message and keep it for the completed key exchange
+    fn decrypt_payload(&mut self, ciphertext: &[u8]) -> OckamResult<Vec<u8>> {
+        let payload = self.decrypt_and_mix_hash(ciphertext)?;
+        self.remote_payloads.push(payload.clone());
+        Ok(payload)
+    }
+
+    /// Take the ephemeral key pair out of the state for a DH computation
+    fn take_ephemeral_key_pair(&mut self) -> OckamResult<KeyPair> {
+        self.ephemeral_key_pair
+            .take()
+            .ok_or_else(|| Error::InvalidState.into())
+    }
+
+    /// Take the static key out of the state for a DH computation
+    fn take_identity_key(&mut self) -> OckamResult<Arc<Box<dyn Secret>>> {
+        self.identity_key
+            .take()
+            .ok_or_else(|| Error::InvalidState.into())
+    }
 }
 
 impl KeyExchange for SymmetricState {
-    fn get_protocol_name(&self) -> &'static [u8] {
-        match self.cipher_suite {
-            CipherSuite::Curve25519AesGcmSha256 => b"Noise_XX_25519_AESGCM_SHA256\0\0\0\0",
-            CipherSuite::P256Aes128GcmSha256 => b"Noise_XX_P256_AES128GCM_SHA256\0\0",
+    fn get_protocol_name(&self) -> Vec<u8> {
+        let pattern = match self.pattern {
+            HandshakePattern::XX => "XX",
+            HandshakePattern::IK => "IK",
+            HandshakePattern::NK => "NK",
+        };
+        let mut modifiers = vec![];
+        if self.is_hybrid() {
+            modifiers.push("hfs");
+        }
+        match self.psk {
+            None => {}
+            Some((PskModifier::Psk0, _)) => modifiers.push("psk0"),
+            Some((PskModifier::Psk2, _)) => modifiers.push("psk2"),
         }
+        let cipher_suite = match self.cipher_suite {
+            CipherSuite::Curve25519AesGcmSha256 => "25519_AESGCM_SHA256",
+            CipherSuite::P256Aes128GcmSha256 => "P256_AES128GCM_SHA256",
+            CipherSuite::Curve25519ChaChaPolySha256 => "25519_ChaChaPoly_SHA256",
+            CipherSuite::Curve25519MlKem768AesGcmSha256 => "25519+MLKEM768_AESGCM_SHA256",
+        };
+        format!("Noise_{}{}_{}", pattern, modifiers.join("+"), cipher_suite).into_bytes()
     }
 
     /// Create a new `HandshakeState` starting with the prologue
@@ -156,8 +502,7 @@ impl KeyExchange for SymmetricState {
         // 5. h = SHA256(h || prologue),
         // prologue is empty
         // mix_hash(xx, NULL, 0);
-        let mut h = [0u8; SHA256_SIZE];
-        h[..self.get_protocol_name().len()].copy_from_slice(self.get_protocol_name());
+        let h = self.initial_hash(&*vault)?;
         let attributes = SecretAttributes {
             stype: SecretType::Buffer,
             persistence: SecretPersistence::Ephemeral,
@@ -171,46 +516,10 @@ impl KeyExchange for SymmetricState {
 
     /// Perform the diffie-hellman computation
     fn dh(&mut self, secret_handle: &Box<dyn Secret>, public_key: &[u8]) -> OckamResult<()> {
-        let ck = self.ck.take().ok_or_else(|| Error::InvalidState.into())?;
-
-        let mut vault = self.vault.lock().unwrap();
-
-        let attributes_ck = SecretAttributes {
-            stype: SecretType::Buffer,
-            persistence: SecretPersistence::Ephemeral,
-            length: SHA256_SIZE,
-        };
-
-        let symmetric_secret_info = self.get_symmetric_key_type_and_length();
-
-        let attributes_k = SecretAttributes {
-            stype: symmetric_secret_info.0,
-            persistence: SecretPersistence::Ephemeral,
-            length: symmetric_secret_info.1,
-        };
-
+        let vault = self.vault.clone();
+        let mut vault = vault.lock().unwrap();
         let ecdh = vault.ec_diffie_hellman(secret_handle, public_key)?;
-
-        let mut hkdf_output =
-            vault.hkdf_sha256(&ck, b"", Some(&ecdh), vec![attributes_ck, attributes_k])?;
-
-        if hkdf_output.len() != 2 {
-            return Err(Error::InternalVaultError.into());
-        }
-
-        let key = self.key.take();
-        if key.is_some() {
-            vault.secret_destroy(key.unwrap())?;
-        }
-
-        self.key = Some(hkdf_output.pop().unwrap());
-
-        vault.secret_destroy(ck)?;
-        self.ck = Some(hkdf_output.pop().unwrap());
-
-        self.nonce = 0;
-
-        Ok(())
+        self.mix_key(&mut *vault, &ecdh)
     }
 
     /// mix hash step in Noise protocol
@@ -224,33 +533,57 @@ impl KeyExchange for SymmetricState {
         Ok(())
     }
 
-    /// Encrypt and mix step in Noise protocol
+    /// Encrypt and mix step in Noise protocol, the plaintext is sent as is
+    /// while there is no key yet
     fn encrypt_and_mix_hash<B: AsRef<[u8]>>(&mut self, plaintext: B) -> OckamResult<Vec<u8>> {
         let h = &self.h.ok_or_else(|| Error::InvalidState.into())?;
+        if self.key.is_none() {
+            self.mix_hash(plaintext.as_ref())?;
+            return Ok(plaintext.as_ref().to_vec());
+        }
 
-        let mut nonce = [0u8; 12];
-        nonce[10..].copy_from_slice(&self.nonce.to_be_bytes());
+        let nonce = self.get_nonce();
         let ciphertext_and_tag = {
             let mut vault = self.vault.lock().unwrap();
             let key = self.key.as_ref().ok_or(Error::InvalidState.into())?;
-            vault.aead_aes_gcm_encrypt(key, plaintext.as_ref(), nonce.as_ref(), h)?
+            match self.cipher_suite {
+                CipherSuite::Curve25519ChaChaPolySha256 => vault.aead_chacha20_poly1305_encrypt(
+                    key,
+                    plaintext.as_ref(),
+                    nonce.as_ref(),
+                    h,
+                )?,
+                _ => vault.aead_aes_gcm_encrypt(key, plaintext.as_ref(), nonce.as_ref(), h)?,
+            }
         };
         self.mix_hash(&ciphertext_and_tag)?;
         self.nonce += 1;
         Ok(ciphertext_and_tag)
     }
 
-    /// Decrypt and mix step in Noise protocol
+    /// Decrypt and mix step in Noise protocol, the ciphertext is plaintext
+    /// while there is no key yet
     fn decrypt_and_mix_hash<B: AsRef<[u8]>>(&mut self, ciphertext: B) -> OckamResult<Vec<u8>> {
         let h = &self.h.ok_or_else(|| Error::InvalidState.into())?;
 
-        let mut nonce = [0u8; 12];
-        nonce[10..].copy_from_slice(&self.nonce.to_be_bytes());
+        let nonce = self.get_nonce();
         let ciphertext = ciphertext.as_ref();
+        if self.key.is_none() {
+            self.mix_hash(ciphertext)?;
+            return Ok(ciphertext.to_vec());
+        }
+        if ciphertext.len() < AES_GCM_TAGSIZE {
+            return Err(Error::MessageLenMismatch.into());
+        }
         let plaintext = {
             let mut vault = self.vault.lock().unwrap();
             let key = self.key.as_ref().ok_or(Error::InvalidState.into())?;
-            vault.aead_aes_gcm_decrypt(key, ciphertext, nonce.as_ref(), h)?
+            match self.cipher_suite {
+                CipherSuite::Curve25519ChaChaPolySha256 => {
+                    vault.aead_chacha20_poly1305_decrypt(key, ciphertext, nonce.as_ref(), h)?
+                }
+                _ => vault.aead_aes_gcm_decrypt(key, ciphertext, nonce.as_ref(), h)?,
+            }
         };
         self.mix_hash(ciphertext)?;
         self.nonce += 1;
@@ -302,6 +635,7 @@ impl KeyExchange for SymmetricState {
             decrypt_key,
             local_static_secret,
             remote_static_public_key,
+            remote_payloads: self.remote_payloads,
         })
     }
 }
@@ -321,12 +655,15 @@ impl Initiator {
             .public_key
             .clone();
 
-        let payload = payload.as_ref();
-        self.0.mix_hash(ephemeral_public_key.as_ref())?;
-        self.0.mix_hash(payload)?;
+        self.0.mix_psk(PskModifier::Psk0)?;
+        self.0
+            .mix_ephemeral_public_key(ephemeral_public_key.as_ref())?;
+        let mut kem_public_key = self.0.write_kem_public_key()?;
+        let mut payload = self.0.encrypt_and_mix_hash(payload)?;
 
         let mut output = ephemeral_public_key.as_ref().to_vec();
-        output.extend_from_slice(payload);
+        output.append(&mut kem_public_key);
+        output.append(&mut payload);
         Ok(output)
     }
 
@@ -346,25 +683,27 @@ impl Initiator {
 
         let ephemeral_secret_handle = &ephemeral_key_pair.secret_handle;
 
-        let mut index_l = 0;
-        let mut index_r = public_key_size;
-        let re = &message[..index_r];
+        let re = &message[..public_key_size];
         let re = PublicKey::new(re.to_vec());
-        index_l += public_key_size;
-        index_r += public_key_size + AES_GCM_TAGSIZE;
-        let encrypted_rs_and_tag = &message[index_l..index_r];
-        let encrypted_payload_and_tag = &message[index_r..];
 
-        t.mix_hash(re.as_ref())?;
+        t.mix_ephemeral_public_key(re.as_ref())?;
         t.dh(ephemeral_secret_handle, re.as_ref())?;
         t.remote_ephemeral_public_key = Some(re);
+        let message = t.read_kem_ciphertext(&message[public_key_size..])?;
+        let index = public_key_size + AES_GCM_TAGSIZE;
+        if message.len() < index {
+            return Err(Error::MessageLenMismatch.into());
+        }
+        let encrypted_rs_and_tag = &message[..index];
+        let encrypted_payload_and_tag = &message[index..];
         let rs = t.decrypt_and_mix_hash(encrypted_rs_and_tag)?;
         let rs = PublicKey::new(rs);
         t.dh(ephemeral_secret_handle, rs.as_ref())?;
         t.remote_static_public_key = Some(rs);
+        t.mix_psk(PskModifier::Psk2)?;
 
         t.ephemeral_key_pair = Some(ephemeral_key_pair);
-        let payload = t.decrypt_and_mix_hash(encrypted_payload_and_tag)?;
+        let payload = t.decrypt_payload(encrypted_payload_and_tag)?;
         Ok(payload)
     }
 
@@ -417,10 +756,11 @@ impl Responder {
 
         let re = &message_1[..public_key_size];
         let re = PublicKey::new(re.to_vec());
-        self.0.mix_hash(re.as_ref())?;
-        self.0.mix_hash(&message_1[public_key_size..])?;
+        self.0.mix_psk(PskModifier::Psk0)?;
+        self.0.mix_ephemeral_public_key(re.as_ref())?;
         self.0.remote_ephemeral_public_key = Some(re);
-        Ok(message_1[public_key_size..].to_vec())
+        let message_1 = self.0.read_kem_public_key(&message_1[public_key_size..])?;
+        self.0.decrypt_payload(message_1)
     }
 
     /// Encode the second message to be sent
@@ -443,20 +783,23 @@ impl Responder {
             .take()
             .ok_or_else(|| Error::InvalidState.into())?;
 
-        t.mix_hash(ephemeral_key_pair.public_key.as_ref())?;
+        t.mix_ephemeral_public_key(ephemeral_key_pair.public_key.as_ref())?;
         t.dh(
             &ephemeral_key_pair.secret_handle,
             remote_ephemeral_public_key.as_ref(),
         )?;
+        let mut kem_ciphertext = t.write_kem_ciphertext()?;
 
         let mut encrypted_s_and_tag = t.encrypt_and_mix_hash(static_public.as_ref())?;
         t.dh(&static_secret, remote_ephemeral_public_key.as_ref())?;
+        t.mix_psk(PskModifier::Psk2)?;
         t.remote_ephemeral_public_key = Some(remote_ephemeral_public_key);
         t.identity_key = Some(static_secret);
         let mut encrypted_payload_and_tag = t.encrypt_and_mix_hash(payload)?;
 
         let mut output = ephemeral_key_pair.public_key.as_ref().to_vec();
         t.ephemeral_key_pair = Some(ephemeral_key_pair);
+        output.append(&mut kem_ciphertext);
         output.append(&mut encrypted_s_and_tag);
         output.append(&mut encrypted_payload_and_tag);
         Ok(output)
@@ -480,7 +823,7 @@ impl Responder {
         let rs = PublicKey::new(rs);
         t.dh(&ephemeral_key_pair.secret_handle, rs.as_ref())?;
         t.ephemeral_key_pair = Some(ephemeral_key_pair);
-        let payload = t.decrypt_and_mix_hash(&message_3[public_key_size + AES_GCM_TAGSIZE..])?;
+        let payload = t.decrypt_payload(&message_3[public_key_size + AES_GCM_TAGSIZE..])?;
         t.remote_static_public_key = Some(rs);
         Ok(payload)
     }
@@ -532,6 +875,7 @@ pub struct XXNewKeyExchanger {
     cipher_suite: CipherSuite,
     vault_initiator: Arc<Mutex<dyn XXVault>>,
     vault_responder: Arc<Mutex<dyn XXVault>>,
+    psk: Option<(PskModifier, Arc<Box<dyn Secret>>)>,
 }
 
 impl std::fmt::Debug for XXNewKeyExchanger {
@@ -551,8 +895,15 @@ impl XXNewKeyExchanger {
             cipher_suite,
             vault_initiator,
             vault_responder,
+            psk: None,
         }
     }
+
+    /// Mix `psk`, a 32 byte `SecretType::Buffer` secret, into every handshake at `modifier`.
+    /// Both ends need the same key and modifier
+    pub fn set_psk(&mut self, modifier: PskModifier, psk: Arc<Box<dyn Secret>>) {
+        self.psk = Some((modifier, psk));
+    }
 }
 
 impl NewKeyExchanger<XXInitiator, XXResponder> for XXNewKeyExchanger {
@@ -562,7 +913,8 @@ impl NewKeyExchanger<XXInitiator, XXResponder> for XXNewKeyExchanger {
             self.cipher_suite,
             self.vault_initiator.clone(),
             identity_key,
-        );
+        )
+        .with_psk(self.psk.clone());
         XXInitiator {
             state: InitiatorState::EncodeMessage1,
             initiator: Initiator(ss),
@@ -576,7 +928,8 @@ impl NewKeyExchanger<XXInitiator, XXResponder> for XXNewKeyExchanger {
             self.cipher_suite,
             self.vault_responder.clone(),
             identity_key,
-        );
+        )
+        .with_psk(self.psk.clone());
         XXResponder {
             state: ResponderState::DecodeMessage1,
             responder: Responder(ss),
@@ -714,6 +1067,166 @@ mod tests {
         assert_eq!(s1, s2);
     }
 
+    fn psk(vault: &Arc<Mutex<DefaultVault>>, data: &[u8]) -> Arc<Box<dyn Secret>> {
+        let attributes = SecretAttributes {
+            stype: SecretType::Buffer,
+            persistence: SecretPersistence::Ephemeral,
+            length: SHA256_SIZE,
+        };
+        Arc::new(
+            vault
+                .lock()
+                .unwrap()
+                .secret_import(data, attributes)
+                .unwrap(),
+        )
+    }
+
+    fn psk_handshake(
+        modifier: PskModifier,
+        initiator_psk: &[u8],
+        responder_psk: &[u8],
+    ) -> OckamResult<()> {
+        let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+        let mut initiator_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_initiator.clone(),
+            vault_responder.clone(),
+        );
+        initiator_exchanger.set_psk(modifier, psk(&vault_initiator, initiator_psk));
+        let mut responder_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault_initiator.clone(),
+            vault_responder.clone(),
+        );
+        responder_exchanger.set_psk(modifier, psk(&vault_responder, responder_psk));
+
+        let mut initiator = initiator_exchanger.initiator(None);
+        let mut responder = responder_exchanger.responder(None);
+
+        let m1 = initiator.process(b"m1")?;
+        assert_eq!(responder.process(&m1)?, b"m1");
+        let m2 = responder.process(b"m2")?;
+        assert_eq!(initiator.process(&m2)?, b"m2");
+        let m3 = initiator.process(b"m3")?;
+        assert_eq!(responder.process(&m3)?, b"m3");
+
+        let initiator = Box::new(initiator).finalize()?;
+        let responder = Box::new(responder).finalize()?;
+        assert_eq!(initiator.h, responder.h);
+        assert_eq!(initiator.remote_payloads, vec![b"m2".to_vec()]);
+        assert_eq!(
+            responder.remote_payloads,
+            vec![b"m1".to_vec(), b"m3".to_vec()]
+        );
+
+        let mut vault_in = vault_initiator.lock().unwrap();
+        let mut vault_re = vault_responder.lock().unwrap();
+        let s1 = vault_in.secret_export(&initiator.encrypt_key)?;
+        let s2 = vault_re.secret_export(&responder.decrypt_key)?;
+        assert_eq!(s1, s2);
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    #[test]
+    fn psk_handshake__same_psk__succeeds() {
+        assert!(psk_handshake(PskModifier::Psk0, &[1u8; 32], &[1u8; 32]).is_ok());
+        assert!(psk_handshake(PskModifier::Psk2, &[1u8; 32], &[1u8; 32]).is_ok());
+    }
+
+    #[allow(non_snake_case)]
+    #[test]
+    fn psk_handshake__different_psk__fails() {
+        assert!(psk_handshake(PskModifier::Psk0, &[1u8; 32], &[2u8; 32]).is_err());
+        assert!(psk_handshake(PskModifier::Psk2, &[1u8; 32], &[2u8; 32]).is_err());
+    }
+
+    #[test]
+    fn protocol_name() {
+        let vault = Arc::new(Mutex::new(DefaultVault::default()));
+        let state = SymmetricState::new(CipherSuite::Curve25519AesGcmSha256, vault.clone(), None)
+            .with_psk(Some((PskModifier::Psk2, psk(&vault, &[0u8; 32]))));
+        assert_eq!(
+            state.get_protocol_name(),
+            b"Noise_XXpsk2_25519_AESGCM_SHA256".to_vec()
+        );
+        let h = state.initial_hash(&*vault.lock().unwrap()).unwrap();
+        assert_eq!(&h, b"Noise_XXpsk2_25519_AESGCM_SHA256");
+
+        // longer than a hash, so it is hashed instead of padded
+        let state =
+            SymmetricState::new(CipherSuite::Curve25519ChaChaPolySha256, vault.clone(), None)
+                .with_psk(Some((PskModifier::Psk0, psk(&vault, &[0u8; 32]))));
+        let h = state.initial_hash(&*vault.lock().unwrap()).unwrap();
+        let exp_h = vault
+            .lock()
+            .unwrap()
+            .sha256(b"Noise_XXpsk0_25519_ChaChaPoly_SHA256")
+            .unwrap();
+        assert_eq!(h, exp_h);
+
+        let state = SymmetricState::new(
+            CipherSuite::Curve25519MlKem768AesGcmSha256,
+            vault.clone(),
+            None,
+        )
+        .with_psk(Some((PskModifier::Psk0, psk(&vault, &[0u8; 32]))));
+        assert_eq!(
+            state.get_protocol_name(),
+            b"Noise_XXhfs+psk0_25519+MLKEM768_AESGCM_SHA256".to_vec()
+        );
+    }
+
+    #[test]
+    fn hybrid_handshake() {
+        let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+        let key_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519MlKem768AesGcmSha256,
+            vault_initiator.clone(),
+            vault_responder.clone(),
+        );
+
+        let mut initiator = key_exchanger.initiator(None);
+        let mut responder = key_exchanger.responder(None);
+
+        let m1 = initiator.process(b"m1").unwrap();
+        assert_eq!(m1.len(), 32 + ML_KEM_768_PUBLIC_LENGTH + 2);
+        assert_eq!(responder.process(&m1).unwrap(), b"m1");
+        let m2 = responder.process(b"m2").unwrap();
+        assert_eq!(
+            m2.len(),
+            32 + ML_KEM_768_CIPHERTEXT_LENGTH + 32 + 2 + 3 * AES_GCM_TAGSIZE
+        );
+        assert_eq!(initiator.process(&m2).unwrap(), b"m2");
+        let m3 = initiator.process(b"m3").unwrap();
+        assert_eq!(responder.process(&m3).unwrap(), b"m3");
+
+        let initiator = Box::new(initiator).finalize().unwrap();
+        let responder = Box::new(responder).finalize().unwrap();
+        assert_eq!(initiator.h, responder.h);
+
+        // the KEM ciphertext is authenticated like the rest of the handshake
+        let mut tampered_initiator = key_exchanger.initiator(None);
+        let mut tampered_responder = key_exchanger.responder(None);
+        let m1 = tampered_initiator.process(&[]).unwrap();
+        tampered_responder.process(&m1).unwrap();
+        let mut m2 = tampered_responder.process(&[]).unwrap();
+        m2[40] ^= 1;
+        assert!(tampered_initiator.process(&m2).is_err());
+
+        let mut vault_in = vault_initiator.lock().unwrap();
+        let mut vault_re = vault_responder.lock().unwrap();
+        let s1 = vault_in.secret_export(&initiator.encrypt_key).unwrap();
+        let s2 = vault_re.secret_export(&responder.decrypt_key).unwrap();
+        assert_eq!(s1, s2);
+        let s1 = vault_in.secret_export(&initiator.decrypt_key).unwrap();
+        let s2 = vault_re.secret_export(&responder.encrypt_key).unwrap();
+        assert_eq!(s1, s2);
+    }
+
     #[test]
     fn prologue() {
         let exp_h = [
@@ -761,6 +1274,7 @@ mod tests {
             MSG_2_CIPHERTEXT,
             MSG_3_PAYLOAD,
             MSG_3_CIPHERTEXT,
+            CipherSuite::Curve25519AesGcmSha256,
         );
     }
 
@@ -791,6 +1305,38 @@ mod tests {
             MSG_2_CIPHERTEXT,
             MSG_3_PAYLOAD,
             MSG_3_CIPHERTEXT,
+            CipherSuite::Curve25519AesGcmSha256,
+        );
+    }
+
+    #[test]
+    fn handshake_chachapoly() {
+        const INIT_STATIC: &str =
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        const RESP_STATIC: &str =
+            "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20";
+        const INIT_EPH: &str = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+        const RESP_EPH: &str = "4142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f60";
+        const MSG_1_PAYLOAD: &str = "746573745f6d73675f30";
+        const MSG_1_CIPHERTEXT: &str =
+            "358072d6365880d1aeea329adf9121383851ed21a28e3b75e965d0d2cd166254746573745f6d73675f30";
+        const MSG_2_PAYLOAD: &str = "746573745f6d73675f31";
+        const MSG_2_CIPHERTEXT: &str = "64b101b1d0be5a8704bd078f9895001fc03e8e9f9522f188dd128d9846d484663414af878d3e46a2f58911a816d6e8346d4ea17a6f2a0bb4ef4ed56c133cff4572e7a2ba5123ac30618b3d205f5c2d17f50cbca216483ac56bcc78e33bf520303278db641e5e731b2e3a";
+        const MSG_3_PAYLOAD: &str = "746573745f6d73675f32";
+        const MSG_3_CIPHERTEXT: &str = "87f864c11ba449f46a0a4f4e2eacbb7b0457784f4fca1937f572c93603e9c4d9f27e318e43ba630594c4d08eeb3b36d97c7377a2f4f9144b2f0c8095ad92140505b2ab53eff244b14138";
+
+        mock_handshake(
+            INIT_STATIC,
+            INIT_EPH,
+            RESP_STATIC,
+            RESP_EPH,
+            MSG_1_PAYLOAD,
+            MSG_1_CIPHERTEXT,
+            MSG_2_PAYLOAD,
+            MSG_2_CIPHERTEXT,
+            MSG_3_PAYLOAD,
+            MSG_3_CIPHERTEXT,
+            CipherSuite::Curve25519ChaChaPolySha256,
         );
     }
 
@@ -813,8 +1359,18 @@ mod tests {
         let vault_init = Arc::new(Mutex::new(DefaultVault::default()));
         let vault_resp = Arc::new(Mutex::new(DefaultVault::default()));
 
-        let ss_init = mock_prologue(vault_init.clone(), INIT_STATIC, INIT_EPH);
-        let ss_resp = mock_prologue(vault_resp.clone(), RESP_STATIC, RESP_EPH);
+        let ss_init = mock_prologue(
+            vault_init.clone(),
+            INIT_STATIC,
+            INIT_EPH,
+            CipherSuite::Curve25519AesGcmSha256,
+        );
+        let ss_resp = mock_prologue(
+            vault_resp.clone(),
+            RESP_STATIC,
+            RESP_EPH,
+            CipherSuite::Curve25519AesGcmSha256,
+        );
         let mut initiator = XXInitiator {
             state: InitiatorState::EncodeMessage1,
             initiator: Initiator(ss_init),
@@ -896,12 +1452,13 @@ mod tests {
         msg_2_ciphertext: &str,
         msg_3_payload: &str,
         msg_3_ciphertext: &str,
+        cipher_suite: CipherSuite,
     ) {
         let vault_init = Arc::new(Mutex::new(DefaultVault::default()));
         let vault_resp = Arc::new(Mutex::new(DefaultVault::default()));
 
-        let ss_init = mock_prologue(vault_init.clone(), init_static, init_eph);
-        let ss_resp = mock_prologue(vault_resp.clone(), resp_static, resp_eph);
+        let ss_init = mock_prologue(vault_init.clone(), init_static, init_eph, cipher_suite);
+        let ss_resp = mock_prologue(vault_resp.clone(), resp_static, resp_eph, cipher_suite);
         let mut initiator = Initiator(ss_init);
         let mut responder = Responder(ss_resp);
 
@@ -938,6 +1495,7 @@ mod tests {
         vault_mutex: Arc<Mutex<DefaultVault>>,
         static_private: &str,
         ephemeral_private: &str,
+        cipher_suite: CipherSuite,
     ) -> SymmetricState {
         let attributes = SecretAttributes {
             stype: SecretType::Curve25519,
@@ -962,14 +1520,14 @@ mod tests {
         // 3. Set k to empty, Set n to 0
         let nonce = 0;
 
-        // 4. Set h and ck to 'Noise_XX_25519_AESGCM_SHA256'
+        // 4. Set h and ck to the protocol name
         // 5. h = SHA256(h || prologue),
         // prologue is empty
         // mix_hash(xx, NULL, 0);
-        let h = vault
-            .sha256(b"Noise_XX_25519_AESGCM_SHA256\0\0\0\0")
+        let ck = SymmetricState::new(cipher_suite, vault_mutex.clone(), None)
+            .initial_hash(&*vault)
             .unwrap();
-        let ck = *b"Noise_XX_25519_AESGCM_SHA256\0\0\0\0";
+        let h = vault.sha256(&ck).unwrap();
 
         let attributes = SecretAttributes {
             stype: SecretType::Buffer,
@@ -978,7 +1536,9 @@ mod tests {
         };
         let ck = vault.secret_import(&ck[..], attributes).unwrap();
         SymmetricState {
-            cipher_suite: CipherSuite::Curve25519AesGcmSha256,
+            cipher_suite,
+            pattern: HandshakePattern::XX,
+            psk: None,
             identity_public_key: Some(static_public_key),
             ephemeral_key_pair: Some(KeyPair {
                 public_key: ephemeral_public_key,
@@ -986,6 +1546,9 @@ mod tests {
             }),
             remote_ephemeral_public_key: None,
             remote_static_public_key: None,
+            kem_key_pair: None,
+            remote_kem_public_key: None,
+            remote_payloads: vec![],
             identity_key: Some(Arc::new(static_secret_handle)),
             key: None,
             nonce,
@@ -0,0 +1,356 @@
+//! The Noise NK pattern: the initiator knows the responder's static key and stays
+//! anonymous itself, the key agreement finishes after a single round trip.
+//!
+//! ```text
+//! <- s
+//! ...
+//! -> e, es
+//! <- e, ee
+//! ```
+//!
+//! With a hybrid cipher suite the `hfs` tokens are added:
+//! `-> e, es, e1` and `<- e, ee, ekem1`.
+
+use crate::error::Error;
+use crate::{HandshakePattern, PskModifier, SymmetricState, XXVault};
+use ockam_common::error::OckamResult;
+use ockam_kex::{
+    CipherSuite, CompletedKeyExchange, KeyExchange, KeyExchanger, NewKeyExchanger, AES_GCM_TAGSIZE,
+};
+use ockam_vault::{types::PublicKey, Secret};
+use std::sync::{Arc, Mutex};
+
+/// The states the NK pattern initiator completes
+#[derive(Debug)]
+enum InitiatorState {
+    /// Run encode message 1
+    EncodeMessage1,
+    /// Run decode message 2
+    DecodeMessage2,
+    /// Finished
+    Done,
+}
+
+/// The states the NK pattern responder completes
+#[derive(Debug)]
+enum ResponderState {
+    /// Run decode message 1
+    DecodeMessage1,
+    /// Run encode message 2
+    EncodeMessage2,
+    /// Finished
+    Done,
+}
+
+/// Represents an NK initiator
+#[derive(Debug)]
+pub struct NKInitiator {
+    state: InitiatorState,
+    ss: SymmetricState,
+    responder_public_key: Option<PublicKey>,
+}
+
+impl NKInitiator {
+    /// Encode the first message to be sent
+    fn encode_message_1<B: AsRef<[u8]>>(&mut self, payload: B) -> OckamResult<Vec<u8>> {
+        let rs = self
+            .responder_public_key
+            .clone()
+            .ok_or_else(|| Error::UnknownResponderKey.into())?;
+        let t = &mut self.ss;
+        t.prologue()?;
+        // pre-message: <- s
+        t.mix_hash(rs.as_ref())?;
+
+        let ephemeral_key_pair = t.take_ephemeral_key_pair()?;
+        t.mix_psk(PskModifier::Psk0)?;
+        t.mix_ephemeral_public_key(ephemeral_key_pair.public_key.as_ref())?;
+        t.dh(&ephemeral_key_pair.secret_handle, rs.as_ref())?;
+        let mut kem_public_key = t.write_kem_public_key()?;
+        t.remote_static_public_key = Some(rs);
+        let mut encrypted_payload_and_tag = t.encrypt_and_mix_hash(payload)?;
+
+        let mut output = ephemeral_key_pair.public_key.as_ref().to_vec();
+        t.ephemeral_key_pair = Some(ephemeral_key_pair);
+        output.append(&mut kem_public_key);
+        output.append(&mut encrypted_payload_and_tag);
+        Ok(output)
+    }
+
+    /// Decode the second message in the sequence, sent from the responder
+    fn decode_message_2<B: AsRef<[u8]>>(&mut self, message: B) -> OckamResult<Vec<u8>> {
+        let t = &mut self.ss;
+        let public_key_size = t.get_public_key_size();
+        let message = message.as_ref();
+        if message.len() < public_key_size + AES_GCM_TAGSIZE {
+            return Err(Error::MessageLenMismatch.into());
+        }
+
+        let ephemeral_key_pair = t.take_ephemeral_key_pair()?;
+        let re = PublicKey::new(message[..public_key_size].to_vec());
+        t.mix_ephemeral_public_key(re.as_ref())?;
+        t.dh(&ephemeral_key_pair.secret_handle, re.as_ref())?;
+        let message = t.read_kem_ciphertext(&message[public_key_size..])?;
+        t.mix_psk(PskModifier::Psk2)?;
+        t.ephemeral_key_pair = Some(ephemeral_key_pair);
+        t.remote_ephemeral_public_key = Some(re);
+        t.decrypt_payload(message)
+    }
+}
+
+/// Represents an NK responder. The initiator is anonymous, so the completed
+/// key exchange reports an empty remote static public key
+#[derive(Debug)]
+pub struct NKResponder {
+    state: ResponderState,
+    ss: SymmetricState,
+}
+
+impl NKResponder {
+    /// Decode the first message sent
+    fn decode_message_1<B: AsRef<[u8]>>(&mut self, message: B) -> OckamResult<Vec<u8>> {
+        let t = &mut self.ss;
+        t.prologue()?;
+        // pre-message: <- s
+        let static_public = t
+            .identity_public_key
+            .clone()
+            .ok_or_else(|| Error::InvalidState.into())?;
+        t.mix_hash(static_public.as_ref())?;
+
+        let public_key_size = t.get_public_key_size();
+        let message = message.as_ref();
+        if message.len() < public_key_size + AES_GCM_TAGSIZE {
+            return Err(Error::MessageLenMismatch.into());
+        }
+
+        let static_secret = t.take_identity_key()?;
+        let re = PublicKey::new(message[..public_key_size].to_vec());
+        t.mix_psk(PskModifier::Psk0)?;
+        t.mix_ephemeral_public_key(re.as_ref())?;
+        t.dh(&static_secret, re.as_ref())?;
+        t.identity_key = Some(static_secret);
+        let message = t.read_kem_public_key(&message[public_key_size..])?;
+        t.remote_ephemeral_public_key = Some(re);
+        t.remote_static_public_key = Some(PublicKey::new(vec![]));
+        t.decrypt_payload(message)
+    }
+
+    /// Encode the second and final message
+    fn encode_message_2<B: AsRef<[u8]>>(&mut self, payload: B) -> OckamResult<Vec<u8>> {
+        let t = &mut self.ss;
+        let ephemeral_key_pair = t.take_ephemeral_key_pair()?;
+        let re = t
+            .remote_ephemeral_public_key
+            .clone()
+            .ok_or_else(|| Error::InvalidState.into())?;
+
+        t.mix_ephemeral_public_key(ephemeral_key_pair.public_key.as_ref())?;
+        t.dh(&ephemeral_key_pair.secret_handle, re.as_ref())?;
+        let mut kem_ciphertext = t.write_kem_ciphertext()?;
+        t.mix_psk(PskModifier::Psk2)?;
+        let mut encrypted_payload_and_tag = t.encrypt_and_mix_hash(payload)?;
+
+        let mut output = ephemeral_key_pair.public_key.as_ref().to_vec();
+        t.ephemeral_key_pair = Some(ephemeral_key_pair);
+        output.append(&mut kem_ciphertext);
+        output.append(&mut encrypted_payload_and_tag);
+        Ok(output)
+    }
+}
+
+/// Represents an NK NewKeyExchanger
+pub struct NKNewKeyExchanger {
+    cipher_suite: CipherSuite,
+    vault_initiator: Arc<Mutex<dyn XXVault>>,
+    vault_responder: Arc<Mutex<dyn XXVault>>,
+    responder_public_key: Option<PublicKey>,
+    psk: Option<(PskModifier, Arc<Box<dyn Secret>>)>,
+}
+
+impl std::fmt::Debug for NKNewKeyExchanger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.cipher_suite.fmt(f)
+    }
+}
+
+impl NKNewKeyExchanger {
+    /// Create a new NKNewKeyExchanger. Initiators need the static public key of
+    /// the responder they connect to, responders can leave it out
+    pub fn new(
+        cipher_suite: CipherSuite,
+        vault_initiator: Arc<Mutex<dyn XXVault>>,
+        vault_responder: Arc<Mutex<dyn XXVault>>,
+        responder_public_key: Option<PublicKey>,
+    ) -> Self {
+        Self {
+            cipher_suite,
+            vault_initiator,
+            vault_responder,
+            responder_public_key,
+            psk: None,
+        }
+    }
+
+    /// Mix `psk`, a 32 byte `SecretType::Buffer` secret, into every handshake at `modifier`.
+    /// Both ends need the same key and modifier
+    pub fn set_psk(&mut self, modifier: PskModifier, psk: Arc<Box<dyn Secret>>) {
+        self.psk = Some((modifier, psk));
+    }
+}
+
+impl NewKeyExchanger<NKInitiator, NKResponder> for NKNewKeyExchanger {
+    /// Create a new initiator using the provided backing vault
+    fn initiator(&self, identity_key: Option<Arc<Box<dyn Secret>>>) -> NKInitiator {
+        let ss = SymmetricState::new(
+            self.cipher_suite,
+            self.vault_initiator.clone(),
+            identity_key,
+        )
+        .with_pattern(HandshakePattern::NK)
+        .with_psk(self.psk.clone());
+        NKInitiator {
+            state: InitiatorState::EncodeMessage1,
+            ss,
+            responder_public_key: self.responder_public_key.clone(),
+        }
+    }
+
+    /// Create a new responder using the provided backing vault
+    fn responder(&self, identity_key: Option<Arc<Box<dyn Secret>>>) -> NKResponder {
+        let ss = SymmetricState::new(
+            self.cipher_suite,
+            self.vault_responder.clone(),
+            identity_key,
+        )
+        .with_pattern(HandshakePattern::NK)
+        .with_psk(self.psk.clone());
+        NKResponder {
+            state: ResponderState::DecodeMessage1,
+            ss,
+        }
+    }
+}
+
+impl KeyExchanger for NKInitiator {
+    fn process(&mut self, data: &[u8]) -> OckamResult<Vec<u8>> {
+        match self.state {
+            InitiatorState::EncodeMessage1 => {
+                let msg = self.encode_message_1(data)?;
+                self.state = InitiatorState::DecodeMessage2;
+                Ok(msg)
+            }
+            InitiatorState::DecodeMessage2 => {
+                let msg = self.decode_message_2(data)?;
+                self.state = InitiatorState::Done;
+                Ok(msg)
+            }
+            InitiatorState::Done => Ok(vec![]),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        matches!(self.state, InitiatorState::Done)
+    }
+
+    fn finalize(mut self: Box<Self>) -> OckamResult<CompletedKeyExchange> {
+        match self.state {
+            InitiatorState::Done => {
+                let keys = self.ss.split()?;
+                self.ss.finalize(keys.1, keys.0)
+            }
+            _ => Err(Error::InvalidState.into()),
+        }
+    }
+}
+
+impl KeyExchanger for NKResponder {
+    fn process(&mut self, data: &[u8]) -> OckamResult<Vec<u8>> {
+        match self.state {
+            ResponderState::DecodeMessage1 => {
+                let msg = self.decode_message_1(data)?;
+                self.state = ResponderState::EncodeMessage2;
+                Ok(msg)
+            }
+            ResponderState::EncodeMessage2 => {
+                let msg = self.encode_message_2(data)?;
+                self.state = ResponderState::Done;
+                Ok(msg)
+            }
+            ResponderState::Done => Ok(vec![]),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        matches!(self.state, ResponderState::Done)
+    }
+
+    fn finalize(mut self: Box<Self>) -> OckamResult<CompletedKeyExchange> {
+        match self.state {
+            ResponderState::Done => {
+                let keys = self.ss.split()?;
+                self.ss.finalize(keys.0, keys.1)
+            }
+            _ => Err(Error::InvalidState.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_vault::{types::*, SecretVault};
+    use ockam_vault_software::DefaultVault;
+
+    #[allow(non_snake_case)]
+    #[test]
+    fn full_flow__correct_credentials__keys_should_match() {
+        let vault_initiator = Arc::new(Mutex::new(DefaultVault::default()));
+        let vault_responder = Arc::new(Mutex::new(DefaultVault::default()));
+        let (responder_secret, responder_public_key) = {
+            let mut vault = vault_responder.lock().unwrap();
+            let attributes = SecretAttributes {
+                stype: SecretType::Curve25519,
+                persistence: SecretPersistence::Ephemeral,
+                length: CURVE25519_SECRET_LENGTH,
+            };
+            let secret = vault.secret_generate(attributes).unwrap();
+            let public_key = vault.secret_public_key_get(&secret).unwrap();
+            (Arc::new(secret), public_key)
+        };
+        let key_exchanger = NKNewKeyExchanger::new(
+            CipherSuite::Curve25519ChaChaPolySha256,
+            vault_initiator.clone(),
+            vault_responder.clone(),
+            Some(responder_public_key.clone()),
+        );
+
+        let mut initiator = key_exchanger.initiator(None);
+        let mut responder = key_exchanger.responder(Some(responder_secret));
+
+        let m1 = initiator.process(b"hello").unwrap();
+        assert_eq!(responder.process(&m1).unwrap(), b"hello");
+        let m2 = responder.process(b"world").unwrap();
+        assert!(responder.is_complete());
+        assert_eq!(initiator.process(&m2).unwrap(), b"world");
+        assert!(initiator.is_complete());
+
+        let initiator = Box::new(initiator).finalize().unwrap();
+        let responder = Box::new(responder).finalize().unwrap();
+
+        let mut vault_in = vault_initiator.lock().unwrap();
+        let mut vault_re = vault_responder.lock().unwrap();
+
+        assert_eq!(initiator.h, responder.h);
+        assert_eq!(initiator.remote_static_public_key, responder_public_key);
+        assert!(responder.remote_static_public_key.as_ref().is_empty());
+
+        let s1 = vault_in.secret_export(&initiator.encrypt_key).unwrap();
+        let s2 = vault_re.secret_export(&responder.decrypt_key).unwrap();
+        assert_eq!(s1, s2);
+
+        let s1 = vault_in.secret_export(&initiator.decrypt_key).unwrap();
+        let s2 = vault_re.secret_export(&responder.encrypt_key).unwrap();
+        assert_eq!(s1, s2);
+    }
+}
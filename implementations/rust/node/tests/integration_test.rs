@@ -52,6 +52,7 @@ impl Poll for TestWorker {
             },
             message_type: MessageType::Payload,
             message_body: msg_text.to_vec(),
+            extensions: vec![],
         };
         let mut q = enqueue_message_ref.deref().borrow_mut();
         q.enqueue_message(m)?;
@@ -144,6 +145,7 @@ impl Poll for TestTcpWorker {
                 },
                 message_type: MessageType::Payload,
                 message_body: "hello".as_bytes().to_vec(),
+                extensions: vec![],
             };
             let mut q = enqueue_message_ref.deref().borrow_mut();
             q.enqueue_message(m)?;
@@ -179,6 +181,7 @@ impl ProcessMessage for TestTcpWorker {
                 },
                 message_type: MessageType::Payload,
                 message_body: "hello".as_bytes().to_vec(),
+                extensions: vec![],
             };
             {
                 let mut q = enqueue_message_ref.clone(); //rb
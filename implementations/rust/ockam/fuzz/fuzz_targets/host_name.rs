@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ockam::message::{Codec, HostName};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((value, _)) = HostName::decode(data) {
+        let mut encoded = vec![];
+        HostName::encode(&value, &mut encoded).unwrap();
+        let (decoded, rest) = HostName::decode(&encoded).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, value);
+    }
+});
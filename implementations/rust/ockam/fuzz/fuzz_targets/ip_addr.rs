@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ockam::message::Codec;
+use std::net::IpAddr;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((value, _)) = IpAddr::decode(data) {
+        let mut encoded = vec![];
+        IpAddr::encode(&value, &mut encoded).unwrap();
+        let (decoded, rest) = IpAddr::decode(&encoded).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, value);
+    }
+});
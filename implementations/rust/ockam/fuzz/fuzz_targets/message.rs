@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ockam::message::{Codec, Message};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((message, rest)) = Message::decode(data) {
+        assert!(rest.is_empty());
+        let mut encoded = vec![];
+        Message::encode(&message, &mut encoded).unwrap();
+        let (decoded, _) = Message::decode(&encoded).unwrap();
+        assert_eq!(
+            decoded.onward_route.addresses,
+            message.onward_route.addresses
+        );
+        assert_eq!(
+            decoded.return_route.addresses,
+            message.return_route.addresses
+        );
+        assert_eq!(decoded.message_type as u8, message.message_type as u8);
+        assert_eq!(decoded.message_body, message.message_body);
+        assert_eq!(decoded.extensions, message.extensions);
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ockam::message::{Codec, Route};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((route, _)) = Route::decode(data) {
+        let mut encoded = vec![];
+        Route::encode(&route, &mut encoded).unwrap();
+        let (decoded, rest) = Route::decode(&encoded).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.addresses, route.addresses);
+    }
+});
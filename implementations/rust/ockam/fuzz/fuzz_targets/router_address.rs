@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ockam::message::{Codec, RouterAddress};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((value, _)) = RouterAddress::decode(data) {
+        let mut encoded = vec![];
+        RouterAddress::encode(&value, &mut encoded).unwrap();
+        let (decoded, rest) = RouterAddress::decode(&encoded).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, value);
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ockam::message::Codec;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((value, _)) = u64::decode(data) {
+        let mut encoded = vec![];
+        u64::encode(&value, &mut encoded).unwrap();
+        let (decoded, rest) = u64::decode(&encoded).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, value);
+    }
+});
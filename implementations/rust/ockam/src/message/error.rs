@@ -0,0 +1,62 @@
+use ockam_common::error::OckamError;
+use std::fmt::{Display, Formatter};
+
+/// Represents the failures that can occur encoding
+/// or decoding an Ockam message
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageError {
+    /// None
+    None,
+    /// Input ends before the field being decoded
+    Truncated,
+    /// Message is encoded with a wire protocol version this node doesn't speak
+    UnsupportedVersion,
+    /// Unknown message type
+    UnknownMessageType,
+    /// Unknown address type
+    UnknownAddressType,
+    /// Unknown host address type
+    UnknownHostAddressType,
+    /// Host name isn't a valid DNS name
+    InvalidHostName,
+    /// Address length doesn't match the address it prefixes
+    InvalidAddressLength,
+    /// Address doesn't belong to the type of its router address
+    AddressTypeMismatch,
+    /// Value is too large for its encoding
+    ValueTooLarge,
+    /// Message carries a critical extension the peer's wire protocol version can't carry
+    CriticalExtension,
+}
+
+impl MessageError {
+    /// Error domain
+    pub const ERROR_DOMAIN: &'static str = "OCKAM_MESSAGE_ERROR_DOMAIN";
+}
+
+impl Into<OckamError> for MessageError {
+    fn into(self) -> OckamError {
+        OckamError::new(self as u32, MessageError::ERROR_DOMAIN)
+    }
+}
+
+impl Display for MessageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            MessageError::None => "no error",
+            MessageError::Truncated => "message is truncated",
+            MessageError::UnsupportedVersion => "unsupported wire protocol version",
+            MessageError::UnknownMessageType => "unknown message type",
+            MessageError::UnknownAddressType => "unknown address type",
+            MessageError::UnknownHostAddressType => "unknown host address type",
+            MessageError::InvalidHostName => "invalid host name",
+            MessageError::InvalidAddressLength => "address length doesn't match the address",
+            MessageError::AddressTypeMismatch => "address doesn't match its address type",
+            MessageError::ValueTooLarge => "value is too large to encode",
+            MessageError::CriticalExtension => "peer can't receive a critical extension",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::error::Error for MessageError {}
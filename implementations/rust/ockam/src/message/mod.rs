@@ -16,7 +16,17 @@ use std::ops::Add;
 use std::slice;
 use std::str::FromStr;
 
-const WIRE_PROTOCOL_VERSION: u8 = 1;
+pub mod error;
+pub use error::MessageError;
+
+/// Highest wire protocol version this node speaks
+pub const WIRE_PROTOCOL_VERSION: u8 = 2;
+
+/// Lowest wire protocol version this node still speaks, every node understands it
+pub const MIN_WIRE_PROTOCOL_VERSION: u8 = 1;
+
+/// First wire protocol version with a header extension area
+const EXTENSIONS_WIRE_PROTOCOL_VERSION: u8 = 2;
 
 /// If the message needs additional routing, return Ok(Some(msg))
 pub trait Receiver {
@@ -27,11 +37,28 @@ pub trait Sender {
     fn send(&mut self, m: Message) -> bool;
 }
 
+/// Decoding never panics: input that is truncated or malformed is rejected with an error
 pub trait Codec {
     type Inner;
 
-    fn encode(&self, v: &mut Vec<u8>) -> Result<(), String>;
-    fn decode(s: &[u8]) -> Result<(Self::Inner, &[u8]), String>;
+    fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError>;
+    fn decode(s: &[u8]) -> Result<(Self::Inner, &[u8]), MessageError>;
+}
+
+/// Split the first byte off `u`
+fn take_u8(u: &[u8]) -> Result<(u8, &[u8]), MessageError> {
+    match u.split_first() {
+        Some((b, rest)) => Ok((*b, rest)),
+        None => Err(MessageError::Truncated),
+    }
+}
+
+/// Split the first `n` bytes off `u`
+fn take(u: &[u8], n: usize) -> Result<(&[u8], &[u8]), MessageError> {
+    if u.len() < n {
+        return Err(MessageError::Truncated);
+    }
+    Ok(u.split_at(n))
 }
 
 //    #[repr(C)]
@@ -41,6 +68,148 @@ pub struct Message {
     pub return_route: Route,
     pub message_type: MessageType,
     pub message_body: Vec<u8>,
+    pub extensions: Vec<Extension>,
+}
+
+/// Extensions with this bit set in their type can't be dropped when a message is sent to
+/// a peer whose wire protocol version predates extensions, the message is refused instead
+pub const CRITICAL_EXTENSION: u8 = 0x80;
+
+/// Types of the header extensions defined so far, extensions of other types are kept
+/// as they are so that nodes forward what they don't understand
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExtensionType {
+    /// Identifies the trace the message belongs to
+    TraceId = 1,
+    /// Delivery priority, higher is more urgent
+    Priority = 2,
+    /// Hops the message may still take
+    Ttl = 3,
+    /// The body is compressed with the algorithm the value names
+    Compression = CRITICAL_EXTENSION as isize | 4,
+    /// The message is one fragment of a larger one, the value is its `FragmentHeader`
+    Fragment = CRITICAL_EXTENSION as isize | 5,
+}
+
+/// A header extension, encoded as type, length and value from wire protocol version 2 on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Extension {
+    pub e_type: u8,
+    pub value: Vec<u8>,
+}
+
+impl Extension {
+    pub fn is_critical(&self) -> bool {
+        self.e_type & CRITICAL_EXTENSION != 0
+    }
+}
+
+impl Codec for Extension {
+    type Inner = Extension;
+    fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError> {
+        v.push(self.e_type);
+        let length = u16::try_from(self.value.len()).map_err(|_| MessageError::ValueTooLarge)?;
+        u16::encode(&length, v)?;
+        v.extend_from_slice(&self.value);
+        Ok(())
+    }
+    fn decode(u: &[u8]) -> Result<(Extension, &[u8]), MessageError> {
+        let (e_type, w) = take_u8(u)?;
+        let (length, w) = u16::decode(w)?;
+        let (value, w) = take(w, length as usize)?;
+        Ok((
+            Extension {
+                e_type,
+                value: value.to_vec(),
+            },
+            w,
+        ))
+    }
+}
+
+/// Places a fragment within the message it was split from. Messages are split into at
+/// most 0x7fff fragments, as many as the fragment count's encoding holds
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FragmentHeader {
+    /// Identifies the message among those its sender split
+    pub message_id: u64,
+    pub index: u16,
+    pub count: u16,
+}
+
+impl Codec for FragmentHeader {
+    type Inner = FragmentHeader;
+    fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError> {
+        u64::encode(&self.message_id, v)?;
+        u16::encode(&self.index, v)?;
+        u16::encode(&self.count, v)
+    }
+    fn decode(u: &[u8]) -> Result<(FragmentHeader, &[u8]), MessageError> {
+        let (message_id, w) = u64::decode(u)?;
+        let (index, w) = u16::decode(w)?;
+        let (count, w) = u16::decode(w)?;
+        Ok((
+            FragmentHeader {
+                message_id,
+                index,
+                count,
+            },
+            w,
+        ))
+    }
+}
+
+impl Message {
+    /// The value of the extension of the given type
+    pub fn extension(&self, e_type: u8) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|e| e.e_type == e_type)
+            .map(|e| e.value.as_slice())
+    }
+
+    /// Add an extension, replacing any of the same type
+    pub fn set_extension(&mut self, e_type: u8, value: Vec<u8>) {
+        self.extensions.retain(|e| e.e_type != e_type);
+        self.extensions.push(Extension { e_type, value });
+    }
+
+    /// Encode for a peer that speaks up to the given version. Peers predating extensions
+    /// get the message without them, unless one is critical
+    pub fn encode_for_version(
+        &self,
+        peer: &WireProtocolVersion,
+        u: &mut Vec<u8>,
+    ) -> Result<(), MessageError> {
+        if peer.v < MIN_WIRE_PROTOCOL_VERSION as u16 {
+            return Err(MessageError::UnsupportedVersion);
+        }
+        if self.extensions.is_empty() || peer.v >= EXTENSIONS_WIRE_PROTOCOL_VERSION as u16 {
+            return Message::encode(self, u);
+        }
+        if self.extensions.iter().any(Extension::is_critical) {
+            return Err(MessageError::CriticalExtension);
+        }
+        self.encode_version(MIN_WIRE_PROTOCOL_VERSION, u)
+    }
+
+    fn encode_version(&self, version: u8, u: &mut Vec<u8>) -> Result<(), MessageError> {
+        u.push(version);
+        if version >= EXTENSIONS_WIRE_PROTOCOL_VERSION {
+            let mut area = vec![];
+            for extension in &self.extensions {
+                Extension::encode(extension, &mut area)?;
+            }
+            let length = u16::try_from(area.len()).map_err(|_| MessageError::ValueTooLarge)?;
+            u16::encode(&length, u)?;
+            u.append(&mut area);
+        }
+        Route::encode(&self.onward_route, u)?;
+        Route::encode(&self.return_route, u)?;
+        u.push(self.message_type as u8);
+        u.extend(&self.message_body[0..]);
+        Ok(())
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -51,7 +220,11 @@ pub enum MessageType {
     KeyAgreementM1 = 3,
     KeyAgreementM2 = 4,
     KeyAgreementM3 = 5,
+    ChannelClose = 6,
+    SessionTicket = 7,
+    ResumptionM1 = 8,
     NoSuchChannel = 9,
+    ResumptionM2 = 10,
     None = 255,
 }
 
@@ -62,46 +235,52 @@ impl Default for Message {
             return_route: Route { addresses: vec![] },
             message_type: MessageType::Payload,
             message_body: vec![0],
+            extensions: vec![],
         }
     }
 }
 
+/// Messages are encoded with the lowest wire protocol version that carries them, so that
+/// messages without extensions stay readable by every node
 impl Codec for Message {
     type Inner = Message;
-    fn encode(&self, u: &mut Vec<u8>) -> Result<(), String> {
-        u.push(1);
-        Route::encode(&self.onward_route.clone(), u);
-        Route::encode(&self.return_route.clone(), u);
-        u.push(self.message_type as u8);
-        u.extend(&self.message_body[0..]);
-        Ok(())
+    fn encode(&self, u: &mut Vec<u8>) -> Result<(), MessageError> {
+        if self.extensions.is_empty() {
+            self.encode_version(MIN_WIRE_PROTOCOL_VERSION, u)
+        } else {
+            self.encode_version(EXTENSIONS_WIRE_PROTOCOL_VERSION, u)
+        }
     }
 
-    fn decode(u: &[u8]) -> Result<(Message, &[u8]), String> {
-        let mut msg = Message::default();
-        let mut w = &u[1..];
-        match Route::decode(w) {
-            Ok((r, u1)) => {
-                msg.onward_route = r;
-                w = u1;
-            }
-            Err(s) => {
-                return Err(s);
-            }
-        }
-        match Route::decode(w) {
-            Ok((r, u1)) => {
-                msg.return_route = r;
-                w = u1;
-            }
-            Err(s) => {
-                return Err(s);
+    fn decode(u: &[u8]) -> Result<(Message, &[u8]), MessageError> {
+        let (version, w) = take_u8(u)?;
+        let (extensions, w) = match version {
+            MIN_WIRE_PROTOCOL_VERSION => (vec![], w),
+            EXTENSIONS_WIRE_PROTOCOL_VERSION => {
+                let (length, w) = u16::decode(w)?;
+                let (mut area, w) = take(w, length as usize)?;
+                let mut extensions = vec![];
+                while !area.is_empty() {
+                    let (extension, rest) = Extension::decode(area)?;
+                    extensions.push(extension);
+                    area = rest;
+                }
+                (extensions, w)
             }
-        }
-        msg.message_type = MessageType::try_from(w[0])?;
-        let mut w = &w[1..];
-        msg.message_body = w.to_vec();
-        Ok((msg, w))
+            _ => return Err(MessageError::UnsupportedVersion),
+        };
+        let (onward_route, w) = Route::decode(w)?;
+        let (return_route, w) = Route::decode(w)?;
+        let (message_type, w) = take_u8(w)?;
+        let msg = Message {
+            onward_route,
+            return_route,
+            message_type: MessageType::try_from(message_type)?,
+            message_body: w.to_vec(),
+            extensions,
+        };
+        // the body takes up the rest of the message
+        Ok((msg, &w[w.len()..]))
     }
 }
 
@@ -127,6 +306,95 @@ impl Clone for AddressType {
     }
 }
 
+/// The longest host name that fits the one byte length of a router address
+pub const MAX_HOST_NAME_LENGTH: usize = 251;
+
+/// A DNS host name and port, resolved by the transport each time it sends to it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HostName {
+    pub host: String,
+    pub port: u16,
+}
+
+impl HostName {
+    pub fn new(host: &str, port: u16) -> Result<HostName, String> {
+        let valid_label = |l: &str| {
+            !l.is_empty()
+                && l.len() <= 63
+                && !l.starts_with('-')
+                && !l.ends_with('-')
+                && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        };
+        let host = host.strip_suffix('.').unwrap_or(host);
+        // a numeric top level label means a mistyped IPv4 address rather than a name
+        let numeric = host
+            .rsplit('.')
+            .next()
+            .unwrap()
+            .chars()
+            .all(|c| c.is_ascii_digit());
+        if host.len() > MAX_HOST_NAME_LENGTH || numeric || !host.split('.').all(valid_label) {
+            return Err(format!("invalid host name: {}", host));
+        }
+        Ok(HostName {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+impl FromStr for HostName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once(':') {
+            Some((host, port)) => match u16::from_str(port) {
+                Ok(port) => HostName::new(host, port),
+                Err(_) => Err(format!("invalid port in: {}", s)),
+            },
+            None => Err(format!("missing port in: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for HostName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+impl Codec for HostName {
+    type Inner = HostName;
+    fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError> {
+        if self.host.len() > MAX_HOST_NAME_LENGTH {
+            return Err(MessageError::ValueTooLarge);
+        }
+        v.push(HostAddressType::Dns as u8);
+        v.push(self.host.len() as u8);
+        v.extend_from_slice(self.host.as_bytes());
+        v.extend_from_slice(&self.port.to_le_bytes());
+        Ok(())
+    }
+    fn decode(u: &[u8]) -> Result<(HostName, &[u8]), MessageError> {
+        let (host_type, w) = take_u8(u)?;
+        match HostAddressType::try_from(host_type)? {
+            HostAddressType::Dns => {
+                let (length, w) = take_u8(w)?;
+                let (host, w) = take(w, length as usize)?;
+                let (port, w) = take(w, 2)?;
+                let host = std::str::from_utf8(host).map_err(|_| MessageError::InvalidHostName)?;
+                let port = u16::from_le_bytes([port[0], port[1]]);
+                // names are only sent the way HostName::new normalizes them
+                match HostName::new(host, port) {
+                    Ok(h) if h.host == host => Ok((h, w)),
+                    _ => Err(MessageError::InvalidHostName),
+                }
+            }
+            _ => Err(MessageError::AddressTypeMismatch),
+        }
+    }
+}
+
 //    #[repr(C)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum Address {
@@ -134,6 +402,8 @@ pub enum Address {
     UdpAddress(SocketAddr),
     ChannelAddress(Vec<u8>),
     WorkerAddress(Vec<u8>),
+    TcpHostAddress(HostName),
+    UdpHostAddress(HostName),
 }
 
 impl Address {
@@ -142,7 +412,7 @@ impl Address {
             Address::UdpAddress(socket) => socket.to_string(),
             Address::TcpAddress(socket) => socket.to_string(),
             Address::ChannelAddress(u) | Address::WorkerAddress(u) => hex::encode(u.as_slice()),
-            _ => "error".to_string(),
+            Address::UdpHostAddress(host) | Address::TcpHostAddress(host) => host.to_string(),
         }
     }
     pub fn worker_address_from_string(s: &str) -> Result<Address, String> {
@@ -160,17 +430,32 @@ impl Address {
     pub fn size_of(&self) -> u8 {
         match self {
             Address::WorkerAddress(a) => a.len() as u8,
-            Address::UdpAddress(s) => 7,
-            Address::TcpAddress(s) => 7,
+            Address::UdpAddress(s) => socket_addr_size(s),
+            Address::TcpAddress(s) => socket_addr_size(s),
             Address::ChannelAddress(a) => a.len() as u8,
-            _ => 0,
+            Address::UdpHostAddress(h) => host_name_size(h),
+            Address::TcpHostAddress(h) => host_name_size(h),
         }
     }
 }
 
+/// Encoded size of a host name: host address type, name length, name and port
+pub fn host_name_size(h: &HostName) -> u8 {
+    (h.host.len() + 4) as u8
+}
+
+/// Encoded size of a socket address: host address type, IPv4 or IPv6 octets and port
+pub fn socket_addr_size(s: &SocketAddr) -> u8 {
+    match s {
+        SocketAddr::V4(_) => 7,
+        SocketAddr::V6(_) => 19,
+    }
+}
+
 pub enum HostAddressType {
     Ipv4 = 0,
     Ipv6 = 1,
+    Dns = 2,
 }
 
 #[derive(Copy)]
@@ -216,7 +501,7 @@ impl PartialEq for AddressType {
 }
 
 impl TryFrom<u8> for MessageType {
-    type Error = String;
+    type Error = MessageError;
     fn try_from(data: u8) -> Result<Self, Self::Error> {
         match data {
             0 => Ok(MessageType::Ping),
@@ -225,24 +510,30 @@ impl TryFrom<u8> for MessageType {
             3 => Ok(MessageType::KeyAgreementM1),
             4 => Ok(MessageType::KeyAgreementM2),
             5 => Ok(MessageType::KeyAgreementM3),
-            _ => Err("Unknown message type".to_string()),
+            6 => Ok(MessageType::ChannelClose),
+            7 => Ok(MessageType::SessionTicket),
+            8 => Ok(MessageType::ResumptionM1),
+            9 => Ok(MessageType::NoSuchChannel),
+            10 => Ok(MessageType::ResumptionM2),
+            _ => Err(MessageError::UnknownMessageType),
         }
     }
 }
 
 impl TryFrom<u8> for HostAddressType {
-    type Error = String;
+    type Error = MessageError;
     fn try_from(data: u8) -> Result<Self, Self::Error> {
         match data {
             0 => Ok(HostAddressType::Ipv4),
             1 => Ok(HostAddressType::Ipv6),
-            _ => Err("Unknown host address type".to_string()),
+            2 => Ok(HostAddressType::Dns),
+            _ => Err(MessageError::UnknownHostAddressType),
         }
     }
 }
 
 impl TryFrom<u8> for AddressType {
-    type Error = String;
+    type Error = MessageError;
     fn try_from(data: u8) -> Result<AddressType, Self::Error> {
         match data {
             255 => Ok(AddressType::Undefined),
@@ -250,108 +541,83 @@ impl TryFrom<u8> for AddressType {
             2 => Ok(AddressType::Udp),
             129 => Ok(AddressType::Channel),
             0 => Ok(AddressType::Worker),
-            _ => Err("Unknown address type".to_string()),
+            _ => Err(MessageError::UnknownAddressType),
         }
     }
 }
 
 impl Codec for RouterAddress {
     type Inner = RouterAddress;
-    fn encode(&self, v: &mut Vec<u8>) -> Result<(), String> {
-        v.push(self.a_type as u8);
-        v.push(self.length as u8);
-
-        match self.a_type {
-            AddressType::Worker => {
-                if let Address::WorkerAddress(mut wa) = self.address.clone() {
-                    v.append(&mut wa);
-                }
+    fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError> {
+        let mut encoded = vec![];
+        match (self.a_type, &self.address) {
+            (AddressType::Worker, Address::WorkerAddress(a))
+            | (AddressType::Channel, Address::ChannelAddress(a)) => encoded.extend_from_slice(a),
+            (AddressType::Udp, Address::UdpAddress(sock_addr))
+            | (AddressType::Tcp, Address::TcpAddress(sock_addr)) => {
+                SocketAddr::encode(sock_addr, &mut encoded)?
             }
-            AddressType::Udp => {
-                if let Address::UdpAddress(sock_addr) = self.address.clone() {
-                    SocketAddr::encode(&sock_addr, v);
-                }
-            }
-            AddressType::Tcp => {
-                if let Address::TcpAddress(sock_addr) = self.address.clone() {
-                    SocketAddr::encode(&sock_addr, v);
-                }
-            }
-            AddressType::Channel => {
-                if let Address::ChannelAddress(mut ca) = self.address.clone() {
-                    v.append(&mut ca);
-                }
+            (AddressType::Udp, Address::UdpHostAddress(host))
+            | (AddressType::Tcp, Address::TcpHostAddress(host)) => {
+                HostName::encode(host, &mut encoded)?
             }
-            _ => {}
+            _ => return Err(MessageError::AddressTypeMismatch),
+        }
+        if encoded.len() > u8::MAX as usize {
+            return Err(MessageError::ValueTooLarge);
         }
+        v.push(self.a_type as u8);
+        v.push(encoded.len() as u8);
+        v.append(&mut encoded);
         Ok(())
     }
-    fn decode(u: &[u8]) -> Result<(RouterAddress, &[u8]), String> {
-        let mut a_type: AddressType;
-        a_type = AddressType::Undefined;
-        match AddressType::try_from(u[0]) {
-            Ok(t) => {
-                a_type = t;
-            }
-            Err(s) => return Err(s),
-        }
-        match a_type {
-            AddressType::Channel => {
-                let length = u[1] as usize;
-                let addr: Vec<u8> = u[2..(length + 2)].to_vec();
-                Ok((
-                    RouterAddress {
-                        a_type: AddressType::Channel,
-                        length: addr.len() as u8,
-                        address: Address::ChannelAddress(addr),
-                    },
-                    &u[(length + 2)..],
-                ))
-            }
-            AddressType::Worker => {
-                let length = u[1] as usize;
-                let addr: Vec<u8> = u[2..(length + 2)].to_vec();
-                Ok((
-                    RouterAddress {
-                        a_type: AddressType::Worker,
-                        length: addr.len() as u8,
-                        address: Address::WorkerAddress(addr),
-                    },
-                    &u[(length + 2)..],
-                ))
-            }
-            AddressType::Udp => {
-                let (sock, v) = SocketAddr::decode(&u[2..])?;
-                let address = Address::UdpAddress(sock);
-                Ok((
-                    RouterAddress {
-                        a_type: AddressType::Udp,
-                        length: u[1],
-                        address: Address::UdpAddress(sock),
-                    },
-                    &u[u[1] as usize + 2..],
-                ))
-            }
-            AddressType::Tcp => {
-                let (sock, v) = SocketAddr::decode(&u[2..])?;
-                let address = Address::TcpAddress(sock);
-                Ok((
-                    RouterAddress {
-                        a_type: AddressType::Tcp,
-                        length: u[1],
-                        address: Address::TcpAddress(sock),
-                    },
-                    &u[u[1] as usize + 2..],
-                ))
+    fn decode(u: &[u8]) -> Result<(RouterAddress, &[u8]), MessageError> {
+        let (a_type, w) = take_u8(u)?;
+        let a_type = AddressType::try_from(a_type)?;
+        let (length, w) = take_u8(w)?;
+        let (addr, w) = take(w, length as usize)?;
+        let address = match a_type {
+            AddressType::Channel => Address::ChannelAddress(addr.to_vec()),
+            AddressType::Worker => Address::WorkerAddress(addr.to_vec()),
+            AddressType::Udp | AddressType::Tcp => {
+                let (host_type, _) = take_u8(addr)?;
+                let (address, rest) = match HostAddressType::try_from(host_type)? {
+                    HostAddressType::Dns => {
+                        let (host, rest) = HostName::decode(addr)?;
+                        match a_type {
+                            AddressType::Udp => (Address::UdpHostAddress(host), rest),
+                            _ => (Address::TcpHostAddress(host), rest),
+                        }
+                    }
+                    _ => {
+                        let (sock, rest) = SocketAddr::decode(addr)?;
+                        match a_type {
+                            AddressType::Udp => (Address::UdpAddress(sock), rest),
+                            _ => (Address::TcpAddress(sock), rest),
+                        }
+                    }
+                };
+                if !rest.is_empty() {
+                    return Err(MessageError::InvalidAddressLength);
+                }
+                address
             }
-            _ => Err("unimplemented address type".to_string()),
-        }
+            AddressType::Undefined => return Err(MessageError::UnknownAddressType),
+        };
+        Ok((
+            RouterAddress {
+                a_type,
+                length,
+                address,
+            },
+            w,
+        ))
     }
 }
 
 impl Codec for IpAddr {
     type Inner = IpAddr;
-    fn encode(&self, v: &mut Vec<u8>) -> Result<(), String> {
+    fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError> {
         match self {
             std::net::IpAddr::V4(ip4) => {
                 v.push(HostAddressType::Ipv4 as u8);
@@ -364,47 +630,37 @@ impl Codec for IpAddr {
         }
         Ok(())
     }
-    fn decode(u: &[u8]) -> Result<(IpAddr, &[u8]), String> {
-        match (HostAddressType::try_from(u[0])?, &u[1..]) {
-            (HostAddressType::Ipv4, addr) => {
+    fn decode(u: &[u8]) -> Result<(IpAddr, &[u8]), MessageError> {
+        let (host_type, w) = take_u8(u)?;
+        match HostAddressType::try_from(host_type)? {
+            HostAddressType::Ipv4 => {
+                let (addr, w) = take(w, 4)?;
                 let ip4 = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
-                let ip_addr = IpAddr::V4(ip4);
-                Ok((ip_addr, &u[5..]))
+                Ok((IpAddr::V4(ip4), w))
             }
-            _ => Err("".to_string()),
+            HostAddressType::Ipv6 => {
+                let (addr, w) = take(w, 16)?;
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(addr);
+                Ok((IpAddr::V6(Ipv6Addr::from(octets)), w))
+            }
+            HostAddressType::Dns => Err(MessageError::AddressTypeMismatch),
         }
     }
 }
 
 impl Codec for SocketAddr {
     type Inner = SocketAddr;
-    fn encode(&self, v: &mut Vec<u8>) -> Result<(), String> {
-        match self {
-            std::net::SocketAddr::V4(sock4) => {
-                v.push(HostAddressType::Ipv4 as u8);
-                v.extend_from_slice(sock4.ip().octets().as_ref());
-                let p = sock4.port();
-                v.extend_from_slice(&p.to_le_bytes());
-            }
-            std::net::SocketAddr::V6(sock6) => {
-                v.push(HostAddressType::Ipv6 as u8);
-                v.extend_from_slice(sock6.ip().octets().as_ref());
-                let p = sock6.port();
-                v.extend_from_slice(&p.to_le_bytes());
-            }
-        }
+    fn encode(&self, v: &mut Vec<u8>) -> Result<(), MessageError> {
+        IpAddr::encode(&self.ip(), v)?;
+        v.extend_from_slice(&self.port().to_le_bytes());
         Ok(())
     }
-    fn decode(u: &[u8]) -> Result<(SocketAddr, &[u8]), String> {
-        match (HostAddressType::try_from(u[0])?, &u[1..]) {
-            (HostAddressType::Ipv4, addr) => {
-                let ip4 = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
-                let port = u16::from_le_bytes([addr[4], addr[5]]);
-                let sock = SocketAddr::new(IpAddr::V4(ip4), port);
-                Ok((sock, &addr[6..]))
-            }
-            _ => Err("".to_string()),
-        }
+    fn decode(u: &[u8]) -> Result<(SocketAddr, &[u8]), MessageError> {
+        let (ip, w) = IpAddr::decode(u)?;
+        let (port, w) = take(w, 2)?;
+        let port = u16::from_le_bytes([port[0], port[1]]);
+        Ok((SocketAddr::new(ip, port), w))
     }
 }
 
@@ -424,8 +680,11 @@ impl Route {
                 Address::ChannelAddress(ca) => {
                     println!("Channel: {}", hex::encode(ca));
                 }
-                _ => {
-                    println!("print_route not implemented for type");
+                Address::UdpHostAddress(host) => {
+                    println!("Udp: {}", host);
+                }
+                Address::TcpHostAddress(host) => {
+                    println!("Tcp: {}", host);
                 }
             }
         }
@@ -436,10 +695,11 @@ impl RouterAddress {
     pub fn size_of(&self) -> u8 {
         match &self.address {
             Address::WorkerAddress(a) => a.len() as u8,
-            Address::UdpAddress(_unused) => 7,
-            Address::TcpAddress(_unused) => 7,
+            Address::UdpAddress(s) => socket_addr_size(s),
+            Address::TcpAddress(s) => socket_addr_size(s),
             Address::ChannelAddress(a) => a.len() as u8,
-            _ => 0,
+            Address::UdpHostAddress(h) => host_name_size(h),
+            Address::TcpHostAddress(h) => host_name_size(h),
         }
     }
     pub fn from_address(a: Address) -> Option<RouterAddress> {
@@ -464,27 +724,48 @@ impl RouterAddress {
                 length: ca.len() as u8,
                 address: Address::WorkerAddress(ca.clone()),
             }),
-            _ => None,
+            Address::UdpHostAddress(host) => Some(RouterAddress {
+                a_type: AddressType::Udp,
+                length: host_name_size(host),
+                address: Address::UdpHostAddress(host.clone()),
+            }),
+            Address::TcpHostAddress(host) => Some(RouterAddress {
+                a_type: AddressType::Tcp,
+                length: host_name_size(host),
+                address: Address::TcpHostAddress(host.clone()),
+            }),
         }
     }
+    /// Parses either a socket address or a host name and port, such as `sink.example:4000`
     pub fn udp_router_address_from_str(s: &str) -> Result<RouterAddress, String> {
-        match SocketAddr::from_str(s) {
-            Ok(s) => Ok(RouterAddress {
+        match (SocketAddr::from_str(s), HostName::from_str(s)) {
+            (Ok(s), _) => Ok(RouterAddress {
                 a_type: AddressType::Udp,
-                length: 7,
+                length: socket_addr_size(&s),
                 address: Address::UdpAddress(s),
             }),
-            Err(_unused) => Err("failed to parse router address".to_string()),
+            (_, Ok(h)) => Ok(RouterAddress {
+                a_type: AddressType::Udp,
+                length: host_name_size(&h),
+                address: Address::UdpHostAddress(h),
+            }),
+            _ => Err("failed to parse router address".to_string()),
         }
     }
+    /// Parses either a socket address or a host name and port, such as `sink.example:4000`
     pub fn tcp_router_address_from_str(s: &str) -> Result<RouterAddress, String> {
-        match SocketAddr::from_str(s) {
-            Ok(s) => Ok(RouterAddress {
+        match (SocketAddr::from_str(s), HostName::from_str(s)) {
+            (Ok(s), _) => Ok(RouterAddress {
                 a_type: AddressType::Tcp,
-                length: 7,
+                length: socket_addr_size(&s),
                 address: Address::TcpAddress(s),
             }),
-            Err(_unused) => Err("failed to parse router address".to_string()),
+            (_, Ok(h)) => Ok(RouterAddress {
+                a_type: AddressType::Tcp,
+                length: host_name_size(&h),
+                address: Address::TcpHostAddress(h),
+            }),
+            _ => Err("failed to parse router address".to_string()),
         }
     }
     pub fn channel_router_address_from_str(a: &str) -> Result<RouterAddress, String> {
@@ -530,30 +811,23 @@ impl Clone for Route {
 
 impl Codec for Route {
     type Inner = Route;
-    fn encode(&self, u: &mut Vec<u8>) -> Result<(), String> {
-        if self.addresses.is_empty() {
-            u.push(0 as u8)
-        } else {
-            u.push(self.addresses.len() as u8);
-            for i in 0..self.addresses.len() {
-                RouterAddress::encode(&self.addresses[i].clone(), u);
-            }
+    fn encode(&self, u: &mut Vec<u8>) -> Result<(), MessageError> {
+        if self.addresses.len() > u8::MAX as usize {
+            return Err(MessageError::ValueTooLarge);
+        }
+        u.push(self.addresses.len() as u8);
+        for address in &self.addresses {
+            RouterAddress::encode(address, u)?;
         }
         Ok(())
     }
-    fn decode(encoded: &[u8]) -> Result<(Route, &[u8]), String> {
+    fn decode(encoded: &[u8]) -> Result<(Route, &[u8]), MessageError> {
         let mut route = Route { addresses: vec![] };
-        let mut next_address = &encoded[1..];
-        if 0 < encoded[0] {
-            for i in 0..encoded[0] as usize {
-                match RouterAddress::decode(next_address) {
-                    Ok((a, x)) => {
-                        route.addresses.push(a);
-                        next_address = x;
-                    }
-                    Err(s) => {}
-                }
-            }
+        let (count, mut next_address) = take_u8(encoded)?;
+        for _ in 0..count {
+            let (a, x) = RouterAddress::decode(next_address)?;
+            route.addresses.push(a);
+            next_address = x;
         }
         Ok((route, next_address))
     }
@@ -572,9 +846,9 @@ pub fn varint_size(i: u16) -> usize {
 
 impl Codec for u16 {
     type Inner = u16;
-    fn encode(&self, u: &mut Vec<u8>) -> Result<(), String> {
-        if self >= &0xC000 {
-            return Err("Maximum value exceeded".to_string());
+    fn encode(&self, u: &mut Vec<u8>) -> Result<(), MessageError> {
+        if self >= &0x8000 {
+            return Err(MessageError::ValueTooLarge);
         }
         let mut bytes = self.to_le_bytes();
 
@@ -591,31 +865,78 @@ impl Codec for u16 {
         }
         Ok(())
     }
-    fn decode(u: &[u8]) -> Result<(Self::Inner, &[u8]), String> {
+    fn decode(u: &[u8]) -> Result<(Self::Inner, &[u8]), MessageError> {
         let mut bytes = [0, 0];
-        let mut i = 1;
-
-        bytes[0] = u[0] & 0x7f;
-        if (u[0] & 0x80) == 0x80 as u8 {
-            bytes[0] += (u[1] & 0x01) << 7;
-            bytes[1] = u[1] >> 1;
-            i = 2;
+        let (first, mut w) = take_u8(u)?;
+
+        bytes[0] = first & 0x7f;
+        if (first & 0x80) == 0x80 as u8 {
+            let (second, rest) = take_u8(w)?;
+            bytes[0] += (second & 0x01) << 7;
+            bytes[1] = second >> 1;
+            w = rest;
         }
         let ul2 = ((bytes[1] as u16) << 8) + bytes[0] as u16;
 
-        Ok((ul2, &u[i..]))
+        Ok((ul2, w))
     }
 }
 
-#[derive(Debug)]
+// u64's, such as channel nonces, are encoded as fixed-length big-endian
+impl Codec for u64 {
+    type Inner = u64;
+    fn encode(&self, u: &mut Vec<u8>) -> Result<(), MessageError> {
+        u.extend_from_slice(&self.to_be_bytes());
+        Ok(())
+    }
+    fn decode(u: &[u8]) -> Result<(Self::Inner, &[u8]), MessageError> {
+        let (b, w) = take(u, 8)?;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(b);
+        Ok((u64::from_be_bytes(bytes), w))
+    }
+}
+
+/// A wire protocol version, as nodes announce the highest one they speak to each other
+#[derive(Copy, Clone, Debug, PartialEq)]
 //    #[repr(C)]
 pub struct WireProtocolVersion {
     pub v: u16,
 }
 
+/// Peers that haven't announced their version are assumed to speak the oldest one
 impl Default for WireProtocolVersion {
     fn default() -> WireProtocolVersion {
-        WireProtocolVersion { v: 1 }
+        WireProtocolVersion {
+            v: MIN_WIRE_PROTOCOL_VERSION as u16,
+        }
+    }
+}
+
+impl WireProtocolVersion {
+    /// The highest version this node speaks
+    pub fn this_node() -> WireProtocolVersion {
+        WireProtocolVersion {
+            v: WIRE_PROTOCOL_VERSION as u16,
+        }
+    }
+
+    /// The version to speak with a peer announcing `self`
+    pub fn negotiate(&self) -> WireProtocolVersion {
+        WireProtocolVersion {
+            v: self.v.min(WIRE_PROTOCOL_VERSION as u16),
+        }
+    }
+}
+
+impl Codec for WireProtocolVersion {
+    type Inner = WireProtocolVersion;
+    fn encode(&self, u: &mut Vec<u8>) -> Result<(), MessageError> {
+        u16::encode(&self.v, u)
+    }
+    fn decode(u: &[u8]) -> Result<(WireProtocolVersion, &[u8]), MessageError> {
+        let (v, w) = u16::decode(u)?;
+        Ok((WireProtocolVersion { v }, w))
     }
 }
 
@@ -745,6 +1066,316 @@ mod tests {
         }
     }
 
+    #[test]
+    fn ip6_address_codec() {
+        let mut v: Vec<u8> = vec![];
+        let ip6a = IpAddr::from_str("2001:db8::1").unwrap();
+        IpAddr::encode(&ip6a, &mut v).unwrap();
+        assert_eq!(v.len(), 17);
+        assert_eq!(v[..3], [1, 0x20, 0x01]);
+        v.push(42);
+        let (decoded, rest) = IpAddr::decode(&v).unwrap();
+        assert_eq!(decoded, ip6a);
+        assert_eq!(rest, &[42]);
+    }
+
+    #[test]
+    fn ip6_router_address_codec() {
+        let ra = RouterAddress::udp_router_address_from_str("[::1]:4000").unwrap();
+        assert_eq!(ra.length, 19);
+        assert_eq!(ra.size_of(), 19);
+        assert_eq!(ra.address.size_of(), 19);
+        assert_eq!(ra.address.as_string(), "[::1]:4000");
+
+        let route = Route {
+            addresses: vec![
+                RouterAddress::tcp_router_address_from_str("[2001:db8::7]:9000").unwrap(),
+                RouterAddress::channel_router_address_from_str("00000000").unwrap(),
+            ],
+        };
+        let mut v: Vec<u8> = vec![];
+        Route::encode(&route, &mut v).unwrap();
+        assert_eq!(v.len(), 1 + 21 + 6);
+        let (decoded, rest) = Route::decode(&v).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.addresses, route.addresses);
+        assert_eq!(
+            decoded.addresses[0].address,
+            Address::TcpAddress(SocketAddr::from_str("[2001:db8::7]:9000").unwrap())
+        );
+    }
+
+    #[test]
+    fn malformed_message_decode() {
+        let m = Message {
+            onward_route: Route {
+                addresses: vec![
+                    RouterAddress::udp_router_address_from_str("127.0.0.1:4000").unwrap(),
+                    RouterAddress::tcp_router_address_from_str("[::1]:4000").unwrap(),
+                    RouterAddress::udp_router_address_from_str("sink.example:4000").unwrap(),
+                    RouterAddress::worker_router_address_from_str("00112233").unwrap(),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![RouterAddress::channel_router_address_from_str("aabbccdd").unwrap()],
+            },
+            message_type: MessageType::Payload,
+            message_body: vec![1, 2, 3],
+            extensions: vec![],
+        };
+        let mut v = vec![];
+        Message::encode(&m, &mut v).unwrap();
+
+        // every truncation ending before the message body is rejected
+        for i in 0..(v.len() - m.message_body.len()) {
+            assert!(Message::decode(&v[..i]).is_err());
+        }
+        // and corrupting any byte never panics
+        for i in 0..v.len() {
+            for b in &[0u8, 1, 2, 0x7f, 0x80, 0x81, 0xff] {
+                let mut w = v.clone();
+                w[i] = *b;
+                let _ = Message::decode(&w);
+            }
+        }
+
+        let mut w = v.clone();
+        w[0] = WIRE_PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Message::decode(&w).err(),
+            Some(MessageError::UnsupportedVersion)
+        );
+        assert_eq!(
+            Message::decode(&[1, 0, 0, 42]).err(),
+            Some(MessageError::UnknownMessageType)
+        );
+        assert_eq!(
+            Message::decode(&[1, 1, 7]).err(),
+            Some(MessageError::UnknownAddressType)
+        );
+        assert_eq!(
+            RouterAddress::decode(&[2, 9, 0, 127, 0, 0, 1, 0x10, 0x0f, 0, 0]).err(),
+            Some(MessageError::InvalidAddressLength)
+        );
+        assert_eq!(
+            RouterAddress::decode(&[2, 6, 2, 2, b'A', b'b', 1, 0]).err(),
+            Some(MessageError::InvalidHostName)
+        );
+        assert_eq!(u16::decode(&[0x80]).err(), Some(MessageError::Truncated));
+
+        let mismatched = RouterAddress {
+            a_type: AddressType::Udp,
+            length: 4,
+            address: Address::WorkerAddress(vec![0; 4]),
+        };
+        assert_eq!(
+            RouterAddress::encode(&mismatched, &mut vec![]).err(),
+            Some(MessageError::AddressTypeMismatch)
+        );
+        let too_long = RouterAddress::from_address(Address::WorkerAddress(vec![0; 256])).unwrap();
+        assert_eq!(
+            RouterAddress::encode(&too_long, &mut vec![]).err(),
+            Some(MessageError::ValueTooLarge)
+        );
+    }
+
+    #[test]
+    fn header_extensions() {
+        let mut m = Message {
+            onward_route: Route {
+                addresses: vec![
+                    RouterAddress::udp_router_address_from_str("127.0.0.1:4000").unwrap()
+                ],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: vec![1, 2, 3],
+            extensions: vec![],
+        };
+        let mut legacy = vec![];
+        Message::encode(&m, &mut legacy).unwrap();
+        assert_eq!(legacy[0], MIN_WIRE_PROTOCOL_VERSION);
+
+        // messages without extensions are encoded the same for every peer
+        for version in MIN_WIRE_PROTOCOL_VERSION..=WIRE_PROTOCOL_VERSION {
+            let mut v = vec![];
+            m.encode_for_version(&WireProtocolVersion { v: version as u16 }, &mut v)
+                .unwrap();
+            assert_eq!(v, legacy);
+        }
+
+        m.set_extension(ExtensionType::TraceId as u8, vec![0xab; 16]);
+        m.set_extension(ExtensionType::Priority as u8, vec![1]);
+        m.set_extension(ExtensionType::Priority as u8, vec![7]);
+        m.set_extension(0x40, vec![]);
+        let mut v = vec![];
+        m.encode_for_version(&WireProtocolVersion::this_node(), &mut v)
+            .unwrap();
+        assert_eq!(v[0], WIRE_PROTOCOL_VERSION);
+        let (decoded, rest) = Message::decode(&v).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.extensions, m.extensions);
+        assert_eq!(
+            decoded.extension(ExtensionType::Priority as u8),
+            Some(&[7u8][..])
+        );
+        assert_eq!(decoded.extension(0x40), Some(&[][..]));
+        assert_eq!(decoded.message_body, m.message_body);
+
+        // version 1 peers get the message without its extensions
+        let mut v = vec![];
+        m.encode_for_version(&WireProtocolVersion { v: 1 }, &mut v)
+            .unwrap();
+        assert_eq!(v, legacy);
+
+        // unless one of them is critical
+        m.set_extension(ExtensionType::Compression as u8, vec![1]);
+        assert_eq!(
+            m.encode_for_version(&WireProtocolVersion { v: 1 }, &mut vec![])
+                .err(),
+            Some(MessageError::CriticalExtension)
+        );
+        assert_eq!(
+            m.encode_for_version(&WireProtocolVersion { v: 0 }, &mut vec![])
+                .err(),
+            Some(MessageError::UnsupportedVersion)
+        );
+
+        // extensions that overrun their area are rejected
+        assert_eq!(
+            Message::decode(&[2, 3, 1, 5, 0]).err(),
+            Some(MessageError::Truncated)
+        );
+
+        // lengths that don't fit their encoding are refused rather than cut short
+        for length in &[0x8000, 0x10000, 0x10001] {
+            let extension = Extension {
+                e_type: 0x40,
+                value: vec![0; *length],
+            };
+            assert_eq!(
+                Extension::encode(&extension, &mut vec![]).err(),
+                Some(MessageError::ValueTooLarge)
+            );
+        }
+        let mut m = Message::default();
+        for e_type in 0x40..0x4a {
+            m.set_extension(e_type, vec![0; 0x1fff]);
+        }
+        assert_eq!(
+            Message::encode(&m, &mut vec![]).err(),
+            Some(MessageError::ValueTooLarge)
+        );
+    }
+
+    /// Every truncation of `encoded` fails to decode, and neither corrupted bytes
+    /// nor garbage make decoding panic
+    fn check_decode<T: Codec>(encoded: &[u8]) {
+        assert!(T::decode(encoded).is_ok());
+        for i in 0..encoded.len() {
+            assert!(T::decode(&encoded[..i]).is_err());
+        }
+        for i in 0..encoded.len() {
+            for b in &[0u8, 1, 2, 0x7f, 0x80, 0xff] {
+                let mut w = encoded.to_vec();
+                w[i] = *b;
+                let _ = T::decode(&w);
+            }
+        }
+        let mut x = 0x2545_f491u32;
+        for len in 0..64 {
+            let garbage: Vec<u8> = (0..len)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 17;
+                    x ^= x << 5;
+                    x as u8
+                })
+                .collect();
+            let _ = T::decode(&garbage);
+        }
+    }
+
+    #[test]
+    fn truncated_decode() {
+        let addresses = vec![
+            RouterAddress::udp_router_address_from_str("127.0.0.1:4000").unwrap(),
+            RouterAddress::tcp_router_address_from_str("[::1]:4000").unwrap(),
+            RouterAddress::udp_router_address_from_str("sink.example:4000").unwrap(),
+            RouterAddress::worker_router_address_from_str("00112233").unwrap(),
+            RouterAddress::channel_router_address_from_str("aabbccdd").unwrap(),
+        ];
+        for address in &addresses {
+            let mut v = vec![];
+            RouterAddress::encode(address, &mut v).unwrap();
+            check_decode::<RouterAddress>(&v);
+        }
+        let mut v = vec![];
+        Route::encode(&Route { addresses }, &mut v).unwrap();
+        check_decode::<Route>(&v);
+
+        let mut v = vec![];
+        HostName::encode(&HostName::new("sink.example", 4000).unwrap(), &mut v).unwrap();
+        check_decode::<HostName>(&v);
+        for socket in &["127.0.0.1:4000", "[::1]:4000"] {
+            let mut v = vec![];
+            SocketAddr::encode(&SocketAddr::from_str(socket).unwrap(), &mut v).unwrap();
+            check_decode::<SocketAddr>(&v);
+        }
+    }
+
+    #[test]
+    fn host_name_router_address_codec() {
+        let ra = RouterAddress::udp_router_address_from_str("Sink.Example.Internal.:4000").unwrap();
+        let host = HostName::new("sink.example.internal", 4000).unwrap();
+        assert_eq!(ra.a_type, AddressType::Udp);
+        assert_eq!(ra.address, Address::UdpHostAddress(host.clone()));
+        assert_eq!(ra.length, 25);
+        assert_eq!(ra.address.as_string(), "sink.example.internal:4000");
+
+        let route = Route {
+            addresses: vec![
+                ra,
+                RouterAddress::tcp_router_address_from_str("localhost:9000").unwrap(),
+                RouterAddress::tcp_router_address_from_str("127.0.0.1:9000").unwrap(),
+            ],
+        };
+        let mut v: Vec<u8> = vec![];
+        Route::encode(&route, &mut v).unwrap();
+        assert_eq!(v.len(), 1 + 27 + 15 + 9);
+        assert_eq!(v[3..6], [HostAddressType::Dns as u8, 21, b's']);
+        let (decoded, rest) = Route::decode(&v).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.addresses, route.addresses);
+        assert_eq!(
+            decoded.addresses[1].address,
+            Address::TcpHostAddress(HostName::new("localhost", 9000).unwrap())
+        );
+
+        for bad in &[
+            "sink.example",
+            "-sink.example:1",
+            "sink..example:1",
+            "10.0.0.300:1",
+        ] {
+            assert!(RouterAddress::udp_router_address_from_str(bad).is_err());
+        }
+        assert!(HostName::new(&format!("{}com", "a.".repeat(124)), 1).is_ok());
+        assert!(HostName::new(&format!("{}com", "a.".repeat(125)), 1).is_err());
+    }
+
+    #[test]
+    fn u64_codec() {
+        let mut v: Vec<u8> = vec![];
+        u64::encode(&0x10000, &mut v).unwrap();
+        assert_eq!(v, vec![0, 0, 0, 0, 0, 1, 0, 0]);
+        v.push(42);
+        let (n, rest) = u64::decode(&v).unwrap();
+        assert_eq!(n, 0x10000);
+        assert_eq!(rest, &[42]);
+        assert!(u64::decode(&v[..7]).is_err());
+    }
+
     #[test]
     fn address_codec() {
         // Socket address
@@ -1068,6 +1699,7 @@ mod tests {
             return_route,
             message_type: MessageType::Payload,
             message_body,
+            extensions: vec![],
         };
         let mut u: Vec<u8> = vec![];
         Message::encode(&msg, &mut u);
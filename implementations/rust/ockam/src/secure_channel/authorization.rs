@@ -0,0 +1,118 @@
+//! Policies deciding which remote static keys may establish a secure channel.
+//!
+//! The channel manager consults its authorizer as soon as the key agreement
+//! reveals the remote static key: the initiator before sending M3 and the
+//! responder after receiving it, so no payload flows on a rejected channel.
+
+use crate::secure_channel::error::Error;
+use ockam_common::error::OckamResult;
+use ockam_vault::types::PublicKey;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+/// Decides whether the holder of a remote static key may open a channel
+pub trait ChannelAuthorizer: Send {
+    /// Returns true when a channel with the owner of `remote_static_public_key` is allowed
+    fn authorize(&self, remote_static_public_key: &PublicKey) -> bool;
+}
+
+/// Any `Fn(&PublicKey) -> bool` can be used as a callback authorizer
+impl<F> ChannelAuthorizer for F
+where
+    F: Fn(&PublicKey) -> bool + Send,
+{
+    fn authorize(&self, remote_static_public_key: &PublicKey) -> bool {
+        self(remote_static_public_key)
+    }
+}
+
+/// Accepts only the remote static keys it was given. NK initiators have no static key,
+/// so a responder of NK channels with an allow list refuses every one of them
+#[derive(Clone, Debug, Default)]
+pub struct AllowList {
+    keys: BTreeSet<Vec<u8>>,
+}
+
+impl AllowList {
+    /// Create an allow list from raw public keys
+    pub fn new<K: AsRef<[u8]>>(keys: &[K]) -> Self {
+        Self {
+            keys: keys.iter().map(|k| k.as_ref().to_vec()).collect(),
+        }
+    }
+
+    /// Read an allow list from a file with one hex encoded public key per line.
+    /// Empty lines and lines starting with `#` are ignored
+    pub fn from_file<P: AsRef<Path>>(path: P) -> OckamResult<Self> {
+        let contents = fs::read_to_string(path).map_err(|_| Error::InvalidAllowList.into())?;
+        let mut allow_list = Self::default();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            allow_list.add_hex(line)?;
+        }
+        Ok(allow_list)
+    }
+
+    /// Trust one more raw public key
+    pub fn add(&mut self, key: &[u8]) {
+        self.keys.insert(key.to_vec());
+    }
+
+    /// Trust one more hex encoded public key
+    pub fn add_hex(&mut self, key: &str) -> OckamResult<()> {
+        let key = hex::decode(key).map_err(|_| Error::InvalidAllowList.into())?;
+        self.keys.insert(key);
+        Ok(())
+    }
+
+    /// Number of trusted keys
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// True when no key is trusted
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl ChannelAuthorizer for AllowList {
+    fn authorize(&self, remote_static_public_key: &PublicKey) -> bool {
+        self.keys.contains(remote_static_public_key.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn allow_list_from_file() {
+        let path = std::env::temp_dir().join(format!("ockam_allow_list_{}", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(file, "# trusted devices").unwrap();
+        writeln!(file, "0102").unwrap();
+        writeln!(file).unwrap();
+        writeln!(file, "  a0b0  ").unwrap();
+        drop(file);
+
+        let allow_list = AllowList::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(allow_list.len(), 2);
+        assert!(allow_list.authorize(&PublicKey::new(vec![1, 2])));
+        assert!(allow_list.authorize(&PublicKey::new(vec![0xa0, 0xb0])));
+        assert!(!allow_list.authorize(&PublicKey::new(vec![3])));
+    }
+
+    #[test]
+    fn callback_authorizer() {
+        let authorizer: Box<dyn ChannelAuthorizer> =
+            Box::new(|key: &PublicKey| key.as_ref().first() == Some(&7));
+        assert!(authorizer.authorize(&PublicKey::new(vec![7, 1])));
+        assert!(!authorizer.authorize(&PublicKey::new(vec![1, 7])));
+    }
+}
@@ -18,6 +18,22 @@ pub enum Error {
     CantSend,
     /// Receive error
     RecvError,
+    /// All nonces of the channel have been used
+    NonceExhausted,
+    /// Encrypted payload is too short or carries a reserved nonce
+    MalformedPayload,
+    /// Payload was encrypted in a key phase that is no longer, or not yet, available
+    StaleKeyPhase,
+    /// Nonce was already accepted on this channel
+    ReplayedNonce,
+    /// Nonce is too far behind the replay window to be checked
+    NonceTooOld,
+    /// No channel has the given address
+    UnknownChannel,
+    /// Allow list file can't be read or holds a key that isn't hex
+    InvalidAllowList,
+    /// Session ticket can't be opened, is malformed or has expired
+    InvalidTicket,
 }
 
 impl Error {
@@ -25,31 +25,87 @@ while_true
 use crate::message::{Address, AddressType, Codec, Message, MessageType, Route, RouterAddress};
 use crate::system::commands::OckamCommand::Router;
 use crate::system::commands::{ChannelCommand, OckamCommand, RouterCommand};
+use authorization::ChannelAuthorizer;
 use core::marker::PhantomData;
 use error::*;
 use ockam_common::error::OckamResult;
 use ockam_kex::{CompletedKeyExchange, KeyExchanger, NewKeyExchanger};
 use ockam_kex_xx::XXVault;
-use ockam_vault::types::PublicKey;
-use ockam_vault::Secret;
-use rand::{thread_rng, Rng};
+use ockam_vault::types::{PublicKey, SecretAttributes, SecretPersistence, SecretType};
+use ockam_vault::{PersistentVault, Secret};
+use replay::{ReplayCounters, ReplayWindow};
 use std::{
     collections::BTreeMap,
     sync::{
         mpsc::{Receiver, Sender},
         Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
+use ticket::{TicketContents, TicketKeys, RESUMPTION_NONCE_LENGTH};
+use zeroize::Zeroize;
 
 /// A channel address of zero indicates to the channel manager that
 /// a new channel is being initiated
 pub const CHANNEL_ZERO: &str = "00000000";
 
+/// Number of messages encrypted with one key before a channel rekeys, unless configured otherwise
+pub const DEFAULT_REKEY_MESSAGES: u64 = 1 << 16;
+
+/// The largest nonce is reserved for deriving the next key, as in Noise
+const REKEY_NONCE: u64 = u64::MAX;
+
+/// An encrypted payload starts with a one byte key phase and an eight byte nonce
+const PAYLOAD_HEADER_LENGTH: usize = 9;
+
+/// How many rekeys a peer may be ahead of us before its messages are dropped.
+/// The key phase byte is not authenticated, so this bounds the key derivations
+/// a forged payload can cost us
+const MAX_KEY_PHASES_AHEAD: u8 = 2;
+
+/// How many messages in a row may fail to open before a channel is closed, so that the
+/// worker waiting on it can set up a new one
+const MAX_FAILED_OPENS: u32 = 8;
+
+/// The body of a ChannelClose, sealed so that no other sealed message passes for one
+const CLOSE_MARKER: &[u8] = b"close";
+
+/// How long to wait for the next key agreement message before retransmitting the last one
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times the last key agreement message is retransmitted before the channel fails
+pub const DEFAULT_HANDSHAKE_RETRIES: u32 = 3;
+
+/// Application payloads attached to the key agreement messages sent by this end: `m1` and `m3`
+/// when initiating, `m2` when responding. The first XX message travels in the clear, every
+/// later payload is encrypted
+#[derive(Clone, Debug, Default)]
+pub struct HandshakePayloads {
+    /// Sent with M1
+    pub m1: Vec<u8>,
+    /// Sent with M2
+    pub m2: Vec<u8>,
+    /// Sent with M3
+    pub m3: Vec<u8>,
+}
+
 enum ExchangerRole {
     Initiator,
     Responder,
 }
 
+/// A session ticket received from a responder, with what the initiator needs to resume
+/// a channel with it
+#[derive(Debug)]
+struct SessionTicket {
+    ticket: Vec<u8>,
+    resumption_secret: Box<dyn Secret>,
+    key_attributes: SecretAttributes,
+    local_static_secret: Arc<Box<dyn Secret>>,
+    remote_static_public_key: PublicKey,
+    expires: Instant,
+}
+
 /// A Channel Manager creates secure channels on demand using the specified key exchange
 /// generic. All keys will be created in the associated vault object
 pub struct ChannelManager<
@@ -67,6 +123,23 @@ pub struct ChannelManager<
     phantom_r: PhantomData<R>,
     resp_key_ctx: Option<Arc<Box<dyn Secret>>>,
     init_key_ctx: Option<Arc<Box<dyn Secret>>>,
+    key_vault: Option<Arc<Mutex<dyn PersistentVault + Send>>>,
+    resp_key_label: Option<String>,
+    init_key_label: Option<String>,
+    rekey_messages: Option<u64>,
+    rekey_elapsed: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    handshake_timeout: Duration,
+    handshake_retries: u32,
+    authorizer: Option<Box<dyn ChannelAuthorizer>>,
+    handshake_payloads: HandshakePayloads,
+    ticket_keys: Option<TicketKeys>,
+    tickets: BTreeMap<String, SessionTicket>,
+    /// M1 of the key agreements we answered, to the ciphertext address of the channel
+    /// that answered, until the initiator shows it got our M2
+    answered: BTreeMap<Vec<u8>, String>,
+    /// Initiator nonces of the ResumptionM1 we accepted, to the expiry of their ticket
+    resumed: BTreeMap<Vec<u8>, u64>,
 }
 
 impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> std::fmt::Debug
@@ -112,9 +185,142 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             phantom_r: PhantomData,
             resp_key_ctx,
             init_key_ctx,
+            key_vault: None,
+            resp_key_label: None,
+            init_key_label: None,
+            rekey_messages: Some(DEFAULT_REKEY_MESSAGES),
+            rekey_elapsed: None,
+            idle_timeout: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshake_retries: DEFAULT_HANDSHAKE_RETRIES,
+            authorizer: None,
+            handshake_payloads: HandshakePayloads::default(),
+            ticket_keys: None,
+            tickets: BTreeMap::new(),
+            answered: BTreeMap::new(),
+            resumed: BTreeMap::new(),
         })
     }
 
+    /// Issue session tickets valid for `lifetime` once a channel is up, and resume channels
+    /// over routes we hold a ticket for instead of running a new key agreement. Responders
+    /// only issue tickets when they have a static key of their own. `None`, the default,
+    /// turns tickets off and forgets every ticket issued or received so far.
+    /// See the `ticket` module for the forward secrecy resumed channels give up
+    pub fn set_session_tickets(&mut self, lifetime: Option<Duration>) -> OckamResult<()> {
+        let mut vault = self.vault.lock().unwrap();
+        if let Some(mut keys) = self.ticket_keys.take() {
+            keys.destroy(&mut *vault)?;
+        }
+        for (_, ticket) in std::mem::take(&mut self.tickets) {
+            vault.secret_destroy(ticket.resumption_secret)?;
+        }
+        self.ticket_keys = lifetime.map(TicketKeys::new);
+        Ok(())
+    }
+
+    /// Tickets are kept per route the channel was initiated over
+    fn ticket_route(route: &Route) -> String {
+        let mut encoded = vec![];
+        Route::encode(route, &mut encoded).unwrap();
+        hex::encode(encoded)
+    }
+
+    /// Attach `payloads` to the key agreement messages of every new channel
+    pub fn set_handshake_payloads(&mut self, payloads: HandshakePayloads) {
+        self.handshake_payloads = payloads;
+    }
+
+    /// Only establish channels with remote ends whose static key `authorizer` accepts
+    pub fn set_authorizer(&mut self, authorizer: Box<dyn ChannelAuthorizer>) {
+        self.authorizer = Some(authorizer);
+    }
+
+    /// Ask the authorizer, if any, about the remote static key of a finished key agreement
+    fn authorized(&self, completed_key_exchange: &CompletedKeyExchange) -> bool {
+        match &self.authorizer {
+            Some(authorizer) => {
+                authorizer.authorize(&completed_key_exchange.remote_static_public_key)
+            }
+            None => true,
+        }
+    }
+
+    /// Close channels that have neither sent nor received a payload for `timeout`.
+    /// `None` keeps idle channels open
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Retransmit the last key agreement message when no answer arrived within `timeout`,
+    /// and fail the channel after `retries` retransmissions. Responders close channels
+    /// that came up with M2, as IK and NK channels do, when no payload arrives within
+    /// `timeout * (retries + 1)`
+    pub fn set_handshake_timeout(&mut self, timeout: Duration, retries: u32) {
+        self.handshake_timeout = timeout;
+        self.handshake_retries = retries;
+    }
+
+    /// Rekey the sending side of every channel after `messages` messages or once `elapsed`
+    /// has passed since the last rekey, whichever comes first. `None` disables that trigger
+    pub fn set_rekey_interval(&mut self, messages: Option<u64>, elapsed: Option<Duration>) {
+        self.rekey_messages = messages;
+        self.rekey_elapsed = elapsed;
+    }
+
+    /// Look the responder static key up by label in `vault` for every new channel,
+    /// so that a rotated key is used without restarting the channel manager
+    pub fn set_responder_key_label(
+        &mut self,
+        vault: Arc<Mutex<dyn PersistentVault + Send>>,
+        label: String,
+    ) {
+        self.key_vault = Some(vault);
+        self.resp_key_label = Some(label);
+    }
+
+    /// Look the initiator static key up by label in `vault` for every new channel,
+    /// so that a rotated key is used without restarting the channel manager
+    pub fn set_initiator_key_label(
+        &mut self,
+        vault: Arc<Mutex<dyn PersistentVault + Send>>,
+        label: String,
+    ) {
+        self.key_vault = Some(vault);
+        self.init_key_label = Some(label);
+    }
+
+    /// Returns the current version of a labeled key, falling back to the fixed key
+    fn current_key(
+        &self,
+        label: &Option<String>,
+        fixed: &Option<Arc<Box<dyn Secret>>>,
+    ) -> Option<Arc<Box<dyn Secret>>> {
+        if let (Some(vault), Some(label)) = (&self.key_vault, label) {
+            match vault.lock().unwrap().get_persistent_secret_by_label(label) {
+                Ok(secret) => return Some(Arc::new(secret)),
+                Err(e) => println!("failed to find key labeled {}: {}", label, e),
+            }
+        }
+        fixed.clone()
+    }
+
+    /// Messages dropped by the replay filter of the channel with the given address
+    pub fn replay_counters(&self, address: &str) -> Option<ReplayCounters> {
+        self.channels
+            .get(address)
+            .map(|channel| channel.lock().unwrap().replay_window.counters())
+    }
+
+    /// Payloads the remote end attached to its key agreement messages, once the channel is up
+    pub fn remote_payloads(&self, address: &str) -> Option<Vec<Vec<u8>>> {
+        let channel = self.channels.get(address)?.lock().unwrap();
+        channel
+            .completed_key_exchange
+            .as_ref()
+            .map(|completed_key_exchange| completed_key_exchange.remote_payloads.clone())
+    }
+
     /// Check for work to be done and do it
     pub fn poll(&mut self) -> OckamResult<bool> {
         let keep_going = true;
@@ -129,6 +335,9 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
                     )) => {
                         self.initiate_new_channel(route, return_address)?;
                     }
+                    OckamCommand::Channel(ChannelCommand::Close(address)) => {
+                        self.handle_close(address)?;
+                    }
                     OckamCommand::Channel(ChannelCommand::Stop) => {
                         self.channels.clear();
                         break;
@@ -146,9 +355,145 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
                 }
             }
         }
+        self.check_timeouts()?;
         Ok(keep_going)
     }
 
+    /// Close the channel with the given address, letting the remote end know
+    fn handle_close(&mut self, address: Address) -> OckamResult<()> {
+        match self.channels.get(&address.as_string()) {
+            Some(channel) => {
+                let channel = channel.clone();
+                self.close_channel(channel, true)
+            }
+            None => Err(Error::UnknownChannel.into()),
+        }
+    }
+
+    /// Retransmit or fail stalled key agreements, close idle channels and
+    /// forget ticket keys, tickets and resumption nonces that have outlived their lifetime
+    fn check_timeouts(&mut self) -> OckamResult<()> {
+        let now = Instant::now();
+        {
+            let mut vault = self.vault.lock().unwrap();
+            if let Some(keys) = &mut self.ticket_keys {
+                keys.rotate(&mut *vault)?;
+            }
+            let expired: Vec<String> = self
+                .tickets
+                .iter()
+                .filter(|(_, ticket)| ticket.expires <= now)
+                .map(|(route, _)| route.clone())
+                .collect();
+            for route in expired {
+                let ticket = self.tickets.remove(&route).unwrap();
+                vault.secret_destroy(ticket.resumption_secret)?;
+            }
+            let unix_now = ticket::unix_time();
+            self.resumed.retain(|_, expires| *expires > unix_now);
+        }
+        let mut failed = vec![];
+        let mut idle = vec![];
+        for (address, channel) in self.channels.iter() {
+            let mut ch = channel.lock().unwrap();
+            // every channel is listed under both its addresses, look at it once
+            if *address != ch.as_ciphertext_address().as_string() {
+                continue;
+            }
+            match ch.handshake_deadline {
+                Some(deadline) if now >= deadline => match ch.handshake_message.clone() {
+                    Some(m) if ch.handshake_retransmits < self.handshake_retries => {
+                        ch.handshake_retransmits += 1;
+                        ch.handshake_deadline = Some(now + self.handshake_timeout);
+                        self.router_tx
+                            .send(Router(RouterCommand::SendMessage(m)))
+                            .map_err(|e| Error::from(e).into())?;
+                    }
+                    _ => failed.push(channel.clone()),
+                },
+                Some(_) => {}
+                None => {
+                    if let Some(timeout) = self.idle_timeout {
+                        if ch.completed_key_exchange.is_some()
+                            && now.duration_since(ch.last_activity) >= timeout
+                        {
+                            idle.push(channel.clone());
+                        }
+                    }
+                }
+            }
+        }
+        for channel in failed {
+            println!("key agreement timed out");
+            self.close_channel(channel, false)?;
+        }
+        for channel in idle {
+            self.close_channel(channel, true)?;
+        }
+        Ok(())
+    }
+
+    /// Forget a channel and destroy its keys, telling the remote end with an encrypted
+    /// ChannelClose message if `notify_remote`, and the local worker waiting on it if any
+    fn close_channel(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        notify_remote: bool,
+    ) -> OckamResult<()> {
+        let mut channel = channel.lock().unwrap();
+        self.channels
+            .remove(&channel.as_cleartext_address().as_string());
+        self.channels
+            .remove(&channel.as_ciphertext_address().as_string());
+        if let Some((m1, _)) = channel.answered.take() {
+            self.answered.remove(&m1);
+        }
+
+        let mut vault = self.vault.lock().unwrap();
+        if notify_remote && channel.completed_key_exchange.is_some() {
+            let body = channel.seal(
+                &mut *vault,
+                MessageType::ChannelClose,
+                CLOSE_MARKER,
+                None,
+                None,
+            )?;
+            let m = Message {
+                onward_route: channel.route.clone(),
+                return_route: Route {
+                    addresses: vec![
+                        RouterAddress::from_address(channel.as_ciphertext_address()).unwrap()
+                    ],
+                },
+                message_type: MessageType::ChannelClose,
+                message_body: body,
+                extensions: vec![],
+            };
+            self.router_tx
+                .send(Router(RouterCommand::SendMessage(m)))
+                .map_err(|e| Error::from(e).into())?;
+        }
+        channel.destroy(&mut *vault)?;
+
+        if let Some(route) = channel.notify.take() {
+            let m = Message {
+                onward_route: route,
+                return_route: Route {
+                    addresses: vec![
+                        RouterAddress::from_address(channel.as_cleartext_address()).unwrap()
+                    ],
+                },
+                message_type: MessageType::ChannelClose,
+                message_body: vec![],
+                extensions: vec![],
+            };
+            self.router_tx
+                .send(Router(RouterCommand::ReceiveMessage(m)))
+                .map_err(|e| Error::from(e).into())?;
+        }
+        Ok(())
+    }
+
     fn handle_send(&mut self, mut m: Message) -> OckamResult<()> {
         if m.onward_route.addresses.is_empty() {
             return Err(Error::CantSend.into());
@@ -172,22 +517,14 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
                     Message::encode(&m, &mut encoded_mb).unwrap();
 
                     // encrypt it
-                    let mut encrypted_mb: Vec<u8> = vec![];
-                    u16::encode(&channel.nonce, &mut encrypted_mb)
-                        .or_else(|_| Err(Error::CantSend.into()))?;
-
-                    let cke = channel.completed_key_exchange.as_ref().unwrap();
-                    let nonce = Channel::nonce_16_to_96(channel.nonce);
                     let mut vault = self.vault.lock().unwrap();
-                    let mut ciphertext_and_tag = vault.aead_aes_gcm_encrypt(
-                        &cke.encrypt_key,
+                    let encrypted_mb = channel.seal(
+                        &mut *vault,
+                        MessageType::Payload,
                         &encoded_mb,
-                        &nonce,
-                        &cke.h,
+                        self.rekey_messages,
+                        self.rekey_elapsed,
                     )?;
-                    channel.nonce += 1;
-
-                    encrypted_mb.append(&mut ciphertext_and_tag);
 
                     // construct the new message
                     let new_m = Message {
@@ -200,6 +537,7 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
                         },
                         message_type: MessageType::Payload,
                         message_body: encrypted_mb,
+                        extensions: vec![],
                     };
 
                     // and send
@@ -232,6 +570,21 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
         // Respond accordingly
         let mut recv_address_str = m.onward_route.addresses[0].address.as_string();
         if recv_address_str == CHANNEL_ZERO {
+            if matches!(
+                m.message_type,
+                MessageType::KeyAgreementM1 | MessageType::ResumptionM1
+            ) {
+                if let Some(address) = self.answered.get(&m.message_body) {
+                    // the initiator retransmitted M1 because our M2 got lost, send it again
+                    let channel = self.channels.get(address).unwrap().lock().unwrap();
+                    if let Some((_, m2)) = &channel.answered {
+                        self.router_tx
+                            .send(Router(RouterCommand::SendMessage(m2.clone())))
+                            .map_err(|e| Error::from(e).into())?;
+                    }
+                    return Ok(());
+                }
+            }
             if let Some((_clear, cipher)) = self.create_channel(ExchangerRole::Responder) {
                 recv_address_str = cipher;
             } else {
@@ -255,19 +608,49 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
 
                 return match m.message_type {
                     MessageType::KeyAgreementM1 => {
-                        self.handle_m1_recv(channel, m)?;
+                        if !self.handle_m1_recv(channel.clone(), m)? {
+                            println!("initiator key not authorized, dropping channel");
+                            self.close_channel(channel, false)?;
+                        }
                         Ok(())
                     }
                     MessageType::KeyAgreementM2 => {
-                        self.handle_m2_recv(channel, m)?;
+                        if !self.handle_m2_recv(channel.clone(), m)? {
+                            println!("responder key not authorized, dropping channel");
+                            self.close_channel(channel, false)?;
+                        }
                         Ok(())
                     }
                     MessageType::KeyAgreementM3 => {
-                        self.handle_m3_recv(channel, m)?;
+                        if !self.handle_m3_recv(channel.clone(), m)? {
+                            println!("initiator key not authorized, closing channel");
+                            self.close_channel(channel, true)?;
+                        }
                         Ok(())
                     }
                     MessageType::Payload => {
-                        self.handle_payload_recv(channel, m)?;
+                        self.handle_payload_recv(channel.clone(), m)?;
+                        self.close_if_failing(channel)
+                    }
+                    MessageType::ChannelClose => {
+                        self.handle_close_recv(channel.clone(), m)?;
+                        self.close_if_failing(channel)
+                    }
+                    MessageType::SessionTicket => {
+                        self.handle_ticket_recv(channel.clone(), m)?;
+                        self.close_if_failing(channel)
+                    }
+                    MessageType::ResumptionM1 => {
+                        if !self.handle_resumption_m1_recv(channel.clone(), m)? {
+                            self.close_channel(channel, false)?;
+                        }
+                        Ok(())
+                    }
+                    MessageType::ResumptionM2 => {
+                        if !self.handle_resumption_m2_recv(channel.clone(), m)? {
+                            println!("channel resumption failed, dropping channel");
+                            self.close_channel(channel, false)?;
+                        }
                         Ok(())
                     }
                     _ => {
@@ -277,14 +660,50 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
                 };
             }
             None => {
-                debug_assert!(false, "unknown channel address");
+                // the channel may have been closed while this message was in flight
+                println!("dropping message for unknown channel {}", recv_address_str);
             }
         }
         Ok(())
     }
 
-    fn handle_payload_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> OckamResult<()> {
-        let channel = channel.lock().unwrap();
+    /// Nothing shows the initiator of a channel that came up with M2 is there until its
+    /// first payload, give up on the channel if none arrives while the initiator could
+    /// still be retransmitting M1
+    fn confirmation_deadline(&self) -> Option<Instant> {
+        Some(Instant::now() + self.handshake_timeout * (self.handshake_retries + 1))
+    }
+
+    /// The initiator got our M2: a retransmitted M1 is no longer answered from `channel`,
+    /// a channel that came up with M2 is no longer closed for want of a first payload,
+    /// and the worker gets to know about the channel
+    fn confirmed(&mut self, channel: &mut Channel) -> OckamResult<()> {
+        if let Some((m1, _)) = channel.answered.take() {
+            self.answered.remove(&m1);
+        }
+        channel.handshake_deadline = None;
+        if let Some(notification) = channel.notification.take() {
+            channel.notify = Some(notification.onward_route.clone());
+            self.router_tx
+                .send(Router(RouterCommand::ReceiveMessage(notification)))
+                .map_err(|e| Error::from(e).into())?;
+        }
+        Ok(())
+    }
+
+    /// Close a channel that too many messages in a row failed to open on,
+    /// its keys are out of step with the remote end or someone is sending garbage
+    fn close_if_failing(&mut self, channel: Arc<Mutex<Channel>>) -> OckamResult<()> {
+        let failing = channel.lock().unwrap().failed_opens >= MAX_FAILED_OPENS;
+        if failing {
+            println!("too many messages failed to open, closing channel");
+            self.close_channel(channel, true)?;
+        }
+        Ok(())
+    }
+
+    fn handle_payload_recv(&mut self, channel: Arc<Mutex<Channel>>, m: Message) -> OckamResult<()> {
+        let mut channel = channel.lock().unwrap();
 
         match &m.onward_route.addresses[0].address {
             Address::ChannelAddress(ca) => {
@@ -296,13 +715,22 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
         }
 
         // unwrap the payload and decode the message (payload *should* be an encrypted Message)
-        let (nonce, encrypted_msg) = u16::decode(&m.message_body).unwrap();
-        let nonce_96 = Channel::nonce_16_to_96(nonce);
-        let kex = channel.completed_key_exchange.as_ref().unwrap();
-        let mut vault = self.vault.lock().unwrap();
-        let encoded_msg =
-            vault.aead_aes_gcm_decrypt(&kex.decrypt_key, encrypted_msg, &nonce_96, &kex.h)?;
-        let (mut decoded_msg, _) = Message::decode(&encoded_msg).unwrap();
+        let encoded_msg = {
+            let mut vault = self.vault.lock().unwrap();
+            match channel.open_or_drop(&mut *vault, m.message_type, &m.message_body) {
+                Some(encoded_msg) => encoded_msg,
+                None => return Ok(()),
+            }
+        };
+        self.confirmed(&mut channel)?;
+        let (mut decoded_msg, _) = match Message::decode(&encoded_msg) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                // the remote end is authenticated but sent something we can't route, drop it
+                println!("dropping malformed payload: {:?}", e);
+                return Ok(());
+            }
+        };
         decoded_msg.return_route.addresses.insert(
             0,
             RouterAddress::from_address(channel.as_cleartext_address()).unwrap(),
@@ -315,7 +743,29 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
         Ok(())
     }
 
-    fn handle_m1_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> OckamResult<()> {
+    fn handle_close_recv(&mut self, channel: Arc<Mutex<Channel>>, m: Message) -> OckamResult<()> {
+        // only a close sealed by the remote end tears the channel down
+        let authenticated = {
+            let mut ch = channel.lock().unwrap();
+            let mut vault = self.vault.lock().unwrap();
+            match ch.open_or_drop(&mut *vault, m.message_type, &m.message_body) {
+                Some(body) if body == CLOSE_MARKER => true,
+                Some(_) => {
+                    println!("dropping channel close without the close marker");
+                    false
+                }
+                None => false,
+            }
+        };
+        if authenticated {
+            self.close_channel(channel, false)?;
+        }
+        Ok(())
+    }
+
+    /// Returns false when a one round trip key agreement finished with an
+    /// initiator whose static key is not authorized
+    fn handle_m1_recv(&mut self, channel: Arc<Mutex<Channel>>, m: Message) -> OckamResult<bool> {
         let channel = &mut *channel.lock().unwrap();
         let cleartext_address = channel.as_cleartext_address();
         let ciphertext_address = channel.as_ciphertext_address();
@@ -326,10 +776,11 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             }
         };
 
-        // send cleartext channel address as payload
+        // send cleartext channel address ahead of the payload
         let cleartext_router_addr = RouterAddress::from_address(cleartext_address).unwrap();
         let mut cleartext_addr_encoded = vec![];
         RouterAddress::encode(&cleartext_router_addr, &mut cleartext_addr_encoded).unwrap();
+        cleartext_addr_encoded.extend_from_slice(&self.handshake_payloads.m2);
 
         agreement.process(&m.message_body)?;
         let m2 = agreement.process(&cleartext_addr_encoded)?;
@@ -337,45 +788,109 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
         let new_m = Message {
             onward_route: m.return_route.clone(),
             return_route: Route {
-                addresses: vec![RouterAddress::from_address(ciphertext_address).unwrap()],
+                addresses: vec![RouterAddress::from_address(ciphertext_address.clone()).unwrap()],
             },
             message_type: MessageType::KeyAgreementM2,
             message_body: m2,
+            extensions: vec![],
         };
+        let complete = agreement.is_complete();
+        if complete {
+            // patterns like IK and NK are done once M2 is out
+            let agreement = channel.agreement.take().unwrap();
+            if !self.complete_responder(channel, agreement.finalize()?, m.return_route)? {
+                return Ok(false);
+            }
+            channel.handshake_deadline = self.confirmation_deadline();
+        } else {
+            channel.handshake_sent(&new_m, self.handshake_timeout);
+        }
+        // a lost M2 makes the initiator retransmit M1, which this channel answers again
+        self.answered
+            .insert(m.message_body.clone(), ciphertext_address.as_string());
+        channel.answered = Some((m.message_body, new_m.clone()));
         self.router_tx
             .send(Router(RouterCommand::SendMessage(new_m)))
             .unwrap();
-        Ok(())
+        if complete {
+            self.issue_ticket(channel)?;
+        }
+        Ok(true)
     }
 
-    fn handle_m2_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> OckamResult<()> {
+    /// Returns false when the responder's static key is not authorized
+    fn handle_m2_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> OckamResult<bool> {
         let mut channel = &mut *channel.lock().unwrap();
         let mut agreement = match channel.agreement.take() {
             Some(e) => e,
+            None if channel.completed_key_exchange.is_some() => {
+                // M2 was retransmitted because our M3 got lost, send M3 again
+                if let Some(m3) = channel.handshake_message.clone() {
+                    self.router_tx
+                        .send(Router(RouterCommand::SendMessage(m3)))
+                        .map_err(|e| Error::from(e).into())?;
+                }
+                return Ok(true);
+            }
             None => {
                 return Err(Error::InvalidState.into());
             }
         };
 
         let return_route = m.return_route.clone();
-        let channel_cleartext_addr_encoded = agreement.process(&m.message_body)?;
-        let m3 = agreement.process(&[])?;
-        let m = Message {
+        let mut channel_cleartext_addr_encoded = agreement.process(&m.message_body)?;
+        // one round trip patterns are complete after M2 and have no M3
+        let m3 = if agreement.is_complete() {
+            None
+        } else {
+            Some(agreement.process(&self.handshake_payloads.m3)?)
+        };
+        let mut completed_key_exchange = agreement.finalize()?;
+
+        // split the responder's cleartext address off its payload
+        let payload_len = RouterAddress::decode(&channel_cleartext_addr_encoded)
+            .map_err(|_| Error::MalformedPayload.into())?
+            .1
+            .len();
+        let payload = channel_cleartext_addr_encoded
+            .split_off(channel_cleartext_addr_encoded.len() - payload_len);
+        if let Some(last) = completed_key_exchange.remote_payloads.last_mut() {
+            *last = payload;
+        }
+        if !self.authorized(&completed_key_exchange) {
+            // M3 is never sent, the responder gives up once its handshake times out
+            channel.completed_key_exchange = Some(completed_key_exchange);
+            return Ok(false);
+        }
+        // keep M3 around in case the responder retransmits M2
+        channel.handshake_message = m3.map(|m3| Message {
             onward_route: return_route.clone(),
             return_route: Route {
                 addresses: vec![m.onward_route.addresses[0].clone()],
             },
             message_type: MessageType::KeyAgreementM3,
             message_body: m3,
-        };
-        self.router_tx
-            .send(Router(RouterCommand::SendMessage(m)))
-            .unwrap();
-        let completed_key_exchange = agreement.finalize()?;
+            extensions: vec![],
+        });
+        channel.handshake_deadline = None;
+        if let Some(m3) = channel.handshake_message.clone() {
+            self.router_tx
+                .send(Router(RouterCommand::SendMessage(m3)))
+                .unwrap();
+        }
         let mut static_public_key = completed_key_exchange
             .remote_static_public_key
             .as_ref()
             .to_vec();
+        if self.ticket_keys.is_some() && channel.ticket_route.is_some() {
+            // keep the secret a ticket from the responder will be good for
+            let mut vault = self.vault.lock().unwrap();
+            channel.resumption_secret = Some(ticket::resumption_secret(
+                &mut *vault,
+                &completed_key_exchange.encrypt_key,
+                &completed_key_exchange.h,
+            )?);
+        }
         channel.completed_key_exchange = Some(completed_key_exchange);
         channel.route = return_route;
 
@@ -395,72 +910,411 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
                 return Err(Error::NotImplemented.into());
             }
         }
-        Ok(())
+        Ok(true)
     }
 
-    fn handle_m3_recv(&self, channel: Arc<Mutex<Channel>>, m: Message) -> OckamResult<()> {
+    /// Returns false when the initiator's static key is not authorized
+    fn handle_m3_recv(&mut self, channel: Arc<Mutex<Channel>>, m: Message) -> OckamResult<bool> {
         let mut channel = channel.lock().unwrap();
         let mut agreement = match channel.agreement.take() {
             Some(e) => e,
+            // a retransmitted M3 for a channel that is already up
+            None if channel.completed_key_exchange.is_some() => return Ok(true),
             None => {
                 return Err(Error::InvalidState.into());
             }
         };
 
-        let return_route = m.return_route.clone();
         // For now ignore anything returned from M3
         let _ = agreement.process(&m.message_body)?;
         debug_assert!(agreement.is_complete());
         if channel.completed_key_exchange.is_none() {
-            // key agreement has finished, now can process any pending messages
-            let pending = channel.pending.clone();
             let completed_key_exchange = agreement.finalize()?;
-            let remote_static_public_key = completed_key_exchange
-                .remote_static_public_key
-                .as_ref()
-                .to_vec();
-            channel.completed_key_exchange = Some(completed_key_exchange);
-            channel.route = return_route;
-            let pending = channel.pending.clone();
-            match pending {
-                Some(mut p) => {
-                    p.return_route = channel.route.clone();
-                    p.return_route.addresses.insert(
-                        0,
-                        RouterAddress::from_address(channel.as_cleartext_address()).unwrap(),
-                    );
-                    // add the channel's remote public key as the message body
-                    p.message_body = remote_static_public_key;
+            if !self.complete_responder(&mut channel, completed_key_exchange, m.return_route)? {
+                return Ok(false);
+            }
+            self.confirmed(&mut channel)?;
+            self.issue_ticket(&mut channel)?;
+        }
+        Ok(true)
+    }
 
+    /// Finish the key agreement on the responder side and prepare to let the worker know
+    /// the channel is up, which happens once `confirmed`. Returns false when the initiator's
+    /// static key is not authorized
+    fn complete_responder(
+        &self,
+        channel: &mut Channel,
+        completed_key_exchange: CompletedKeyExchange,
+        return_route: Route,
+    ) -> OckamResult<bool> {
+        // key agreement has finished, now can process any pending messages
+        let remote_static_public_key = completed_key_exchange
+            .remote_static_public_key
+            .as_ref()
+            .to_vec();
+        let authorized = self.authorized(&completed_key_exchange);
+        channel.completed_key_exchange = Some(completed_key_exchange);
+        channel.route = return_route;
+        channel.handshake_message = None;
+        channel.handshake_deadline = None;
+        if !authorized {
+            return Ok(false);
+        }
+        let pending = channel.pending.take();
+        match pending {
+            Some(mut p) => {
+                p.return_route = channel.route.clone();
+                p.return_route.addresses.insert(
+                    0,
+                    RouterAddress::from_address(channel.as_cleartext_address()).unwrap(),
+                );
+                // add the channel's remote public key as the message body
+                p.message_body = remote_static_public_key;
+                channel.notification = Some(p);
+            }
+            _ => {
+                let mut return_route = channel.route.clone();
+                return_route.addresses.insert(
+                    0,
+                    RouterAddress::from_address(channel.as_cleartext_address()).unwrap(),
+                );
+                channel.notification = Some(Message {
+                    onward_route: Route {
+                        addresses: vec![RouterAddress::worker_router_address_from_str(
+                            CHANNEL_ZERO,
+                        )
+                        .unwrap()],
+                    },
+                    return_route,
+                    message_type: MessageType::None,
+                    message_body: vec![],
+                    extensions: vec![],
+                });
+            }
+        }
+        Ok(true)
+    }
+
+    /// Send the initiator of a channel that just came up a session ticket, when tickets
+    /// are on and we have a static key to resume channels with
+    fn issue_ticket(&mut self, channel: &mut Channel) -> OckamResult<()> {
+        if self.ticket_keys.is_none()
+            || self
+                .current_key(&self.resp_key_label, &self.resp_key_ctx)
+                .is_none()
+        {
+            return Ok(());
+        }
+        let keys = self.ticket_keys.as_mut().unwrap();
+        let mut vault = self.vault.lock().unwrap();
+        let now = ticket::unix_time();
+        // tickets issued on resumed channels keep the expiry of the ticket that was resumed
+        let expires = channel
+            .ticket_expires
+            .unwrap_or(now + keys.lifetime().as_secs());
+        if expires <= now {
+            return Ok(());
+        }
+        let kex = channel
+            .completed_key_exchange
+            .as_ref()
+            .ok_or_else(|| Error::InvalidState.into())?;
+        let mut key_attributes = vault.secret_attributes_get(&kex.encrypt_key)?;
+        key_attributes.persistence = SecretPersistence::Ephemeral;
+        let contents = TicketContents {
+            expires,
+            key_attributes,
+            resumption_secret: ticket::resumption_secret(&mut *vault, &kex.decrypt_key, &kex.h)?,
+            remote_static_public_key: kex.remote_static_public_key.clone(),
+        };
+        let ticket = keys.seal(&mut *vault, &contents);
+        vault.secret_destroy(contents.resumption_secret)?;
+
+        let mut body = vec![];
+        u64::encode(&(expires - now), &mut body).map_err(|_| Error::CantSend.into())?;
+        body.append(&mut ticket?);
+        let body = channel.seal(
+            &mut *vault,
+            MessageType::SessionTicket,
+            &body,
+            self.rekey_messages,
+            self.rekey_elapsed,
+        )?;
+        let m = Message {
+            onward_route: channel.route.clone(),
+            return_route: Route {
+                addresses: vec![
+                    RouterAddress::from_address(channel.as_ciphertext_address()).unwrap()
+                ],
+            },
+            message_type: MessageType::SessionTicket,
+            message_body: body,
+            extensions: vec![],
+        };
+        self.router_tx
+            .send(Router(RouterCommand::SendMessage(m)))
+            .map_err(|e| Error::from(e).into())
+    }
+
+    /// Keep the session ticket a responder issued, to resume a channel over the same route
+    fn handle_ticket_recv(&mut self, channel: Arc<Mutex<Channel>>, m: Message) -> OckamResult<()> {
+        let mut channel = channel.lock().unwrap();
+        let mut vault = self.vault.lock().unwrap();
+        let body = match channel.open_or_drop(&mut *vault, m.message_type, &m.message_body) {
+            Some(body) => body,
+            None => return Ok(()),
+        };
+        let (lifetime, ticket) = match u64::decode(&body) {
+            Ok(decoded) => decoded,
+            Err(_) => {
+                println!("dropping malformed session ticket");
+                return Ok(());
+            }
+        };
+        let resumption_secret = match channel.resumption_secret.take() {
+            Some(secret) => secret,
+            // tickets are off, or this channel already got one
+            None => return Ok(()),
+        };
+        let route = match (&channel.ticket_route, &self.ticket_keys) {
+            (Some(route), Some(_)) => route.clone(),
+            _ => return vault.secret_destroy(resumption_secret),
+        };
+        let kex = channel.completed_key_exchange.as_ref().unwrap();
+        let mut key_attributes = vault.secret_attributes_get(&kex.encrypt_key)?;
+        key_attributes.persistence = SecretPersistence::Ephemeral;
+        let ticket = SessionTicket {
+            ticket: ticket.to_vec(),
+            resumption_secret,
+            key_attributes,
+            local_static_secret: kex.local_static_secret.clone(),
+            remote_static_public_key: kex.remote_static_public_key.clone(),
+            expires: Instant::now() + Duration::from_secs(lifetime),
+        };
+        if let Some(previous) = self.tickets.insert(route, ticket) {
+            vault.secret_destroy(previous.resumption_secret)?;
+        }
+        Ok(())
+    }
+
+    /// Resume a channel with the session ticket an initiator presented and answer with our
+    /// nonce and cleartext address. A ticket we can't accept is answered with an empty
+    /// ResumptionM2, upon which the initiator runs a full key agreement instead.
+    /// Returns false when the channel was not resumed
+    fn handle_resumption_m1_recv(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> OckamResult<bool> {
+        let channel = &mut *channel.lock().unwrap();
+        let mut reply = Message {
+            onward_route: m.return_route.clone(),
+            return_route: Route {
+                addresses: vec![
+                    RouterAddress::from_address(channel.as_ciphertext_address()).unwrap()
+                ],
+            },
+            message_type: MessageType::ResumptionM2,
+            message_body: vec![],
+            extensions: vec![],
+        };
+        let (completed_key_exchange, expires, mut responder_nonce) =
+            match self.resume_responder(&m.message_body)? {
+                Some(resumed) => resumed,
+                None => {
                     self.router_tx
-                        .send(Router(RouterCommand::ReceiveMessage(p)))
-                        .unwrap();
-                    channel.pending = None;
-                }
-                _ => {
-                    let mut return_route = channel.route.clone();
-                    return_route.addresses.insert(
-                        0,
-                        RouterAddress::from_address(channel.as_cleartext_address()).unwrap(),
-                    );
-                    let new_m = Message {
-                        onward_route: Route {
-                            addresses: vec![RouterAddress::worker_router_address_from_str(
-                                CHANNEL_ZERO,
-                            )
-                            .unwrap()],
-                        },
-                        return_route,
-                        message_type: MessageType::None,
-                        message_body: vec![],
-                    };
-                    self.router_tx
-                        .send(Router(RouterCommand::ReceiveMessage(new_m)))
-                        .unwrap();
+                        .send(Router(RouterCommand::SendMessage(reply)))
+                        .map_err(|e| Error::from(e).into())?;
+                    return Ok(false);
                 }
+            };
+        channel.agreement = None;
+        channel.ticket_expires = Some(expires);
+        if !self.complete_responder(channel, completed_key_exchange, m.return_route)? {
+            println!("initiator key not authorized, dropping channel");
+            return Ok(false);
+        }
+        channel.handshake_deadline = self.confirmation_deadline();
+
+        // the cleartext address goes first on the new keys, which confirms them to the initiator
+        let cleartext_router_addr =
+            RouterAddress::from_address(channel.as_cleartext_address()).unwrap();
+        let mut cleartext_addr_encoded = vec![];
+        RouterAddress::encode(&cleartext_router_addr, &mut cleartext_addr_encoded).unwrap();
+        {
+            let mut vault = self.vault.lock().unwrap();
+            let mut sealed = channel.seal(
+                &mut *vault,
+                MessageType::ResumptionM2,
+                &cleartext_addr_encoded,
+                None,
+                None,
+            )?;
+            reply.message_body.append(&mut responder_nonce);
+            reply.message_body.append(&mut sealed);
+        }
+        // a lost ResumptionM2 makes the initiator retransmit, which this channel answers again
+        self.answered.insert(
+            m.message_body.clone(),
+            channel.as_ciphertext_address().as_string(),
+        );
+        channel.answered = Some((m.message_body, reply.clone()));
+        self.router_tx
+            .send(Router(RouterCommand::SendMessage(reply)))
+            .map_err(|e| Error::from(e).into())?;
+        self.issue_ticket(channel)?;
+        Ok(true)
+    }
+
+    /// Open the ticket of a ResumptionM1 body and derive the keys of the resumed channel.
+    /// Returns the completed key exchange, the ticket expiry and our nonce, or `None`
+    /// when the ticket can't be used
+    fn resume_responder(
+        &mut self,
+        body: &[u8],
+    ) -> OckamResult<Option<(CompletedKeyExchange, u64, Vec<u8>)>> {
+        let local_static_secret = match self.current_key(&self.resp_key_label, &self.resp_key_ctx) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let keys = match &mut self.ticket_keys {
+            Some(keys) if body.len() > RESUMPTION_NONCE_LENGTH => keys,
+            _ => return Ok(None),
+        };
+        let mut vault = self.vault.lock().unwrap();
+        let (initiator_nonce, ticket) = body.split_at(RESUMPTION_NONCE_LENGTH);
+        let contents = match keys.open(&mut *vault, ticket) {
+            Ok(contents) => contents,
+            Err(e) => {
+                println!("rejecting session ticket: {}", e);
+                return Ok(None);
             }
+        };
+        // a ResumptionM1 is only good once, a replayed one must not bring up another channel
+        if self
+            .resumed
+            .insert(initiator_nonce.to_vec(), contents.expires)
+            .is_some()
+        {
+            println!("rejecting replayed session ticket");
+            vault.secret_destroy(contents.resumption_secret)?;
+            return Ok(None);
         }
-        Ok(())
+
+        let mut responder_nonce = vec![0u8; RESUMPTION_NONCE_LENGTH];
+        vault.random_bytes_generate(&mut responder_nonce)?;
+        let keys = ticket::resumption_keys(
+            &mut *vault,
+            &contents.resumption_secret,
+            contents.key_attributes,
+            initiator_nonce,
+            &responder_nonce,
+        );
+        vault.secret_destroy(contents.resumption_secret)?;
+        let (initiator_key, responder_key) = keys?;
+        let mut transcript = body.to_vec();
+        transcript.extend_from_slice(&responder_nonce);
+        let completed_key_exchange = CompletedKeyExchange {
+            h: vault.sha256(&transcript)?,
+            encrypt_key: responder_key,
+            decrypt_key: initiator_key,
+            local_static_secret,
+            remote_static_public_key: contents.remote_static_public_key,
+            remote_payloads: vec![],
+        };
+        Ok(Some((
+            completed_key_exchange,
+            contents.expires,
+            responder_nonce,
+        )))
+    }
+
+    /// Finish resuming a channel, or run a full key agreement when the responder rejected
+    /// our ticket. Returns false when the responder's answer doesn't authenticate or its
+    /// static key is not authorized
+    fn handle_resumption_m2_recv(
+        &mut self,
+        channel: Arc<Mutex<Channel>>,
+        m: Message,
+    ) -> OckamResult<bool> {
+        let channel = &mut *channel.lock().unwrap();
+        let (initiator_nonce, ticket) = match channel.resumption.take() {
+            Some(resumption) => resumption,
+            // a duplicate answer to a retransmitted ResumptionM1
+            None => return Ok(true),
+        };
+        if m.message_body.len() <= RESUMPTION_NONCE_LENGTH {
+            println!("session ticket rejected, running a full key agreement");
+            self.vault
+                .lock()
+                .unwrap()
+                .secret_destroy(ticket.resumption_secret)?;
+            let route = match &channel.handshake_message {
+                Some(m1) => m1.onward_route.clone(),
+                None => return Err(Error::InvalidState.into()),
+            };
+            self.send_m1(channel, route)?;
+            return Ok(true);
+        }
+
+        let (responder_nonce, sealed) = m.message_body.split_at(RESUMPTION_NONCE_LENGTH);
+        let mut vault = self.vault.lock().unwrap();
+        let keys = ticket::resumption_keys(
+            &mut *vault,
+            &ticket.resumption_secret,
+            ticket.key_attributes,
+            &initiator_nonce,
+            responder_nonce,
+        );
+        vault.secret_destroy(ticket.resumption_secret)?;
+        let (initiator_key, responder_key) = keys?;
+        let mut transcript = initiator_nonce;
+        transcript.extend_from_slice(&ticket.ticket);
+        transcript.extend_from_slice(responder_nonce);
+        channel.completed_key_exchange = Some(CompletedKeyExchange {
+            h: vault.sha256(&transcript)?,
+            encrypt_key: initiator_key,
+            decrypt_key: responder_key,
+            local_static_secret: ticket.local_static_secret,
+            remote_static_public_key: ticket.remote_static_public_key,
+            remote_payloads: vec![],
+        });
+        let channel_cleartext_addr_encoded =
+            match channel.open(&mut *vault, MessageType::ResumptionM2, sealed) {
+                Ok(Some(encoded)) if RouterAddress::decode(&encoded).is_ok() => encoded,
+                _ => return Ok(false),
+            };
+        let kex = channel.completed_key_exchange.as_ref().unwrap();
+        if !self.authorized(kex) {
+            return Ok(false);
+        }
+        // keep the secret the next ticket from the responder will be good for
+        channel.resumption_secret = Some(ticket::resumption_secret(
+            &mut *vault,
+            &kex.encrypt_key,
+            &kex.h,
+        )?);
+        let mut static_public_key = kex.remote_static_public_key.as_ref().to_vec();
+        channel.agreement = None;
+        channel.handshake_message = None;
+        channel.handshake_deadline = None;
+        channel.route = m.return_route;
+
+        // let the worker know the channel is up, as after a full key agreement
+        match channel.pending.clone() {
+            Some(mut p) => {
+                p.message_body = channel_cleartext_addr_encoded;
+                p.message_body.append(&mut static_public_key);
+                self.router_tx
+                    .send(Router(RouterCommand::ReceiveMessage(p)))
+                    .unwrap();
+            }
+            None => {
+                return Err(Error::NotImplemented.into());
+            }
+        }
+        Ok(true)
     }
 
     /// Initiates key exchange to create new secure channel over supplied route.
@@ -482,14 +1336,26 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             cipher_address = cipher;
         }
 
-        let channel = self.channels.get_mut(&cipher_address).unwrap();
-        let mut channel = &mut *channel.lock().unwrap();
-        let agreement = match &mut channel.agreement {
-            Some(e) => e.as_mut(),
-            None => {
-                return Err(Error::InvalidState.into());
+        // resume over routes we hold an unexpired session ticket for
+        let ticket_route = self
+            .ticket_keys
+            .as_ref()
+            .map(|_| Self::ticket_route(&route));
+        let ticket = match ticket_route.as_ref().and_then(|r| self.tickets.remove(r)) {
+            Some(ticket) if ticket.expires > Instant::now() => Some(ticket),
+            Some(ticket) => {
+                self.vault
+                    .lock()
+                    .unwrap()
+                    .secret_destroy(ticket.resumption_secret)?;
+                None
             }
+            None => None,
         };
+
+        let channel = self.channels.get(&cipher_address).unwrap().clone();
+        let channel = &mut *channel.lock().unwrap();
+        channel.ticket_route = ticket_route;
         channel.pending = Some(Message {
             onward_route: Route {
                 addresses: vec![pending_return],
@@ -501,38 +1367,99 @@ impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> ChannelManager<
             },
             message_type: MessageType::None,
             message_body: vec![],
+            extensions: vec![],
         });
-        let ka_m1 = agreement.process(&[])?;
+        channel.notify = channel.pending.as_ref().map(|p| p.onward_route.clone());
+        match ticket {
+            Some(ticket) => self.send_resumption_m1(channel, route, ticket)?,
+            None => self.send_m1(channel, route)?,
+        }
+        Ok(Address::channel_address_from_string(&clear_address).unwrap())
+    }
+
+    /// Start a full key agreement over `route`
+    fn send_m1(&self, channel: &mut Channel, route: Route) -> OckamResult<()> {
+        let agreement = channel
+            .agreement
+            .as_mut()
+            .ok_or_else(|| Error::InvalidState.into())?;
+        let ka_m1 = agreement.process(&self.handshake_payloads.m1)?;
         let m = Message {
             onward_route: route,
             return_route: Route {
                 addresses: vec![
-                    RouterAddress::channel_router_address_from_str(&cipher_address).unwrap(),
+                    RouterAddress::from_address(channel.as_ciphertext_address()).unwrap()
                 ],
             },
             message_type: MessageType::KeyAgreementM1,
             message_body: ka_m1,
+            extensions: vec![],
         };
+        channel.handshake_sent(&m, self.handshake_timeout);
         self.router_tx
             .send(Router(RouterCommand::SendMessage(m)))
-            .map_err(|e| Error::from(e).into())?;
-        Ok(Address::channel_address_from_string(&clear_address).unwrap())
+            .map_err(|e| Error::from(e).into())
+    }
+
+    /// Present a session ticket over `route` instead of starting a full key agreement
+    fn send_resumption_m1(
+        &self,
+        channel: &mut Channel,
+        route: Route,
+        ticket: SessionTicket,
+    ) -> OckamResult<()> {
+        let mut initiator_nonce = vec![0u8; RESUMPTION_NONCE_LENGTH];
+        self.vault
+            .lock()
+            .unwrap()
+            .random_bytes_generate(&mut initiator_nonce)?;
+        let mut body = initiator_nonce.clone();
+        body.extend_from_slice(&ticket.ticket);
+        let m = Message {
+            onward_route: route,
+            return_route: Route {
+                addresses: vec![
+                    RouterAddress::from_address(channel.as_ciphertext_address()).unwrap()
+                ],
+            },
+            message_type: MessageType::ResumptionM1,
+            message_body: body,
+            extensions: vec![],
+        };
+        channel.resumption = Some((initiator_nonce, ticket));
+        channel.handshake_sent(&m, self.handshake_timeout);
+        self.router_tx
+            .send(Router(RouterCommand::SendMessage(m)))
+            .map_err(|e| Error::from(e).into())
     }
 
     fn create_channel(&mut self, role: ExchangerRole) -> Option<(String, String)> {
-        let mut rng = thread_rng();
-        let clear_u32 = rng.gen::<u32>();
-        let cipher_u32 = rng.gen::<u32>();
+        let (clear_u32, cipher_u32) = {
+            let mut vault = self.vault.lock().unwrap();
+            let mut clear = [0u8; 4];
+            let mut cipher = [0u8; 4];
+            vault.random_bytes_generate(&mut clear).ok()?;
+            vault.random_bytes_generate(&mut cipher).ok()?;
+            (u32::from_le_bytes(clear), u32::from_le_bytes(cipher))
+        };
         let channel = match role {
             ExchangerRole::Initiator => Arc::new(Mutex::new(Channel::new(
                 clear_u32,
                 cipher_u32,
-                Box::new(self.new_key_exchanger.initiator(self.init_key_ctx.clone())),
+                Box::new(
+                    self.new_key_exchanger
+                        .initiator(self.current_key(&self.init_key_label, &self.init_key_ctx)),
+                ),
+                self.handshake_timeout,
             ))),
             ExchangerRole::Responder => Arc::new(Mutex::new(Channel::new(
                 clear_u32,
                 cipher_u32,
-                Box::new(self.new_key_exchanger.responder(self.resp_key_ctx.clone())),
+                Box::new(
+                    self.new_key_exchanger
+                        .responder(self.current_key(&self.resp_key_label, &self.resp_key_ctx)),
+                ),
+                self.handshake_timeout,
             ))),
         };
         let clear_address = Address::ChannelAddress(clear_u32.to_le_bytes().to_vec());
@@ -550,17 +1477,43 @@ struct Channel {
     cleartext_address: u32,
     ciphertext_address: u32,
     agreement: Option<Box<dyn KeyExchanger>>,
-    nonce: u16,
+    nonce: u64,
+    key_phase: u8,
+    encrypted_since_rekey: u64,
+    rekeyed_at: Instant,
+    decrypt_key_phase: u8,
+    previous_decrypt_key: Option<Box<dyn Secret>>,
+    replay_window: ReplayWindow,
+    /// Messages in a row that failed to open
+    failed_opens: u32,
+    last_activity: Instant,
+    handshake_message: Option<Message>,
+    handshake_deadline: Option<Instant>,
+    handshake_retransmits: u32,
     route: Route,
     pending: Option<Message>,
+    notify: Option<Route>,
+    /// Initiator: the route this channel was initiated over, tickets for it are kept
+    ticket_route: Option<String>,
+    /// Initiator: the secret the responder's ticket will be good for, until it arrives
+    resumption_secret: Option<Box<dyn Secret>>,
+    /// Initiator: our nonce and the ticket presented, while resuming
+    resumption: Option<(Vec<u8>, SessionTicket)>,
+    /// Responder: expiry of the ticket this channel was resumed with
+    ticket_expires: Option<u64>,
+    /// Responder: the M1 this channel answered and our M2, until the initiator shows it got M2
+    answered: Option<(Vec<u8>, Message)>,
+    /// Responder: the message telling the worker about the channel, until the initiator
+    /// shows it holds the keys
+    notification: Option<Message>,
 }
 
 impl std::fmt::Debug for Channel {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Channel {{ completed_key_exchange: {:?}, id: {:?}, nonce: {:?}, agreement }}",
-            self.completed_key_exchange, self.cleartext_address, self.nonce
+            "Channel {{ completed_key_exchange: {:?}, id: {:?}, nonce: {:?}, key_phase: {:?}, agreement }}",
+            self.completed_key_exchange, self.cleartext_address, self.nonce, self.key_phase
         )
     }
 }
@@ -570,6 +1523,7 @@ impl Channel {
         cleartext_address: u32,
         ciphertext_address: u32,
         agreement: Box<dyn KeyExchanger>,
+        handshake_timeout: Duration,
     ) -> Self {
         Self {
             cleartext_address,
@@ -577,9 +1531,27 @@ impl Channel {
             agreement: Some(agreement),
             completed_key_exchange: None,
             nonce: 0,
+            key_phase: 0,
+            encrypted_since_rekey: 0,
+            rekeyed_at: Instant::now(),
+            decrypt_key_phase: 0,
+            previous_decrypt_key: None,
+            replay_window: ReplayWindow::default(),
+            failed_opens: 0,
+            last_activity: Instant::now(),
+            handshake_message: None,
+            handshake_deadline: Some(Instant::now() + handshake_timeout),
+            handshake_retransmits: 0,
             route: Route { addresses: vec![] },
             pending: None,
+            notify: None,
             remote_public_key: None,
+            ticket_route: None,
+            resumption_secret: None,
+            resumption: None,
+            ticket_expires: None,
+            answered: None,
+            notification: None,
         }
     }
 
@@ -591,121 +1563,861 @@ impl Channel {
         Address::ChannelAddress(self.ciphertext_address.to_le_bytes().to_vec())
     }
 
-    pub fn nonce_16_to_96(n16: u16) -> [u8; 12] {
-        // the nonce value is an le u16, whereas the nonce
-        // byte array is 10 bytes of 0's follow by the be
-        // representation of the nonce
+    pub fn nonce_to_96(n64: u64) -> [u8; 12] {
+        // the nonce byte array is 4 bytes of 0's followed
+        // by the be representation of the nonce
         let mut n: [u8; 12] = [0; 12];
-        let b = n16.to_be_bytes();
-        n[10] = b[0];
-        n[11] = b[1];
+        n[4..].copy_from_slice(&n64.to_be_bytes());
         n
     }
 
-    pub fn nonce_from_96(n: &[u8; 12]) -> u16 {
-        let bytes: [u8; 2] = [n[10], n[11]];
-        u16::from_be_bytes(bytes)
+    pub fn nonce_from_96(n: &[u8; 12]) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&n[4..]);
+        u64::from_be_bytes(bytes)
+    }
+
+    /// Remember the last key agreement message sent, to retransmit it when no answer
+    /// arrives within `timeout`
+    fn handshake_sent(&mut self, m: &Message, timeout: Duration) {
+        self.handshake_message = Some(m.clone());
+        self.handshake_deadline = Some(Instant::now() + timeout);
+        self.handshake_retransmits = 0;
+    }
+
+    /// Encrypt `plaintext` with the next nonce, rekeying first when the rekey interval
+    /// is reached, and return the key phase, nonce and ciphertext to send in a message
+    /// of `message_type`
+    fn seal(
+        &mut self,
+        vault: &mut dyn XXVault,
+        message_type: MessageType,
+        plaintext: &[u8],
+        rekey_messages: Option<u64>,
+        rekey_elapsed: Option<Duration>,
+    ) -> OckamResult<Vec<u8>> {
+        if self.completed_key_exchange.is_none() {
+            return Err(Error::InvalidState.into());
+        }
+        if self.rekey_due(rekey_messages, rekey_elapsed) {
+            self.rekey_encrypt_key(vault)?;
+        }
+        if self.nonce == REKEY_NONCE {
+            return Err(Error::NonceExhausted.into());
+        }
+
+        let mut sealed: Vec<u8> = vec![self.key_phase];
+        u64::encode(&self.nonce, &mut sealed).map_err(|_| Error::CantSend.into())?;
+        let kex = self.completed_key_exchange.as_ref().unwrap();
+        let nonce = Self::nonce_to_96(self.nonce);
+        let ad = Self::associated_data(&kex.h, message_type);
+        let mut ciphertext_and_tag =
+            Self::encrypt(vault, &kex.encrypt_key, plaintext, &nonce, &ad)?;
+        self.nonce += 1;
+        self.encrypted_since_rekey += 1;
+        self.last_activity = Instant::now();
+
+        sealed.append(&mut ciphertext_and_tag);
+        Ok(sealed)
+    }
+
+    /// Decrypt a body produced by `seal` on the remote end for a message of `message_type`.
+    /// Returns `None` when the replay window drops the message
+    fn open(
+        &mut self,
+        vault: &mut dyn XXVault,
+        message_type: MessageType,
+        body: &[u8],
+    ) -> OckamResult<Option<Vec<u8>>> {
+        if body.len() < PAYLOAD_HEADER_LENGTH {
+            return Err(Error::MalformedPayload.into());
+        }
+        let key_phase = body[0];
+        let (nonce, ciphertext) =
+            u64::decode(&body[1..]).map_err(|_| Error::MalformedPayload.into())?;
+        if nonce == REKEY_NONCE {
+            return Err(Error::MalformedPayload.into());
+        }
+        if let Err(e) = self.replay_window.check(nonce) {
+            // replays are dropped here rather than failing the channel manager
+            println!("dropping message with nonce {}: {:?}", nonce, e);
+            return Ok(None);
+        }
+        let plaintext = self.decrypt_payload(vault, message_type, key_phase, nonce, ciphertext)?;
+        self.replay_window.accept(nonce);
+        self.last_activity = Instant::now();
+        Ok(Some(plaintext))
+    }
+
+    /// Like `open`, but a body that fails to open is dropped rather than failing the
+    /// channel manager, and counted until a body opens again
+    fn open_or_drop(
+        &mut self,
+        vault: &mut dyn XXVault,
+        message_type: MessageType,
+        body: &[u8],
+    ) -> Option<Vec<u8>> {
+        match self.open(vault, message_type, body) {
+            Ok(Some(plaintext)) => {
+                self.failed_opens = 0;
+                Some(plaintext)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                println!("dropping message that failed to open: {:?}", e);
+                self.failed_opens += 1;
+                None
+            }
+        }
+    }
+
+    /// Destroy the keys of a channel that is being closed
+    fn destroy(&mut self, vault: &mut dyn XXVault) -> OckamResult<()> {
+        if let Some(kex) = self.completed_key_exchange.take() {
+            Self::destroy_keys(vault, vec![kex.encrypt_key, kex.decrypt_key])?;
+        }
+        if let Some(key) = self.previous_decrypt_key.take() {
+            vault.secret_destroy(key)?;
+        }
+        if let Some(secret) = self.resumption_secret.take() {
+            vault.secret_destroy(secret)?;
+        }
+        if let Some((_, ticket)) = self.resumption.take() {
+            vault.secret_destroy(ticket.resumption_secret)?;
+        }
+        Ok(())
+    }
+
+    /// True when the encryption key has been used for `messages` messages or
+    /// for longer than `elapsed`
+    fn rekey_due(&self, messages: Option<u64>, elapsed: Option<Duration>) -> bool {
+        if self.encrypted_since_rekey == 0 {
+            return false;
+        }
+        matches!(messages, Some(m) if self.encrypted_since_rekey >= m)
+            || matches!(elapsed, Some(e) if self.rekeyed_at.elapsed() >= e)
+    }
+
+    /// Replace the encryption key with the next one and move to the next key phase
+    fn rekey_encrypt_key(&mut self, vault: &mut dyn XXVault) -> OckamResult<()> {
+        let kex = self
+            .completed_key_exchange
+            .as_mut()
+            .ok_or_else(|| Error::InvalidState.into())?;
+        let next = Self::rekey(vault, &kex.encrypt_key)?;
+        let previous = std::mem::replace(&mut kex.encrypt_key, next);
+        vault.secret_destroy(previous)?;
+        self.key_phase = self.key_phase.wrapping_add(1);
+        self.encrypted_since_rekey = 0;
+        self.rekeyed_at = Instant::now();
+        Ok(())
+    }
+
+    /// Decrypt a payload sent in `key_phase`, following the peer's rekeys.
+    /// The decryption keys only move forward once a payload of the new phase authenticates
+    fn decrypt_payload(
+        &mut self,
+        vault: &mut dyn XXVault,
+        message_type: MessageType,
+        key_phase: u8,
+        nonce: u64,
+        ciphertext: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        let kex = self
+            .completed_key_exchange
+            .as_mut()
+            .ok_or_else(|| Error::InvalidState.into())?;
+        let nonce = Self::nonce_to_96(nonce);
+        let ad = Self::associated_data(&kex.h, message_type);
+        let ahead = key_phase.wrapping_sub(self.decrypt_key_phase);
+        if ahead == 0 {
+            return Self::decrypt(vault, &kex.decrypt_key, ciphertext, &nonce, &ad);
+        }
+        if ahead == u8::MAX {
+            return match &self.previous_decrypt_key {
+                Some(key) => Self::decrypt(vault, key, ciphertext, &nonce, &ad),
+                None => Err(Error::StaleKeyPhase.into()),
+            };
+        }
+        if ahead > MAX_KEY_PHASES_AHEAD {
+            return Err(Error::StaleKeyPhase.into());
+        }
+
+        let mut keys: Vec<Box<dyn Secret>> = Vec::with_capacity(ahead as usize);
+        for _ in 0..ahead {
+            let next = Self::rekey(vault, keys.last().unwrap_or(&kex.decrypt_key));
+            match next {
+                Ok(next) => keys.push(next),
+                Err(e) => {
+                    Self::destroy_keys(vault, keys)?;
+                    return Err(e);
+                }
+            }
+        }
+        let plaintext = match Self::decrypt(vault, keys.last().unwrap(), ciphertext, &nonce, &ad) {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                Self::destroy_keys(vault, keys)?;
+                return Err(e);
+            }
+        };
+
+        let current = keys.pop().unwrap();
+        let replaced = std::mem::replace(&mut kex.decrypt_key, current);
+        let previous = match keys.pop() {
+            Some(key) => {
+                keys.push(replaced);
+                key
+            }
+            None => replaced,
+        };
+        Self::destroy_keys(vault, keys)?;
+        if let Some(stale) = self.previous_decrypt_key.replace(previous) {
+            vault.secret_destroy(stale)?;
+        }
+        self.decrypt_key_phase = key_phase;
+        Ok(plaintext)
+    }
+
+    /// Derive the key that follows `key`, with the Noise REKEY function
+    fn rekey(vault: &mut dyn XXVault, key: &Box<dyn Secret>) -> OckamResult<Box<dyn Secret>> {
+        let attributes = vault.secret_attributes_get(key)?;
+        let zeros = [0u8; 32];
+        let mut ciphertext =
+            Self::encrypt(vault, key, &zeros, &Self::nonce_to_96(REKEY_NONCE), &[])?;
+        let next = vault.secret_import(&ciphertext[..attributes.length], attributes);
+        ciphertext.zeroize();
+        next
+    }
+
+    /// Sealed messages are bound to the key agreement and to their message type, so that
+    /// one kind of message can't be passed off as another
+    fn associated_data(h: &[u8], message_type: MessageType) -> Vec<u8> {
+        let mut ad = h.to_vec();
+        ad.push(message_type as u8);
+        ad
+    }
+
+    fn destroy_keys(vault: &mut dyn XXVault, keys: Vec<Box<dyn Secret>>) -> OckamResult<()> {
+        for key in keys {
+            vault.secret_destroy(key)?;
+        }
+        Ok(())
+    }
+
+    /// Encrypt using the AEAD that matches the type of the negotiated key
+    fn encrypt(
+        vault: &mut dyn XXVault,
+        key: &Box<dyn Secret>,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        match vault.secret_attributes_get(key)?.stype {
+            SecretType::ChaCha20Poly1305 => {
+                vault.aead_chacha20_poly1305_encrypt(key, plaintext, nonce, aad)
+            }
+            _ => vault.aead_aes_gcm_encrypt(key, plaintext, nonce, aad),
+        }
+    }
+
+    /// Decrypt using the AEAD that matches the type of the negotiated key
+    fn decrypt(
+        vault: &mut dyn XXVault,
+        key: &Box<dyn Secret>,
+        ciphertext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        match vault.secret_attributes_get(key)?.stype {
+            SecretType::ChaCha20Poly1305 => {
+                vault.aead_chacha20_poly1305_decrypt(key, ciphertext, nonce, aad)
+            }
+            _ => vault.aead_aes_gcm_decrypt(key, ciphertext, nonce, aad),
+        }
     }
 }
 
+pub mod authorization;
 /// Represents the errors that occur within a channel
 pub mod error;
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use ockam_kex::xx::{XXInitiator, XXNewKeyExchanger, XXResponder};
-//     use ockam_kex::CipherSuite;
-//     use ockam_message::message::AddressType;
-//     use ockam_vault::software::DefaultVault;
-//     use std::sync::mpsc::channel;
-//
-//     type XXInitiatorChannelManager = ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>;
-//     type XXResponderChannelManager = ChannelManager<XXInitiator, XXResponder, XXNewKeyExchanger>;
-//
-//     #[test]
-//     fn new_channel_initiator() {
-//         let (tx_router, rx_router) = channel();
-//         let (tx_channel, rx_channel) = channel();
-//
-//         let new_key_exchanger = XXNewKeyExchanger::new(CipherSuite::Curve25519AesGcmSha256);
-//         let vault = Arc::new(Mutex::new(DefaultVault::default()));
-//
-//         let mut router = ockam_router::router::Router::new(rx_router);
-//         let mut channel = XXInitiatorChannelManager::new(
-//             tx_channel.clone(),
-//             rx_channel,
-//             tx_router.clone(),
-//             vault.clone(),
-//             new_key_exchanger,
-//         );
-//
-//         tx_router
-//             .send(OckamCommand::Router(RouterCommand::Register(
-//                 AddressType::Channel,
-//                 tx_channel.clone(),
-//             )))
-//             .unwrap();
-//
-//         let message = Message {
-//             onward_route: Route {
-//                 addresses:
-// vec![RouterAddress::channel_router_address_from_str("deadbeef").unwrap()],             },
-//             return_route: Route { addresses: vec![] },
-//             message_type: MessageType::Payload,
-//             message_body: b"Hello Bob".to_vec(),
-//         };
-//
-//         tx_router
-//             .send(OckamCommand::Router(RouterCommand::SendMessage(message)))
-//             .unwrap();
-//         assert!(router.poll());
-//         let res = channel.poll();
-//         assert!(res.is_ok());
-//         assert!(res.unwrap());
-//     }
-//
-//     #[test]
-//     fn new_channel_responder() {
-//         let (tx_router, rx_router) = channel();
-//         let (tx_channel, rx_channel) = channel();
-//
-//         let new_key_exchanger = XXNewKeyExchanger::new(CipherSuite::Curve25519AesGcmSha256);
-//         let vault = Arc::new(Mutex::new(DefaultVault::default()));
-//
-//         let mut router = ockam_router::router::Router::new(rx_router);
-//         let mut channel = XXResponderChannelManager::new(
-//             tx_channel.clone(),
-//             rx_channel,
-//             tx_router.clone(),
-//             vault.clone(),
-//             new_key_exchanger,
-//         );
-//
-//         tx_router
-//             .send(OckamCommand::Router(RouterCommand::Register(
-//                 AddressType::Channel,
-//                 tx_channel.clone(),
-//             )))
-//             .unwrap();
-//
-//         let message = Message {
-//             onward_route: Route {
-//                 addresses: vec![RouterAddress::channel_router_address_from_str("00").unwrap()],
-//             },
-//             return_route: Route { addresses: vec![] },
-//             message_type: MessageType::KeyAgreementM1,
-//             message_body: vec![
-//                 79, 30, 59, 197, 255, 25, 84, 22, 3, 63, 63, 45, 98, 206, 16, 137, 39, 108, 13,
-//                 171, 237, 191, 172, 115, 63, 124, 209, 114, 59, 97, 28, 82,
-//             ],
-//         };
-//
-//         tx_router
-//             .send(OckamCommand::Router(RouterCommand::SendMessage(message)))
-//             .unwrap();
-//         assert!(router.poll());
-//         let res = channel.poll();
-//         assert!(res.is_ok());
-//         assert!(res.unwrap());
-//     }
-// }
+pub mod replay;
+pub mod ticket;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_kex::CipherSuite;
+    use ockam_kex_xx::{
+        NKInitiator, NKNewKeyExchanger, NKResponder, XXInitiator, XXNewKeyExchanger, XXResponder,
+    };
+    use ockam_vault::SecretVault;
+    use ockam_vault_software::{DefaultVault, DefaultVaultSecret};
+    use std::sync::mpsc::channel;
+
+    /// A channel manager with its own vault, and what it delivered to local workers
+    struct End<I: KeyExchanger + 'static, R: KeyExchanger + 'static, E: NewKeyExchanger<I, R>> {
+        manager: ChannelManager<I, R, E>,
+        tx: Sender<OckamCommand>,
+        router_rx: Receiver<OckamCommand>,
+        delivered: Vec<Message>,
+    }
+
+    impl<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>> End<I, R, E> {
+        fn new(
+            vault: Arc<Mutex<DefaultVault>>,
+            new_key_exchanger: E,
+            resp_key_ctx: Option<Arc<Box<dyn Secret>>>,
+        ) -> Self {
+            let (tx, rx) = channel();
+            let (router_tx, router_rx) = channel();
+            let manager = ChannelManager::new(
+                rx,
+                tx.clone(),
+                router_tx,
+                vault,
+                new_key_exchanger,
+                resp_key_ctx,
+                None,
+            )
+            .unwrap();
+            Self {
+                manager,
+                tx,
+                router_rx,
+                delivered: vec![],
+            }
+        }
+
+        fn command(&self, command: ChannelCommand) {
+            self.tx.send(OckamCommand::Channel(command)).unwrap();
+        }
+
+        fn receive(&self, messages: Vec<Message>) {
+            for m in messages {
+                self.command(ChannelCommand::ReceiveMessage(m));
+            }
+        }
+
+        /// Poll the channel manager and return the messages it sent to the remote end
+        fn poll(&mut self) -> Vec<Message> {
+            self.manager.poll().unwrap();
+            let mut sent = vec![];
+            while let Ok(command) = self.router_rx.try_recv() {
+                match command {
+                    Router(RouterCommand::SendMessage(m)) => sent.push(m),
+                    Router(RouterCommand::ReceiveMessage(m)) => self.delivered.push(m),
+                    _ => {}
+                }
+            }
+            sent
+        }
+
+        fn last_delivered(&self) -> &Message {
+            self.delivered.last().unwrap()
+        }
+    }
+
+    /// A static key to respond with, and its public key
+    fn static_key(vault: &Arc<Mutex<DefaultVault>>) -> (Arc<Box<dyn Secret>>, PublicKey) {
+        let mut vault = vault.lock().unwrap();
+        let attributes = SecretAttributes {
+            stype: SecretType::Curve25519,
+            persistence: SecretPersistence::Persistent,
+            length: 32,
+        };
+        let key = vault.secret_generate(attributes).unwrap();
+        let public_key = vault.secret_public_key_get(&key).unwrap();
+        (Arc::new(key), public_key)
+    }
+
+    fn xx_end() -> End<XXInitiator, XXResponder, XXNewKeyExchanger> {
+        let vault = Arc::new(Mutex::new(DefaultVault::default()));
+        let (key, _) = static_key(&vault);
+        let new_key_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+        );
+        End::new(vault, new_key_exchanger, Some(key))
+    }
+
+    type NKEnd = End<NKInitiator, NKResponder, NKNewKeyExchanger>;
+
+    /// An NK initiator and the responder whose static key it knows
+    fn nk_ends() -> (NKEnd, NKEnd) {
+        let vault = Arc::new(Mutex::new(DefaultVault::default()));
+        let (key, public_key) = static_key(&vault);
+        let responder = NKNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+            None,
+        );
+        let responder = End::new(vault, responder, Some(key));
+
+        let vault = Arc::new(Mutex::new(DefaultVault::default()));
+        let initiator = NKNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+            Some(public_key),
+        );
+        (End::new(vault, initiator, None), responder)
+    }
+
+    /// Hand what each end sends to the other until neither has anything left to send
+    fn exchange<I, R, E>(a: &mut End<I, R, E>, b: &mut End<I, R, E>)
+    where
+        I: KeyExchanger,
+        R: KeyExchanger,
+        E: NewKeyExchanger<I, R>,
+    {
+        loop {
+            let from_a = a.poll();
+            let from_b = b.poll();
+            if from_a.is_empty() && from_b.is_empty() {
+                return;
+            }
+            b.receive(from_a);
+            a.receive(from_b);
+        }
+    }
+
+    fn initiate<I: KeyExchanger, R: KeyExchanger, E: NewKeyExchanger<I, R>>(a: &End<I, R, E>) {
+        a.command(ChannelCommand::Initiate(
+            Route {
+                addresses: vec![
+                    RouterAddress::channel_router_address_from_str(CHANNEL_ZERO).unwrap()
+                ],
+            },
+            Address::WorkerAddress(vec![0, 1, 2, 3]),
+            None,
+        ));
+    }
+
+    /// Send `body` from a worker to the worker at the other end of the channel `clear`
+    fn send<I, R, E>(a: &End<I, R, E>, clear: &Address, body: &[u8])
+    where
+        I: KeyExchanger,
+        R: KeyExchanger,
+        E: NewKeyExchanger<I, R>,
+    {
+        a.command(ChannelCommand::SendMessage(Message {
+            onward_route: Route {
+                addresses: vec![
+                    RouterAddress::from_address(clear.clone()).unwrap(),
+                    RouterAddress::worker_router_address_from_str("01020304").unwrap(),
+                ],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: body.to_vec(),
+            extensions: vec![],
+        }));
+    }
+
+    /// Set up a channel from `a` to `b`, confirm it to `b` with a first payload and
+    /// return the cleartext addresses of both ends
+    fn establish<I, R, E>(a: &mut End<I, R, E>, b: &mut End<I, R, E>) -> (Address, Address)
+    where
+        I: KeyExchanger,
+        R: KeyExchanger,
+        E: NewKeyExchanger<I, R>,
+    {
+        initiate(a);
+        exchange(a, b);
+        let a_clear = a.last_delivered().return_route.addresses[0].address.clone();
+        send(a, &a_clear, b"hello");
+        exchange(a, b);
+        assert_eq!(b.last_delivered().message_body, b"hello".to_vec());
+        (
+            a_clear,
+            b.last_delivered().return_route.addresses[0].address.clone(),
+        )
+    }
+
+    fn is_close(m: &Message) -> bool {
+        matches!(m.message_type, MessageType::ChannelClose)
+    }
+
+    fn aes_key(vault: &mut dyn XXVault, value: u8) -> Box<dyn Secret> {
+        let attributes = SecretAttributes {
+            stype: SecretType::Aes,
+            persistence: SecretPersistence::Ephemeral,
+            length: 32,
+        };
+        vault.secret_import(&[value; 32], attributes).unwrap()
+    }
+
+    /// Two ends of a channel whose key agreement completed with made up keys
+    fn channel_pair(vault: &Arc<Mutex<DefaultVault>>) -> (Channel, Channel) {
+        let new_key_exchanger = XXNewKeyExchanger::new(
+            CipherSuite::Curve25519AesGcmSha256,
+            vault.clone(),
+            vault.clone(),
+        );
+        let mut v = vault.lock().unwrap();
+        let mut channel = |encrypt: u8, decrypt: u8| {
+            let mut channel = Channel::new(
+                encrypt as u32,
+                decrypt as u32,
+                Box::new(new_key_exchanger.initiator(None)),
+                DEFAULT_HANDSHAKE_TIMEOUT,
+            );
+            channel.completed_key_exchange = Some(CompletedKeyExchange {
+                h: [0u8; 32],
+                encrypt_key: aes_key(&mut *v, encrypt),
+                decrypt_key: aes_key(&mut *v, decrypt),
+                local_static_secret: Arc::new(aes_key(&mut *v, 0)),
+                remote_static_public_key: PublicKey::new(vec![0u8; 32]),
+                remote_payloads: vec![],
+            });
+            channel
+        };
+        (channel(1, 2), channel(2, 1))
+    }
+
+    /// A second handle on the current decryption key, to check whether it still exists
+    fn decrypt_key(channel: &Channel) -> Box<dyn Secret> {
+        let kex = channel.completed_key_exchange.as_ref().unwrap();
+        Box::new(*DefaultVaultSecret::downcast_secret(&kex.decrypt_key).unwrap())
+    }
+
+    #[test]
+    fn key_phase_jump() {
+        let vault = Arc::new(Mutex::new(DefaultVault::default()));
+        let (mut alice, mut bob) = channel_pair(&vault);
+        let mut v = vault.lock().unwrap();
+        let first = decrypt_key(&bob);
+
+        // alice rekeys after every message and bob misses the first two
+        let mut sealed = vec![];
+        for message in 0..6u8 {
+            sealed.push(
+                alice
+                    .seal(&mut *v, MessageType::Payload, &[message], Some(1), None)
+                    .unwrap(),
+            );
+        }
+        assert_eq!(
+            bob.open(&mut *v, MessageType::Payload, &sealed[2])
+                .unwrap()
+                .unwrap(),
+            vec![2u8]
+        );
+        assert_eq!(bob.decrypt_key_phase, 2);
+        // the skipped key of phase 1 is kept as the previous key, the key of phase 0 is gone
+        assert!(v.secret_attributes_get(&first).is_err());
+        assert_eq!(
+            bob.open(&mut *v, MessageType::Payload, &sealed[1])
+                .unwrap()
+                .unwrap(),
+            vec![1u8]
+        );
+
+        // three phases ahead is more than a peer may skip
+        assert!(bob.open(&mut *v, MessageType::Payload, &sealed[5]).is_err());
+        assert_eq!(bob.decrypt_key_phase, 2);
+        assert_eq!(
+            bob.open(&mut *v, MessageType::Payload, &sealed[4])
+                .unwrap()
+                .unwrap(),
+            vec![4u8]
+        );
+    }
+
+    #[test]
+    fn handshake_retransmit_and_give_up() {
+        let mut a = xx_end();
+        a.manager
+            .set_handshake_timeout(Duration::from_millis(10), 2);
+        initiate(&a);
+        let m1 = a.poll();
+        assert_eq!(m1.len(), 1);
+
+        // nobody answers, M1 goes out again twice before the channel fails
+        for _ in 0..2 {
+            std::thread::sleep(Duration::from_millis(15));
+            let retransmitted = a.poll();
+            assert_eq!(retransmitted.len(), 1);
+            assert_eq!(retransmitted[0].message_body, m1[0].message_body);
+        }
+        std::thread::sleep(Duration::from_millis(15));
+        assert!(a.poll().is_empty());
+        assert!(a.manager.channels.is_empty());
+        assert!(is_close(a.last_delivered()));
+    }
+
+    #[test]
+    fn retransmitted_m1_reuses_channel() {
+        let mut a = xx_end();
+        let mut b = xx_end();
+        a.manager
+            .set_handshake_timeout(Duration::from_millis(10), 2);
+        initiate(&a);
+        b.receive(a.poll());
+        let m2 = b.poll();
+        assert_eq!(m2.len(), 1);
+
+        // M2 gets lost, the retransmitted M1 is answered by the same channel
+        std::thread::sleep(Duration::from_millis(15));
+        b.receive(a.poll());
+        let again = b.poll();
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].message_body, m2[0].message_body);
+        assert_eq!(
+            again[0].return_route.addresses[0].address,
+            m2[0].return_route.addresses[0].address
+        );
+        assert_eq!(b.manager.channels.len(), 2);
+
+        a.receive(again);
+        exchange(&mut a, &mut b);
+        assert_eq!(b.manager.channels.len(), 2);
+        assert!(b.manager.answered.is_empty());
+        assert!(!is_close(a.last_delivered()));
+        assert!(!is_close(b.last_delivered()));
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let mut a = xx_end();
+        let mut b = xx_end();
+        establish(&mut a, &mut b);
+        b.manager.set_idle_timeout(Some(Duration::from_millis(10)));
+        std::thread::sleep(Duration::from_millis(15));
+
+        // b closes the idle channel and tells a, both workers hear about it
+        exchange(&mut a, &mut b);
+        assert!(a.manager.channels.is_empty());
+        assert!(b.manager.channels.is_empty());
+        assert!(is_close(a.last_delivered()));
+        assert!(is_close(b.last_delivered()));
+    }
+
+    #[test]
+    fn close_propagates() {
+        for initiator_closes in [true, false].iter() {
+            let mut a = xx_end();
+            let mut b = xx_end();
+            let (a_clear, b_clear) = establish(&mut a, &mut b);
+            if *initiator_closes {
+                a.command(ChannelCommand::Close(a_clear));
+            } else {
+                b.command(ChannelCommand::Close(b_clear));
+            }
+            exchange(&mut a, &mut b);
+            assert!(a.manager.channels.is_empty());
+            assert!(b.manager.channels.is_empty());
+            assert!(is_close(a.last_delivered()));
+            assert!(is_close(b.last_delivered()));
+        }
+    }
+
+    #[test]
+    fn unconfirmed_one_round_trip_channel() {
+        // M2 goes nowhere, as it does for an M1 sent from a spoofed address
+        let (mut a, mut b) = nk_ends();
+        b.manager
+            .set_handshake_timeout(Duration::from_millis(10), 1);
+        initiate(&a);
+        b.receive(a.poll());
+        assert_eq!(b.poll().len(), 1);
+        assert_eq!(b.manager.channels.len(), 2);
+        std::thread::sleep(Duration::from_millis(25));
+        b.poll();
+        assert!(b.manager.channels.is_empty());
+        // the worker never heard of the channel
+        assert!(b.delivered.is_empty());
+
+        // the first payload shows the initiator is there, the worker hears of the channel first
+        let (mut a, mut b) = nk_ends();
+        b.manager
+            .set_handshake_timeout(Duration::from_millis(10), 1);
+        establish(&mut a, &mut b);
+        assert_eq!(b.delivered.len(), 2);
+        assert!(matches!(b.delivered[0].message_type, MessageType::None));
+        std::thread::sleep(Duration::from_millis(25));
+        exchange(&mut a, &mut b);
+        assert_eq!(b.manager.channels.len(), 2);
+    }
+
+    #[test]
+    fn remote_payloads() {
+        let payloads = HandshakePayloads {
+            m1: b"m1".to_vec(),
+            m2: b"m2".to_vec(),
+            m3: b"m3".to_vec(),
+        };
+        let mut a = xx_end();
+        let mut b = xx_end();
+        a.manager.set_handshake_payloads(payloads.clone());
+        b.manager.set_handshake_payloads(payloads.clone());
+        let (a_clear, b_clear) = establish(&mut a, &mut b);
+        assert_eq!(
+            a.manager.remote_payloads(&a_clear.as_string()),
+            Some(vec![b"m2".to_vec()])
+        );
+        assert_eq!(
+            b.manager.remote_payloads(&b_clear.as_string()),
+            Some(vec![b"m1".to_vec(), b"m3".to_vec()])
+        );
+
+        // one round trip patterns have no M3
+        let (mut a, mut b) = nk_ends();
+        a.manager.set_handshake_payloads(payloads.clone());
+        b.manager.set_handshake_payloads(payloads);
+        let (a_clear, b_clear) = establish(&mut a, &mut b);
+        assert_eq!(
+            a.manager.remote_payloads(&a_clear.as_string()),
+            Some(vec![b"m2".to_vec()])
+        );
+        assert_eq!(
+            b.manager.remote_payloads(&b_clear.as_string()),
+            Some(vec![b"m1".to_vec()])
+        );
+    }
+
+    #[test]
+    fn replayed_resumption() {
+        let mut a = xx_end();
+        let mut b = xx_end();
+        a.manager
+            .set_session_tickets(Some(Duration::from_secs(60)))
+            .unwrap();
+        b.manager
+            .set_session_tickets(Some(Duration::from_secs(60)))
+            .unwrap();
+        establish(&mut a, &mut b);
+        assert_eq!(a.manager.tickets.len(), 1);
+
+        // the second channel over the same route is resumed, b's worker hears of it
+        // once the first payload arrives
+        initiate(&a);
+        let resumption_m1 = a.poll();
+        assert!(matches!(
+            resumption_m1[0].message_type,
+            MessageType::ResumptionM1
+        ));
+        b.receive(resumption_m1.clone());
+        let delivered = b.delivered.len();
+        a.receive(b.poll());
+        assert_eq!(b.delivered.len(), delivered);
+        a.poll();
+        let a_clear = a.last_delivered().return_route.addresses[0].address.clone();
+        send(&a, &a_clear, b"resumed");
+        exchange(&mut a, &mut b);
+        assert_eq!(b.delivered.len(), delivered + 2);
+        assert_eq!(b.last_delivered().message_body, b"resumed".to_vec());
+
+        // a replayed ResumptionM1 gets an empty answer and no channel
+        b.receive(resumption_m1);
+        let answer = b.poll();
+        assert!(matches!(answer[0].message_type, MessageType::ResumptionM2));
+        assert!(answer[0].message_body.is_empty());
+        assert_eq!(b.manager.channels.len(), 4);
+        assert_eq!(b.delivered.len(), delivered + 2);
+    }
+
+    #[test]
+    fn malformed_payload() {
+        let mut a = xx_end();
+        let mut b = xx_end();
+        let (a_clear, _) = establish(&mut a, &mut b);
+        let delivered = b.delivered.len();
+
+        // an authenticated payload that doesn't decode to a message is dropped
+        let m = {
+            let channel = a.manager.channels.get(&a_clear.as_string()).unwrap();
+            let mut channel = channel.lock().unwrap();
+            let mut vault = a.manager.vault.lock().unwrap();
+            Message {
+                onward_route: channel.route.clone(),
+                return_route: Route {
+                    addresses: vec![
+                        RouterAddress::from_address(channel.as_ciphertext_address()).unwrap()
+                    ],
+                },
+                message_type: MessageType::Payload,
+                message_body: channel
+                    .seal(&mut *vault, MessageType::Payload, &[0xff; 3], None, None)
+                    .unwrap(),
+                extensions: vec![],
+            }
+        };
+        b.receive(vec![m]);
+        b.poll();
+        assert_eq!(b.delivered.len(), delivered);
+
+        send(&a, &a_clear, b"still up");
+        exchange(&mut a, &mut b);
+        assert_eq!(b.last_delivered().message_body, b"still up".to_vec());
+    }
+
+    #[test]
+    fn payloads_that_fail_to_open() {
+        let mut a = xx_end();
+        let mut b = xx_end();
+        let (a_clear, _) = establish(&mut a, &mut b);
+        let delivered = b.delivered.len();
+
+        let forged = |body: Vec<u8>| {
+            let channel = a.manager.channels.get(&a_clear.as_string()).unwrap();
+            let channel = channel.lock().unwrap();
+            Message {
+                onward_route: channel.route.clone(),
+                return_route: Route {
+                    addresses: vec![
+                        RouterAddress::from_address(channel.as_ciphertext_address()).unwrap()
+                    ],
+                },
+                message_type: MessageType::Payload,
+                message_body: body,
+                extensions: vec![],
+            }
+        };
+        // a key phase too far ahead, a body too short to hold a nonce and a bad tag
+        let mut stale = vec![3u8];
+        stale.extend_from_slice(&[0u8; 8 + 16]);
+        let mut bad_tag = vec![0u8];
+        bad_tag.extend_from_slice(&[1u8; 8 + 16]);
+        let run = vec![forged(bad_tag.clone()); MAX_FAILED_OPENS as usize];
+        b.receive(vec![forged(stale), forged(vec![0u8; 4]), forged(bad_tag)]);
+        b.poll();
+        assert_eq!(b.delivered.len(), delivered);
+
+        // the channel survives a few of them
+        send(&a, &a_clear, b"still up");
+        exchange(&mut a, &mut b);
+        assert_eq!(b.last_delivered().message_body, b"still up".to_vec());
+
+        // but not a run of them, the worker learns the channel is gone
+        let delivered = b.delivered.len();
+        b.receive(run);
+        b.poll();
+        assert!(b.manager.channels.is_empty());
+        assert_eq!(b.delivered.len(), delivered + 1);
+        assert!(is_close(b.last_delivered()));
+    }
+
+    #[test]
+    fn only_sealed_closes_close() {
+        let mut a = xx_end();
+        let mut b = xx_end();
+        let (a_clear, _) = establish(&mut a, &mut b);
+
+        // a payload relabelled as a ChannelClose, and a close that isn't sealed at all
+        send(&a, &a_clear, b"");
+        let mut relabelled = a.poll().pop().unwrap();
+        relabelled.message_type = MessageType::ChannelClose;
+        let mut garbage = relabelled.clone();
+        garbage.message_body = vec![0u8; 4];
+        b.receive(vec![relabelled, garbage]);
+        b.poll();
+        assert_eq!(b.manager.channels.len(), 2);
+
+        send(&a, &a_clear, b"still up");
+        exchange(&mut a, &mut b);
+        assert_eq!(b.last_delivered().message_body, b"still up".to_vec());
+    }
+}
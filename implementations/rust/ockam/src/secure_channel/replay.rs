@@ -0,0 +1,121 @@
+//! Sliding window replay filter for channel nonces, in the manner of IPsec and DTLS.
+//!
+//! The window remembers the highest authenticated nonce and which of the
+//! `REPLAY_WINDOW_SIZE` nonces below it were accepted, so messages may arrive
+//! out of order but each nonce is accepted at most once.
+
+use crate::secure_channel::error::Error;
+
+/// Number of nonces, counting down from the highest accepted one, that are tracked
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// Counts of the messages rejected by a replay window
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayCounters {
+    /// Messages whose nonce had already been accepted
+    pub duplicate: u64,
+    /// Messages whose nonce fell behind the window
+    pub too_old: u64,
+}
+
+/// Tracks the nonces accepted on the receiving side of a channel
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `i` is set when nonce `highest - i` was accepted
+    bitmap: u64,
+    counters: ReplayCounters,
+}
+
+impl ReplayWindow {
+    /// Check `nonce` before decrypting, counting it if it is rejected
+    pub fn check(&mut self, nonce: u64) -> Result<(), Error> {
+        if let Some(highest) = self.highest {
+            if nonce <= highest {
+                let offset = highest - nonce;
+                if offset >= REPLAY_WINDOW_SIZE {
+                    self.counters.too_old += 1;
+                    return Err(Error::NonceTooOld);
+                }
+                if self.bitmap & (1 << offset) != 0 {
+                    self.counters.duplicate += 1;
+                    return Err(Error::ReplayedNonce);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark `nonce` as accepted once its message has been authenticated
+    pub fn accept(&mut self, nonce: u64) {
+        match self.highest {
+            Some(highest) if nonce <= highest => {
+                self.bitmap |= 1 << (highest - nonce);
+            }
+            Some(highest) => {
+                let shift = nonce - highest;
+                self.bitmap = if shift >= REPLAY_WINDOW_SIZE {
+                    1
+                } else {
+                    (self.bitmap << shift) | 1
+                };
+                self.highest = Some(nonce);
+            }
+            None => {
+                self.bitmap = 1;
+                self.highest = Some(nonce);
+            }
+        }
+    }
+
+    /// Messages rejected so far
+    pub fn counters(&self) -> ReplayCounters {
+        self.counters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receive(window: &mut ReplayWindow, nonce: u64) -> bool {
+        let accepted = window.check(nonce).is_ok();
+        if accepted {
+            window.accept(nonce);
+        }
+        accepted
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(receive(&mut window, 0));
+        assert!(!receive(&mut window, 0));
+        assert!(receive(&mut window, 5));
+        // reordered messages within the window are accepted once
+        assert!(receive(&mut window, 3));
+        assert!(receive(&mut window, 1));
+        assert!(!receive(&mut window, 3));
+        assert!(receive(&mut window, 100));
+        assert!(receive(&mut window, 37));
+        assert!(!receive(&mut window, 36));
+        assert!(!receive(&mut window, 5));
+        assert!(!receive(&mut window, 100));
+        assert_eq!(
+            window.counters(),
+            ReplayCounters {
+                duplicate: 3,
+                too_old: 2
+            }
+        );
+    }
+
+    #[test]
+    fn unauthenticated_nonces_are_not_accepted() {
+        let mut window = ReplayWindow::default();
+        assert!(window.check(7).is_ok());
+        assert!(window.check(7).is_ok());
+        window.accept(7);
+        assert!(window.check(7).is_err());
+    }
+}
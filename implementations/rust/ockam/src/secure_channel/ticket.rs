@@ -0,0 +1,342 @@
+//! Session tickets, which let an initiator resume a channel without a new key agreement.
+//!
+//! Once a key agreement completes both ends derive a resumption secret from the handshake
+//! hash `h` and the initiator to responder key. The responder seals that secret, the
+//! initiator's static public key and an expiry under a ticket key only it knows, and hands
+//! the ticket to the initiator over the channel. To resume, the initiator presents the ticket
+//! with a fresh nonce, the responder answers with a fresh nonce of its own, and both derive
+//! the keys of the new channel from the resumption secret and the two nonces, without any
+//! Diffie-Hellman. The responder can send payloads as soon as it answers, the initiator after
+//! one round trip.
+//!
+//! Resumed channels trade forward secrecy for that speed: whoever learns a ticket key can
+//! open the tickets it sealed and derive the keys of the channels resumed with them. The
+//! exposure is bounded by the ticket lifetime. Ticket keys only live in memory, are replaced
+//! every lifetime and destroyed one lifetime later, once the last ticket they sealed has
+//! expired. Tickets issued on a resumed channel keep the expiry of the ticket it was resumed
+//! with, so no resumed channel reaches back further than one lifetime to a full key agreement.
+
+use crate::secure_channel::error::Error;
+use ockam_common::error::OckamResult;
+use ockam_kex_xx::XXVault;
+use ockam_vault::types::{PublicKey, SecretAttributes, SecretPersistence, SecretType};
+use ockam_vault::Secret;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroize;
+
+/// Length of the nonces exchanged in a resumption
+pub const RESUMPTION_NONCE_LENGTH: usize = 32;
+
+/// Length of the secret a ticket carries
+const RESUMPTION_SECRET_LENGTH: usize = 32;
+
+/// Tickets are sealed with AES-256-GCM under a random 96 bit nonce
+const TICKET_NONCE_LENGTH: usize = 12;
+
+/// Expiry, channel key type and length, ahead of the secret and the public key
+const TICKET_HEADER_LENGTH: usize = 10;
+
+const RESUMPTION_SECRET_INFO: &[u8] = b"ockam resumption secret";
+const RESUMPTION_KEYS_INFO: &[u8] = b"ockam resumption keys";
+
+/// What a session ticket carries, readable only by the responder that issued it
+#[derive(Debug)]
+pub struct TicketContents {
+    /// Seconds since the unix epoch after which the ticket is rejected
+    pub expires: u64,
+    /// Attributes of the channel keys derived on resumption
+    pub key_attributes: SecretAttributes,
+    /// The secret the channel keys are derived from
+    pub resumption_secret: Box<dyn Secret>,
+    /// Static public key of the initiator the ticket was issued to
+    pub remote_static_public_key: PublicKey,
+}
+
+/// The keys a responder seals its tickets with, newest first
+#[derive(Debug)]
+pub struct TicketKeys {
+    lifetime: Duration,
+    keys: Vec<(Box<dyn Secret>, Instant)>,
+}
+
+impl TicketKeys {
+    /// Ticket keys for tickets valid for `lifetime`
+    pub fn new(lifetime: Duration) -> Self {
+        Self {
+            lifetime,
+            keys: vec![],
+        }
+    }
+
+    /// How long the tickets sealed with these keys are valid
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Replace the current key once it has been used for a lifetime and destroy
+    /// the previous one, whose tickets have all expired by then
+    pub fn rotate(&mut self, vault: &mut dyn XXVault) -> OckamResult<()> {
+        let due = match self.keys.first() {
+            Some((_, created)) => created.elapsed() >= self.lifetime,
+            None => true,
+        };
+        if !due {
+            return Ok(());
+        }
+        let key = vault.secret_generate(SecretAttributes {
+            stype: SecretType::Aes,
+            persistence: SecretPersistence::Ephemeral,
+            length: 32,
+        })?;
+        self.keys.insert(0, (key, Instant::now()));
+        while self.keys.len() > 2 {
+            let (key, _) = self.keys.pop().unwrap();
+            vault.secret_destroy(key)?;
+        }
+        Ok(())
+    }
+
+    /// Seal `contents` into a ticket with the current key
+    pub fn seal(
+        &mut self,
+        vault: &mut dyn XXVault,
+        contents: &TicketContents,
+    ) -> OckamResult<Vec<u8>> {
+        self.rotate(vault)?;
+        let mut plaintext = contents.expires.to_be_bytes().to_vec();
+        plaintext.push(contents.key_attributes.stype.to_usize() as u8);
+        plaintext.push(contents.key_attributes.length as u8);
+        plaintext.extend_from_slice(vault.secret_export(&contents.resumption_secret)?.as_ref());
+        plaintext.extend_from_slice(contents.remote_static_public_key.as_ref());
+
+        let mut ticket = vec![0u8; TICKET_NONCE_LENGTH];
+        vault.random_bytes_generate(&mut ticket)?;
+        let ciphertext = vault.aead_aes_gcm_encrypt(&self.keys[0].0, &plaintext, &ticket, &[]);
+        plaintext.zeroize();
+        ticket.append(&mut ciphertext?);
+        Ok(ticket)
+    }
+
+    /// Open a ticket sealed with one of the keys that are still around.
+    /// Returns `Error::InvalidTicket` when no key opens it or it has expired
+    pub fn open(&mut self, vault: &mut dyn XXVault, ticket: &[u8]) -> OckamResult<TicketContents> {
+        if ticket.len() < TICKET_NONCE_LENGTH {
+            return Err(Error::InvalidTicket.into());
+        }
+        let (nonce, ciphertext) = ticket.split_at(TICKET_NONCE_LENGTH);
+        let mut plaintext = self
+            .keys
+            .iter()
+            .find_map(|(key, _)| vault.aead_aes_gcm_decrypt(key, ciphertext, nonce, &[]).ok())
+            .ok_or_else(|| Error::InvalidTicket.into())?;
+        let contents = Self::parse(vault, &plaintext);
+        plaintext.zeroize();
+        let contents = contents?;
+        if contents.expires <= unix_time() {
+            vault.secret_destroy(contents.resumption_secret)?;
+            return Err(Error::InvalidTicket.into());
+        }
+        Ok(contents)
+    }
+
+    fn parse(vault: &mut dyn XXVault, plaintext: &[u8]) -> OckamResult<TicketContents> {
+        if plaintext.len() < TICKET_HEADER_LENGTH + RESUMPTION_SECRET_LENGTH {
+            return Err(Error::InvalidTicket.into());
+        }
+        let mut expires = [0u8; 8];
+        expires.copy_from_slice(&plaintext[..8]);
+        let key_attributes = SecretAttributes {
+            stype: SecretType::from_usize(plaintext[8] as usize)?,
+            persistence: SecretPersistence::Ephemeral,
+            length: plaintext[9] as usize,
+        };
+        let (secret, public_key) =
+            plaintext[TICKET_HEADER_LENGTH..].split_at(RESUMPTION_SECRET_LENGTH);
+        let resumption_secret = vault.secret_import(secret, buffer_attributes(secret.len()))?;
+        Ok(TicketContents {
+            expires: u64::from_be_bytes(expires),
+            key_attributes,
+            resumption_secret,
+            remote_static_public_key: PublicKey::new(public_key.to_vec()),
+        })
+    }
+
+    /// Destroy all ticket keys, invalidating every ticket issued so far
+    pub fn destroy(&mut self, vault: &mut dyn XXVault) -> OckamResult<()> {
+        for (key, _) in self.keys.drain(..) {
+            vault.secret_destroy(key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Seconds since the unix epoch, as carried in ticket expiries
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Derive the resumption secret of a channel from its handshake hash and
+/// the key the initiator encrypts with
+pub fn resumption_secret(
+    vault: &mut dyn XXVault,
+    initiator_key: &Box<dyn Secret>,
+    h: &[u8; 32],
+) -> OckamResult<Box<dyn Secret>> {
+    let ikm = vault.secret_import(h, buffer_attributes(h.len()))?;
+    let secret = vault.hkdf_sha256(
+        initiator_key,
+        RESUMPTION_SECRET_INFO,
+        Some(&ikm),
+        vec![buffer_attributes(RESUMPTION_SECRET_LENGTH)],
+    );
+    vault.secret_destroy(ikm)?;
+    Ok(secret?.pop().unwrap())
+}
+
+/// Derive the initiator to responder and responder to initiator keys of a resumed channel
+pub fn resumption_keys(
+    vault: &mut dyn XXVault,
+    resumption_secret: &Box<dyn Secret>,
+    key_attributes: SecretAttributes,
+    initiator_nonce: &[u8],
+    responder_nonce: &[u8],
+) -> OckamResult<(Box<dyn Secret>, Box<dyn Secret>)> {
+    let mut nonces = initiator_nonce.to_vec();
+    nonces.extend_from_slice(responder_nonce);
+    let ikm = vault.secret_import(&nonces, buffer_attributes(nonces.len()))?;
+    let keys = vault.hkdf_sha256(
+        resumption_secret,
+        RESUMPTION_KEYS_INFO,
+        Some(&ikm),
+        vec![key_attributes, key_attributes],
+    );
+    vault.secret_destroy(ikm)?;
+    let mut keys = keys?;
+    let responder_key = keys.pop().unwrap();
+    let initiator_key = keys.pop().unwrap();
+    Ok((initiator_key, responder_key))
+}
+
+fn buffer_attributes(length: usize) -> SecretAttributes {
+    SecretAttributes {
+        stype: SecretType::Buffer,
+        persistence: SecretPersistence::Ephemeral,
+        length,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_vault::SecretVault;
+    use ockam_vault_software::DefaultVault;
+
+    fn contents(vault: &mut dyn XXVault, expires: u64) -> TicketContents {
+        TicketContents {
+            expires,
+            key_attributes: SecretAttributes {
+                stype: SecretType::Aes,
+                persistence: SecretPersistence::Ephemeral,
+                length: 32,
+            },
+            resumption_secret: vault
+                .secret_import(&[7u8; 32], buffer_attributes(32))
+                .unwrap(),
+            remote_static_public_key: PublicKey::new(vec![9u8; 32]),
+        }
+    }
+
+    #[test]
+    fn seal_open() {
+        let mut vault = DefaultVault::default();
+        let mut keys = TicketKeys::new(Duration::from_secs(60));
+        let sealed = contents(&mut vault, unix_time() + 60);
+        let ticket = keys.seal(&mut vault, &sealed).unwrap();
+
+        let opened = keys.open(&mut vault, &ticket).unwrap();
+        assert_eq!(opened.expires, sealed.expires);
+        assert_eq!(opened.key_attributes, sealed.key_attributes);
+        assert_eq!(
+            opened.remote_static_public_key.as_ref(),
+            sealed.remote_static_public_key.as_ref()
+        );
+        assert_eq!(
+            vault
+                .secret_export(&opened.resumption_secret)
+                .unwrap()
+                .as_ref(),
+            &[7u8; 32][..]
+        );
+
+        let mut tampered = ticket.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(keys.open(&mut vault, &tampered).is_err());
+        assert!(TicketKeys::new(Duration::from_secs(60))
+            .open(&mut vault, &ticket)
+            .is_err());
+    }
+
+    #[test]
+    fn expired_ticket() {
+        let mut vault = DefaultVault::default();
+        let mut keys = TicketKeys::new(Duration::from_secs(60));
+        let sealed = contents(&mut vault, unix_time() - 1);
+        let ticket = keys.seal(&mut vault, &sealed).unwrap();
+        assert!(keys.open(&mut vault, &ticket).is_err());
+    }
+
+    #[test]
+    fn rotation() {
+        let mut vault = DefaultVault::default();
+        let mut keys = TicketKeys::new(Duration::from_millis(20));
+        let sealed = contents(&mut vault, unix_time() + 60);
+        let ticket = keys.seal(&mut vault, &sealed).unwrap();
+
+        // the previous key still opens its tickets for one more lifetime
+        std::thread::sleep(Duration::from_millis(25));
+        keys.rotate(&mut vault).unwrap();
+        assert_eq!(keys.keys.len(), 2);
+        assert!(keys.open(&mut vault, &ticket).is_ok());
+
+        std::thread::sleep(Duration::from_millis(25));
+        keys.rotate(&mut vault).unwrap();
+        assert_eq!(keys.keys.len(), 2);
+        assert!(keys.open(&mut vault, &ticket).is_err());
+    }
+
+    #[test]
+    fn both_ends_derive_the_same_keys() {
+        let mut vault = DefaultVault::default();
+        let channel_key = vault
+            .secret_generate(SecretAttributes {
+                stype: SecretType::Aes,
+                persistence: SecretPersistence::Ephemeral,
+                length: 32,
+            })
+            .unwrap();
+        let secret = resumption_secret(&mut vault, &channel_key, &[1u8; 32]).unwrap();
+        let other = resumption_secret(&mut vault, &channel_key, &[2u8; 32]).unwrap();
+        assert_ne!(
+            vault.secret_export(&secret).unwrap().as_ref(),
+            vault.secret_export(&other).unwrap().as_ref()
+        );
+
+        let attributes = vault.secret_attributes_get(&channel_key).unwrap();
+        let (i1, r1) =
+            resumption_keys(&mut vault, &secret, attributes, &[3; 32], &[4; 32]).unwrap();
+        let (i2, r2) =
+            resumption_keys(&mut vault, &secret, attributes, &[3; 32], &[4; 32]).unwrap();
+        let (i3, _) = resumption_keys(&mut vault, &secret, attributes, &[3; 32], &[5; 32]).unwrap();
+        let export = |vault: &mut DefaultVault, key: &Box<dyn Secret>| {
+            vault.secret_export(key).unwrap().as_ref().to_vec()
+        };
+        assert_eq!(export(&mut vault, &i1), export(&mut vault, &i2));
+        assert_eq!(export(&mut vault, &r1), export(&mut vault, &r2));
+        assert_ne!(export(&mut vault, &i1), export(&mut vault, &r1));
+        assert_ne!(export(&mut vault, &i1), export(&mut vault, &i3));
+        assert_eq!(vault.secret_attributes_get(&i1).unwrap(), attributes);
+    }
+}
@@ -39,6 +39,7 @@ pub enum ChannelCommand {
                                                              * address */
     SendMessage(Message),
     ReceiveMessage(Message),
+    Close(Address), // close the channel with this cleartext or ciphertext address
     Stop,
 }
 
@@ -31,7 +31,7 @@ impl ProcessMessage for TcpWorker {
             .addresses
             .insert(0, RouterAddress::from_address(local_address).unwrap());
         let mut v = vec![];
-        Message::encode(&message, &mut v)?;
+        Message::encode(&message, &mut v).map_err(|e| e.to_string())?;
 
         // encode the message length and write it as the first byte (or 2)
         let mut msg_len: Vec<u8> = vec![];
@@ -108,6 +108,9 @@ impl TcpWorker {
 
     fn set_msg_len(&mut self, varint: &mut Vec<u8>) -> Result<(), String> {
         if let Ok((l, b)) = u16::decode(varint) {
+            if l as usize > MAX_MESSAGE_SIZE {
+                return Err("message length exceeds the maximum message size".into());
+            }
             self.message_length = l as usize;
             varint.remove(0);
             if varint_size(l) == 2 {
@@ -127,8 +130,11 @@ impl TcpWorker {
             Ok((mut m_decoded, _)) => {
                 // fix up return tcp address with nat-ed address
                 let tcp_return = Address::TcpAddress(self.stream.peer_addr().unwrap());
-                m_decoded.return_route.addresses[0] =
-                    RouterAddress::from_address(tcp_return).unwrap();
+                let tcp_return = RouterAddress::from_address(tcp_return).unwrap();
+                match m_decoded.return_route.addresses.get_mut(0) {
+                    Some(ra) => *ra = tcp_return,
+                    None => m_decoded.return_route.addresses.push(tcp_return),
+                }
                 if !m_decoded.onward_route.addresses.is_empty()
                     && ((m_decoded.onward_route.addresses[0].a_type == AddressType::Udp)
                         || (m_decoded.onward_route.addresses[0].a_type == AddressType::Tcp))
@@ -142,7 +148,11 @@ impl TcpWorker {
                     Ok(true)
                 }
             }
-            Err(_) => Err("message decode failed".into()),
+            Err(e) => {
+                // the stream is still framed, so only the malformed message is dropped
+                println!("dropped tcp message: {}", e);
+                Ok(true)
+            }
         }
     }
 
@@ -153,7 +163,7 @@ impl TcpWorker {
             .addresses
             .insert(0, RouterAddress::from_address(local_address).unwrap());
         let mut v = vec![];
-        Message::encode(&m, &mut v)?;
+        Message::encode(&m, &mut v).map_err(|e| e.to_string())?;
 
         // encode the message length and write it as the first byte (or 2)
         let mut mlen: Vec<u8> = vec![];
@@ -0,0 +1,532 @@
+use ockam::message::*;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Room left in every fragment so that relays, which swap the onward hop they send to
+/// for a return hop of their own, forward fragments without splitting them again
+pub const FRAGMENT_HEADROOM: usize = 64;
+
+/// How long the fragments of a message wait for the rest of them
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest message reassembled from fragments
+pub const DEFAULT_MAX_REASSEMBLED_SIZE: usize = 64 * 1024 * 1024;
+
+/// Most bytes of incomplete messages held at once, across all peers
+pub const DEFAULT_MAX_REASSEMBLY_BUFFER: usize = 128 * 1024 * 1024;
+
+/// Most incomplete messages held at once for a single peer
+pub const MAX_PARTIALS_PER_PEER: usize = 16;
+
+/// Most incomplete messages held at once, across all peers
+pub const MAX_PARTIALS: usize = 1024;
+
+/// Most messages given up on whose later fragments are recognized and dropped
+const MAX_DISCARDED: usize = 1024;
+
+/// Largest number of fragments a message is split into
+const MAX_FRAGMENTS: u16 = 0x7fff;
+
+/// Every fragment but the last carries at least this much of the body, so that a message
+/// with many fragments takes many large datagrams to build up
+const MIN_CHUNK: usize = MAX_MESSAGE_SIZE / 2;
+
+/// What the reassembler holds for each fragment of a message, besides its body
+const SLOT_SIZE: usize = std::mem::size_of::<Option<Vec<u8>>>();
+
+/// A message id to start counting from, different across restarts so that peers don't
+/// mix the fragments of a new message with those they still hold from before
+pub fn initial_message_id() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Split `m` into fragments that each encode to at most `MAX_MESSAGE_SIZE` bytes, or
+/// return it as it is when it fits already. Every fragment carries the routes, type and
+/// extensions of `m`, a slice of its body and a critical `Fragment` extension, so only
+/// peers that speak a wire protocol version with extensions can be sent fragments
+pub fn fragment(m: Message, message_id: u64) -> Result<Vec<Message>, MessageError> {
+    let Message {
+        onward_route,
+        return_route,
+        message_type,
+        message_body,
+        extensions,
+    } = m;
+    let mut template = Message {
+        onward_route,
+        return_route,
+        message_type,
+        message_body: vec![],
+        extensions,
+    };
+    // the body is encoded last and as it is, so it adds its length to the header's
+    let mut header = vec![];
+    Message::encode(&template, &mut header)?;
+    if header.len() + message_body.len() <= MAX_MESSAGE_SIZE {
+        template.message_body = message_body;
+        return Ok(vec![template]);
+    }
+
+    let largest = FragmentHeader {
+        message_id,
+        index: MAX_FRAGMENTS,
+        count: MAX_FRAGMENTS,
+    };
+    template.set_extension(ExtensionType::Fragment as u8, fragment_header(&largest)?);
+    header.clear();
+    Message::encode(&template, &mut header)?;
+    let chunk = MAX_MESSAGE_SIZE.saturating_sub(header.len() + FRAGMENT_HEADROOM);
+    if chunk < MIN_CHUNK {
+        return Err(MessageError::ValueTooLarge);
+    }
+    let count = message_body.chunks(chunk).len();
+    if count > MAX_FRAGMENTS as usize {
+        return Err(MessageError::ValueTooLarge);
+    }
+
+    let mut fragments = Vec::with_capacity(count);
+    for (index, slice) in message_body.chunks(chunk).enumerate() {
+        let header = FragmentHeader {
+            message_id,
+            index: index as u16,
+            count: count as u16,
+        };
+        let mut f = template.clone();
+        f.set_extension(ExtensionType::Fragment as u8, fragment_header(&header)?);
+        f.message_body = slice.to_vec();
+        fragments.push(f);
+    }
+    Ok(fragments)
+}
+
+fn fragment_header(header: &FragmentHeader) -> Result<Vec<u8>, MessageError> {
+    let mut v = vec![];
+    FragmentHeader::encode(header, &mut v)?;
+    Ok(v)
+}
+
+/// The fragments of a message received so far
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    bytes: usize,
+    // body length of the fragments but the last, they all carry the same
+    chunk: Option<usize>,
+    started: Instant,
+}
+
+impl Partial {
+    /// Bytes counted against the reassembly buffer: the fragments and their slots
+    fn held(&self) -> usize {
+        self.bytes + self.fragments.len() * SLOT_SIZE
+    }
+}
+
+/// Puts messages back together from their fragments. Fragments are told apart by the
+/// peer they came from and the id of their message, and messages are given up on when
+/// they take too long, grow too large or don't fit the buffer, and when their peer or all
+/// peers have too many incomplete messages already
+pub struct Reassembler<P> {
+    timeout: Duration,
+    max_message_size: usize,
+    max_buffered: usize,
+    buffered: usize,
+    partials: HashMap<(P, u64), Partial>,
+    // messages given up on, whose later fragments are dropped until they would have expired
+    discarded: HashMap<(P, u64), Instant>,
+}
+
+impl<P: Eq + Hash + Clone> Reassembler<P> {
+    pub fn new(timeout: Duration, max_message_size: usize, max_buffered: usize) -> Self {
+        Reassembler {
+            timeout,
+            max_message_size,
+            max_buffered,
+            buffered: 0,
+            partials: HashMap::new(),
+            discarded: HashMap::new(),
+        }
+    }
+
+    /// Bytes held by incomplete messages, with the slots waiting for their other fragments
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Number of incomplete messages
+    pub fn pending(&self) -> usize {
+        self.partials.len()
+    }
+
+    /// Give up on the messages whose first fragment arrived longer than the timeout ago
+    pub fn expire(&mut self) {
+        let timeout = self.timeout;
+        let mut expired = 0;
+        self.partials.retain(|_, p| {
+            let keep = p.started.elapsed() < timeout;
+            if !keep {
+                expired += p.held();
+            }
+            keep
+        });
+        self.buffered -= expired;
+        self.discarded
+            .retain(|_, started| started.elapsed() < timeout);
+    }
+
+    /// Messages that aren't fragments are returned as they are. Fragments are held until
+    /// all the fragments of their message arrived, the message is then returned with the
+    /// routes, type and extensions of the last one. Dropped fragments are reported as errors
+    pub fn receive(&mut self, peer: P, mut m: Message) -> Result<Option<Message>, String> {
+        self.expire();
+        let header = match m.extension(ExtensionType::Fragment as u8) {
+            Some(value) => {
+                FragmentHeader::decode(value)
+                    .map_err(|e| format!("dropped fragment: {}", e))?
+                    .0
+            }
+            None => return Ok(Some(m)),
+        };
+        if header.count < 2 || header.index >= header.count {
+            return Err("dropped fragment: invalid fragment header".to_string());
+        }
+        let length = m.message_body.len();
+        let last = header.index + 1 == header.count;
+        if length == 0 || (!last && length < MIN_CHUNK) {
+            return Err("dropped fragment: shorter than a chunk".to_string());
+        }
+
+        let key = (peer, header.message_id);
+        if self.discarded.contains_key(&key) {
+            return Err("dropped fragment of a dropped message".to_string());
+        }
+        if !self.partials.contains_key(&key) {
+            if let Err(e) = self.start(&key, header.count as usize) {
+                self.remember_discarded(&key, Instant::now());
+                return Err(e);
+            }
+        }
+        let partial = self.partials.get_mut(&key).unwrap();
+        if partial.fragments.len() != header.count as usize {
+            self.discard(&key);
+            return Err("dropped message: its fragment count changed".to_string());
+        }
+        if partial.fragments[header.index as usize].is_some() {
+            // a duplicate, of a datagram or of a message resent by a relay
+            return Ok(None);
+        }
+        if !last {
+            match partial.chunk {
+                Some(chunk) if chunk != length => {
+                    self.discard(&key);
+                    return Err("dropped message: its fragments differ in length".to_string());
+                }
+                _ => partial.chunk = Some(length),
+            }
+        }
+        if partial.bytes + length > self.max_message_size {
+            self.discard(&key);
+            return Err("dropped message: too large to reassemble".to_string());
+        }
+        if self.buffered + length > self.max_buffered {
+            self.discard(&key);
+            return Err("dropped message: reassembly buffer is full".to_string());
+        }
+
+        partial.fragments[header.index as usize] = Some(std::mem::take(&mut m.message_body));
+        partial.received += 1;
+        partial.bytes += length;
+        self.buffered += length;
+        if partial.received < partial.fragments.len() {
+            return Ok(None);
+        }
+
+        let partial = self.partials.remove(&key).unwrap();
+        self.buffered -= partial.held();
+        let mut body = Vec::with_capacity(partial.bytes);
+        for f in partial.fragments.into_iter().flatten() {
+            body.extend(f);
+        }
+        m.message_body = body;
+        m.extensions
+            .retain(|e| e.e_type != ExtensionType::Fragment as u8);
+        Ok(Some(m))
+    }
+
+    /// Hold the slots of a new message, unless it is too large for its fragment count,
+    /// there are too many incomplete messages or its slots don't fit the buffer
+    fn start(&mut self, key: &(P, u64), count: usize) -> Result<(), String> {
+        if (count - 1) * MIN_CHUNK > self.max_message_size {
+            return Err("dropped message: too large to reassemble".to_string());
+        }
+        if self.partials.len() >= MAX_PARTIALS {
+            return Err("dropped message: too many messages are being reassembled".to_string());
+        }
+        let from_peer = self.partials.keys().filter(|(p, _)| *p == key.0).count();
+        if from_peer >= MAX_PARTIALS_PER_PEER {
+            return Err(
+                "dropped message: too many messages from its peer are being reassembled"
+                    .to_string(),
+            );
+        }
+        let slots = count * SLOT_SIZE;
+        if self.buffered + slots > self.max_buffered {
+            return Err("dropped message: reassembly buffer is full".to_string());
+        }
+        self.buffered += slots;
+        self.partials.insert(
+            key.clone(),
+            Partial {
+                fragments: vec![None; count],
+                received: 0,
+                bytes: 0,
+                chunk: None,
+                started: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn discard(&mut self, key: &(P, u64)) {
+        if let Some(partial) = self.partials.remove(key) {
+            self.buffered -= partial.held();
+            self.remember_discarded(key, partial.started);
+        }
+    }
+
+    /// Drop the later fragments of a message given up on, as long as there is room to
+    /// remember it. Those that aren't remembered start a message that soon expires
+    fn remember_discarded(&mut self, key: &(P, u64), started: Instant) {
+        if self.discarded.len() < MAX_DISCARDED {
+            self.discarded.insert(key.clone(), started);
+        }
+    }
+}
+
+impl<P: Eq + Hash + Clone> Default for Reassembler<P> {
+    fn default() -> Self {
+        Reassembler::new(
+            DEFAULT_REASSEMBLY_TIMEOUT,
+            DEFAULT_MAX_REASSEMBLED_SIZE,
+            DEFAULT_MAX_REASSEMBLY_BUFFER,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(body: Vec<u8>) -> Message {
+        let mut m = Message {
+            onward_route: Route {
+                addresses: vec![RouterAddress::worker_router_address_from_str("00010203").unwrap()],
+            },
+            return_route: Route {
+                addresses: vec![
+                    RouterAddress::udp_router_address_from_str("127.0.0.1:4000").unwrap()
+                ],
+            },
+            message_type: MessageType::Payload,
+            message_body: body,
+            extensions: vec![],
+        };
+        m.set_extension(ExtensionType::TraceId as u8, vec![9; 16]);
+        m
+    }
+
+    fn body(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn small_messages_pass_through() {
+        let fragments = fragment(message(vec![1, 2, 3]), 7).unwrap();
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].message_body, vec![1, 2, 3]);
+        assert!(fragments[0]
+            .extension(ExtensionType::Fragment as u8)
+            .is_none());
+
+        let mut reassembler = Reassembler::default();
+        let m = reassembler
+            .receive(1, fragments[0].clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(m.message_body, vec![1, 2, 3]);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn fragments_round_trip() {
+        let big = body(3 * 1024 * 1024 + 17);
+        let fragments = fragment(message(big.clone()), 7).unwrap();
+        assert!(fragments.len() > 1);
+        for f in &fragments {
+            let mut v = vec![];
+            Message::encode(f, &mut v).unwrap();
+            assert!(v.len() + FRAGMENT_HEADROOM <= MAX_MESSAGE_SIZE);
+            let (decoded, _) = Message::decode(&v).unwrap();
+            assert_eq!(decoded.extensions, f.extensions);
+        }
+
+        // fragments may arrive in any order, more than once
+        let mut reassembler = Reassembler::default();
+        let (first, rest) = fragments.split_first().unwrap();
+        for f in rest.iter().rev() {
+            assert!(reassembler.receive(1, f.clone()).unwrap().is_none());
+        }
+        assert!(reassembler.receive(1, rest[0].clone()).unwrap().is_none());
+        assert_eq!(reassembler.pending(), 1);
+        assert_eq!(
+            reassembler.buffered(),
+            big.len() - first.message_body.len() + fragments.len() * SLOT_SIZE
+        );
+
+        let m = reassembler.receive(1, first.clone()).unwrap().unwrap();
+        assert_eq!(m.message_body, big);
+        assert_eq!(m.extensions, message(vec![]).extensions);
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.buffered(), 0);
+    }
+
+    #[test]
+    fn messages_from_different_peers() {
+        let a = fragment(message(body(40000)), 7).unwrap();
+        let b = fragment(message(body(50000)), 7).unwrap();
+        let mut reassembler = Reassembler::default();
+        for f in &a[1..] {
+            assert!(reassembler.receive("a", f.clone()).unwrap().is_none());
+        }
+        for f in &b[1..] {
+            assert!(reassembler.receive("b", f.clone()).unwrap().is_none());
+        }
+        assert_eq!(reassembler.pending(), 2);
+        let m = reassembler.receive("b", b[0].clone()).unwrap().unwrap();
+        assert_eq!(m.message_body, body(50000));
+        let m = reassembler.receive("a", a[0].clone()).unwrap().unwrap();
+        assert_eq!(m.message_body, body(40000));
+    }
+
+    #[test]
+    fn reassembly_limits() {
+        let fragments = fragment(message(body(100000)), 7).unwrap();
+
+        let mut reassembler = Reassembler::new(DEFAULT_REASSEMBLY_TIMEOUT, 50000, 1 << 20);
+        let mut dropped = false;
+        for f in &fragments {
+            dropped |= reassembler.receive(1, f.clone()).is_err();
+        }
+        assert!(dropped);
+        assert_eq!(reassembler.buffered(), 0);
+
+        let mut reassembler = Reassembler::new(DEFAULT_REASSEMBLY_TIMEOUT, 1 << 20, 50000);
+        assert!(reassembler
+            .receive(1, fragments[0].clone())
+            .unwrap()
+            .is_none());
+        let other = fragment(message(body(100000)), 8).unwrap();
+        let mut dropped = false;
+        for f in &other {
+            dropped |= reassembler.receive(1, f.clone()).is_err();
+        }
+        assert!(dropped);
+        assert_eq!(reassembler.pending(), 1);
+        assert_eq!(
+            reassembler.buffered(),
+            fragments[0].message_body.len() + fragments.len() * SLOT_SIZE
+        );
+
+        let mut reassembler = Reassembler::new(Duration::from_millis(0), 1 << 20, 1 << 20);
+        assert!(reassembler
+            .receive(1, fragments[0].clone())
+            .unwrap()
+            .is_none());
+        reassembler.expire();
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.buffered(), 0);
+
+        let mut invalid = fragments[0].clone();
+        let header = FragmentHeader {
+            message_id: 7,
+            index: 3,
+            count: 3,
+        };
+        invalid.set_extension(
+            ExtensionType::Fragment as u8,
+            fragment_header(&header).unwrap(),
+        );
+        assert!(Reassembler::default().receive(1, invalid).is_err());
+    }
+
+    fn crafted(message_id: u64, index: u16, count: u16, length: usize) -> Message {
+        let mut m = message(body(length));
+        let header = FragmentHeader {
+            message_id,
+            index,
+            count,
+        };
+        m.set_extension(
+            ExtensionType::Fragment as u8,
+            fragment_header(&header).unwrap(),
+        );
+        m
+    }
+
+    #[test]
+    fn small_fragments_hold_little() {
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.receive(1, crafted(1, 2, 3, 0)).is_err());
+        assert!(reassembler
+            .receive(1, crafted(1, 0, 3, MIN_CHUNK - 1))
+            .is_err());
+        // the slots of a message are only held when full chunks could fill them
+        assert!(reassembler
+            .receive(1, crafted(2, MAX_FRAGMENTS - 1, MAX_FRAGMENTS, 10))
+            .is_err());
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.buffered(), 0);
+
+        assert!(reassembler
+            .receive(1, crafted(3, 2, 3, 10))
+            .unwrap()
+            .is_none());
+        assert_eq!(reassembler.buffered(), 10 + 3 * SLOT_SIZE);
+        assert!(reassembler
+            .receive(1, crafted(3, 0, 3, MIN_CHUNK))
+            .unwrap()
+            .is_none());
+        assert!(reassembler
+            .receive(1, crafted(3, 1, 3, MIN_CHUNK + 1))
+            .is_err());
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.buffered(), 0);
+    }
+
+    #[test]
+    fn incomplete_messages_are_capped() {
+        let mut reassembler = Reassembler::default();
+        for id in 0..MAX_PARTIALS_PER_PEER as u64 {
+            assert!(reassembler.receive(0, crafted(id, 1, 2, 10)).is_ok());
+        }
+        assert!(reassembler.receive(0, crafted(99, 1, 2, 10)).is_err());
+        for peer in 1..MAX_PARTIALS - MAX_PARTIALS_PER_PEER + 1 {
+            assert!(reassembler.receive(peer, crafted(0, 1, 2, 10)).is_ok());
+        }
+        assert_eq!(reassembler.pending(), MAX_PARTIALS);
+        assert!(reassembler
+            .receive(MAX_PARTIALS, crafted(0, 1, 2, 10))
+            .is_err());
+
+        // a message completes as usual even so
+        assert!(reassembler
+            .receive(0, crafted(0, 0, 2, MIN_CHUNK))
+            .unwrap()
+            .is_some());
+        assert_eq!(reassembler.pending(), MAX_PARTIALS - 1);
+    }
+}
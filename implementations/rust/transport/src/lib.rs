@@ -1,2 +1,103 @@
+pub mod fragmentation;
+pub mod negotiation;
+pub mod resolver;
 pub mod tcp;
 pub mod udp;
+
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// Open a socket bound to `address`. The unspecified addresses `0.0.0.0` and `::` bind a
+/// dual-stack IPv6 socket, reachable over both IPv4 and IPv6, unless the host has no IPv6
+fn bind_socket(address: SocketAddr, socket_type: Type) -> std::io::Result<Socket> {
+    if address.ip().is_unspecified() {
+        let any = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), address.port());
+        if let Ok(socket) = new_socket(any, socket_type) {
+            if socket.set_only_v6(false).is_ok() && socket.bind(&SockAddr::from(any)).is_ok() {
+                return Ok(socket);
+            }
+        }
+    }
+    let socket = new_socket(address, socket_type)?;
+    socket.bind(&SockAddr::from(address))?;
+    Ok(socket)
+}
+
+fn new_socket(address: SocketAddr, socket_type: Type) -> std::io::Result<Socket> {
+    let domain = match address {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, socket_type, None)?;
+    if i32::from(socket_type) == i32::from(Type::stream()) {
+        // as std::net::TcpListener::bind does
+        socket.set_reuse_address(true)?;
+    }
+    Ok(socket)
+}
+
+/// Peers reaching a dual-stack socket over IPv4 show up with IPv4-mapped IPv6 addresses,
+/// turn those back into the IPv4 addresses they stand for
+pub fn canonical_socket_addr(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(a) => match a.ip().to_ipv4_mapped() {
+            Some(ip4) => SocketAddr::new(IpAddr::V4(ip4), a.port()),
+            None => address,
+        },
+        _ => address,
+    }
+}
+
+/// The address to reach `remote` at from a socket bound to `local`: an IPv6 socket
+/// reaches IPv4 peers through their IPv4-mapped address
+fn destination_socket_addr(local: SocketAddr, remote: SocketAddr) -> SocketAddr {
+    match (local, remote) {
+        (SocketAddr::V6(_), SocketAddr::V4(r)) => {
+            SocketAddr::new(IpAddr::V6(r.ip().to_ipv6_mapped()), r.port())
+        }
+        _ => remote,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::str::FromStr;
+
+    #[test]
+    fn mapped_addresses() {
+        let mapped = SocketAddr::from_str("[::ffff:10.0.0.1]:4000").unwrap();
+        let ip4 = SocketAddr::from_str("10.0.0.1:4000").unwrap();
+        let ip6 = SocketAddr::from_str("[2001:db8::1]:4000").unwrap();
+        assert_eq!(canonical_socket_addr(mapped), ip4);
+        assert_eq!(canonical_socket_addr(ip4), ip4);
+        assert_eq!(canonical_socket_addr(ip6), ip6);
+        assert_eq!(destination_socket_addr(ip6, ip4), mapped);
+        assert_eq!(destination_socket_addr(ip4, ip4), ip4);
+        assert_eq!(destination_socket_addr(ip6, ip6), ip6);
+    }
+
+    #[test]
+    fn dual_stack_udp() {
+        let socket = match bind_socket(SocketAddr::from_str("0.0.0.0:0").unwrap(), Type::dgram()) {
+            Ok(socket) => socket.into_udp_socket(),
+            Err(_) => return,
+        };
+        let port = socket.local_addr().unwrap().port();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.send_to(b"v4", ("127.0.0.1", port)).unwrap();
+        let mut buf = [0u8; 2];
+        let (_, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(canonical_socket_addr(from), peer.local_addr().unwrap());
+        assert_eq!(&buf, b"v4");
+
+        // only hosts with an IPv6 loopback can take the IPv6 half of the test
+        if let Ok(peer) = UdpSocket::bind("[::1]:0") {
+            peer.send_to(b"v6", ("::1", port)).unwrap();
+            let (_, from) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(from, peer.local_addr().unwrap());
+            assert_eq!(&buf, b"v6");
+        }
+    }
+}
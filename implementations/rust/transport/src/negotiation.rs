@@ -0,0 +1,288 @@
+use ockam::message::*;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Nodes announce the highest wire protocol version they speak in a version 1 Ping with
+/// an empty onward route, and answer one with a Pong announcing their own. Nodes that
+/// predate negotiation hand announcements to their router, which drops them for lack
+/// of a route, and keep being sent version 1 messages
+pub fn version_announcement(message_type: MessageType, local: RouterAddress) -> Message {
+    let mut body = vec![];
+    WireProtocolVersion::encode(&WireProtocolVersion::this_node(), &mut body)
+        .expect("wire protocol version fits its encoding");
+    Message {
+        onward_route: Route { addresses: vec![] },
+        return_route: Route {
+            addresses: vec![local],
+        },
+        message_type,
+        message_body: body,
+        extensions: vec![],
+    }
+}
+
+/// The version announced by `m`, if it is an announcement
+pub fn announced_version(m: &Message) -> Option<WireProtocolVersion> {
+    match m.message_type {
+        MessageType::Ping | MessageType::Pong if m.onward_route.addresses.is_empty() => {
+            WireProtocolVersion::decode(&m.message_body)
+                .ok()
+                .map(|(v, _)| v)
+        }
+        _ => None,
+    }
+}
+
+/// Most peers whose version is remembered at once
+pub const DEFAULT_MAX_PEERS: usize = 4096;
+
+/// How long the version of a peer that sends nothing is remembered
+pub const DEFAULT_PEER_TTL: Duration = Duration::from_secs(600);
+
+/// Most announcements answered per second, across all peers
+pub const DEFAULT_MAX_ANSWERS_PER_SECOND: u32 = 64;
+
+/// Least time between two answers to the same peer's announcements
+const ANSWER_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Peer {
+    version: WireProtocolVersion,
+    // false while an announcement to the peer waits for its answer
+    heard: bool,
+    last_seen: Instant,
+    last_answered: Option<Instant>,
+}
+
+/// The wire protocol versions peers are known to speak. Peers are spoken to in version 1
+/// until they announce a later one. Peers that go quiet, or that don't fit among the
+/// most recently seen, are forgotten and announced to again
+pub struct PeerVersions<P> {
+    peers: HashMap<P, Peer>,
+    max_peers: usize,
+    ttl: Duration,
+    max_answers_per_second: u32,
+    // answers sent in the current second, and when it started
+    answers: u32,
+    answers_since: Instant,
+}
+
+impl<P: Eq + Hash + Clone> PeerVersions<P> {
+    pub fn new() -> Self {
+        PeerVersions::with_limits(
+            DEFAULT_MAX_PEERS,
+            DEFAULT_PEER_TTL,
+            DEFAULT_MAX_ANSWERS_PER_SECOND,
+        )
+    }
+
+    pub fn with_limits(max_peers: usize, ttl: Duration, max_answers_per_second: u32) -> Self {
+        PeerVersions {
+            peers: HashMap::new(),
+            max_peers,
+            ttl,
+            max_answers_per_second,
+            answers: 0,
+            answers_since: Instant::now(),
+        }
+    }
+
+    /// Number of peers whose version is remembered
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// The version to speak with `peer`, `None` until it has been announced to
+    pub fn get(&self, peer: &P) -> Option<WireProtocolVersion> {
+        self.peers
+            .get(peer)
+            .filter(|p| p.last_seen.elapsed() < self.ttl)
+            .map(|p| p.version)
+    }
+
+    /// Remember that an announcement was sent to `peer`, which is spoken to in
+    /// version 1 until it answers
+    pub fn announced_to(&mut self, peer: P) {
+        if self.get(&peer).is_none() {
+            self.insert(peer, WireProtocolVersion::default(), false);
+        }
+    }
+
+    /// True while `peer` was announced to and neither answered nor sent anything since
+    pub fn awaiting(&self, peer: &P) -> bool {
+        matches!(self.peers.get(peer), Some(p) if !p.heard && p.last_seen.elapsed() < self.ttl)
+    }
+
+    /// `peer` announced the highest version it speaks
+    pub fn announced_by(&mut self, peer: P, version: WireProtocolVersion) {
+        let last_answered = self.peers.get(&peer).and_then(|p| p.last_answered);
+        self.insert(peer.clone(), version.negotiate(), true);
+        self.peers.get_mut(&peer).unwrap().last_answered = last_answered;
+    }
+
+    /// `peer` sent a message of the given version, so it speaks at least that one
+    pub fn received(&mut self, peer: &P, version: u8) {
+        if self.get(peer).is_none() {
+            return;
+        }
+        let p = self.peers.get_mut(peer).unwrap();
+        p.last_seen = Instant::now();
+        p.heard = true;
+        let received = WireProtocolVersion { v: version as u16 }.negotiate();
+        if p.version.v < received.v {
+            p.version = received;
+        }
+    }
+
+    /// Whether to answer an announcement from `peer`, or a message in a version this node
+    /// doesn't speak. Answers are sent at most once a second per peer, and at most
+    /// `max_answers_per_second` in all, so that spoofed announcements don't turn this node
+    /// into a reflector
+    pub fn may_answer(&mut self, peer: &P) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.answers_since) >= Duration::from_secs(1) {
+            self.answers = 0;
+            self.answers_since = now;
+        }
+        if self.answers >= self.max_answers_per_second {
+            return false;
+        }
+        if let Some(p) = self.peers.get_mut(peer) {
+            if matches!(p.last_answered, Some(t) if now.duration_since(t) < ANSWER_INTERVAL) {
+                return false;
+            }
+            p.last_answered = Some(now);
+        }
+        self.answers += 1;
+        true
+    }
+
+    /// Remember the version of `peer`, forgetting expired peers and then the least
+    /// recently seen one to make room
+    fn insert(&mut self, peer: P, version: WireProtocolVersion, heard: bool) {
+        let now = Instant::now();
+        if !self.peers.contains_key(&peer) && self.peers.len() >= self.max_peers {
+            let ttl = self.ttl;
+            self.peers
+                .retain(|_, p| now.duration_since(p.last_seen) < ttl);
+            if self.peers.len() >= self.max_peers {
+                let oldest = self
+                    .peers
+                    .iter()
+                    .min_by_key(|(_, p)| p.last_seen)
+                    .map(|(peer, _)| peer.clone());
+                if let Some(oldest) = oldest {
+                    self.peers.remove(&oldest);
+                }
+            }
+        }
+        self.peers.insert(
+            peer,
+            Peer {
+                version,
+                heard,
+                last_seen: now,
+                last_answered: None,
+            },
+        );
+    }
+}
+
+impl<P: Eq + Hash + Clone> Default for PeerVersions<P> {
+    fn default() -> Self {
+        PeerVersions::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    #[test]
+    fn announcements() {
+        let local = RouterAddress::udp_router_address_from_str("127.0.0.1:4000").unwrap();
+        let ping = version_announcement(MessageType::Ping, local);
+        let mut v = vec![];
+        Message::encode(&ping, &mut v).unwrap();
+        assert_eq!(v[0], MIN_WIRE_PROTOCOL_VERSION);
+        let (decoded, _) = Message::decode(&v).unwrap();
+        assert_eq!(
+            announced_version(&decoded),
+            Some(WireProtocolVersion::this_node())
+        );
+
+        let mut payload = decoded.clone();
+        payload.message_type = MessageType::Payload;
+        assert_eq!(announced_version(&payload), None);
+    }
+
+    #[test]
+    fn peer_versions() {
+        let peer = SocketAddr::from_str("127.0.0.1:4000").unwrap();
+        let mut versions = PeerVersions::new();
+        assert_eq!(versions.get(&peer), None);
+
+        // nothing is learnt from peers that weren't announced to
+        versions.received(&peer, 2);
+        assert_eq!(versions.get(&peer), None);
+
+        versions.announced_to(peer);
+        assert_eq!(versions.get(&peer), Some(WireProtocolVersion { v: 1 }));
+        versions.received(&peer, 2);
+        assert_eq!(versions.get(&peer), Some(WireProtocolVersion { v: 2 }));
+        versions.received(&peer, 1);
+        assert_eq!(versions.get(&peer), Some(WireProtocolVersion { v: 2 }));
+
+        // later versions than this node's are spoken down to its own
+        versions.announced_by(peer, WireProtocolVersion { v: 9 });
+        assert_eq!(versions.get(&peer), Some(WireProtocolVersion::this_node()));
+        versions.announced_by(peer, WireProtocolVersion { v: 1 });
+        assert_eq!(versions.get(&peer), Some(WireProtocolVersion { v: 1 }));
+    }
+
+    #[test]
+    fn peer_versions_are_bounded() {
+        let peer = |port| SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap();
+        let mut versions = PeerVersions::with_limits(2, Duration::from_millis(20), 64);
+        versions.announced_by(peer(1), WireProtocolVersion { v: 2 });
+        std::thread::sleep(Duration::from_millis(2));
+        versions.announced_by(peer(2), WireProtocolVersion { v: 2 });
+        std::thread::sleep(Duration::from_millis(2));
+        versions.received(&peer(1), 2);
+
+        // the least recently seen peer makes room
+        versions.announced_to(peer(3));
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions.get(&peer(2)), None);
+        assert_eq!(versions.get(&peer(1)), Some(WireProtocolVersion { v: 2 }));
+
+        // and quiet peers are forgotten
+        std::thread::sleep(Duration::from_millis(25));
+        assert_eq!(versions.get(&peer(1)), None);
+        versions.announced_to(peer(4));
+        assert_eq!(versions.len(), 1);
+    }
+
+    #[test]
+    fn answers_are_rate_limited() {
+        let peer = |port| SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap();
+        let mut versions = PeerVersions::with_limits(16, Duration::from_secs(60), 3);
+
+        // once a second per peer
+        versions.announced_by(peer(1), WireProtocolVersion { v: 2 });
+        assert!(versions.may_answer(&peer(1)));
+        versions.announced_by(peer(1), WireProtocolVersion { v: 2 });
+        assert!(!versions.may_answer(&peer(1)));
+
+        // and a few a second in all, even to peers never heard from
+        assert!(versions.may_answer(&peer(2)));
+        assert!(versions.may_answer(&peer(3)));
+        assert!(!versions.may_answer(&peer(4)));
+    }
+}
@@ -0,0 +1,138 @@
+use ockam::message::HostName;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// How long resolved addresses are used before the host name is looked up again
+pub const DEFAULT_RESOLVER_TTL: Duration = Duration::from_secs(60);
+
+/// Looks a host name up, returning every address it resolves to
+pub type Lookup = Box<dyn Fn(&HostName) -> io::Result<Vec<SocketAddr>> + Send>;
+
+/// Resolves host name addresses for the transports when they send, caching the answers
+/// for a while so that routes follow hosts whose addresses change
+pub struct Resolver {
+    lookup: Lookup,
+    ttl: Duration,
+    cache: HashMap<HostName, (Vec<SocketAddr>, Instant)>,
+}
+
+impl Resolver {
+    /// Resolve with the system resolver, which also reads `/etc/hosts`
+    pub fn new(ttl: Duration) -> Resolver {
+        Resolver::with_lookup(
+            Box::new(|h: &HostName| {
+                (h.host.as_str(), h.port)
+                    .to_socket_addrs()
+                    .map(|addrs| addrs.collect())
+            }),
+            ttl,
+        )
+    }
+
+    pub fn with_lookup(lookup: Lookup, ttl: Duration) -> Resolver {
+        Resolver {
+            lookup,
+            ttl,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// The addresses `host` resolves to, from the cache unless they have expired
+    pub fn resolve(&mut self, host: &HostName) -> Result<Vec<SocketAddr>, String> {
+        if let Some((addrs, resolved)) = self.cache.get(host) {
+            if resolved.elapsed() < self.ttl {
+                return Ok(addrs.clone());
+            }
+        }
+        self.cache.remove(host);
+        match (self.lookup)(host) {
+            Ok(addrs) if !addrs.is_empty() => {
+                self.cache
+                    .insert(host.clone(), (addrs.clone(), Instant::now()));
+                Ok(addrs)
+            }
+            Ok(_) => Err(format!("{} has no addresses", host)),
+            Err(e) => Err(format!("failed to resolve {}: {}", host, e)),
+        }
+    }
+
+    /// Forget the addresses of `host`, called when they failed so the next send looks it up again
+    pub fn invalidate(&mut self, host: &HostName) {
+        self.cache.remove(host);
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::new(DEFAULT_RESOLVER_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn stub(lookups: Arc<AtomicUsize>) -> Lookup {
+        Box::new(move |h: &HostName| {
+            let n = lookups.fetch_add(1, Ordering::SeqCst);
+            match h.host.as_str() {
+                "sink.example.internal" => Ok(vec![SocketAddr::new(
+                    format!("10.0.0.{}", n + 1).parse().unwrap(),
+                    h.port,
+                )]),
+                "empty.example.internal" => Ok(vec![]),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+            }
+        })
+    }
+
+    #[test]
+    fn caches_until_invalidated() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let mut resolver = Resolver::with_lookup(stub(lookups.clone()), DEFAULT_RESOLVER_TTL);
+        let host = HostName::from_str("sink.example.internal:4000").unwrap();
+        let first = SocketAddr::from_str("10.0.0.1:4000").unwrap();
+
+        assert_eq!(resolver.resolve(&host).unwrap(), vec![first]);
+        assert_eq!(resolver.resolve(&host).unwrap(), vec![first]);
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        resolver.invalidate(&host);
+        let second = SocketAddr::from_str("10.0.0.2:4000").unwrap();
+        assert_eq!(resolver.resolve(&host).unwrap(), vec![second]);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        assert!(resolver
+            .resolve(&HostName::from_str("empty.example.internal:1").unwrap())
+            .is_err());
+        assert!(resolver
+            .resolve(&HostName::from_str("missing.example.internal:1").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let mut resolver = Resolver::with_lookup(stub(lookups.clone()), Duration::from_millis(0));
+        let host = HostName::from_str("sink.example.internal:4000").unwrap();
+        resolver.resolve(&host).unwrap();
+        resolver.resolve(&host).unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn system_resolver() {
+        let mut resolver = Resolver::default();
+        let addrs = resolver
+            .resolve(&HostName::from_str("localhost:4000").unwrap())
+            .unwrap();
+        assert!(addrs
+            .iter()
+            .all(|a| a.ip().is_loopback() && a.port() == 4000));
+    }
+}
@@ -1,15 +1,24 @@
+use crate::fragmentation::{fragment, initial_message_id, Reassembler};
+use crate::negotiation::{announced_version, version_announcement};
+use crate::resolver::Resolver;
+use crate::udp::{DEFAULT_PENDING_TIMEOUT, MAX_PENDING_BYTES};
 use futures::io::Error;
 use ockam::message::MAX_MESSAGE_SIZE;
 #[allow(unused)]
 use ockam::message::*;
 use ockam::system::commands::RouterCommand::ReceiveMessage;
 use ockam::system::commands::{OckamCommand, RouterCommand, TransportCommand};
-use std::collections::HashMap;
+use socket2::Type;
+use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::net::{SocketAddr, TcpListener};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Most bytes of frames waiting for a connection to take them, messages that
+/// don't fit are dropped
+pub const MAX_QUEUED_BYTES: usize = 64 * 1024 * 1024;
 
 pub struct TcpManager {
     rx: std::sync::mpsc::Receiver<OckamCommand>,
@@ -19,6 +28,8 @@ pub struct TcpManager {
     listener: Option<TcpListener>,
     connections: HashMap<String, TcpTransport>,
     addresses: Vec<String>,
+    resolver: Resolver,
+    hosts: HashMap<HostName, String>,
 }
 
 impl TcpManager {
@@ -34,6 +45,28 @@ impl TcpManager {
         }
     }
 
+    /// Connect to the first address the host name resolves to that accepts the connection
+    pub fn connect_host(&mut self, host: &HostName) -> Result<Address, String> {
+        let addresses = self.resolver.resolve(host)?;
+        match TcpStream::connect(addresses.as_slice()) {
+            Ok(stream) => {
+                let peer_addr = crate::canonical_socket_addr(stream.peer_addr().unwrap());
+                self.add_connection(stream);
+                self.hosts.insert(host.clone(), peer_addr.to_string());
+                Ok(Address::TcpHostAddress(host.clone()))
+            }
+            Err(e) => {
+                self.resolver.invalidate(host);
+                Err(format!("tcp failed to connect to {}: {}", host, e))
+            }
+        }
+    }
+
+    /// Replace the resolver host name addresses are looked up with
+    pub fn set_resolver(&mut self, resolver: Resolver) {
+        self.resolver = resolver;
+    }
+
     pub fn new(
         rx: std::sync::mpsc::Receiver<OckamCommand>,
         tx: std::sync::mpsc::Sender<OckamCommand>,
@@ -53,7 +86,9 @@ impl TcpManager {
 
         return match listen_addr {
             Some(la) => {
-                if let Ok(l) = TcpListener::bind(la) {
+                let listener = crate::bind_socket(la, Type::stream())
+                    .and_then(|s| s.listen(128).map(|_| s.into_tcp_listener()));
+                if let Ok(l) = listener {
                     l.set_nonblocking(true).unwrap();
                     Ok(TcpManager {
                         rx,
@@ -63,6 +98,8 @@ impl TcpManager {
                         listener: Some(l),
                         connections,
                         addresses: vec![],
+                        resolver: Resolver::default(),
+                        hosts: HashMap::new(),
                     })
                 } else {
                     Err("failed to bind tcp listener".into())
@@ -76,19 +113,48 @@ impl TcpManager {
                 listener: None,
                 connections,
                 addresses: vec![],
+                resolver: Resolver::default(),
+                hosts: HashMap::new(),
             }),
         };
     }
 
     fn add_connection(&mut self, stream: TcpStream) -> bool {
         stream.set_nonblocking(true).unwrap();
-        let peer_addr = stream.peer_addr().unwrap().clone();
+        let peer_addr = crate::canonical_socket_addr(stream.peer_addr().unwrap());
         let tcp_xport = TcpTransport::new(stream, self.router_tx.clone()).unwrap();
         self.connections.insert(peer_addr.to_string(), tcp_xport);
         self.addresses.push(peer_addr.to_string());
         true
     }
 
+    fn remove_connection(&mut self, addr: &str) {
+        self.connections.remove(addr);
+        self.addresses.retain(|a| a != addr);
+        self.hosts.retain(|_, a| a != addr);
+    }
+
+    /// Host names are connected to when first sent to, and connected to again through
+    /// a fresh lookup when their connection fails
+    fn send_to_host(&mut self, host: &HostName, m: Message) -> Result<(), String> {
+        if let Some(addr) = self.hosts.get(host).cloned() {
+            if let Some(tcp_xport) = self.connections.get_mut(&addr) {
+                match tcp_xport.send_message(m.clone()) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => println!("send_message to {} failed: {}", host, e),
+                }
+            }
+            self.remove_connection(&addr);
+            self.resolver.invalidate(host);
+        }
+        self.connect_host(host)?;
+        let addr = self.hosts[host].clone();
+        match self.connections.get_mut(&addr) {
+            Some(tcp_xport) => tcp_xport.send_message(m),
+            None => Err(format!("can't find connection {}", addr)),
+        }
+    }
+
     pub fn poll(&mut self) -> bool {
         let mut got: bool = true;
         let mut keep_going = true;
@@ -121,14 +187,19 @@ impl TcpManager {
                 match tc {
                     OckamCommand::Transport(TransportCommand::SendMessage(mut m)) => {
                         let addr = m.onward_route.addresses.get_mut(0).unwrap();
+                        let host = match &addr.address {
+                            Address::TcpHostAddress(host) => Some(host.clone()),
+                            _ => None,
+                        };
                         let addr = addr.address.as_string();
-                        if let Some(tcp_xport) = self.connections.get_mut(&addr) {
-                            match tcp_xport.send_message(m) {
-                                Err(e) => {
-                                    println!("send_message failed: {}", e);
-                                    keep_going = false;
-                                }
-                                _ => {}
+                        if let Some(host) = host {
+                            // a message that can't be sent is dropped, the transport goes on
+                            if let Err(e) = self.send_to_host(&host, m) {
+                                println!("dropped tcp message: {}", e);
+                            }
+                        } else if let Some(tcp_xport) = self.connections.get_mut(&addr) {
+                            if let Err(e) = tcp_xport.send_message(m) {
+                                println!("dropped tcp message: {}", e);
                             }
                         } else {
                             println!("can't find connection {}", addr);
@@ -153,6 +224,11 @@ impl TcpManager {
                 let mut l = 0;
                 match self.connections.get_mut(&a.to_string()) {
                     Some(t) => {
+                        t.expire_pending(DEFAULT_PENDING_TIMEOUT);
+                        if let Err(s) = t.try_send() {
+                            println!("tcp write failed: {}", s);
+                            return false;
+                        }
                         loop {
                             l += 1;
                             match t.try_receive() {
@@ -186,46 +262,192 @@ pub struct TcpTransport {
     message: [u8; MAX_MESSAGE_SIZE],
     offset: usize,
     message_length: usize,
+    partial_length: Option<u8>,
+    peer_version: WireProtocolVersion,
+    // whether the other end was heard from, until then messages version 1 can't carry
+    // wait in `pending`, fragment by fragment
+    heard: bool,
+    pending: Vec<Vec<Message>>,
+    pending_bytes: usize,
+    pending_since: Option<Instant>,
+    reassembler: Reassembler<SocketAddr>,
+    next_message_id: u64,
+    // frames written as the stream takes them, and how much of the first one it took
+    outgoing: VecDeque<Vec<u8>>,
+    written: usize,
+    queued: usize,
 }
 
 impl TcpTransport {
+    /// Both ends of a connection announce their wire protocol version as it opens,
+    /// and speak version 1 until the other end's announcement arrives
     pub fn new(
         stream: TcpStream,
         router_tx: std::sync::mpsc::Sender<OckamCommand>,
     ) -> Result<TcpTransport, String> {
-        let local_address = Address::TcpAddress(stream.local_addr().unwrap());
-        Ok(TcpTransport {
+        // writes are queued rather than wait for the other end, see `try_send`
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let mut tcp_xport = TcpTransport {
             stream,
             router_tx,
             message: [0u8; MAX_MESSAGE_SIZE],
             offset: 0,
             message_length: 0,
-        })
+            partial_length: None,
+            peer_version: WireProtocolVersion::default(),
+            heard: false,
+            pending: vec![],
+            pending_bytes: 0,
+            pending_since: None,
+            reassembler: Reassembler::default(),
+            next_message_id: initial_message_id(),
+            outgoing: VecDeque::new(),
+            written: 0,
+            queued: 0,
+        };
+        if let Err(e) = tcp_xport.announce(MessageType::Ping) {
+            println!("failed to announce version: {}", e);
+        }
+        Ok(tcp_xport)
+    }
+
+    fn announce(&mut self, message_type: MessageType) -> Result<(), String> {
+        let local_address = Address::TcpAddress(self.stream.local_addr().unwrap());
+        let local_address = RouterAddress::from_address(local_address).unwrap();
+        let mut v = vec![];
+        Message::encode(&version_announcement(message_type, local_address), &mut v)
+            .map_err(|e| e.to_string())?;
+        self.write_messages(vec![v])
+    }
+
+    /// Replace the reassembler with one of other limits
+    pub fn set_reassembler(&mut self, reassembler: Reassembler<SocketAddr>) {
+        self.reassembler = reassembler;
     }
 
+    /// Messages too large for one frame are sent in fragments. They are queued and
+    /// written as the stream takes them, see `try_send`. Messages version 1 can't carry,
+    /// such as fragments, wait for the other end to announce its version
     pub fn send_message(&mut self, mut m: Message) -> Result<(), String> {
         m.onward_route.addresses.remove(0);
         let local_address = Address::TcpAddress(self.stream.local_addr().unwrap());
         m.return_route
             .addresses
             .insert(0, RouterAddress::from_address(local_address).unwrap());
-        let mut v = vec![];
-        Message::encode(&m, &mut v)?;
-
-        // encode the message length and write it as the first byte (or 2)
-        let mut mlen: Vec<u8> = vec![];
-        u16::encode(&(v.len() as u16), &mut mlen);
-        self.stream
-            .write(mlen.as_slice())
-            .expect("tcp write failed");
-        return match self.stream.write(v.as_slice()) {
-            Ok(_) => Ok(()),
-            Err(_) => Err("tcp write failed".into()),
-        };
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        let fragments = fragment(m, message_id).map_err(|e| e.to_string())?;
+        self.send_fragments(fragments)
+    }
+
+    fn send_fragments(&mut self, fragments: Vec<Message>) -> Result<(), String> {
+        let mut encoded = vec![];
+        for f in &fragments {
+            let mut v = vec![];
+            if let Err(e) = f.encode_for_version(&self.peer_version, &mut v) {
+                if !self.heard {
+                    self.hold(fragments);
+                    return Ok(());
+                }
+                return Err(e.to_string());
+            }
+            encoded.push(v);
+        }
+        self.write_messages(encoded)
+    }
+
+    fn hold(&mut self, fragments: Vec<Message>) {
+        let length: usize = fragments.iter().map(|f| f.message_body.len()).sum();
+        if self.pending_bytes + length > MAX_PENDING_BYTES {
+            println!(
+                "dropped tcp message: too many messages wait for the peer to announce a version"
+            );
+            return;
+        }
+        self.pending_bytes += length;
+        self.pending_since.get_or_insert_with(Instant::now);
+        self.pending.push(fragments);
+    }
+
+    /// Send the messages held for the other end once it was heard from
+    fn release(&mut self) {
+        self.heard = true;
+        self.pending_bytes = 0;
+        self.pending_since = None;
+        for fragments in std::mem::take(&mut self.pending) {
+            if let Err(e) = self.send_fragments(fragments) {
+                println!("dropped tcp message: {}", e);
+            }
+        }
+    }
+
+    /// Drop the held messages when the other end didn't announce its version in time
+    pub fn expire_pending(&mut self, timeout: Duration) {
+        if matches!(self.pending_since, Some(since) if since.elapsed() >= timeout) {
+            println!(
+                "dropped {} tcp messages: the peer didn't announce its version",
+                self.pending.len()
+            );
+            self.pending.clear();
+            self.pending_bytes = 0;
+            self.pending_since = None;
+        }
+    }
+
+    /// Queue the frames of a message and write what the stream takes right away.
+    /// A message whose frames don't all fit the queue is dropped
+    fn write_messages(&mut self, messages: Vec<Vec<u8>>) -> Result<(), String> {
+        let mut frames = Vec::with_capacity(messages.len());
+        for v in messages {
+            // encode the message length and write it as the first byte (or 2)
+            let mut frame: Vec<u8> = vec![];
+            u16::encode(&(v.len() as u16), &mut frame).map_err(|e| e.to_string())?;
+            frame.extend(v);
+            frames.push(frame);
+        }
+        let length: usize = frames.iter().map(Vec::len).sum();
+        if self.queued + length > MAX_QUEUED_BYTES {
+            println!("dropped tcp message: the connection's send queue is full");
+            return Ok(());
+        }
+        self.queued += length;
+        self.outgoing.extend(frames);
+        self.try_send()
+    }
+
+    /// Write queued frames until the stream would block
+    pub fn try_send(&mut self) -> Result<(), String> {
+        while let Some(frame) = self.outgoing.front() {
+            match self.stream.write(&frame[self.written..]) {
+                Ok(0) => return Err("tcp write failed".into()),
+                Ok(n) => {
+                    self.written += n;
+                    if self.written == frame.len() {
+                        self.queued -= frame.len();
+                        self.written = 0;
+                        self.outgoing.pop_front();
+                    }
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => {}
+                    _ => return Err("tcp write failed".into()),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes of frames waiting for the stream to take them
+    pub fn queued(&self) -> usize {
+        self.queued
     }
 
     fn set_msg_len(&mut self, varint: &mut Vec<u8>) -> Result<(), String> {
         if let Ok((l, b)) = u16::decode(varint) {
+            if l as usize > MAX_MESSAGE_SIZE {
+                return Err("message length exceeds the maximum message size".into());
+            }
             self.message_length = l as usize;
             varint.remove(0);
             if varint_size(l) == 2 {
@@ -239,25 +461,64 @@ impl TcpTransport {
 
     fn route_message(&mut self) -> Result<(), String> {
         match Message::decode(&self.message[0..self.message_length]) {
+            Ok((m_decoded, _)) if announced_version(&m_decoded).is_some() => {
+                self.peer_version = announced_version(&m_decoded).unwrap().negotiate();
+                if let MessageType::Ping = m_decoded.message_type {
+                    self.announce(MessageType::Pong)?;
+                }
+                self.release();
+                Ok(())
+            }
             Ok((mut m_decoded, _)) => {
+                let received = WireProtocolVersion {
+                    v: self.message[0] as u16,
+                };
+                if self.peer_version.v < received.v {
+                    self.peer_version = received.negotiate();
+                }
+                if !self.heard {
+                    self.release();
+                }
                 // fix up return tcp address with nat-ed address
-                let tcp_return = Address::TcpAddress(self.stream.peer_addr().unwrap());
-                m_decoded.return_route.addresses[0] =
-                    RouterAddress::from_address(tcp_return).unwrap();
+                let tcp_return = Address::TcpAddress(crate::canonical_socket_addr(
+                    self.stream.peer_addr().unwrap(),
+                ));
+                let tcp_return = RouterAddress::from_address(tcp_return).unwrap();
+                match m_decoded.return_route.addresses.get_mut(0) {
+                    Some(ra) => *ra = tcp_return,
+                    None => m_decoded.return_route.addresses.push(tcp_return),
+                }
                 if !m_decoded.onward_route.addresses.is_empty()
                     && ((m_decoded.onward_route.addresses[0].a_type == AddressType::Udp)
                         || (m_decoded.onward_route.addresses[0].a_type == AddressType::Tcp))
                 {
-                    self.send_message(m_decoded)
+                    // a message that can't be forwarded is dropped, the connection goes on
+                    if let Err(e) = self.send_message(m_decoded) {
+                        println!("dropped tcp message: {}", e);
+                    }
+                    Ok(())
                 } else {
-                    self.router_tx
-                        .send(OckamCommand::Router(ReceiveMessage(m_decoded)))
-                        .expect("send to router failed");
+                    let peer = crate::canonical_socket_addr(self.stream.peer_addr().unwrap());
+                    match self.reassembler.receive(peer, m_decoded) {
+                        Ok(Some(m)) => self
+                            .router_tx
+                            .send(OckamCommand::Router(ReceiveMessage(m)))
+                            .expect("send to router failed"),
+                        Ok(None) => {}
+                        Err(e) => println!("{}", e),
+                    }
                     Ok(())
                 }
             }
-            Err(_) => {
-                return Err("message decode failed".into());
+            Err(MessageError::UnsupportedVersion) => {
+                // tell the other end which version to speak instead
+                println!("dropped tcp message: {}", MessageError::UnsupportedVersion);
+                self.announce(MessageType::Ping)
+            }
+            Err(e) => {
+                // the stream is still framed, so only the malformed message is dropped
+                println!("dropped tcp message: {}", e);
+                Ok(())
             }
         }
     }
@@ -275,6 +536,14 @@ impl TcpTransport {
                 while tcp_vec.len() > 0 {
                     // if self.message_length is 0, then decode the next byte(s) as message length
                     if self.message_length == 0 {
+                        if let Some(b) = self.partial_length.take() {
+                            tcp_vec.insert(0, b);
+                        }
+                        if tcp_vec.len() == 1 && (tcp_vec[0] & 0x80) == 0x80 {
+                            // the second byte of the message length comes with the next read
+                            self.partial_length = Some(tcp_vec[0]);
+                            return Ok(false);
+                        }
                         self.set_msg_len(&mut tcp_vec)?;
                     }
 
@@ -308,3 +577,135 @@ impl TcpTransport {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn connect_to_host_name_on_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        let (tx, rx) = channel();
+        let (router_tx, _router_rx) = channel();
+        let mut manager = TcpManager::new(rx, tx.clone(), router_tx, None, None).unwrap();
+        manager.set_resolver(Resolver::with_lookup(
+            Box::new(move |h: &HostName| match h.host.as_str() {
+                "sink.example.internal" => Ok(vec![listen_addr]),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+            }),
+            Duration::from_secs(60),
+        ));
+
+        let sink = format!("sink.example.internal:{}", listen_addr.port());
+        let message = |body: &[u8]| Message {
+            onward_route: Route {
+                addresses: vec![
+                    RouterAddress::tcp_router_address_from_str(&sink).unwrap(),
+                    RouterAddress::worker_router_address_from_str("00010203").unwrap(),
+                ],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: body.to_vec(),
+            extensions: vec![],
+        };
+        for body in &[b"one", b"two"] {
+            tx.send(OckamCommand::Transport(TransportCommand::SendMessage(
+                message(&body[..]),
+            )))
+            .unwrap();
+            assert!(manager.poll());
+        }
+        assert_eq!(manager.connections.len(), 1);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut received = vec![];
+        while received.len() < 2 {
+            let mut buff = [0u8; MAX_MESSAGE_SIZE];
+            let n = stream.read(&mut buff).unwrap();
+            let mut w = &buff[..n];
+            while !w.is_empty() {
+                let (l, rest) = u16::decode(w).unwrap();
+                let (m, _) = Message::decode(&rest[..l as usize]).unwrap();
+                if announced_version(&m).is_none() {
+                    received.push(m.message_body);
+                }
+                w = &rest[l as usize..];
+            }
+        }
+        assert_eq!(received, vec![b"one".to_vec(), b"two".to_vec()]);
+
+        let host = HostName::from_str("gone.example.internal:4000").unwrap();
+        assert!(manager.connect_host(&host).is_err());
+    }
+
+    #[test]
+    fn fragment_large_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let (a_router_tx, a_router) = channel();
+        let (b_router_tx, b_router) = channel();
+        let mut a = TcpTransport::new(client, a_router_tx).unwrap();
+        let mut b = TcpTransport::new(server, b_router_tx).unwrap();
+
+        let message = |to: SocketAddr| Message {
+            onward_route: Route {
+                addresses: vec![
+                    RouterAddress::from_address(Address::TcpAddress(to)).unwrap(),
+                    RouterAddress::worker_router_address_from_str("00010203").unwrap(),
+                ],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect(),
+            extensions: vec![],
+        };
+        // fragments wait for the other end to announce a version that carries them
+        let m = message(b.stream.local_addr().unwrap());
+        a.send_message(m.clone()).unwrap();
+        b.send_message(message(a.stream.local_addr().unwrap()))
+            .unwrap();
+        assert_eq!(a.pending.len(), 1);
+        while a.peer_version != WireProtocolVersion::this_node()
+            || b.peer_version != WireProtocolVersion::this_node()
+        {
+            a.try_receive().unwrap();
+            b.try_receive().unwrap();
+        }
+        assert!(a.pending.is_empty() && b.pending.is_empty());
+
+        // both ends queued more than their socket buffers hold before either reads, on one
+        // thread: sending must not wait for the other end to read
+        assert!(a.queued() > 0);
+
+        let mut received = vec![];
+        let started = std::time::Instant::now();
+        while received.len() < 2 {
+            assert!(started.elapsed() < Duration::from_secs(20));
+            for t in [&mut a, &mut b].iter_mut() {
+                t.try_send().unwrap();
+                while t.try_receive().unwrap() {}
+            }
+            for router in &[&a_router, &b_router] {
+                if let Ok(OckamCommand::Router(RouterCommand::ReceiveMessage(m))) =
+                    router.try_recv()
+                {
+                    received.push(m);
+                }
+            }
+        }
+        assert_eq!(a.queued() + b.queued(), 0);
+        for r in &received {
+            assert_eq!(r.message_body, m.message_body);
+            assert!(r.extensions.is_empty());
+        }
+    }
+}
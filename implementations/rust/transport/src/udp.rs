@@ -1,16 +1,46 @@
+use crate::fragmentation::{fragment, initial_message_id, Reassembler};
+use crate::negotiation::{announced_version, version_announcement, PeerVersions};
+use crate::resolver::Resolver;
 #[allow(unused)]
 use ockam::message::*;
 use ockam::system::commands::RouterCommand::ReceiveMessage;
 use ockam::system::commands::{OckamCommand, RouterCommand, TransportCommand};
+use socket2::Type;
+use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
 use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+/// Size asked for the socket's send and receive buffers, the fragments of a large
+/// message come in a burst. The system may grant less
+pub const UDP_SOCKET_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// How long messages that need a later wire protocol version than 1 wait for
+/// their peer to answer the announcement of this node's version
+pub const DEFAULT_PENDING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most body bytes of messages waiting for their peers to answer, across all peers
+pub const MAX_PENDING_BYTES: usize = 64 * 1024 * 1024;
+
+/// Messages held until their peer answers the announcement of this node's version
+struct Pending {
+    since: Instant,
+    messages: Vec<(Message, SocketAddr, SocketAddr)>,
+}
 
 pub struct UdpTransport {
     socket: UdpSocket,
     rx: std::sync::mpsc::Receiver<OckamCommand>,
     _tx: std::sync::mpsc::Sender<OckamCommand>,
     router_tx: std::sync::mpsc::Sender<OckamCommand>,
+    resolver: Resolver,
+    versions: PeerVersions<SocketAddr>,
+    reassembler: Reassembler<SocketAddr>,
+    next_message_id: u64,
+    pending: HashMap<SocketAddr, Pending>,
+    pending_bytes: usize,
+    pending_timeout: Duration,
 }
 
 impl UdpTransport {
@@ -21,8 +51,11 @@ impl UdpTransport {
         local_udp_socket: SocketAddr,
     ) -> Result<UdpTransport, String> {
         // Try to create socket at given address
-        match UdpSocket::bind(local_udp_socket) {
+        match crate::bind_socket(local_udp_socket, Type::dgram()) {
             Ok(socket) => {
+                let _ = socket.set_recv_buffer_size(UDP_SOCKET_BUFFER_SIZE);
+                let _ = socket.set_send_buffer_size(UDP_SOCKET_BUFFER_SIZE);
+                let socket = socket.into_udp_socket();
                 socket.set_nonblocking(true).unwrap();
                 // Register address type with Router
                 router_tx
@@ -37,6 +70,13 @@ impl UdpTransport {
                     rx,
                     _tx: tx,
                     router_tx,
+                    resolver: Resolver::default(),
+                    versions: PeerVersions::new(),
+                    reassembler: Reassembler::default(),
+                    next_message_id: initial_message_id(),
+                    pending: HashMap::new(),
+                    pending_bytes: 0,
+                    pending_timeout: DEFAULT_PENDING_TIMEOUT,
                 })
             }
             Err(_unused) => {
@@ -46,25 +86,38 @@ impl UdpTransport {
         }
     }
 
+    /// Replace the resolver host name addresses are looked up with
+    pub fn set_resolver(&mut self, resolver: Resolver) {
+        self.resolver = resolver;
+    }
+
+    /// Replace the reassembler with one of other limits
+    pub fn set_reassembler(&mut self, reassembler: Reassembler<SocketAddr>) {
+        self.reassembler = reassembler;
+    }
+
+    /// Messages too large for a datagram are sent in fragments
     pub fn send_message(&mut self, mut m: Message) -> Result<(), String> {
-        let remote_address = m.onward_route.addresses.remove(0);
+        let remote_address = m.onward_route.addresses.remove(0).address;
 
         match self.socket.local_addr() {
             Ok(la) => match RouterAddress::from_address(Address::UdpAddress(la)) {
                 Some(ra) => {
                     m.return_route.addresses.insert(0, ra);
-                    let mut v = vec![];
-                    Message::encode(&m, &mut v)?;
-                    match self
-                        .socket
-                        .send_to(v.as_slice(), remote_address.address.as_string())
-                    {
-                        Ok(_) => Ok(()),
-                        Err(s) => {
-                            println!("send_message failed {}", s.to_string());
-                            Err("send_message error".to_string())
+                    let message_id = self.next_message_id;
+                    self.next_message_id = self.next_message_id.wrapping_add(1);
+                    for f in &fragment(m, message_id).map_err(|e| e.to_string())? {
+                        match &remote_address {
+                            Address::UdpAddress(sa) | Address::TcpAddress(sa) => {
+                                self.send_to(f, la, *sa)?
+                            }
+                            Address::UdpHostAddress(h) | Address::TcpHostAddress(h) => {
+                                self.send_to_host(f, la, h)?
+                            }
+                            _ => return Err("send_message needs a socket address".to_string()),
                         }
                     }
+                    Ok(())
                 }
                 None => Err("send_message error".to_string()),
             },
@@ -72,27 +125,209 @@ impl UdpTransport {
         }
     }
 
+    /// Send `m` in the wire protocol version `remote` speaks, announcing this node's
+    /// version first to peers it wasn't announced to yet. Messages version 1 can't carry,
+    /// such as fragments, wait for the peer's answer
+    fn send_to(
+        &mut self,
+        m: &Message,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Result<(), String> {
+        let peer = crate::canonical_socket_addr(remote);
+        let version = match self.versions.get(&peer) {
+            Some(version) => version,
+            None => {
+                self.announce(MessageType::Ping, peer)?;
+                self.versions.announced_to(peer);
+                WireProtocolVersion::default()
+            }
+        };
+        let mut v = vec![];
+        if let Err(e) = m.encode_for_version(&version, &mut v) {
+            if self.versions.awaiting(&peer) {
+                self.hold(m, local, remote);
+                return Ok(());
+            }
+            return Err(format!("can't send to {}: {}", remote, e));
+        }
+        self.send_bytes(v.as_slice(), local, remote)
+    }
+
+    fn hold(&mut self, m: &Message, local: SocketAddr, remote: SocketAddr) {
+        if self.pending_bytes + m.message_body.len() > MAX_PENDING_BYTES {
+            println!(
+                "dropped udp message to {}: too many messages wait for their peers to announce a version",
+                remote
+            );
+            return;
+        }
+        self.pending_bytes += m.message_body.len();
+        self.pending
+            .entry(crate::canonical_socket_addr(remote))
+            .or_insert_with(|| Pending {
+                since: Instant::now(),
+                messages: vec![],
+            })
+            .messages
+            .push((m.clone(), local, remote));
+    }
+
+    /// Send the messages held for `peer` once it was heard from
+    fn release(&mut self, peer: SocketAddr) {
+        if self.versions.awaiting(&peer) {
+            return;
+        }
+        if let Some(pending) = self.pending.remove(&peer) {
+            for (m, local, remote) in pending.messages {
+                self.pending_bytes -= m.message_body.len();
+                if let Err(e) = self.send_to(&m, local, remote) {
+                    println!("dropped udp message: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Drop the messages held for peers that didn't answer in time
+    fn expire_pending(&mut self) {
+        let timeout = self.pending_timeout;
+        let mut expired = 0;
+        self.pending.retain(|peer, pending| {
+            let keep = pending.since.elapsed() < timeout;
+            if !keep {
+                println!(
+                    "dropped {} udp messages: {} didn't announce its version",
+                    pending.messages.len(),
+                    peer
+                );
+                expired += pending
+                    .messages
+                    .iter()
+                    .map(|(m, _, _)| m.message_body.len())
+                    .sum::<usize>();
+            }
+            keep
+        });
+        self.pending_bytes -= expired;
+    }
+
+    fn announce(&self, message_type: MessageType, remote: SocketAddr) -> Result<(), String> {
+        let local = self.socket.local_addr().map_err(|e| e.to_string())?;
+        let local_address = RouterAddress::from_address(Address::UdpAddress(local)).unwrap();
+        let mut v = vec![];
+        Message::encode(&version_announcement(message_type, local_address), &mut v)
+            .map_err(|e| e.to_string())?;
+        self.send_bytes(v.as_slice(), local, remote)
+    }
+
+    fn send_bytes(&self, v: &[u8], local: SocketAddr, remote: SocketAddr) -> Result<(), String> {
+        match self
+            .socket
+            .send_to(v, crate::destination_socket_addr(local, remote))
+        {
+            Ok(_) => Ok(()),
+            Err(s) => {
+                println!("send_message failed {}", s);
+                Err("send_message error".to_string())
+            }
+        }
+    }
+
+    /// Send to the address the host name resolves to, looking it up again once if
+    /// the cached address can't be sent to
+    fn send_to_host(
+        &mut self,
+        m: &Message,
+        local: SocketAddr,
+        host: &HostName,
+    ) -> Result<(), String> {
+        let mut sent = Err(format!("no address of {} is reachable over udp", host));
+        for _ in 0..2 {
+            let remote = self
+                .resolver
+                .resolve(host)?
+                .into_iter()
+                .find(|a| local.is_ipv6() || a.is_ipv4());
+            sent = match remote {
+                Some(remote) => self.send_to(m, local, remote),
+                None => sent,
+            };
+            if sent.is_ok() {
+                break;
+            }
+            self.resolver.invalidate(host);
+        }
+        sent
+    }
+
     pub fn receive_message(&mut self) -> Result<bool, String> {
         let mut buff = [0; MAX_MESSAGE_SIZE];
         match self.socket.recv_from(&mut buff) {
-            Ok((s, _)) => match Message::decode(&buff[0..s]) {
-                Ok((m, _unused)) => {
+            Ok((s, peer)) => match Message::decode(&buff[0..s]) {
+                Ok((mut m, _unused)) => {
+                    let peer = crate::canonical_socket_addr(peer);
+                    if let Some(version) = announced_version(&m) {
+                        self.versions.announced_by(peer, version);
+                        if matches!(m.message_type, MessageType::Ping)
+                            && self.versions.may_answer(&peer)
+                        {
+                            if let Err(e) = self.announce(MessageType::Pong, peer) {
+                                println!("failed to answer version announcement: {}", e);
+                            }
+                        }
+                        self.release(peer);
+                        return Ok(true);
+                    }
+                    self.versions.received(&peer, buff[0]);
+                    self.release(peer);
+
+                    // fix up return udp address with the address the message came from,
+                    // the sender only knows the address its socket is bound to
+                    if let Some(ra) = m.return_route.addresses.get_mut(0) {
+                        if ra.a_type == AddressType::Udp {
+                            let peer = Address::UdpAddress(peer);
+                            *ra = RouterAddress::from_address(peer).unwrap();
+                        }
+                    }
                     if !m.onward_route.addresses.is_empty()
                         && ((m.onward_route.addresses[0].a_type == AddressType::Udp)
                             || (m.onward_route.addresses[0].a_type == AddressType::Tcp))
                     {
-                        match self.send_message(m) {
-                            Err(s) => Err(s),
-                            Ok(()) => Ok(true),
+                        // a message that can't be forwarded is dropped, the transport goes on
+                        if let Err(e) = self.send_message(m) {
+                            println!("dropped udp message: {}", e);
                         }
+                        Ok(true)
                     } else {
+                        // fragments are forwarded as they are, and put back together
+                        // by the node they are for
+                        let m = match self.reassembler.receive(peer, m) {
+                            Ok(Some(m)) => m,
+                            Ok(None) => return Ok(true),
+                            Err(e) => {
+                                println!("{}", e);
+                                return Ok(true);
+                            }
+                        };
                         match self.router_tx.send(OckamCommand::Router(ReceiveMessage(m))) {
                             Ok(_unused) => Ok(true),
                             Err(_) => Err("send to router failed".to_string()),
                         }
                     }
                 }
-                _ => Err("decode failed".to_string()),
+                Err(e) => {
+                    println!("dropped udp message: {}", e);
+                    let peer = crate::canonical_socket_addr(peer);
+                    if let MessageError::UnsupportedVersion = e {
+                        // tell the sender which version to speak instead
+                        if self.versions.may_answer(&peer) {
+                            if let Err(e) = self.announce(MessageType::Ping, peer) {
+                                println!("failed to announce version: {}", e);
+                            }
+                        }
+                    }
+                    Ok(true)
+                }
             },
             Err(e) => match e.kind() {
                 io::ErrorKind::WouldBlock => Ok(false),
@@ -105,6 +340,7 @@ impl UdpTransport {
         let mut got: bool = true;
         let mut keep_going = true;
 
+        self.expire_pending();
         while got && keep_going {
             match self.receive_message() {
                 Ok(b) => {
@@ -122,9 +358,9 @@ impl UdpTransport {
             if let Ok(tc) = self.rx.try_recv() {
                 match tc {
                     OckamCommand::Transport(TransportCommand::SendMessage(m)) => {
+                        // a message that can't be sent is dropped, the transport goes on
                         if let Err(s) = self.send_message(m) {
-                            println!("udp send_message failed: {}", s);
-                            return false;
+                            println!("dropped udp message: {}", s);
                         }
                     }
                     OckamCommand::Transport(TransportCommand::Stop) => {
@@ -140,3 +376,275 @@ impl UdpTransport {
         keep_going
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn send_to_host_name() {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        let (tx, rx) = channel();
+        let (router_tx, _router_rx) = channel();
+        let mut transport = UdpTransport::new(
+            rx,
+            tx,
+            router_tx,
+            SocketAddr::from_str("127.0.0.1:0").unwrap(),
+        )
+        .unwrap();
+        transport.set_resolver(Resolver::with_lookup(
+            Box::new(move |h: &HostName| match h.host.as_str() {
+                "sink.example.internal" => Ok(vec![
+                    SocketAddr::from_str("[::1]:1").unwrap(),
+                    SocketAddr::new(peer_addr.ip(), h.port),
+                ]),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+            }),
+            Duration::from_secs(60),
+        ));
+
+        let mut onward_route = Route { addresses: vec![] };
+        let sink = format!("sink.example.internal:{}", peer_addr.port());
+        onward_route
+            .addresses
+            .push(RouterAddress::udp_router_address_from_str(&sink).unwrap());
+        onward_route
+            .addresses
+            .push(RouterAddress::worker_router_address_from_str("00010203").unwrap());
+        let m = Message {
+            onward_route,
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: b"hello".to_vec(),
+            extensions: vec![],
+        };
+        transport.send_message(m.clone()).unwrap();
+
+        // the peer was never announced to, so it gets an announcement first
+        let mut buff = [0; MAX_MESSAGE_SIZE];
+        let (n, _) = peer.recv_from(&mut buff).unwrap();
+        let (announcement, _) = Message::decode(&buff[..n]).unwrap();
+        assert!(announced_version(&announcement).is_some());
+        let (n, _) = peer.recv_from(&mut buff).unwrap();
+        let (received, _) = Message::decode(&buff[..n]).unwrap();
+        assert_eq!(received.message_body, b"hello".to_vec());
+        assert_eq!(received.onward_route.addresses.len(), 1);
+
+        let mut m = m;
+        m.onward_route.addresses[0] =
+            RouterAddress::udp_router_address_from_str("gone.example.internal:4000").unwrap();
+        assert!(transport.send_message(m).is_err());
+    }
+
+    fn transport() -> (UdpTransport, Receiver<OckamCommand>, SocketAddr) {
+        let (tx, rx) = channel();
+        let (router_tx, router_rx) = channel();
+        let t = UdpTransport::new(
+            rx,
+            tx,
+            router_tx,
+            SocketAddr::from_str("127.0.0.1:0").unwrap(),
+        )
+        .unwrap();
+        let _ = router_rx.recv().unwrap(); // register
+        let address = t.socket.local_addr().unwrap();
+        (t, router_rx, address)
+    }
+
+    fn receive_all(t: &mut UdpTransport) {
+        std::thread::sleep(Duration::from_millis(50));
+        while t.receive_message().unwrap() {}
+    }
+
+    fn traced(to: SocketAddr, e_type: u8) -> Message {
+        let mut m = Message {
+            onward_route: Route {
+                addresses: vec![
+                    RouterAddress::from_address(Address::UdpAddress(to)).unwrap(),
+                    RouterAddress::worker_router_address_from_str("00010203").unwrap(),
+                ],
+            },
+            return_route: Route { addresses: vec![] },
+            message_type: MessageType::Payload,
+            message_body: b"hello".to_vec(),
+            extensions: vec![],
+        };
+        m.set_extension(e_type, vec![7; 8]);
+        m
+    }
+
+    fn received(router_rx: &Receiver<OckamCommand>) -> Message {
+        match router_rx.try_recv() {
+            Ok(OckamCommand::Router(RouterCommand::ReceiveMessage(m))) => m,
+            _ => panic!("expected a message for the router"),
+        }
+    }
+
+    #[test]
+    fn upgrade_after_announcement() {
+        let (mut a, _a_router, a_address) = transport();
+        let (mut b, b_router, b_address) = transport();
+        let trace_id = ExtensionType::TraceId as u8;
+
+        // until b answers, a speaks version 1 and drops the extension it can't carry, and
+        // holds the message whose extension version 1 must not drop
+        let compression = ExtensionType::Compression as u8;
+        a.send_message(traced(b_address, trace_id)).unwrap();
+        a.send_message(traced(b_address, compression)).unwrap();
+        assert_eq!(a.pending.len(), 1);
+        receive_all(&mut b);
+        assert!(received(&b_router).extensions.is_empty());
+        assert!(b_router.try_recv().is_err());
+        assert_eq!(
+            b.versions.get(&a_address),
+            Some(WireProtocolVersion::this_node())
+        );
+
+        receive_all(&mut a);
+        assert_eq!(
+            a.versions.get(&b_address),
+            Some(WireProtocolVersion::this_node())
+        );
+        assert!(a.pending.is_empty());
+        receive_all(&mut b);
+        assert_eq!(
+            received(&b_router).extension(compression),
+            Some(&[7u8; 8][..])
+        );
+        a.send_message(traced(b_address, trace_id)).unwrap();
+        receive_all(&mut b);
+        assert_eq!(received(&b_router).extension(trace_id), Some(&[7u8; 8][..]));
+    }
+
+    #[test]
+    fn forward_what_the_next_hop_cant_take() {
+        let (mut relay, _relay_router, relay_address) = transport();
+        let next_hop = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let next_hop_address = next_hop.local_addr().unwrap();
+        relay
+            .versions
+            .announced_by(next_hop_address, WireProtocolVersion::default());
+
+        // the next hop speaks version 1, which must not drop the compression extension
+        let mut m = traced(next_hop_address, ExtensionType::Compression as u8);
+        m.onward_route.addresses.insert(
+            0,
+            RouterAddress::from_address(Address::UdpAddress(relay_address)).unwrap(),
+        );
+        let mut v = vec![];
+        Message::encode(&m, &mut v).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(&v, relay_address).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(relay.receive_message().unwrap());
+        assert!(relay.poll());
+    }
+
+    #[test]
+    fn keep_polling_after_failed_sends() {
+        let (mut a, _a_router, _) = transport();
+        let old = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let old_address = old.local_addr().unwrap();
+        a.versions
+            .announced_by(old_address, WireProtocolVersion::default());
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        // a peer that answered version 1 can't take fragments, and a message that
+        // doesn't fit with those already waiting for their peers is dropped
+        let mut large = traced(old_address, ExtensionType::TraceId as u8);
+        large.message_body = vec![0u8; 64 * 1024];
+        a.pending_bytes = MAX_PENDING_BYTES;
+        let waiting = traced(
+            silent.local_addr().unwrap(),
+            ExtensionType::Compression as u8,
+        );
+        for m in vec![large, waiting] {
+            a._tx
+                .send(OckamCommand::Transport(TransportCommand::SendMessage(m)))
+                .unwrap();
+        }
+        assert!(a.poll());
+        assert!(a.pending.is_empty());
+    }
+
+    #[test]
+    fn announce_to_newer_versions() {
+        let (mut b, b_router, b_address) = transport();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // a message from a later version is dropped and answered with an announcement
+        peer.send_to(&[WIRE_PROTOCOL_VERSION + 1, 0, 0, 0], b_address)
+            .unwrap();
+        receive_all(&mut b);
+        assert!(b_router.try_recv().is_err());
+        let mut buff = [0; MAX_MESSAGE_SIZE];
+        let (n, _) = peer.recv_from(&mut buff).unwrap();
+        let (announcement, _) = Message::decode(&buff[..n]).unwrap();
+        assert!(matches!(announcement.message_type, MessageType::Ping));
+        assert_eq!(
+            announced_version(&announcement),
+            Some(WireProtocolVersion::this_node())
+        );
+    }
+
+    #[test]
+    fn fragment_large_messages() {
+        let (mut a, _a_router, _) = transport();
+        let (mut b, b_router, b_address) = transport();
+        let mut m = traced(b_address, ExtensionType::TraceId as u8);
+        m.message_body = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+
+        // fragments wait for the peer to announce a version that carries them
+        a.send_message(m.clone()).unwrap();
+        assert_eq!(a.pending.len(), 1);
+        receive_all(&mut b);
+        assert!(b_router.try_recv().is_err());
+
+        let done = Arc::new(AtomicBool::new(false));
+        let receiving = done.clone();
+        let receiver = std::thread::spawn(move || {
+            while !receiving.load(Ordering::SeqCst) {
+                if !b.receive_message().unwrap() {
+                    std::thread::yield_now();
+                }
+            }
+            b
+        });
+        receive_all(&mut a);
+        assert!(a.pending.is_empty());
+        assert_eq!(a.pending_bytes, 0);
+        let received = match b_router.recv_timeout(Duration::from_secs(10)) {
+            Ok(OckamCommand::Router(RouterCommand::ReceiveMessage(m))) => m,
+            _ => panic!("expected the reassembled message"),
+        };
+        done.store(true, Ordering::SeqCst);
+        let b = receiver.join().unwrap();
+
+        assert_eq!(received.message_body, m.message_body);
+        assert_eq!(received.extensions, m.extensions);
+        assert_eq!(b.reassembler.pending(), 0);
+
+        // held messages are dropped when the peer doesn't answer in time
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.pending_timeout = Duration::from_millis(0);
+        a.send_message(traced(
+            silent.local_addr().unwrap(),
+            ExtensionType::Compression as u8,
+        ))
+        .unwrap();
+        assert_eq!(a.pending.len(), 1);
+        assert!(a.poll());
+        assert!(a.pending.is_empty());
+        assert_eq!(a.pending_bytes, 0);
+    }
+}
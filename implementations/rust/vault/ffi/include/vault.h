@@ -29,6 +29,9 @@ typedef enum {
     OCKAM_VAULT_SECRET_TYPE_AES_KEY,
     OCKAM_VAULT_SECRET_TYPE_CURVE25519_PRIVATEKEY,
     OCKAM_VAULT_SECRET_TYPE_P256_PRIVATEKEY,
+    OCKAM_VAULT_SECRET_TYPE_ED25519_PRIVATEKEY,
+    OCKAM_VAULT_SECRET_TYPE_CHACHA20POLY1305_KEY,
+    OCKAM_VAULT_SECRET_TYPE_MLKEM768_PRIVATEKEY,
 } ockam_vault_secret_type_t;
 
 /**
@@ -65,6 +68,18 @@ void ockam_vault_default_init(ockam_vault_t* vault, ockam_vault_extern_error_t*
  */
 void ockam_vault_file_init(ockam_vault_t* vault, const unsigned char* const path, ockam_vault_extern_error_t* error);
 
+/**
+ * @brief   Generate a random number of desired size.
+ * @param   vault[in]       Vault object to use for random number generation.
+ * @param   buffer[out]     Buffer to fill with random bytes.
+ * @param   buffer_size[in] Number of random bytes to place in the buffer.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+void ockam_vault_random_bytes_generate(ockam_vault_t vault,
+                                       uint8_t*      buffer,
+                                       size_t        buffer_size,
+                                       ockam_vault_extern_error_t* error);
+
 /**
  * @brief   Compute a SHA-256 hash based on input data.
  * @param   vault[in]           Vault object to use for SHA-256.
@@ -79,6 +94,36 @@ void ockam_vault_sha256(ockam_vault_t  vault,
                         uint8_t*       digest,
                         ockam_vault_extern_error_t* error);
 
+/**
+ * @brief   Compute a SHA-512 hash based on input data.
+ * @param   vault[in]           Vault object to use for SHA-512.
+ * @param   input[in]           Buffer containing data to run through SHA-512.
+ * @param   input_length[in]    Length of the data to run through SHA-512.
+ * @param   digest[out]         Buffer to place the resulting SHA-512 hash in. Must be 64 bytes.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+void ockam_vault_sha512(ockam_vault_t  vault,
+                        const uint8_t* input,
+                        size_t         input_length,
+                        uint8_t*       digest,
+                        ockam_vault_extern_error_t* error);
+
+/**
+ * @brief   Compute an HMAC-SHA256 over input data.
+ * @param   vault[in]           Vault object to use for HMAC-SHA256.
+ * @param   secret[in]          Ockam vault buffer secret to use as the HMAC key.
+ * @param   input[in]           Buffer containing data to authenticate.
+ * @param   input_length[in]    Length of the data to authenticate.
+ * @param   mac[out]            Buffer to place the resulting MAC in. Must be 32 bytes.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+void ockam_vault_hmac_sha256(ockam_vault_t        vault,
+                             ockam_vault_secret_t secret,
+                             const uint8_t*       input,
+                             size_t               input_length,
+                             uint8_t*             mac,
+                             ockam_vault_extern_error_t* error);
+
 /**
  * @brief   Generate an ockam secret. Attributes struct must specify the configuration for the type of secret to
  *          generate. For EC keys and AES keys, length is ignored.
@@ -123,6 +168,50 @@ void ockam_vault_secret_export(ockam_vault_t        vault,
                                size_t*              output_buffer_length,
                                ockam_vault_extern_error_t* error);
 
+/**
+ * @brief   Export an ockam vault secret encrypted for another vault and signed by this one.
+ * @param   vault[in]                       Vault object to use for exporting the secret.
+ * @param   secret[in]                      Ockam vault secret to export.
+ * @param   recipient_public_key[in]        Curve25519 or P256 public key of the recipient vault.
+ * @param   recipient_public_key_length[in] Length of the recipient public key.
+ * @param   signing_key[in]                 Ockam vault secret used to sign the wrapped secret.
+ * @param   output_buffer[out]              Buffer to place the wrapped secret in.
+ * @param   output_buffer_size[in]          Size of the output buffer.
+ * @param   output_buffer_length[out]       Amount of data placed in the output buffer.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+void ockam_vault_secret_export_wrapped(ockam_vault_t        vault,
+                                       ockam_vault_secret_t secret,
+                                       const uint8_t*       recipient_public_key,
+                                       size_t               recipient_public_key_length,
+                                       ockam_vault_secret_t signing_key,
+                                       uint8_t*             output_buffer,
+                                       size_t               output_buffer_size,
+                                       size_t*              output_buffer_length,
+                                       ockam_vault_extern_error_t* error);
+
+/**
+ * @brief   Import a secret exported with ockam_vault_secret_export_wrapped.
+ * @param   vault[in]                    Vault object to use for importing the secret.
+ * @param   secret[out]                  Pointer to an ockam vault secret object to be populated.
+ * @param   recipient_secret[in]         Ockam vault secret the secret was wrapped for.
+ * @param   input[in]                    Buffer containing the wrapped secret.
+ * @param   input_length[in]             Length of the wrapped secret.
+ * @param   sender_public_key[in]        Public key of the key that signed the wrapped secret.
+ * @param   sender_public_key_length[in] Length of the sender public key.
+ * @param   sender_public_key_type[in]   Type of the sender public key.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+void ockam_vault_secret_import_wrapped(ockam_vault_t             vault,
+                                       ockam_vault_secret_t*     secret,
+                                       ockam_vault_secret_t      recipient_secret,
+                                       const uint8_t*            input,
+                                       size_t                    input_length,
+                                       const uint8_t*            sender_public_key,
+                                       size_t                    sender_public_key_length,
+                                       ockam_vault_secret_type_t sender_public_key_type,
+                                       ockam_vault_extern_error_t* error);
+
 /**
  * @brief   Retrieve the public key from an ockam vault secret.
  * @param   vault[in]                 Vault object to use for exporting the public key
@@ -192,6 +281,23 @@ void ockam_vault_hkdf_sha256(ockam_vault_t                          vault,
                              ockam_vault_secret_t*                  derived_outputs,
                              ockam_vault_extern_error_t* error);
 
+/**
+ * @brief   Perform an HMAC-SHA512 based key derivation function on the supplied salt and input key material.
+ * @param   vault[in]                      Vault object to use for encryption.
+ * @param   salt[in]                       Ockam vault secret containing the salt for HKDF.
+ * @param   input_key_material[in]         Ockam vault secret containing input key material to use for HKDF.
+ * @param   derived_outputs_attributes[in] Attibutes of output secrets.
+ * @param   derived_outputs[out]           Array of ockam vault secrets resulting from HKDF.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+void ockam_vault_hkdf_sha512(ockam_vault_t                          vault,
+                             ockam_vault_secret_t                   salt,
+                             const ockam_vault_secret_t*            input_key_material,
+                             const ockam_vault_secret_attributes_t* derived_outputs_attributes,
+                             uint8_t                                derived_outputs_count,
+                             ockam_vault_secret_t*                  derived_outputs,
+                             ockam_vault_extern_error_t* error);
+
 /**
  * @brief   Encrypt a payload using AES-GCM.
  * @param   vault[in]                       Vault object to use for encryption.
@@ -244,6 +350,60 @@ void ockam_vault_aead_aes_gcm_decrypt(ockam_vault_t       vault,
                                       size_t*              plaintext_length,
                                       ockam_vault_extern_error_t* error);
 
+/**
+ * @brief   Encrypt a payload using ChaCha20-Poly1305.
+ * @param   vault[in]                       Vault object to use for encryption.
+ * @param   key[in]                         Ockam secret key to use for encryption.
+ * @param   nonce[in]                       Nonce value to use for encryption, encoded as a little-endian
+ *                                          64-bit counter following four zero bytes.
+ * @param   additional_data[in]             Additional data to use for encryption.
+ * @param   additional_data_length[in]      Length of the additional data.
+ * @param   plaintext[in]                   Buffer containing plaintext data to encrypt.
+ * @param   plaintext_length[in]            Length of plaintext data to encrypt.
+ * @param   ciphertext_and_tag[in]          Buffer containing the generated ciphertext and tag data.
+ * @param   ciphertext_and_tag_size[in]     Size of the ciphertext + tag buffer. Must be plaintext_size + 16.
+ * @param   ciphertext_and_tag_length[out]  Amount of data placed in the ciphertext + tag buffer.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+void ockam_vault_aead_chacha20_poly1305_encrypt(ockam_vault_t        vault,
+                                                ockam_vault_secret_t key,
+                                                uint16_t             nonce,
+                                                const uint8_t*       additional_data,
+                                                size_t               additional_data_length,
+                                                const uint8_t*       plaintext,
+                                                size_t               plaintext_length,
+                                                uint8_t*             ciphertext_and_tag,
+                                                size_t               ciphertext_and_tag_size,
+                                                size_t*              ciphertext_and_tag_length,
+                                                ockam_vault_extern_error_t* error);
+
+/**
+ * @brief   Decrypt a payload using ChaCha20-Poly1305.
+ * @param   vault[in]                     Vault object to use for decryption.
+ * @param   key[in]                       Ockam secret key to use for decryption.
+ * @param   nonce[in]                     Nonce value to use for decryption, encoded as a little-endian
+ *                                        64-bit counter following four zero bytes.
+ * @param   additional_data[in]           Additional data to use for decryption.
+ * @param   additional_data_length[in]    Length of the additional data.
+ * @param   ciphertext_and_tag[in]        The ciphertext + tag data to decrypt.
+ * @param   ciphertext_and_tag_length[in] Length of the ciphertext + tag data to decrypt.
+ * @param   plaintext[out]                Buffer to place the decrypted data in.
+ * @param   plaintext_size[in]            Size of the plaintext buffer. Must be ciphertext_tag_size - 16.
+ * @param   plaintext_length[out]         Amount of data placed in the plaintext buffer.
+ * @return  OCKAM_ERROR_NONE on success.
+ */
+void ockam_vault_aead_chacha20_poly1305_decrypt(ockam_vault_t       vault,
+                                                ockam_vault_secret_t key,
+                                                uint16_t             nonce,
+                                                const uint8_t*       additional_data,
+                                                size_t               additional_data_length,
+                                                const uint8_t*       ciphertext_and_tag,
+                                                size_t               ciphertext_and_tag_length,
+                                                uint8_t*             plaintext,
+                                                size_t               plaintext_size,
+                                                size_t*              plaintext_length,
+                                                ockam_vault_extern_error_t* error);
+
 void ockam_vault_get_persistence_id(ockam_vault_t       vault,
                                     ockam_vault_secret_t key,
                                     char*                persistence_id,
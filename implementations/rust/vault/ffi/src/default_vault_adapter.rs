@@ -1,9 +1,13 @@
 use crate::error::Error;
 use ockam_common::error::OckamResult;
-use ockam_vault_software::ockam_vault::types::{PublicKey, SecretAttributes, SecretKey};
+use ockam_vault_software::ockam_vault::types::{
+    PersistentSecretInfo, PublicKey, SecretAttributes, SecretKey, SecretMetadata, SecretType,
+    Signature,
+};
 use ockam_vault_software::ockam_vault::zeroize::Zeroize;
 use ockam_vault_software::ockam_vault::{
-    AsymmetricVault, HashVault, PersistentVault, Secret, SecretVault, SymmetricVault,
+    AsymmetricVault, HashVault, KemVault, PersistentVault, RandomVault, Secret, SecretVault,
+    SignerVault, SymmetricVault, VerifierVault, WrappingVault,
 };
 use ockam_vault_software::DefaultVault;
 
@@ -68,6 +72,30 @@ impl HashVault for DefaultVaultAdapter {
     ) -> OckamResult<Vec<Box<dyn Secret>>> {
         self.0.hkdf_sha256(salt, info, ikm, output_attributes)
     }
+
+    fn sha512(&self, data: &[u8]) -> OckamResult<[u8; 64]> {
+        self.0.sha512(data)
+    }
+
+    fn hkdf_sha512(
+        &mut self,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        self.0.hkdf_sha512(salt, info, ikm, output_attributes)
+    }
+
+    fn hmac_sha256(&mut self, key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<[u8; 32]> {
+        self.0.hmac_sha256(key, data)
+    }
+}
+
+impl RandomVault for DefaultVaultAdapter {
+    fn random_bytes_generate(&mut self, buffer: &mut [u8]) -> OckamResult<()> {
+        self.0.random_bytes_generate(buffer)
+    }
 }
 
 impl SymmetricVault for DefaultVaultAdapter {
@@ -91,6 +119,28 @@ impl SymmetricVault for DefaultVaultAdapter {
         self.0
             .aead_aes_gcm_decrypt(context, cipher_text, nonce, aad)
     }
+
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        self.0
+            .aead_chacha20_poly1305_encrypt(context, plaintext, nonce, aad)
+    }
+
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        self.0
+            .aead_chacha20_poly1305_decrypt(context, cipher_text, nonce, aad)
+    }
 }
 
 impl AsymmetricVault for DefaultVaultAdapter {
@@ -103,6 +153,40 @@ impl AsymmetricVault for DefaultVaultAdapter {
     }
 }
 
+impl KemVault for DefaultVaultAdapter {
+    fn kem_encapsulate(&mut self, public_key: &[u8]) -> OckamResult<(Vec<u8>, Box<dyn Secret>)> {
+        self.0.kem_encapsulate(public_key)
+    }
+
+    fn kem_decapsulate(
+        &mut self,
+        context: &Box<dyn Secret>,
+        ciphertext: &[u8],
+    ) -> OckamResult<Box<dyn Secret>> {
+        self.0.kem_decapsulate(context, ciphertext)
+    }
+}
+
+impl SignerVault for DefaultVaultAdapter {
+    fn sign(&mut self, secret_key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<Signature> {
+        self.0.sign(secret_key, data)
+    }
+}
+
+impl VerifierVault for DefaultVaultAdapter {
+    fn verify(
+        &mut self,
+        signature: &[u8],
+        public_key: &[u8],
+        public_key_type: SecretType,
+        data: &[u8],
+    ) -> OckamResult<()> {
+        self.0.verify(signature, public_key, public_key_type, data)
+    }
+}
+
+impl WrappingVault for DefaultVaultAdapter {}
+
 impl PersistentVault for DefaultVaultAdapter {
     fn get_persistence_id(&self, _secret: &Box<dyn Secret>) -> OckamResult<String> {
         Err(Error::VaultDoesntSupportPersistence.into())
@@ -111,4 +195,31 @@ impl PersistentVault for DefaultVaultAdapter {
     fn get_persistent_secret(&self, _persistence_id: &str) -> OckamResult<Box<dyn Secret>> {
         Err(Error::VaultDoesntSupportPersistence.into())
     }
+
+    fn get_persistent_secret_by_label(&self, _label: &str) -> OckamResult<Box<dyn Secret>> {
+        Err(Error::VaultDoesntSupportPersistence.into())
+    }
+
+    fn get_secret_metadata(&self, _secret: &Box<dyn Secret>) -> OckamResult<SecretMetadata> {
+        Err(Error::VaultDoesntSupportPersistence.into())
+    }
+
+    fn set_secret_metadata(
+        &mut self,
+        _secret: &Box<dyn Secret>,
+        _metadata: SecretMetadata,
+    ) -> OckamResult<()> {
+        Err(Error::VaultDoesntSupportPersistence.into())
+    }
+
+    fn set_secrets_metadata(
+        &mut self,
+        _updates: Vec<(&Box<dyn Secret>, SecretMetadata)>,
+    ) -> OckamResult<()> {
+        Err(Error::VaultDoesntSupportPersistence.into())
+    }
+
+    fn list_persistent_secrets(&mut self) -> OckamResult<Vec<PersistentSecretInfo>> {
+        Err(Error::VaultDoesntSupportPersistence.into())
+    }
 }
@@ -6,7 +6,8 @@ use ockam_common::error::OckamResult;
 use ockam_vault_file::FilesystemVault;
 use ockam_vault_software::ockam_vault::types::{PublicKey, SecretAttributes, SecretType};
 use ockam_vault_software::ockam_vault::{
-    AsymmetricVault, HashVault, PersistentVault, Secret, SecretVault, SymmetricVault,
+    AsymmetricVault, HashVault, PersistentVault, RandomVault, Secret, SecretVault, SymmetricVault,
+    WrappingVault,
 };
 use ockam_vault_software::DefaultVault;
 use std::collections::BTreeMap;
@@ -27,10 +28,25 @@ mod default_vault_adapter;
 pub mod error;
 mod types;
 
-trait FfiVault: SecretVault + HashVault + SymmetricVault + AsymmetricVault + PersistentVault {}
+trait FfiVault:
+    SecretVault
+    + HashVault
+    + SymmetricVault
+    + AsymmetricVault
+    + PersistentVault
+    + RandomVault
+    + WrappingVault
+{
+}
 
 impl<D> FfiVault for D where
-    D: SecretVault + HashVault + SymmetricVault + AsymmetricVault + PersistentVault
+    D: SecretVault
+        + HashVault
+        + SymmetricVault
+        + AsymmetricVault
+        + PersistentVault
+        + RandomVault
+        + WrappingVault
 {
 }
 
@@ -116,6 +132,76 @@ pub extern "C" fn ockam_vault_sha256(
     });
 }
 
+/// Compute the SHA-512 hash on `input` and put the result in `digest`.
+/// `digest` must be 64 bytes in length
+#[no_mangle]
+pub extern "C" fn ockam_vault_sha512(
+    context: u64,
+    input: *const u8,
+    input_length: u32,
+    digest: *mut u8,
+    error: &mut ExternError,
+) {
+    check_buffer!(input, error);
+    check_buffer!(digest, error);
+
+    let input = unsafe { std::slice::from_raw_parts(input, input_length as usize) };
+
+    VAULTS.call_with_result(error, context, |v| -> Result<(), ExternError> {
+        let d = v.vault.sha512(input)?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(d.as_ptr(), digest, d.len());
+        }
+
+        Ok(())
+    });
+}
+
+/// Compute the HMAC-SHA256 of `input` keyed with the buffer `secret`
+/// and put the result in `mac`. `mac` must be 32 bytes in length
+#[no_mangle]
+pub extern "C" fn ockam_vault_hmac_sha256(
+    context: u64,
+    secret: SecretKeyHandle,
+    input: *const u8,
+    input_length: u32,
+    mac: *mut u8,
+    error: &mut ExternError,
+) {
+    check_buffer!(input, error);
+    check_buffer!(mac, error);
+
+    let input = unsafe { std::slice::from_raw_parts(input, input_length as usize) };
+    let mac = AssertUnwindSafe(mac);
+
+    VAULTS.call_with_result_mut(error, context, move |v| -> Result<(), ExternError> {
+        let ctx = BoxVault::get_secret(&v.map, secret)?;
+        let m = v.vault.hmac_sha256(&ctx, input)?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(m.as_ptr(), *mac, m.len());
+        }
+
+        Ok(())
+    });
+}
+
+/// Fill `buffer` with `buffer_size` random bytes
+#[no_mangle]
+pub extern "C" fn ockam_vault_random_bytes_generate(
+    context: u64,
+    buffer: *mut u8,
+    buffer_size: u32,
+    error: &mut ExternError,
+) {
+    check_buffer!(buffer, buffer_size, error);
+    let buffer = AssertUnwindSafe(buffer);
+    VAULTS.call_with_result_mut(error, context, move |v| -> Result<(), ExternError> {
+        let buffer = unsafe { std::slice::from_raw_parts_mut(*buffer, buffer_size as usize) };
+        v.vault.random_bytes_generate(buffer)?;
+        Ok(())
+    });
+}
+
 /// Generate a secret key with the specific attributes.
 /// Returns a handle for the secret
 #[no_mangle]
@@ -192,6 +278,83 @@ pub extern "C" fn ockam_vault_secret_export(
     });
 }
 
+/// Export a secret key wrapped for the owner of `recipient_public_key`, signed with `signing_key`
+#[no_mangle]
+pub extern "C" fn ockam_vault_secret_export_wrapped(
+    context: u64,
+    secret: SecretKeyHandle,
+    recipient_public_key: *const u8,
+    recipient_public_key_length: u32,
+    signing_key: SecretKeyHandle,
+    output_buffer: &mut u8,
+    output_buffer_size: u32,
+    output_buffer_length: &mut u32,
+    error: &mut ExternError,
+) {
+    check_buffer!(recipient_public_key, recipient_public_key_length, error);
+    let recipient_public_key = unsafe {
+        std::slice::from_raw_parts(recipient_public_key, recipient_public_key_length as usize)
+    };
+    *output_buffer_length = 0;
+    let mut output_buffer = AssertUnwindSafe(output_buffer);
+    let mut output_buffer_length = AssertUnwindSafe(output_buffer_length);
+    VAULTS.call_with_result_mut(error, context, move |v| -> Result<(), ExternError> {
+        let ctx = BoxVault::get_secret(&v.map, secret)?;
+        let signing_ctx = BoxVault::get_secret(&v.map, signing_key)?;
+        let bundle = v
+            .vault
+            .secret_export_wrapped(&ctx, recipient_public_key, &signing_ctx)?;
+        if output_buffer_size < bundle.len() as u32 {
+            return Err(Error::BufferTooSmall.into());
+        }
+        **output_buffer_length = bundle.len() as u32;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bundle.as_ptr(),
+                *output_buffer.deref_mut(),
+                bundle.len(),
+            );
+        };
+        Ok(())
+    });
+}
+
+/// Import a wrapped secret key after checking the sender's signature
+#[no_mangle]
+pub extern "C" fn ockam_vault_secret_import_wrapped(
+    context: u64,
+    secret: &mut SecretKeyHandle,
+    recipient_secret: SecretKeyHandle,
+    input: *const u8,
+    input_length: u32,
+    sender_public_key: *const u8,
+    sender_public_key_length: u32,
+    sender_public_key_type: u32,
+    error: &mut ExternError,
+) {
+    check_buffer!(input, input_length, error);
+    check_buffer!(sender_public_key, sender_public_key_length, error);
+    let input = unsafe { std::slice::from_raw_parts(input, input_length as usize) };
+    let sender_public_key =
+        unsafe { std::slice::from_raw_parts(sender_public_key, sender_public_key_length as usize) };
+    *secret = VAULTS.call_with_result_mut(
+        error,
+        context,
+        move |v| -> Result<SecretKeyHandle, ExternError> {
+            let sender_public_key_type = SecretType::from_usize(sender_public_key_type as usize)
+                .map_err(|_| Error::UnknownPublicKeyType)?;
+            let recipient_ctx = BoxVault::get_secret(&v.map, recipient_secret)?;
+            let ctx = v.vault.secret_import_wrapped(
+                input,
+                &recipient_ctx,
+                sender_public_key,
+                sender_public_key_type,
+            )?;
+            Ok(v.add_secret(ctx))
+        },
+    );
+}
+
 /// Get the public key from a secret key to the output buffer
 #[no_mangle]
 pub extern "C" fn ockam_vault_secret_publickey_get(
@@ -351,6 +514,52 @@ pub extern "C" fn ockam_vault_hkdf_sha256(
     });
 }
 
+/// Perform an HMAC-SHA512 based key derivation function on the supplied salt and input key
+/// material.
+#[no_mangle]
+pub extern "C" fn ockam_vault_hkdf_sha512(
+    context: u64,
+    salt: SecretKeyHandle,
+    input_key_material: *const SecretKeyHandle,
+    derived_outputs_attributes: *const FfiSecretAttributes,
+    derived_outputs_count: u8,
+    derived_outputs: *mut SecretKeyHandle,
+    error: &mut ExternError,
+) {
+    let derived_outputs_count = derived_outputs_count as usize;
+    let derived_outputs = AssertUnwindSafe(derived_outputs);
+    VAULTS.call_with_result_mut(error, context, move |v| -> Result<(), ExternError> {
+        let salt_ctx = BoxVault::get_secret(&v.map, salt)?;
+        let ikm_ctx = if input_key_material.is_null() {
+            None
+        } else {
+            unsafe { Some(BoxVault::get_secret(&v.map, *input_key_material)?) }
+        };
+
+        let array: &[FfiSecretAttributes] =
+            unsafe { slice::from_raw_parts(derived_outputs_attributes, derived_outputs_count) };
+
+        let output_attributes: Vec<SecretAttributes> = array.iter().map(|x| x.into()).collect();
+
+        // Info is empty for the same reason as in `ockam_vault_hkdf_sha256`
+        let hkdf_output: Vec<SecretKeyHandle> = v
+            .vault
+            .hkdf_sha512(&salt_ctx, b"", ikm_ctx, output_attributes)?
+            .into_iter()
+            .map(|x| v.add_secret(x))
+            .collect();
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                hkdf_output.as_ptr(),
+                *derived_outputs,
+                derived_outputs_count,
+            )
+        };
+        Ok(())
+    });
+}
+
 ///   Encrypt a payload using AES-GCM.
 #[no_mangle]
 pub extern "C" fn ockam_vault_aead_aes_gcm_encrypt(
@@ -440,6 +649,98 @@ pub extern "C" fn ockam_vault_aead_aes_gcm_decrypt(
     });
 }
 
+/// Encrypt a payload using ChaCha20-Poly1305.
+#[no_mangle]
+pub extern "C" fn ockam_vault_aead_chacha20_poly1305_encrypt(
+    context: u64,
+    secret: SecretKeyHandle,
+    nonce: u16,
+    additional_data: *const u8,
+    additional_data_length: u32,
+    plaintext: *const u8,
+    plaintext_length: u32,
+    ciphertext_and_tag: &mut u8,
+    ciphertext_and_tag_size: u32,
+    ciphertext_and_tag_length: &mut u32,
+    error: &mut ExternError,
+) {
+    check_buffer!(additional_data, error);
+    check_buffer!(plaintext, error);
+    *ciphertext_and_tag_length = 0;
+    let additional_data =
+        unsafe { std::slice::from_raw_parts(additional_data, additional_data_length as usize) };
+    let plaintext = unsafe { std::slice::from_raw_parts(plaintext, plaintext_length as usize) };
+    let mut ciphertext_and_tag = AssertUnwindSafe(ciphertext_and_tag);
+    let mut ciphertext_and_tag_length = AssertUnwindSafe(ciphertext_and_tag_length);
+    VAULTS.call_with_result_mut(error, context, move |v| -> Result<(), ExternError> {
+        let ctx = BoxVault::get_secret(&v.map, secret)?;
+        let mut nonce_vec = vec![0; 12 - 8];
+        nonce_vec.extend_from_slice(&(nonce as u64).to_le_bytes());
+        let ciphertext =
+            v.vault
+                .aead_chacha20_poly1305_encrypt(&ctx, plaintext, &nonce_vec, additional_data)?;
+
+        if ciphertext_and_tag_size < ciphertext.len() as u32 {
+            return Err(Error::BufferTooSmall.into());
+        }
+        **ciphertext_and_tag_length = ciphertext.len() as u32;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                ciphertext.as_ptr(),
+                *ciphertext_and_tag.deref_mut(),
+                ciphertext.len(),
+            )
+        };
+        Ok(())
+    });
+}
+
+/// Decrypt a payload using ChaCha20-Poly1305.
+#[no_mangle]
+pub extern "C" fn ockam_vault_aead_chacha20_poly1305_decrypt(
+    context: u64,
+    secret: SecretKeyHandle,
+    nonce: u16,
+    additional_data: *const u8,
+    additional_data_length: u32,
+    ciphertext_and_tag: *const u8,
+    ciphertext_and_tag_length: u32,
+    plaintext: &mut u8,
+    plaintext_size: u32,
+    plaintext_length: &mut u32,
+    error: &mut ExternError,
+) {
+    check_buffer!(ciphertext_and_tag, ciphertext_and_tag_length, error);
+    check_buffer!(additional_data, error);
+    *plaintext_length = 0;
+    let additional_data =
+        unsafe { std::slice::from_raw_parts(additional_data, additional_data_length as usize) };
+    let ciphertext_and_tag = unsafe {
+        std::slice::from_raw_parts(ciphertext_and_tag, ciphertext_and_tag_length as usize)
+    };
+    let mut plaintext = AssertUnwindSafe(plaintext);
+    let mut plaintext_length = AssertUnwindSafe(plaintext_length);
+    VAULTS.call_with_result_mut(error, context, move |v| -> Result<(), ExternError> {
+        let ctx = BoxVault::get_secret(&v.map, secret)?;
+        let mut nonce_vec = vec![0; 12 - 8];
+        nonce_vec.extend_from_slice(&(nonce as u64).to_le_bytes());
+        let plain = v.vault.aead_chacha20_poly1305_decrypt(
+            &ctx,
+            ciphertext_and_tag,
+            &nonce_vec,
+            additional_data,
+        )?;
+        if plaintext_size < plain.len() as u32 {
+            return Err(Error::BufferTooSmall.into());
+        }
+        **plaintext_length = plain.len() as u32;
+        unsafe {
+            std::ptr::copy_nonoverlapping(plain.as_ptr(), *plaintext.deref_mut(), plain.len())
+        };
+        Ok(())
+    });
+}
+
 #[no_mangle]
 pub extern "C" fn ockam_vault_get_persistence_id(
     context: u64,
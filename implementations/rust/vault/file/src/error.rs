@@ -10,6 +10,14 @@ pub enum Error {
     IOError,
     InvalidPersistenceId,
     EntryNotFound,
+    PassphraseRequired,
+    InvalidPassphrase,
+    InvalidVaultHeader,
+    UnsupportedVaultVersion,
+    VaultNotEncrypted,
+    LabelAlreadyExists,
+    InvalidMetadata,
+    UnsealedKeyFile,
 }
 
 impl Error {
@@ -0,0 +1,219 @@
+use crate::error::*;
+use ockam_common::error::OckamResult;
+use ockam_vault::types::CHACHA20POLY1305_SECRET_LENGTH;
+use ockam_vault::types::{SecretAttributes, SecretPersistence, SecretType};
+use ockam_vault::{RandomVault, Secret, SecretVault, SymmetricVault};
+use ockam_vault_software::DefaultVault;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroize;
+
+/// Name of the file that marks a vault directory as encrypted
+pub const HEADER_FILENAME: &str = "vault.header";
+
+const HEADER_MAGIC: &[u8; 8] = b"OCKAMVLT";
+const HEADER_VERSION: u8 = 2;
+/// Headers of version 1 have no flags
+const HEADER_VERSION_NO_FLAGS: u8 = 1;
+const KDF_SCRYPT: u8 = 1;
+const SALT_LENGTH: usize = 16;
+const TAG_LENGTH: usize = 16;
+// log_n + r + p + salt, after magic + version + kdf and the flags of version 2
+const KDF_PARAMS_LENGTH: usize = 1 + 4 + 4 + SALT_LENGTH;
+
+/// Set while a plaintext vault is being encrypted, plaintext key files are only
+/// accepted then
+const FLAG_MIGRATING: u8 = 1;
+
+#[cfg(not(test))]
+const SCRYPT_LOG_N: u8 = 15;
+// keep unit tests fast, the cost is stored in the header anyway
+#[cfg(test)]
+const SCRYPT_LOG_N: u8 = 10;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// Headers asking scrypt for more memory or parallelism than this are rejected,
+/// the parameters are read before the passphrase is checked
+const MAX_SCRYPT_MEMORY: u64 = 1 << 30;
+const MAX_SCRYPT_P: u32 = 16;
+
+/// On-disk header of an encrypted filesystem vault.
+///
+/// Holds the KDF parameters used to derive the master key from a passphrase and a
+/// tag computed with that key, so that a wrong passphrase is detected before any
+/// key file is touched.
+#[derive(Debug)]
+pub(crate) struct VaultHeader {
+    version: u8,
+    flags: u8,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: [u8; SALT_LENGTH],
+    tag: [u8; TAG_LENGTH],
+}
+
+impl VaultHeader {
+    /// Create a header with a fresh salt and return it with the derived master key.
+    /// A header created `migrating` lets plaintext key files be loaded until
+    /// [`VaultHeader::finish_migration`]
+    pub(crate) fn create(
+        vault: &mut DefaultVault,
+        passphrase: &[u8],
+        migrating: bool,
+    ) -> OckamResult<(Self, Box<dyn Secret>)> {
+        let mut salt = [0u8; SALT_LENGTH];
+        vault.random_bytes_generate(&mut salt)?;
+
+        let mut header = Self {
+            version: HEADER_VERSION,
+            flags: if migrating { FLAG_MIGRATING } else { 0 },
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt,
+            tag: [0u8; TAG_LENGTH],
+        };
+        let master_key = header.derive_master_key(vault, passphrase)?;
+        header.seal(vault, &master_key)?;
+
+        Ok((header, master_key))
+    }
+
+    /// True while a plaintext vault is being encrypted
+    pub(crate) fn migrating(&self) -> bool {
+        self.flags & FLAG_MIGRATING != 0
+    }
+
+    /// Clear the migration flag once every key file is sealed
+    pub(crate) fn finish_migration(
+        &mut self,
+        vault: &mut DefaultVault,
+        master_key: &Box<dyn Secret>,
+    ) -> OckamResult<()> {
+        self.version = HEADER_VERSION;
+        self.flags &= !FLAG_MIGRATING;
+        self.seal(vault, master_key)
+    }
+
+    fn seal(&mut self, vault: &mut DefaultVault, master_key: &Box<dyn Secret>) -> OckamResult<()> {
+        let tag =
+            vault.aead_chacha20_poly1305_encrypt(master_key, &[], &[0u8; 12], &self.body())?;
+        self.tag.copy_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Derive the master key and check it against the stored tag
+    pub(crate) fn unlock(
+        &self,
+        vault: &mut DefaultVault,
+        passphrase: &[u8],
+    ) -> OckamResult<Box<dyn Secret>> {
+        let master_key = self.derive_master_key(vault, passphrase)?;
+        if vault
+            .aead_chacha20_poly1305_decrypt(&master_key, &self.tag, &[0u8; 12], &self.body())
+            .is_err()
+        {
+            vault.secret_destroy(master_key)?;
+            return Err(Error::InvalidPassphrase.into());
+        }
+
+        Ok(master_key)
+    }
+
+    fn derive_master_key(
+        &self,
+        vault: &mut DefaultVault,
+        passphrase: &[u8],
+    ) -> OckamResult<Box<dyn Secret>> {
+        let params = scrypt::ScryptParams::new(self.log_n, self.r, self.p)
+            .map_err(|_| Error::InvalidVaultHeader.into())?;
+        let mut key = [0u8; CHACHA20POLY1305_SECRET_LENGTH];
+        scrypt::scrypt(passphrase, &self.salt, &params, &mut key)
+            .map_err(|_| Error::InvalidVaultHeader.into())?;
+
+        let attributes = SecretAttributes {
+            stype: SecretType::ChaCha20Poly1305,
+            persistence: SecretPersistence::Ephemeral,
+            length: CHACHA20POLY1305_SECRET_LENGTH,
+        };
+        let master_key = vault.secret_import(&key, attributes);
+        key.zeroize();
+
+        master_key
+    }
+
+    fn body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(8 + 3 + KDF_PARAMS_LENGTH);
+        body.extend_from_slice(HEADER_MAGIC);
+        body.push(self.version);
+        body.push(KDF_SCRYPT);
+        if self.version != HEADER_VERSION_NO_FLAGS {
+            body.push(self.flags);
+        }
+        body.push(self.log_n);
+        body.extend_from_slice(&self.r.to_be_bytes());
+        body.extend_from_slice(&self.p.to_be_bytes());
+        body.extend_from_slice(&self.salt);
+        body
+    }
+
+    /// Read the header from disk
+    pub(crate) fn read(path: &Path) -> OckamResult<Self> {
+        let data = fs::read(path).map_err(|_| Error::IOError.into())?;
+        if data.len() < 10 || &data[0..8] != HEADER_MAGIC {
+            return Err(Error::InvalidVaultHeader.into());
+        }
+        let version = data[8];
+        let (flags, params) = match version {
+            HEADER_VERSION_NO_FLAGS => (0, &data[10..]),
+            HEADER_VERSION if data.len() > 10 => (data[10], &data[11..]),
+            HEADER_VERSION => return Err(Error::InvalidVaultHeader.into()),
+            _ => return Err(Error::UnsupportedVaultVersion.into()),
+        };
+        if data[9] != KDF_SCRYPT || params.len() != KDF_PARAMS_LENGTH + TAG_LENGTH {
+            return Err(Error::InvalidVaultHeader.into());
+        }
+
+        let log_n = params[0];
+        let r = u32::from_be_bytes(params[1..5].try_into().unwrap());
+        let p = u32::from_be_bytes(params[5..9].try_into().unwrap());
+        if !scrypt_params_bounded(log_n, r, p) {
+            return Err(Error::InvalidVaultHeader.into());
+        }
+        let mut salt = [0u8; SALT_LENGTH];
+        salt.copy_from_slice(&params[9..KDF_PARAMS_LENGTH]);
+        let mut tag = [0u8; TAG_LENGTH];
+        tag.copy_from_slice(&params[KDF_PARAMS_LENGTH..]);
+
+        Ok(Self {
+            version,
+            flags,
+            log_n,
+            r,
+            p,
+            salt,
+            tag,
+        })
+    }
+
+    /// Write the header to disk
+    pub(crate) fn write(&self, path: &Path) -> OckamResult<()> {
+        let mut data = self.body();
+        data.extend_from_slice(&self.tag);
+        fs::write(path, data).map_err(|_| Error::IOError.into())
+    }
+}
+
+/// Scrypt takes `128 * r * 2^log_n` bytes of memory and `p` times the work of one pass
+fn scrypt_params_bounded(log_n: u8, r: u32, p: u32) -> bool {
+    if log_n == 0 || log_n >= 64 || r == 0 || p == 0 || p > MAX_SCRYPT_P {
+        return false;
+    }
+    matches!(
+        (128 * r as u64).checked_mul(1u64 << log_n),
+        Some(memory) if memory <= MAX_SCRYPT_MEMORY
+    )
+}
@@ -1,35 +1,56 @@
 use crate::error::*;
-use ockam_vault::types::{PublicKey, SecretAttributes, SecretKey, SecretPersistence};
+use crate::header::{VaultHeader, HEADER_FILENAME};
+use ockam_vault::types::{
+    PersistentSecretInfo, PublicKey, SecretAttributes, SecretKey, SecretMetadata,
+    SecretPersistence, SecretType, Signature,
+};
 use ockam_vault::{
-    AsymmetricVault, HashVault, PersistentVault, Secret, SecretVault, SignerVault, SymmetricVault,
-    VerifierVault,
+    AsymmetricVault, HashVault, KemVault, PersistentVault, RandomVault, RotationVault, Secret,
+    SecretVault, SignerVault, SymmetricVault, VerifierVault, WrappingVault,
 };
-use ockam_vault_software::DefaultVault;
+use ockam_vault_software::{DefaultVault, HkdfHash};
 use std::cmp::max;
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use zeroize::Zeroize;
 
 use ockam_common::error::OckamResult;
 pub use ockam_vault;
 
 pub mod error;
+mod header;
 
 const ATTRS_BYTE_LENGTH: usize = 6;
 
+/// Prefix of a key file sealed under the vault master key, followed by a format version
+const ENCRYPTED_KEY_MAGIC: &[u8; 4] = b"OKEY";
+const ENCRYPTED_KEY_VERSION: u8 = 1;
+const ENCRYPTED_KEY_NONCE_LENGTH: usize = 12;
+
 /// A FilesystemVault is an implementation of an Ockam Vault that wraps the software vault and uses
 /// the disk as a persistent store.
+///
+/// Key files are stored in plaintext unless the vault is opened with
+/// [`FilesystemVault::new_encrypted`], in which case every key file is sealed with
+/// ChaCha20-Poly1305 under a master key derived from a passphrase with scrypt.
+/// Labels and metadata are kept next to the key files in plaintext `.meta` files.
 #[derive(Debug)]
 pub struct FilesystemVault {
     v: DefaultVault,
     path: PathBuf,
     map: BTreeMap<usize, Box<dyn Secret>>,
+    metadata: BTreeMap<usize, SecretMetadata>,
     next_id: usize,
+    master_key: Option<Box<dyn Secret>>,
 }
 
 pub const FILENAME_KEY_SUFFIX: &str = ".key";
+pub const FILENAME_METADATA_SUFFIX: &str = ".meta";
+/// Metadata updates of several secrets being written, replayed if the vault is opened
+/// before they are all written
+const METADATA_JOURNAL_FILENAME: &str = "metadata.journal";
 
 /// Default vault secret
 #[derive(Debug, Copy, Clone)]
@@ -59,87 +80,185 @@ impl FilesystemVault {
     }
 
     /// Creates a new FilesystemVault using the provided path on disk to store secrets.
+    ///
+    /// Fails with `PassphraseRequired` if the path holds an encrypted vault.
     pub fn new(path: PathBuf) -> OckamResult<Self> {
-        let mut map = BTreeMap::<usize, Box<dyn Secret>>::new();
-        let mut next_id: usize = 0;
+        fs::create_dir_all(&path).or_else(|_| Err(Error::IOError.into()))?;
+        if path.join(HEADER_FILENAME).exists() {
+            return Err(Error::PassphraseRequired.into());
+        }
 
-        let create_path = path.clone();
-        fs::create_dir_all(create_path).or_else(|_| Err(Error::IOError.into()))?;
+        Self::load(path, DefaultVault::default(), None, false)
+    }
+
+    /// Creates a new FilesystemVault whose key files are encrypted with a master key
+    /// derived from `passphrase`.
+    ///
+    /// An empty path is initialized as an encrypted vault. A path holding a plaintext
+    /// vault is rejected with `VaultNotEncrypted`, see [`FilesystemVault::migrate_to_encrypted`].
+    /// A plaintext key file in an encrypted vault is rejected with `UnsealedKeyFile`,
+    /// unless it is left over from an interrupted migration.
+    pub fn new_encrypted(path: PathBuf, passphrase: &[u8]) -> OckamResult<Self> {
+        fs::create_dir_all(&path).or_else(|_| Err(Error::IOError.into()))?;
 
         let mut vault = DefaultVault::default();
-        let to_secret = |data: &[u8]| -> OckamResult<(SecretKey, SecretAttributes)> {
-            if data.len() < ATTRS_BYTE_LENGTH {
-                return Err(Error::InvalidSecret.into());
+        let header_path = path.join(HEADER_FILENAME);
+        let (header, master_key) = if header_path.exists() {
+            let header = VaultHeader::read(&header_path)?;
+            let master_key = header.unlock(&mut vault, passphrase)?;
+            (header, master_key)
+        } else {
+            if !key_files(&path)?.is_empty() {
+                return Err(Error::VaultNotEncrypted.into());
             }
+            let (header, master_key) = VaultHeader::create(&mut vault, passphrase, false)?;
+            header.write(&header_path)?;
+            (header, master_key)
+        };
 
-            let mut attrs = [0u8; ATTRS_BYTE_LENGTH];
-            attrs.copy_from_slice(&data[0..ATTRS_BYTE_LENGTH]);
-            let attributes = SecretAttributes::try_from(attrs)?;
+        let migrating = header.migrating();
+        let mut fs_vault = Self::load(path, vault, Some(master_key), migrating)?;
+        if migrating {
+            fs_vault.finish_migration(header)?;
+        }
+        Ok(fs_vault)
+    }
 
-            Ok((
-                SecretKey::new(data[ATTRS_BYTE_LENGTH..].to_vec()),
-                attributes,
-            ))
+    /// Encrypts an existing plaintext vault in place with a master key derived from
+    /// `passphrase` and returns it opened.
+    ///
+    /// The header is written first, marked as migrating, and each key file is then
+    /// replaced atomically, so an interrupted migration is completed the next time the
+    /// vault is opened with [`FilesystemVault::new_encrypted`].
+    pub fn migrate_to_encrypted(path: PathBuf, passphrase: &[u8]) -> OckamResult<Self> {
+        let mut vault = Self::new(path)?;
+        let (header, master_key) = VaultHeader::create(&mut vault.v, passphrase, true)?;
+        header.write(&vault.path.join(HEADER_FILENAME))?;
+        vault.master_key = Some(master_key);
+
+        let ids: Vec<usize> = vault.map.keys().copied().collect();
+        for id in ids {
+            vault.reseal_secret(id)?;
+        }
+        vault.finish_migration(header)?;
+
+        Ok(vault)
+    }
+
+    /// Every key file is sealed, plaintext key files are rejected from now on
+    fn finish_migration(&mut self, mut header: VaultHeader) -> OckamResult<()> {
+        let master_key = self
+            .master_key
+            .as_ref()
+            .ok_or(Error::InvalidSecret.into())?;
+        header.finish_migration(&mut self.v, master_key)?;
+        header.write(&self.path.join(HEADER_FILENAME))
+    }
+
+    /// Returns true if the vault at `path` has been encrypted
+    pub fn is_encrypted(path: &Path) -> bool {
+        path.join(HEADER_FILENAME).exists()
+    }
+
+    fn load(
+        path: PathBuf,
+        vault: DefaultVault,
+        master_key: Option<Box<dyn Secret>>,
+        migrating: bool,
+    ) -> OckamResult<Self> {
+        let mut fs_vault = Self {
+            v: vault,
+            map: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+            path,
+            next_id: 0,
+            master_key,
         };
-        let fs_path = path.clone();
-
-        path.read_dir()
-            .or_else(|_| Err(Error::IOError.into()))?
-            .filter(|r| {
-                // ignore directories within vault path
-                if let Ok(e) = r {
-                    match fs::metadata(e.path()) {
-                        Ok(md) => md.is_file(),
-                        Err(_) => false,
-                    }
-                } else {
-                    false
+
+        // plaintext files found while the header is marked as migrating are left over
+        // from an interrupted migration
+        let mut unsealed = Vec::new();
+        for entry in key_files(&fs_vault.path)? {
+            // Files are read in any order
+            let item_id = entry
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<usize>().ok());
+            let id = match item_id {
+                Some(id) => id,
+                None => {
+                    eprintln!("invalid key file name: {:?}", entry);
+                    continue;
                 }
-            })
-            .for_each(|entry| {
-                if let Ok(entry) = entry {
-                    match fs::read(entry.path()) {
-                        Ok(data) => {
-                            let (secret, attrs) =
-                                &to_secret(data.as_slice()).unwrap_or_else(|_| {
-                                    panic!("failed to get secret {:?} from file", entry.file_name())
-                                });
-                            // Files are read in any order
-                            let fname = entry.file_name();
-                            let t: &std::path::Path = fname.as_os_str().as_ref();
-                            let mut item_id: Option<usize> = None;
-                            if let Some(stem) = t.file_stem() {
-                                if let Some(str) = stem.to_str() {
-                                    if let Ok(id) = str.parse::<usize>() {
-                                        item_id = Some(id);
-                                    }
-                                }
-                            }
-                            match item_id {
-                                Some(id) => {
-                                    let secret = vault.secret_import(secret.as_ref(), *attrs);
-                                    match secret {
-                                        Ok(secret) => {
-                                            map.insert(id, secret);
-                                            next_id = max(next_id, id);
-                                        }
-                                        Err(e) => eprintln!("{}", e),
-                                    }
-                                }
-                                None => eprintln!("invalid key file name: {:?}", entry),
-                            }
-                        }
-                        Err(e) => eprintln!("{}", e),
+            };
+
+            let mut data = fs::read(&entry).or_else(|_| Err(Error::IOError.into()))?;
+            let is_sealed = data.starts_with(ENCRYPTED_KEY_MAGIC);
+            if !is_sealed && fs_vault.master_key.is_some() && !migrating {
+                data.zeroize();
+                return Err(Error::UnsealedKeyFile.into());
+            }
+            let mut data = if is_sealed {
+                fs_vault.open_key_file(id, &data)?
+            } else {
+                data
+            };
+            let secret = to_secret(&data);
+            data.zeroize();
+            let (secret, attrs) = secret?;
+
+            match fs_vault.v.secret_import(secret.as_ref(), attrs) {
+                Ok(secret) => {
+                    let metadata_path = fs_vault.path.join(id_to_metadata_path(id));
+                    if metadata_path.is_file() {
+                        let data = fs::read_to_string(metadata_path)
+                            .or_else(|_| Err(Error::IOError.into()))?;
+                        fs_vault.metadata.insert(id, parse_metadata(&data)?);
+                    }
+                    fs_vault.map.insert(id, secret);
+                    fs_vault.next_id = max(fs_vault.next_id, id);
+                    if !is_sealed {
+                        unsealed.push(id);
                     }
                 }
-            });
+                Err(e) => eprintln!("{}", e),
+            }
+        }
 
-        Ok(Self {
-            v: vault,
-            map,
-            path: fs_path,
-            next_id,
-        })
+        if fs_vault.master_key.is_some() {
+            for id in unsealed {
+                fs_vault.reseal_secret(id)?;
+            }
+        }
+
+        let journal_path = fs_vault.path.join(METADATA_JOURNAL_FILENAME);
+        if journal_path.is_file() {
+            let data = fs::read_to_string(&journal_path).or_else(|_| Err(Error::IOError.into()))?;
+            for (id, metadata) in parse_metadata_journal(&data)? {
+                if fs_vault.map.contains_key(&id) {
+                    fs_vault.write_metadata(id, metadata)?;
+                }
+            }
+            fs::remove_file(journal_path).or_else(|_| Err(Error::IOError.into()))?;
+        }
+
+        Ok(fs_vault)
+    }
+
+    fn write_metadata(&mut self, id: usize, metadata: SecretMetadata) -> OckamResult<()> {
+        let data = format_metadata(&metadata)?;
+        write_atomically(&self.path.join(id_to_metadata_path(id)), data.as_bytes())?;
+        self.metadata.insert(id, metadata);
+        Ok(())
+    }
+
+    fn write_metadata_journal(&self, updates: &[(usize, SecretMetadata)]) -> OckamResult<()> {
+        let mut data = String::new();
+        for (id, metadata) in updates {
+            data.push_str(&format!("secret\t{}\n", id));
+            data.push_str(&format_metadata(metadata)?);
+        }
+        write_atomically(&self.path.join(METADATA_JOURNAL_FILENAME), data.as_bytes())
     }
 
     fn add_secret(&mut self, secret: Box<dyn Secret>) -> usize {
@@ -147,25 +266,216 @@ impl FilesystemVault {
         self.map.insert(self.next_id, secret);
         self.next_id
     }
-}
 
-fn id_to_path(id: usize) -> PathBuf {
-    format!("{}.key", id.to_string()).into()
-}
+    /// Rewrite the key file of a loaded secret using the current on-disk format
+    fn reseal_secret(&mut self, id: usize) -> OckamResult<()> {
+        let secret = self.map.get(&id).ok_or(Error::EntryNotFound.into())?;
+        let attrs = self.v.secret_attributes_get(secret)?;
+        let key = self.v.secret_export(secret)?;
+        self.write_secret(id, key.as_ref(), attrs)
+    }
+
+    fn write_secret(&mut self, id: usize, key: &[u8], attrs: SecretAttributes) -> OckamResult<()> {
+        if !matches!(attrs.persistence, SecretPersistence::Persistent) {
+            return Ok(());
+        }
 
-fn fs_write_secret(
-    path: PathBuf,
-    id: usize,
-    key: &[u8],
-    attrs: SecretAttributes,
-) -> OckamResult<()> {
-    if matches!(attrs.persistence, SecretPersistence::Persistent) {
         let mut bytes = attrs.to_bytes().to_vec();
         bytes.extend_from_slice(key.as_ref());
 
-        fs::write(path.join(id_to_path(id)), bytes).or_else(|_| Err(Error::IOError.into()))?;
+        let data = match &self.master_key {
+            Some(master_key) => {
+                let mut nonce = [0u8; ENCRYPTED_KEY_NONCE_LENGTH];
+                self.v.random_bytes_generate(&mut nonce)?;
+                let aad = key_file_aad(id);
+
+                let mut data = aad[..ENCRYPTED_KEY_MAGIC.len() + 1].to_vec();
+                data.extend_from_slice(&nonce);
+                let ciphertext = self
+                    .v
+                    .aead_chacha20_poly1305_encrypt(master_key, &bytes, &nonce, &aad);
+                bytes.zeroize();
+                data.extend_from_slice(&ciphertext?);
+                data
+            }
+            None => bytes,
+        };
+
+        write_atomically(&self.path.join(id_to_path(id)), &data)
+    }
+
+    /// Returns the id of a secret, checking that it is persisted on disk
+    fn persistent_id(&mut self, secret: &Box<dyn Secret>) -> OckamResult<usize> {
+        let id = FilesystemVaultSecret::downcast_secret(secret)?.0;
+        let entry = self.map.get(&id).ok_or(Error::EntryNotFound.into())?;
+        match self.v.secret_attributes_get(entry)?.persistence {
+            SecretPersistence::Persistent => Ok(id),
+            SecretPersistence::Ephemeral => Err(Error::InvalidSecret.into()),
+        }
+    }
+
+    fn open_key_file(&mut self, id: usize, data: &[u8]) -> OckamResult<Vec<u8>> {
+        let master_key = self
+            .master_key
+            .as_ref()
+            .ok_or(Error::PassphraseRequired.into())?;
+
+        let header_length = ENCRYPTED_KEY_MAGIC.len() + 1;
+        if data.len() < header_length + ENCRYPTED_KEY_NONCE_LENGTH {
+            return Err(Error::InvalidSecret.into());
+        }
+        if data[ENCRYPTED_KEY_MAGIC.len()] != ENCRYPTED_KEY_VERSION {
+            return Err(Error::UnsupportedVaultVersion.into());
+        }
+
+        let nonce = &data[header_length..header_length + ENCRYPTED_KEY_NONCE_LENGTH];
+        let ciphertext = &data[header_length + ENCRYPTED_KEY_NONCE_LENGTH..];
+        self.v
+            .aead_chacha20_poly1305_decrypt(master_key, ciphertext, nonce, &key_file_aad(id))
+            .map_err(|_| Error::InvalidSecret.into())
+    }
+
+    /// Run a HKDF of the software vault and hand out handles for its outputs
+    fn hkdf(
+        &mut self,
+        hash: HkdfHash,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        let ikm = match ikm {
+            Some(secret) => Some(Self::get_entry_map(&self.map, secret)?),
+            None => None,
+        };
+        let salt_context = Self::get_entry_map(&self.map, salt)?;
+
+        self.v
+            .hkdf(hash, salt_context, info, ikm, output_attributes)?
+            .into_iter()
+            .map(|secret| {
+                let id = self.add_secret(secret);
+                // TODO: What if result is persistent?
+                // TODO: Should we remove it
+
+                let res: Box<dyn Secret> = Box::new(FilesystemVaultSecret(id));
+                Ok(res)
+            })
+            .collect()
+    }
+}
+
+fn to_secret(data: &[u8]) -> OckamResult<(SecretKey, SecretAttributes)> {
+    if data.len() < ATTRS_BYTE_LENGTH {
+        return Err(Error::InvalidSecret.into());
     }
-    return Ok(());
+
+    let mut attrs = [0u8; ATTRS_BYTE_LENGTH];
+    attrs.copy_from_slice(&data[0..ATTRS_BYTE_LENGTH]);
+    let attributes = SecretAttributes::try_from(attrs)?;
+
+    Ok((
+        SecretKey::new(data[ATTRS_BYTE_LENGTH..].to_vec()),
+        attributes,
+    ))
+}
+
+/// Serializes metadata as one tab separated record per line
+fn format_metadata(metadata: &SecretMetadata) -> OckamResult<String> {
+    let valid = |s: &str| !s.contains(|c| c == '\t' || c == '\n' || c == '\r');
+
+    let mut data = String::new();
+    if let Some(label) = &metadata.label {
+        if !valid(label) {
+            return Err(Error::InvalidMetadata.into());
+        }
+        data.push_str(&format!("label\t{}\n", label));
+    }
+    for (key, value) in &metadata.entries {
+        if !valid(key) || !valid(value) {
+            return Err(Error::InvalidMetadata.into());
+        }
+        data.push_str(&format!("entry\t{}\t{}\n", key, value));
+    }
+
+    Ok(data)
+}
+
+/// Parses the metadata records of a journal, each one following a `secret\t<id>` line
+fn parse_metadata_journal(data: &str) -> OckamResult<Vec<(usize, SecretMetadata)>> {
+    let mut records: Vec<(usize, String)> = Vec::new();
+    for line in data.lines() {
+        match (line.strip_prefix("secret\t"), records.last_mut()) {
+            (Some(id), _) => {
+                let id = id.parse().map_err(|_| Error::InvalidMetadata.into())?;
+                records.push((id, String::new()));
+            }
+            (None, Some((_, record))) => {
+                record.push_str(line);
+                record.push('\n');
+            }
+            (None, None) => return Err(Error::InvalidMetadata.into()),
+        }
+    }
+    records
+        .into_iter()
+        .map(|(id, record)| Ok((id, parse_metadata(&record)?)))
+        .collect()
+}
+
+fn parse_metadata(data: &str) -> OckamResult<SecretMetadata> {
+    let mut metadata = SecretMetadata::default();
+    for line in data.lines().filter(|l| !l.is_empty()) {
+        match line.split('\t').collect::<Vec<&str>>().as_slice() {
+            ["label", label] => metadata.label = Some(label.to_string()),
+            ["entry", key, value] => {
+                metadata.entries.insert(key.to_string(), value.to_string());
+            }
+            _ => return Err(Error::InvalidMetadata.into()),
+        }
+    }
+
+    Ok(metadata)
+}
+
+/// Write to a temporary file first so an existing file is replaced atomically
+fn write_atomically(path: &Path, data: &[u8]) -> OckamResult<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, data).or_else(|_| Err(Error::IOError.into()))?;
+    fs::rename(tmp_path, path).or_else(|_| Err(Error::IOError.into()))
+}
+
+/// Paths of the key files within the vault directory, ignoring anything else
+fn key_files(path: &Path) -> OckamResult<Vec<PathBuf>> {
+    Ok(path
+        .read_dir()
+        .or_else(|_| Err(Error::IOError.into()))?
+        .filter_map(|r| r.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.is_file()
+                && p.file_name()
+                    .and_then(|n| n.to_str())
+                    .map_or(false, |n| n.ends_with(FILENAME_KEY_SUFFIX))
+        })
+        .collect())
+}
+
+/// Binds a sealed key file to its format version and id, so files can't be swapped
+fn key_file_aad(id: usize) -> Vec<u8> {
+    let mut aad = ENCRYPTED_KEY_MAGIC.to_vec();
+    aad.push(ENCRYPTED_KEY_VERSION);
+    aad.extend_from_slice(id.to_string().as_bytes());
+    aad
+}
+
+fn id_to_path(id: usize) -> PathBuf {
+    format!("{}.key", id.to_string()).into()
+}
+
+fn id_to_metadata_path(id: usize) -> PathBuf {
+    format!("{}{}", id, FILENAME_METADATA_SUFFIX).into()
 }
 
 impl SecretVault for FilesystemVault {
@@ -175,7 +485,7 @@ impl SecretVault for FilesystemVault {
         let ctx = self.v.secret_generate(attributes)?;
         let secret = self.v.secret_export(&ctx)?;
         let id = self.add_secret(ctx);
-        fs_write_secret(self.path.clone(), id, secret.as_ref(), attributes)?;
+        self.write_secret(id, secret.as_ref(), attributes)?;
 
         Ok(Box::new(FilesystemVaultSecret(id)))
     }
@@ -189,7 +499,7 @@ impl SecretVault for FilesystemVault {
         // write the secret to disk using the context id
         let ctx = self.v.secret_import(secret, attributes)?;
         let id = self.add_secret(ctx);
-        fs_write_secret(self.path.clone(), id, &secret, attributes)?;
+        self.write_secret(id, &secret, attributes)?;
 
         Ok(Box::new(FilesystemVaultSecret(id)))
     }
@@ -219,13 +529,16 @@ impl SecretVault for FilesystemVault {
     fn secret_destroy(&mut self, context: Box<dyn Secret>) -> OckamResult<()> {
         let id = FilesystemVaultSecret::downcast_secret(&context)?.0;
 
-        let path = self.path.join(id_to_path(id));
-        match fs::metadata(path.clone()) {
-            Ok(md) if md.is_file() => {
-                fs::remove_file(path).map_err(|_| Error::IOError.into())?;
+        for path in &[id_to_path(id), id_to_metadata_path(id)] {
+            let path = self.path.join(path);
+            match fs::metadata(path.clone()) {
+                Ok(md) if md.is_file() => {
+                    fs::remove_file(path).map_err(|_| Error::IOError.into())?;
+                }
+                _ => {}
             }
-            _ => {}
         }
+        self.metadata.remove(&id);
 
         let context = FilesystemVaultSecret::downcast_secret(&context)?;
         let context = self
@@ -256,6 +569,27 @@ impl AsymmetricVault for FilesystemVault {
     }
 }
 
+impl KemVault for FilesystemVault {
+    fn kem_encapsulate(&mut self, public_key: &[u8]) -> OckamResult<(Vec<u8>, Box<dyn Secret>)> {
+        let (ciphertext, shared_secret) = self.v.kem_encapsulate(public_key)?;
+        let id = self.add_secret(shared_secret);
+
+        Ok((ciphertext, Box::new(FilesystemVaultSecret(id))))
+    }
+
+    fn kem_decapsulate(
+        &mut self,
+        context: &Box<dyn Secret>,
+        ciphertext: &[u8],
+    ) -> OckamResult<Box<dyn Secret>> {
+        let context = Self::get_entry_map(&self.map, context)?;
+        let shared_secret = self.v.kem_decapsulate(context, ciphertext)?;
+        let id = self.add_secret(shared_secret);
+
+        Ok(Box::new(FilesystemVaultSecret(id)))
+    }
+}
+
 impl SymmetricVault for FilesystemVault {
     /// Encrypt a payload using AES-GCM
     fn aead_aes_gcm_encrypt(
@@ -281,18 +615,50 @@ impl SymmetricVault for FilesystemVault {
         self.v
             .aead_aes_gcm_decrypt(context, cipher_text, nonce, aad)
     }
+
+    /// Encrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        let context = Self::get_entry_map(&self.map, context)?;
+        self.v
+            .aead_chacha20_poly1305_encrypt(context, plaintext, nonce, aad)
+    }
+
+    /// Decrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        let context = Self::get_entry_map(&self.map, context)?;
+        self.v
+            .aead_chacha20_poly1305_decrypt(context, cipher_text, nonce, aad)
+    }
 }
 
 impl SignerVault for FilesystemVault {
-    fn sign(&mut self, secret_key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<[u8; 64]> {
+    fn sign(&mut self, secret_key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<Signature> {
         let context = Self::get_entry_map(&self.map, secret_key)?;
         self.v.sign(context, data)
     }
 }
 
 impl VerifierVault for FilesystemVault {
-    fn verify(&mut self, signature: &[u8; 64], public_key: &[u8], data: &[u8]) -> OckamResult<()> {
-        self.v.verify(signature, public_key, data)
+    fn verify(
+        &mut self,
+        signature: &[u8],
+        public_key: &[u8],
+        public_key_type: SecretType,
+        data: &[u8],
+    ) -> OckamResult<()> {
+        self.v.verify(signature, public_key, public_key_type, data)
     }
 }
 
@@ -311,26 +677,40 @@ impl HashVault for FilesystemVault {
         ikm: Option<&Box<dyn Secret>>,
         output_attributes: Vec<SecretAttributes>,
     ) -> OckamResult<Vec<Box<dyn Secret>>> {
-        let ikm = match ikm {
-            Some(secret) => Some(Self::get_entry_map(&self.map, secret)?),
-            None => None,
-        };
-        let salt_context = Self::get_entry_map(&self.map, salt)?;
+        self.hkdf(HkdfHash::Sha256, salt, info, ikm, output_attributes)
+    }
 
-        self.v
-            .hkdf_sha256(salt_context, info, ikm, output_attributes)?
-            .into_iter()
-            .map(|secret| {
-                let id = self.add_secret(secret);
-                // TODO: What if result is persistent?
-                // TODO: Should we remove it
+    /// Compute the SHA-512 digest given input `data`
+    fn sha512(&self, data: &[u8]) -> OckamResult<[u8; 64]> {
+        self.v.sha512(data)
+    }
 
-                let res: Box<dyn Secret> = Box::new(FilesystemVaultSecret(id));
-                Ok(res)
-            })
-            .collect()
+    /// Compute the HKDF-SHA512 using the specified salt and input key material
+    ///
+    /// and return the output key material of the specified length
+    fn hkdf_sha512(
+        &mut self,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        self.hkdf(HkdfHash::Sha512, salt, info, ikm, output_attributes)
+    }
+
+    /// Compute the HMAC-SHA256 of `data` keyed with the buffer secret `key`
+    fn hmac_sha256(&mut self, key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<[u8; 32]> {
+        let key = Self::get_entry_map(&self.map, key)?;
+        self.v.hmac_sha256(key, data)
+    }
+}
+
+impl RandomVault for FilesystemVault {
+    fn random_bytes_generate(&mut self, buffer: &mut [u8]) -> OckamResult<()> {
+        self.v.random_bytes_generate(buffer)
     }
 }
+
 impl PersistentVault for FilesystemVault {
     fn get_persistence_id(&self, secret: &Box<dyn Secret>) -> OckamResult<String> {
         let id = FilesystemVaultSecret::downcast_secret(secret)?.0;
@@ -349,8 +729,93 @@ impl PersistentVault for FilesystemVault {
             Err(Error::InvalidPersistenceId.into())
         }
     }
+
+    fn get_persistent_secret_by_label(&self, label: &str) -> OckamResult<Box<dyn Secret>> {
+        self.metadata
+            .iter()
+            .find(|(_, m)| m.label.as_deref() == Some(label))
+            .map(|(id, _)| {
+                let secret: Box<dyn Secret> = Box::new(FilesystemVaultSecret(*id));
+                secret
+            })
+            .ok_or(Error::EntryNotFound.into())
+    }
+
+    fn get_secret_metadata(&self, secret: &Box<dyn Secret>) -> OckamResult<SecretMetadata> {
+        let id = FilesystemVaultSecret::downcast_secret(secret)?.0;
+        if !self.map.contains_key(&id) {
+            return Err(Error::EntryNotFound.into());
+        }
+        Ok(self.metadata.get(&id).cloned().unwrap_or_default())
+    }
+
+    fn set_secret_metadata(
+        &mut self,
+        secret: &Box<dyn Secret>,
+        metadata: SecretMetadata,
+    ) -> OckamResult<()> {
+        self.set_secrets_metadata(vec![(secret, metadata)])
+    }
+
+    /// Several updates are written to a journal first, see `METADATA_JOURNAL_FILENAME`
+    fn set_secrets_metadata(
+        &mut self,
+        updates: Vec<(&Box<dyn Secret>, SecretMetadata)>,
+    ) -> OckamResult<()> {
+        let mut labels = self.metadata.clone();
+        let mut records = Vec::new();
+        for (secret, metadata) in updates {
+            let id = self.persistent_id(secret)?;
+            format_metadata(&metadata)?;
+            if let Some(label) = &metadata.label {
+                let taken = labels
+                    .iter()
+                    .any(|(other, m)| *other != id && m.label.as_ref() == Some(label));
+                if taken {
+                    return Err(Error::LabelAlreadyExists.into());
+                }
+            }
+            labels.insert(id, metadata.clone());
+            records.push((id, metadata));
+        }
+
+        let journaled = records.len() > 1;
+        if journaled {
+            self.write_metadata_journal(&records)?;
+        }
+        for (id, metadata) in records {
+            self.write_metadata(id, metadata)?;
+        }
+        if journaled {
+            fs::remove_file(self.path.join(METADATA_JOURNAL_FILENAME))
+                .or_else(|_| Err(Error::IOError.into()))?;
+        }
+
+        Ok(())
+    }
+
+    fn list_persistent_secrets(&mut self) -> OckamResult<Vec<PersistentSecretInfo>> {
+        let mut secrets = Vec::new();
+        for (id, secret) in &self.map {
+            let attributes = self.v.secret_attributes_get(secret)?;
+            if !matches!(attributes.persistence, SecretPersistence::Persistent) {
+                continue;
+            }
+            secrets.push(PersistentSecretInfo {
+                persistence_id: format!("{}{}", id, FILENAME_KEY_SUFFIX),
+                attributes,
+                metadata: self.metadata.get(id).cloned().unwrap_or_default(),
+            });
+        }
+
+        Ok(secrets)
+    }
 }
 
+impl RotationVault for FilesystemVault {}
+
+impl WrappingVault for FilesystemVault {}
+
 impl Zeroize for FilesystemVault {
     fn zeroize(&mut self) {
         self.v.zeroize();
@@ -360,7 +825,7 @@ impl Zeroize for FilesystemVault {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ockam_vault::types::{SecretPersistence, SecretType, CURVE25519_SECRET_LENGTH};
+    use ockam_vault::types::CURVE25519_SECRET_LENGTH;
 
     #[test]
     fn persistence_test() {
@@ -398,4 +863,251 @@ mod tests {
         assert_eq!(sk_data2, sk2_data_2);
         assert_eq!(sk_data3, sk2_data_3);
     }
+
+    #[test]
+    fn encrypted_persistence_test() {
+        let path = std::path::PathBuf::from("__encrypted_persistence_test");
+        if path.exists() {
+            std::fs::remove_dir_all(path.clone()).unwrap();
+        }
+        let mut vault = FilesystemVault::new_encrypted(path.clone(), b"passphrase").unwrap();
+        let atts = SecretAttributes {
+            stype: SecretType::Curve25519,
+            persistence: SecretPersistence::Persistent,
+            length: CURVE25519_SECRET_LENGTH,
+        };
+        let sk = vault.secret_generate(atts).unwrap();
+        let sk_data = vault.secret_export(&sk).unwrap();
+        let sk_persistence_id = vault.get_persistence_id(&sk).unwrap();
+
+        let file = std::fs::read(path.join(&sk_persistence_id)).unwrap();
+        assert!(file.starts_with(ENCRYPTED_KEY_MAGIC));
+        assert!(!file
+            .windows(sk_data.as_ref().len())
+            .any(|w| w == sk_data.as_ref()));
+
+        assert!(FilesystemVault::new(path.clone()).is_err());
+        assert!(FilesystemVault::new_encrypted(path.clone(), b"wrong").is_err());
+
+        let mut vault2 = FilesystemVault::new_encrypted(path, b"passphrase").unwrap();
+        let sk = vault2.get_persistent_secret(&sk_persistence_id).unwrap();
+        assert_eq!(sk_data, vault2.secret_export(&sk).unwrap());
+    }
+
+    #[test]
+    fn migrate_to_encrypted_test() {
+        let path = std::path::PathBuf::from("__migrate_to_encrypted_test");
+        if path.exists() {
+            std::fs::remove_dir_all(path.clone()).unwrap();
+        }
+        let mut vault = FilesystemVault::new(path.clone()).unwrap();
+        let atts = SecretAttributes {
+            stype: SecretType::Curve25519,
+            persistence: SecretPersistence::Persistent,
+            length: CURVE25519_SECRET_LENGTH,
+        };
+        let sk = vault.secret_generate(atts).unwrap();
+        let sk_data = vault.secret_export(&sk).unwrap();
+        let sk_persistence_id = vault.get_persistence_id(&sk).unwrap();
+
+        assert!(FilesystemVault::new_encrypted(path.clone(), b"passphrase").is_err());
+        FilesystemVault::migrate_to_encrypted(path.clone(), b"passphrase").unwrap();
+        assert!(FilesystemVault::is_encrypted(&path));
+
+        let file = std::fs::read(path.join(&sk_persistence_id)).unwrap();
+        assert!(file.starts_with(ENCRYPTED_KEY_MAGIC));
+
+        let mut vault2 = FilesystemVault::new_encrypted(path.clone(), b"passphrase").unwrap();
+        let sk = vault2.get_persistent_secret(&sk_persistence_id).unwrap();
+        assert_eq!(sk_data, vault2.secret_export(&sk).unwrap());
+
+        // a plaintext key file showing up once the migration is done is rejected
+        let mut plaintext = atts.to_bytes().to_vec();
+        plaintext.extend_from_slice(&[7u8; CURVE25519_SECRET_LENGTH]);
+        std::fs::write(path.join(id_to_path(100)), &plaintext).unwrap();
+        assert!(FilesystemVault::new_encrypted(path.clone(), b"passphrase").is_err());
+
+        // but one left over from an interrupted migration is sealed on the next open
+        std::fs::remove_dir_all(path.clone()).unwrap();
+        let mut vault = FilesystemVault::new(path.clone()).unwrap();
+        let sk = vault.secret_generate(atts).unwrap();
+        let sk_data = vault.secret_export(&sk).unwrap();
+        let sk_persistence_id = vault.get_persistence_id(&sk).unwrap();
+        let header_path = path.join(HEADER_FILENAME);
+        let (header, _) = VaultHeader::create(&mut vault.v, b"passphrase", true).unwrap();
+        header.write(&header_path).unwrap();
+
+        let mut vault3 = FilesystemVault::new_encrypted(path.clone(), b"passphrase").unwrap();
+        let sk = vault3.get_persistent_secret(&sk_persistence_id).unwrap();
+        assert_eq!(sk_data, vault3.secret_export(&sk).unwrap());
+        assert!(std::fs::read(path.join(&sk_persistence_id))
+            .unwrap()
+            .starts_with(ENCRYPTED_KEY_MAGIC));
+        assert!(!VaultHeader::read(&header_path).unwrap().migrating());
+    }
+
+    #[test]
+    fn unbounded_kdf_parameters() {
+        let path = std::path::PathBuf::from("__unbounded_kdf_parameters_test");
+        if path.exists() {
+            std::fs::remove_dir_all(path.clone()).unwrap();
+        }
+        FilesystemVault::new_encrypted(path.clone(), b"passphrase").unwrap();
+        let header_path = path.join(HEADER_FILENAME);
+        let header = std::fs::read(&header_path).unwrap();
+
+        // log_n, r and p follow magic, version, kdf and flags
+        for (offset, value) in [(11, 40u8), (12, 0xff), (16, 0xff)].iter() {
+            let mut data = header.clone();
+            data[*offset] = *value;
+            std::fs::write(&header_path, &data).unwrap();
+            assert!(VaultHeader::read(&header_path).is_err());
+        }
+        std::fs::write(&header_path, &header).unwrap();
+        assert!(FilesystemVault::new_encrypted(path, b"passphrase").is_ok());
+    }
+
+    #[test]
+    fn metadata_test() {
+        let path = std::path::PathBuf::from("__metadata_test");
+        if path.exists() {
+            std::fs::remove_dir_all(path.clone()).unwrap();
+        }
+        let mut vault = FilesystemVault::new(path.clone()).unwrap();
+        let atts = SecretAttributes {
+            stype: SecretType::Curve25519,
+            persistence: SecretPersistence::Persistent,
+            length: CURVE25519_SECRET_LENGTH,
+        };
+        let sk1 = vault.secret_generate(atts).unwrap();
+        let sk2 = vault.secret_generate(atts).unwrap();
+
+        let mut metadata = SecretMetadata::default();
+        metadata.label = Some("my-sensor-identity".into());
+        metadata.entries.insert("purpose".into(), "identity".into());
+        vault.set_secret_metadata(&sk1, metadata.clone()).unwrap();
+        assert!(vault.set_secret_metadata(&sk2, metadata.clone()).is_err());
+
+        let mut vault2 = FilesystemVault::new(path).unwrap();
+        let sk = vault2
+            .get_persistent_secret_by_label("my-sensor-identity")
+            .unwrap();
+        assert_eq!(
+            vault2.get_persistence_id(&sk).unwrap(),
+            vault.get_persistence_id(&sk1).unwrap()
+        );
+        assert_eq!(vault2.get_secret_metadata(&sk).unwrap(), metadata);
+
+        let list = vault2.list_persistent_secrets().unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list.iter().filter(|s| s.metadata.label.is_some()).count(),
+            1
+        );
+
+        vault2.secret_destroy(sk).unwrap();
+        assert!(vault2
+            .get_persistent_secret_by_label("my-sensor-identity")
+            .is_err());
+    }
+
+    #[test]
+    fn rotation_test() {
+        use ockam_vault::types::METADATA_VERSION;
+        use std::time::Duration;
+
+        let path = std::path::PathBuf::from("__rotation_test");
+        if path.exists() {
+            std::fs::remove_dir_all(path.clone()).unwrap();
+        }
+        let mut vault = FilesystemVault::new(path).unwrap();
+        let atts = SecretAttributes {
+            stype: SecretType::Curve25519,
+            persistence: SecretPersistence::Persistent,
+            length: CURVE25519_SECRET_LENGTH,
+        };
+        let sk1 = vault.secret_generate(atts).unwrap();
+        let mut metadata = SecretMetadata::default();
+        metadata.label = Some("identity".into());
+        vault.set_secret_metadata(&sk1, metadata).unwrap();
+        assert!(vault
+            .secret_needs_rotation("identity", Duration::from_secs(3600))
+            .unwrap());
+
+        let sk2 = vault
+            .secret_rotate("identity", Duration::from_secs(3600))
+            .unwrap();
+        assert_ne!(
+            vault.secret_public_key_get(&sk1).unwrap(),
+            vault.secret_public_key_get(&sk2).unwrap()
+        );
+        let current = vault.get_persistent_secret_by_label("identity").unwrap();
+        assert_eq!(
+            vault.get_persistence_id(&current).unwrap(),
+            vault.get_persistence_id(&sk2).unwrap()
+        );
+        assert_eq!(
+            vault.get_secret_metadata(&current).unwrap().entries[METADATA_VERSION],
+            "2"
+        );
+        assert!(!vault
+            .secret_needs_rotation("identity", Duration::from_secs(3600))
+            .unwrap());
+        assert!(vault.get_persistent_secret_by_label("identity@1").is_ok());
+
+        // without a grace period the previous versions are destroyed right away
+        vault
+            .secret_rotate("identity", Duration::from_secs(0))
+            .unwrap();
+        assert!(vault.get_persistent_secret_by_label("identity@1").is_ok());
+        assert!(vault.get_persistent_secret_by_label("identity@2").is_err());
+        assert_eq!(vault.list_persistent_secrets().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn metadata_journal_test() {
+        let path = std::path::PathBuf::from("__metadata_journal_test");
+        if path.exists() {
+            std::fs::remove_dir_all(path.clone()).unwrap();
+        }
+        let mut vault = FilesystemVault::new(path.clone()).unwrap();
+        let atts = SecretAttributes {
+            stype: SecretType::Curve25519,
+            persistence: SecretPersistence::Persistent,
+            length: CURVE25519_SECRET_LENGTH,
+        };
+        let sk1 = vault.secret_generate(atts).unwrap();
+        let sk2 = vault.secret_generate(atts).unwrap();
+        let mut metadata = SecretMetadata::default();
+        metadata.label = Some("identity".into());
+        vault.set_secret_metadata(&sk1, metadata.clone()).unwrap();
+        let sk1_persistence_id = vault.get_persistence_id(&sk1).unwrap();
+        let sk2_persistence_id = vault.get_persistence_id(&sk2).unwrap();
+
+        // an update that would leave the label on two secrets changes nothing
+        assert!(vault
+            .set_secrets_metadata(vec![
+                (&sk2, metadata.clone()),
+                (&sk1, SecretMetadata::default())
+            ])
+            .is_err());
+        assert_eq!(vault.get_secret_metadata(&sk1).unwrap(), metadata);
+
+        // interrupted after the journal is written, the label moves when the vault is opened
+        let mut retired = SecretMetadata::default();
+        retired.label = Some("identity@1".into());
+        let id1 = vault.persistent_id(&sk1).unwrap();
+        let id2 = vault.persistent_id(&sk2).unwrap();
+        vault
+            .write_metadata_journal(&[(id1, retired.clone()), (id2, metadata.clone())])
+            .unwrap();
+        drop(vault);
+
+        let vault = FilesystemVault::new(path.clone()).unwrap();
+        assert!(!path.join(METADATA_JOURNAL_FILENAME).exists());
+        let sk1 = vault.get_persistent_secret(&sk1_persistence_id).unwrap();
+        let sk2 = vault.get_persistent_secret(&sk2_persistence_id).unwrap();
+        assert_eq!(vault.get_secret_metadata(&sk1).unwrap(), retired);
+        assert_eq!(vault.get_secret_metadata(&sk2).unwrap(), metadata);
+    }
 }
@@ -0,0 +1,32 @@
+use ockam_common::error::OckamError;
+
+/// Represents the failures that can occur in
+/// an Ockam PKCS#11 Vault
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    None,
+    SecretFromAnotherVault,
+    InvalidSecret,
+    ModuleError,
+    LoginFailed,
+    TokenError,
+    InvalidPersistenceId,
+    EntryNotFound,
+    UnsupportedSecretType,
+    InvalidPublicKey,
+    SecretNotExtractable,
+    AeadDecryptFailed,
+    MetadataNotSupported,
+    LabelAlreadyExists,
+}
+
+impl Error {
+    /// Error domain
+    pub const ERROR_DOMAIN: &'static str = "VAULT_PKCS11_ERROR_DOMAIN";
+}
+
+impl Into<OckamError> for Error {
+    fn into(self) -> OckamError {
+        OckamError::new(self as u32, Error::ERROR_DOMAIN)
+    }
+}
@@ -0,0 +1,1158 @@
+use crate::error::*;
+use ockam_vault::types::{
+    PersistentSecretInfo, PublicKey, SecretAttributes, SecretKey, SecretMetadata,
+    SecretPersistence, SecretType, Signature, CURVE25519_PUBLIC_LENGTH, CURVE25519_SECRET_LENGTH,
+    ED25519_PUBLIC_LENGTH, ED25519_SECRET_LENGTH, P256_PUBLIC_LENGTH, P256_SECRET_LENGTH,
+};
+use ockam_vault::{
+    AsymmetricVault, HashVault, KemVault, PersistentVault, RandomVault, Secret, SecretVault,
+    SignerVault, SymmetricVault, VerifierVault,
+};
+use ockam_vault_software::{DefaultVault, HkdfHash};
+use pkcs11::types::*;
+use pkcs11::Ctx;
+use std::convert::TryInto;
+use std::mem::size_of;
+use std::path::Path;
+use std::ptr;
+use zeroize::Zeroize;
+
+use ockam_common::error::OckamResult;
+pub use ockam_vault;
+
+pub mod error;
+
+// PKCS#11 v3.0 identifiers, not defined by the v2.40 bindings
+const CKK_EC_EDWARDS: CK_KEY_TYPE = 0x40;
+const CKK_EC_MONTGOMERY: CK_KEY_TYPE = 0x41;
+const CKM_EC_EDWARDS_KEY_PAIR_GEN: CK_MECHANISM_TYPE = 0x1055;
+const CKM_EC_MONTGOMERY_KEY_PAIR_GEN: CK_MECHANISM_TYPE = 0x1056;
+const CKM_EDDSA: CK_MECHANISM_TYPE = 0x1057;
+const CKM_HKDF_DERIVE: CK_MECHANISM_TYPE = 0x402a;
+const CKM_HKDF_DATA: CK_MECHANISM_TYPE = 0x402b;
+const CKF_HKDF_SALT_KEY: CK_ULONG = 0x4;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+struct CK_HKDF_PARAMS {
+    bExtract: CK_BBOOL,
+    bExpand: CK_BBOOL,
+    prfHashMechanism: CK_MECHANISM_TYPE,
+    ulSaltType: CK_ULONG,
+    pSalt: CK_BYTE_PTR,
+    ulSaltLen: CK_ULONG,
+    hSaltKey: CK_OBJECT_HANDLE,
+    pInfo: CK_BYTE_PTR,
+    ulInfoLen: CK_ULONG,
+}
+
+/// DER encoded object identifiers of the supported curves
+const P256_OID: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const X25519_OID: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x6e];
+const ED25519_OID: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+
+/// Length of the random CKA_ID linking the objects of a key
+const OBJECT_ID_LENGTH: usize = 16;
+const DH_SECRET_LENGTH: usize = 32;
+/// Every HKDF output takes a chunk of this many bytes of the output key material,
+/// as with the software vault
+const HKDF_OUTPUT_LENGTH: usize = 32;
+const AES_GCM_TAG_BITS: CK_ULONG = 128;
+/// How many object handles to fetch per C_FindObjects call
+const FIND_BATCH_SIZE: CK_ULONG = 16;
+
+/// A Pkcs11Vault is an implementation of an Ockam Vault that keeps secrets in a PKCS#11 token,
+/// such as an HSM or SoftHSM2.
+///
+/// Key generation, ECDH, HKDF, signing and AES-GCM happen inside the token and private keys
+/// can't be exported. ECDH and HKDF outputs are sensitive token objects as well, HKDF needs
+/// a token that supports `CKM_HKDF_DERIVE` and `CKM_HKDF_DATA` of PKCS#11 3.0.
+///
+/// Persistent secrets are token objects, identified by their `CKA_ID`. Labels are stored as
+/// `CKA_LABEL`, other metadata entries are not supported.
+pub struct Pkcs11Vault {
+    v: DefaultVault,
+    ctx: Ctx,
+    session: CK_SESSION_HANDLE,
+}
+
+// the module is only used through the single session of this vault, callers that share
+// the vault between threads wrap it in a Mutex
+unsafe impl Send for Pkcs11Vault {}
+
+impl std::fmt::Debug for Pkcs11Vault {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Pkcs11Vault")
+            .field("session", &self.session)
+            .finish()
+    }
+}
+
+/// PKCS#11 vault secret, the handle of a private or secret key object
+#[derive(Debug, Copy, Clone)]
+pub struct Pkcs11VaultSecret(CK_OBJECT_HANDLE);
+
+impl Pkcs11VaultSecret {
+    pub fn downcast_secret(context: &Box<dyn Secret>) -> OckamResult<&Self> {
+        context
+            .downcast_ref::<Pkcs11VaultSecret>()
+            .map_err(|_| Error::SecretFromAnotherVault.into())
+    }
+}
+
+impl Zeroize for Pkcs11VaultSecret {
+    fn zeroize(&mut self) {}
+}
+
+impl Secret for Pkcs11VaultSecret {}
+
+impl Pkcs11Vault {
+    /// Loads the PKCS#11 module at `module`, opens a session on the token in `slot`
+    /// and logs in with the user `pin`.
+    pub fn new<P: AsRef<Path>>(module: P, slot: CK_SLOT_ID, pin: &str) -> OckamResult<Self> {
+        let ctx =
+            Ctx::new_and_initialize(module.as_ref()).map_err(|_| Error::ModuleError.into())?;
+        let session = ctx
+            .open_session(slot, CKF_SERIAL_SESSION | CKF_RW_SESSION, None, None)
+            .map_err(|_| Error::TokenError.into())?;
+        if ctx.login(session, CKU_USER, Some(pin)).is_err() {
+            let _ = ctx.close_session(session);
+            return Err(Error::LoginFailed.into());
+        }
+
+        Ok(Self {
+            v: DefaultVault::default(),
+            ctx,
+            session,
+        })
+    }
+
+    fn object(context: &Box<dyn Secret>) -> OckamResult<CK_OBJECT_HANDLE> {
+        Ok(Pkcs11VaultSecret::downcast_secret(context)?.0)
+    }
+
+    fn find_objects(&self, template: &[CK_ATTRIBUTE]) -> OckamResult<Vec<CK_OBJECT_HANDLE>> {
+        self.ctx
+            .find_objects_init(self.session, template)
+            .map_err(|_| Error::TokenError.into())?;
+        let mut objects = Vec::new();
+        let result = loop {
+            match self.ctx.find_objects(self.session, FIND_BATCH_SIZE) {
+                Ok(batch) if batch.is_empty() => break Ok(objects),
+                Ok(batch) => objects.extend(batch),
+                Err(_) => break Err(Error::TokenError.into()),
+            }
+        };
+        self.ctx
+            .find_objects_final(self.session)
+            .map_err(|_| Error::TokenError.into())?;
+        result
+    }
+
+    /// Find the private or secret key object stored on the token with the given attribute value
+    fn find_key(
+        &self,
+        attribute_type: CK_ATTRIBUTE_TYPE,
+        value: &[u8],
+    ) -> OckamResult<Box<dyn Secret>> {
+        for class in &[CKO_PRIVATE_KEY, CKO_SECRET_KEY] {
+            let template = [
+                CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(class),
+                CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_TRUE),
+                CK_ATTRIBUTE::new(attribute_type).with_bytes(value),
+            ];
+            if let Some(object) = self.find_objects(&template)?.first() {
+                return Ok(Box::new(Pkcs11VaultSecret(*object)));
+            }
+        }
+        Err(Error::EntryNotFound.into())
+    }
+
+    /// Read the value of an attribute of `object`
+    fn attribute(
+        &self,
+        object: CK_OBJECT_HANDLE,
+        attribute_type: CK_ATTRIBUTE_TYPE,
+    ) -> OckamResult<Vec<u8>> {
+        // the first call returns the length of the value
+        let mut template = vec![CK_ATTRIBUTE::new(attribute_type)];
+        let (rv, _) = self
+            .ctx
+            .get_attribute_value(self.session, object, &mut template)
+            .map_err(|_| Error::TokenError.into())?;
+        match rv {
+            CKR_OK => {}
+            CKR_ATTRIBUTE_SENSITIVE => return Err(Error::SecretNotExtractable.into()),
+            _ => return Err(Error::TokenError.into()),
+        }
+
+        let mut value = vec![0u8; template[0].ulValueLen as usize];
+        template[0].pValue = value.as_mut_ptr() as CK_VOID_PTR;
+        let (rv, _) = self
+            .ctx
+            .get_attribute_value(self.session, object, &mut template)
+            .map_err(|_| Error::TokenError.into())?;
+        if rv != CKR_OK {
+            value.zeroize();
+            return Err(Error::TokenError.into());
+        }
+        Ok(value)
+    }
+
+    fn attribute_ulong(
+        &self,
+        object: CK_OBJECT_HANDLE,
+        attribute_type: CK_ATTRIBUTE_TYPE,
+    ) -> OckamResult<CK_ULONG> {
+        let value = self.attribute(object, attribute_type)?;
+        let bytes = value
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::TokenError.into())?;
+        Ok(CK_ULONG::from_ne_bytes(bytes))
+    }
+
+    fn attribute_bool(
+        &self,
+        object: CK_OBJECT_HANDLE,
+        attribute_type: CK_ATTRIBUTE_TYPE,
+    ) -> OckamResult<bool> {
+        Ok(self.attribute(object, attribute_type)?.first() == Some(&CK_TRUE))
+    }
+
+    fn object_attributes(&self, object: CK_OBJECT_HANDLE) -> OckamResult<SecretAttributes> {
+        let persistence = if self.attribute_bool(object, CKA_TOKEN)? {
+            SecretPersistence::Persistent
+        } else {
+            SecretPersistence::Ephemeral
+        };
+        let (stype, length) = match self.attribute_ulong(object, CKA_KEY_TYPE)? {
+            CKK_EC => (SecretType::P256, P256_SECRET_LENGTH),
+            CKK_EC_MONTGOMERY => (SecretType::Curve25519, CURVE25519_SECRET_LENGTH),
+            CKK_EC_EDWARDS => (SecretType::Ed25519, ED25519_SECRET_LENGTH),
+            CKK_AES => (
+                SecretType::Aes,
+                self.attribute_ulong(object, CKA_VALUE_LEN)? as usize,
+            ),
+            CKK_GENERIC_SECRET => (
+                SecretType::Buffer,
+                self.attribute_ulong(object, CKA_VALUE_LEN)? as usize,
+            ),
+            _ => return Err(Error::UnsupportedSecretType.into()),
+        };
+
+        Ok(SecretAttributes {
+            stype,
+            persistence,
+            length,
+        })
+    }
+
+    /// Find the public key object sharing the CKA_ID of a private key
+    fn public_key_object(&self, object: CK_OBJECT_HANDLE) -> OckamResult<CK_OBJECT_HANDLE> {
+        let id = self.attribute(object, CKA_ID)?;
+        if id.is_empty() {
+            return Err(Error::EntryNotFound.into());
+        }
+        let template = [
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_PUBLIC_KEY),
+            CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+        ];
+        self.find_objects(&template)?
+            .first()
+            .copied()
+            .ok_or(Error::EntryNotFound.into())
+    }
+
+    fn new_object_id(&mut self) -> OckamResult<Vec<u8>> {
+        let mut id = vec![0u8; OBJECT_ID_LENGTH];
+        self.random_bytes_generate(&mut id)?;
+        Ok(id)
+    }
+
+    fn aes_gcm_init(
+        &self,
+        context: &Box<dyn Secret>,
+        nonce: &[u8],
+        aad: &[u8],
+        encrypt: bool,
+    ) -> OckamResult<()> {
+        let object = Self::object(context)?;
+        let mut params = CK_GCM_PARAMS {
+            pIv: nonce.as_ptr() as CK_BYTE_PTR,
+            ulIvLen: nonce.len() as CK_ULONG,
+            ulIvBits: (nonce.len() * 8) as CK_ULONG,
+            pAAD: aad.as_ptr() as CK_BYTE_PTR,
+            ulAADLen: aad.len() as CK_ULONG,
+            ulTagBits: AES_GCM_TAG_BITS,
+        };
+        let mechanism = CK_MECHANISM {
+            mechanism: CKM_AES_GCM,
+            pParameter: &mut params as *mut CK_GCM_PARAMS as CK_VOID_PTR,
+            ulParameterLen: size_of::<CK_GCM_PARAMS>() as CK_ULONG,
+        };
+        if encrypt {
+            self.ctx.encrypt_init(self.session, &mechanism, object)
+        } else {
+            self.ctx.decrypt_init(self.session, &mechanism, object)
+        }
+        .map_err(|_| Error::TokenError.into())
+    }
+
+    /// Run a HKDF inside the token. The output key material stays a sensitive token
+    /// object, each output is extracted from its chunk of it
+    fn hkdf(
+        &mut self,
+        hash: HkdfHash,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        let salt = Self::object(salt)?;
+        // without input key material the HKDF runs on an empty data object
+        let (base, mechanism, empty_ikm) = match ikm {
+            Some(ikm) => (Self::object(ikm)?, CKM_HKDF_DERIVE, None),
+            None => {
+                let template = [
+                    CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_DATA),
+                    CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_FALSE),
+                    CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(&[]),
+                ];
+                let data = self
+                    .ctx
+                    .create_object(self.session, &template)
+                    .map_err(|_| Error::TokenError.into())?;
+                (data, CKM_HKDF_DATA, Some(data))
+            }
+        };
+        let mut params = CK_HKDF_PARAMS {
+            bExtract: CK_TRUE,
+            bExpand: CK_TRUE,
+            prfHashMechanism: match hash {
+                HkdfHash::Sha256 => CKM_SHA256,
+                HkdfHash::Sha512 => CKM_SHA512,
+            },
+            ulSaltType: CKF_HKDF_SALT_KEY,
+            pSalt: ptr::null_mut(),
+            ulSaltLen: 0,
+            hSaltKey: salt,
+            pInfo: info.as_ptr() as CK_BYTE_PTR,
+            ulInfoLen: info.len() as CK_ULONG,
+        };
+        let mechanism = CK_MECHANISM {
+            mechanism,
+            pParameter: &mut params as *mut CK_HKDF_PARAMS as CK_VOID_PTR,
+            ulParameterLen: size_of::<CK_HKDF_PARAMS>() as CK_ULONG,
+        };
+        let length = (output_attributes.len() * HKDF_OUTPUT_LENGTH) as CK_ULONG;
+        let template = [
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_SECRET_KEY),
+            CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&CKK_GENERIC_SECRET),
+            CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&length),
+            CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_FALSE),
+            CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&CK_FALSE),
+            CK_ATTRIBUTE::new(CKA_DERIVE).with_bool(&CK_TRUE),
+        ];
+        let okm = self
+            .ctx
+            .derive_key(self.session, &mechanism, base, &template);
+        if let Some(data) = empty_ikm {
+            let _ = self.ctx.destroy_object(self.session, data);
+        }
+        let okm = okm.map_err(|_| Error::TokenError.into())?;
+
+        let mut outputs: Vec<Box<dyn Secret>> = Vec::with_capacity(output_attributes.len());
+        let mut result = Ok(());
+        for (index, attributes) in output_attributes.into_iter().enumerate() {
+            match self.hkdf_output(okm, index, attributes) {
+                Ok(output) => outputs.push(Box::new(Pkcs11VaultSecret(output))),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let _ = self.ctx.destroy_object(self.session, okm);
+        if let Err(e) = result {
+            for output in outputs {
+                self.secret_destroy(output)?;
+            }
+            return Err(e);
+        }
+        Ok(outputs)
+    }
+
+    /// Extract the `index`th output of a HKDF from its output key material
+    fn hkdf_output(
+        &mut self,
+        okm: CK_OBJECT_HANDLE,
+        index: usize,
+        attributes: SecretAttributes,
+    ) -> OckamResult<CK_OBJECT_HANDLE> {
+        let (key_type, _) = symmetric_key_type(attributes.stype)?;
+        if attributes.length > HKDF_OUTPUT_LENGTH {
+            return Err(Error::UnsupportedSecretType.into());
+        }
+        let id = self.new_object_id()?;
+        let token = token_flag(attributes.persistence);
+        let length = attributes.length as CK_ULONG;
+        let aes = bool_flag(attributes.stype == SecretType::Aes);
+        let buffer = bool_flag(attributes.stype == SecretType::Buffer);
+        let mut bit: CK_EXTRACT_PARAMS = (index * HKDF_OUTPUT_LENGTH * 8) as CK_ULONG;
+        let mechanism = CK_MECHANISM {
+            mechanism: CKM_EXTRACT_KEY_FROM_KEY,
+            pParameter: &mut bit as *mut CK_EXTRACT_PARAMS as CK_VOID_PTR,
+            ulParameterLen: size_of::<CK_EXTRACT_PARAMS>() as CK_ULONG,
+        };
+        let template = [
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_SECRET_KEY),
+            CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type),
+            CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&token),
+            CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+            CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&length),
+            CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&CK_FALSE),
+            CK_ATTRIBUTE::new(CKA_ENCRYPT).with_bool(&aes),
+            CK_ATTRIBUTE::new(CKA_DECRYPT).with_bool(&aes),
+            CK_ATTRIBUTE::new(CKA_DERIVE).with_bool(&buffer),
+            CK_ATTRIBUTE::new(CKA_SIGN).with_bool(&buffer),
+        ];
+        self.ctx
+            .derive_key(self.session, &mechanism, okm, &template)
+            .map_err(|_| Error::TokenError.into())
+    }
+}
+
+/// Key type, key pair generation mechanism and curve of an asymmetric secret type
+fn curve(stype: SecretType) -> OckamResult<(CK_KEY_TYPE, CK_MECHANISM_TYPE, &'static [u8])> {
+    match stype {
+        SecretType::P256 => Ok((CKK_EC, CKM_EC_KEY_PAIR_GEN, P256_OID)),
+        SecretType::Curve25519 => Ok((
+            CKK_EC_MONTGOMERY,
+            CKM_EC_MONTGOMERY_KEY_PAIR_GEN,
+            X25519_OID,
+        )),
+        SecretType::Ed25519 => Ok((CKK_EC_EDWARDS, CKM_EC_EDWARDS_KEY_PAIR_GEN, ED25519_OID)),
+        _ => Err(Error::UnsupportedSecretType.into()),
+    }
+}
+
+/// Key type and key generation mechanism of a symmetric secret type
+fn symmetric_key_type(stype: SecretType) -> OckamResult<(CK_KEY_TYPE, CK_MECHANISM_TYPE)> {
+    match stype {
+        SecretType::Aes => Ok((CKK_AES, CKM_AES_KEY_GEN)),
+        SecretType::Buffer => Ok((CKK_GENERIC_SECRET, CKM_GENERIC_SECRET_KEY_GEN)),
+        _ => Err(Error::UnsupportedSecretType.into()),
+    }
+}
+
+fn bool_flag(value: bool) -> CK_BBOOL {
+    if value {
+        CK_TRUE
+    } else {
+        CK_FALSE
+    }
+}
+
+fn token_flag(persistence: SecretPersistence) -> CK_BBOOL {
+    bool_flag(matches!(persistence, SecretPersistence::Persistent))
+}
+
+/// Strip the DER OCTET STRING wrapping of a CKA_EC_POINT, if any
+fn decode_ec_point(point: Vec<u8>, length: usize) -> OckamResult<Vec<u8>> {
+    if point.len() == length {
+        Ok(point)
+    } else if point.len() == length + 2 && point[0] == 0x04 && point[1] as usize == length {
+        Ok(point[2..].to_vec())
+    } else {
+        Err(Error::InvalidPublicKey.into())
+    }
+}
+
+fn encode_ec_point(point: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![0x04, point.len() as u8];
+    encoded.extend_from_slice(point);
+    encoded
+}
+
+impl SecretVault for Pkcs11Vault {
+    fn secret_generate(&mut self, attributes: SecretAttributes) -> OckamResult<Box<dyn Secret>> {
+        let id = self.new_object_id()?;
+        let token = token_flag(attributes.persistence);
+
+        let object = match attributes.stype {
+            SecretType::P256 | SecretType::Curve25519 | SecretType::Ed25519 => {
+                let (_, mechanism, oid) = curve(attributes.stype)?;
+                let sign = bool_flag(attributes.stype != SecretType::Curve25519);
+                let derive = bool_flag(attributes.stype != SecretType::Ed25519);
+                let public_template = [
+                    CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&token),
+                    CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+                    CK_ATTRIBUTE::new(CKA_EC_PARAMS).with_bytes(oid),
+                ];
+                let private_template = [
+                    CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&token),
+                    CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+                    CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&CK_TRUE),
+                    CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&CK_TRUE),
+                    CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&CK_FALSE),
+                    CK_ATTRIBUTE::new(CKA_SIGN).with_bool(&sign),
+                    CK_ATTRIBUTE::new(CKA_DERIVE).with_bool(&derive),
+                ];
+                let mechanism = CK_MECHANISM {
+                    mechanism,
+                    pParameter: ptr::null_mut(),
+                    ulParameterLen: 0,
+                };
+                let (_public, private) = self
+                    .ctx
+                    .generate_key_pair(
+                        self.session,
+                        &mechanism,
+                        &public_template,
+                        &private_template,
+                    )
+                    .map_err(|_| Error::TokenError.into())?;
+                private
+            }
+            SecretType::Aes | SecretType::Buffer => {
+                let (_, mechanism) = symmetric_key_type(attributes.stype)?;
+                let length = attributes.length as CK_ULONG;
+                let aes = bool_flag(attributes.stype == SecretType::Aes);
+                let buffer = bool_flag(attributes.stype == SecretType::Buffer);
+                let template = [
+                    CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&token),
+                    CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+                    CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&length),
+                    CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&aes),
+                    CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&buffer),
+                    CK_ATTRIBUTE::new(CKA_ENCRYPT).with_bool(&aes),
+                    CK_ATTRIBUTE::new(CKA_DECRYPT).with_bool(&aes),
+                    CK_ATTRIBUTE::new(CKA_DERIVE).with_bool(&buffer),
+                    CK_ATTRIBUTE::new(CKA_SIGN).with_bool(&buffer),
+                ];
+                let mechanism = CK_MECHANISM {
+                    mechanism,
+                    pParameter: ptr::null_mut(),
+                    ulParameterLen: 0,
+                };
+                self.ctx
+                    .generate_key(self.session, &mechanism, &template)
+                    .map_err(|_| Error::TokenError.into())?
+            }
+            _ => return Err(Error::UnsupportedSecretType.into()),
+        };
+
+        Ok(Box::new(Pkcs11VaultSecret(object)))
+    }
+
+    fn secret_import(
+        &mut self,
+        secret: &[u8],
+        attributes: SecretAttributes,
+    ) -> OckamResult<Box<dyn Secret>> {
+        let id = self.new_object_id()?;
+        let token = token_flag(attributes.persistence);
+
+        let object = match attributes.stype {
+            SecretType::P256 | SecretType::Curve25519 | SecretType::Ed25519 => {
+                let (key_type, _, oid) = curve(attributes.stype)?;
+                // the token doesn't compute public keys, the software vault does
+                let software_attributes = SecretAttributes {
+                    persistence: SecretPersistence::Ephemeral,
+                    ..attributes
+                };
+                let software_secret = self.v.secret_import(secret, software_attributes)?;
+                let public_key = self.v.secret_public_key_get(&software_secret);
+                self.v.secret_destroy(software_secret)?;
+                let point = encode_ec_point(public_key?.as_ref());
+
+                let sign = bool_flag(attributes.stype != SecretType::Curve25519);
+                let derive = bool_flag(attributes.stype != SecretType::Ed25519);
+                let public_template = [
+                    CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_PUBLIC_KEY),
+                    CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type),
+                    CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&token),
+                    CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+                    CK_ATTRIBUTE::new(CKA_EC_PARAMS).with_bytes(oid),
+                    CK_ATTRIBUTE::new(CKA_EC_POINT).with_bytes(&point),
+                ];
+                let private_template = [
+                    CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_PRIVATE_KEY),
+                    CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type),
+                    CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&token),
+                    CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+                    CK_ATTRIBUTE::new(CKA_EC_PARAMS).with_bytes(oid),
+                    CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(secret),
+                    CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&CK_TRUE),
+                    CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&CK_TRUE),
+                    CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&CK_FALSE),
+                    CK_ATTRIBUTE::new(CKA_SIGN).with_bool(&sign),
+                    CK_ATTRIBUTE::new(CKA_DERIVE).with_bool(&derive),
+                ];
+                let public = self
+                    .ctx
+                    .create_object(self.session, &public_template)
+                    .map_err(|_| Error::TokenError.into())?;
+                match self.ctx.create_object(self.session, &private_template) {
+                    Ok(private) => private,
+                    Err(_) => {
+                        let _ = self.ctx.destroy_object(self.session, public);
+                        return Err(Error::TokenError.into());
+                    }
+                }
+            }
+            SecretType::Aes | SecretType::Buffer => {
+                let (key_type, _) = symmetric_key_type(attributes.stype)?;
+                let aes = bool_flag(attributes.stype == SecretType::Aes);
+                let buffer = bool_flag(attributes.stype == SecretType::Buffer);
+                let template = [
+                    CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_SECRET_KEY),
+                    CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type),
+                    CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&token),
+                    CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+                    CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(secret),
+                    CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&aes),
+                    CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&buffer),
+                    CK_ATTRIBUTE::new(CKA_ENCRYPT).with_bool(&aes),
+                    CK_ATTRIBUTE::new(CKA_DECRYPT).with_bool(&aes),
+                    CK_ATTRIBUTE::new(CKA_DERIVE).with_bool(&buffer),
+                    CK_ATTRIBUTE::new(CKA_SIGN).with_bool(&buffer),
+                ];
+                self.ctx
+                    .create_object(self.session, &template)
+                    .map_err(|_| Error::TokenError.into())?
+            }
+            _ => return Err(Error::UnsupportedSecretType.into()),
+        };
+
+        Ok(Box::new(Pkcs11VaultSecret(object)))
+    }
+
+    fn secret_export(&mut self, context: &Box<dyn Secret>) -> OckamResult<SecretKey> {
+        let object = Self::object(context)?;
+        Ok(SecretKey::new(self.attribute(object, CKA_VALUE)?))
+    }
+
+    fn secret_attributes_get(
+        &mut self,
+        context: &Box<dyn Secret>,
+    ) -> OckamResult<SecretAttributes> {
+        self.object_attributes(Self::object(context)?)
+    }
+
+    fn secret_public_key_get(&mut self, context: &Box<dyn Secret>) -> OckamResult<PublicKey> {
+        let object = Self::object(context)?;
+        let length = match self.object_attributes(object)?.stype {
+            SecretType::P256 => P256_PUBLIC_LENGTH,
+            SecretType::Curve25519 => CURVE25519_PUBLIC_LENGTH,
+            SecretType::Ed25519 => ED25519_PUBLIC_LENGTH,
+            _ => return Err(Error::UnsupportedSecretType.into()),
+        };
+        let point = self.attribute(self.public_key_object(object)?, CKA_EC_POINT)?;
+        Ok(PublicKey::new(decode_ec_point(point, length)?))
+    }
+
+    fn secret_destroy(&mut self, context: Box<dyn Secret>) -> OckamResult<()> {
+        let object = Self::object(&context)?;
+        let id = self.attribute(object, CKA_ID)?;
+        // destroy the public key object of a key pair along with the private key
+        let objects = if id.is_empty() {
+            vec![object]
+        } else {
+            self.find_objects(&[CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id)])?
+        };
+        for object in objects {
+            self.ctx
+                .destroy_object(self.session, object)
+                .map_err(|_| Error::TokenError.into())?;
+        }
+        Ok(())
+    }
+}
+
+impl AsymmetricVault for Pkcs11Vault {
+    fn ec_diffie_hellman(
+        &mut self,
+        context: &Box<dyn Secret>,
+        peer_public_key: &[u8],
+    ) -> OckamResult<Box<dyn Secret>> {
+        let object = Self::object(context)?;
+        let mut params = CK_ECDH1_DERIVE_PARAMS {
+            kdf: CKD_NULL,
+            ulSharedDataLen: 0,
+            pSharedData: ptr::null_mut(),
+            ulPublicDataLen: peer_public_key.len() as CK_ULONG,
+            pPublicData: peer_public_key.as_ptr() as CK_BYTE_PTR,
+        };
+        let mechanism = CK_MECHANISM {
+            mechanism: CKM_ECDH1_DERIVE,
+            pParameter: &mut params as *mut CK_ECDH1_DERIVE_PARAMS as CK_VOID_PTR,
+            ulParameterLen: size_of::<CK_ECDH1_DERIVE_PARAMS>() as CK_ULONG,
+        };
+        // the shared secret is only used as HKDF input, which is computed inside the token
+        let length = DH_SECRET_LENGTH as CK_ULONG;
+        let template = [
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_SECRET_KEY),
+            CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&CKK_GENERIC_SECRET),
+            CK_ATTRIBUTE::new(CKA_VALUE_LEN).with_ck_ulong(&length),
+            CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_FALSE),
+            CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&CK_FALSE),
+            CK_ATTRIBUTE::new(CKA_DERIVE).with_bool(&CK_TRUE),
+        ];
+        let secret = self
+            .ctx
+            .derive_key(self.session, &mechanism, object, &template)
+            .map_err(|_| Error::TokenError.into())?;
+
+        Ok(Box::new(Pkcs11VaultSecret(secret)))
+    }
+}
+
+impl KemVault for Pkcs11Vault {
+    /// Encapsulation only needs the public key, the software vault computes it
+    /// and the shared secret is imported in the token
+    fn kem_encapsulate(&mut self, public_key: &[u8]) -> OckamResult<(Vec<u8>, Box<dyn Secret>)> {
+        let (ciphertext, shared_secret) = self.v.kem_encapsulate(public_key)?;
+        let mut value = self.v.secret_export(&shared_secret)?;
+        self.v.secret_destroy(shared_secret)?;
+        let attributes = SecretAttributes {
+            stype: SecretType::Buffer,
+            persistence: SecretPersistence::Ephemeral,
+            length: value.as_ref().len(),
+        };
+        let secret = self.secret_import(value.as_ref(), attributes);
+        value.zeroize();
+        Ok((ciphertext, secret?))
+    }
+
+    /// Tokens don't hold KEM decapsulation keys
+    fn kem_decapsulate(
+        &mut self,
+        _context: &Box<dyn Secret>,
+        _ciphertext: &[u8],
+    ) -> OckamResult<Box<dyn Secret>> {
+        Err(Error::UnsupportedSecretType.into())
+    }
+}
+
+impl SymmetricVault for Pkcs11Vault {
+    fn aead_aes_gcm_encrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        self.aes_gcm_init(context, nonce, aad, true)?;
+        self.ctx
+            .encrypt(self.session, plaintext)
+            .map_err(|_| Error::TokenError.into())
+    }
+
+    fn aead_aes_gcm_decrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        self.aes_gcm_init(context, nonce, aad, false)?;
+        self.ctx
+            .decrypt(self.session, cipher_text)
+            .map_err(|_| Error::AeadDecryptFailed.into())
+    }
+
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        _context: &Box<dyn Secret>,
+        _plaintext: &[u8],
+        _nonce: &[u8],
+        _aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        Err(Error::UnsupportedSecretType.into())
+    }
+
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        _context: &Box<dyn Secret>,
+        _cipher_text: &[u8],
+        _nonce: &[u8],
+        _aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        Err(Error::UnsupportedSecretType.into())
+    }
+}
+
+impl SignerVault for Pkcs11Vault {
+    fn sign(&mut self, secret_key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<Signature> {
+        let object = Self::object(secret_key)?;
+        let (mechanism, input) = match self.object_attributes(object)?.stype {
+            SecretType::P256 => (CKM_ECDSA, self.v.sha256(data)?.to_vec()),
+            SecretType::Ed25519 => (CKM_EDDSA, data.to_vec()),
+            _ => return Err(Error::UnsupportedSecretType.into()),
+        };
+        let mechanism = CK_MECHANISM {
+            mechanism,
+            pParameter: ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        self.ctx
+            .sign_init(self.session, &mechanism, object)
+            .map_err(|_| Error::TokenError.into())?;
+        let signature = self
+            .ctx
+            .sign(self.session, &input)
+            .map_err(|_| Error::TokenError.into())?;
+        Ok(Signature::new(signature))
+    }
+}
+
+impl VerifierVault for Pkcs11Vault {
+    fn verify(
+        &mut self,
+        signature: &[u8],
+        public_key: &[u8],
+        public_key_type: SecretType,
+        data: &[u8],
+    ) -> OckamResult<()> {
+        self.v.verify(signature, public_key, public_key_type, data)
+    }
+}
+
+impl HashVault for Pkcs11Vault {
+    /// Compute the SHA-256 digest given input `data`
+    fn sha256(&self, data: &[u8]) -> OckamResult<[u8; 32]> {
+        self.v.sha256(data)
+    }
+    /// Compute the HKDF-SHA256 using the specified salt and input key material
+    ///
+    /// and return the output key material of the specified length
+    fn hkdf_sha256(
+        &mut self,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        self.hkdf(HkdfHash::Sha256, salt, info, ikm, output_attributes)
+    }
+
+    /// Compute the SHA-512 digest given input `data`
+    fn sha512(&self, data: &[u8]) -> OckamResult<[u8; 64]> {
+        self.v.sha512(data)
+    }
+
+    /// Compute the HKDF-SHA512 using the specified salt and input key material
+    ///
+    /// and return the output key material of the specified length
+    fn hkdf_sha512(
+        &mut self,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        self.hkdf(HkdfHash::Sha512, salt, info, ikm, output_attributes)
+    }
+
+    /// Compute the HMAC-SHA256 of `data` inside the token, keyed with the buffer secret `key`
+    fn hmac_sha256(&mut self, key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<[u8; 32]> {
+        let object = Self::object(key)?;
+        if self.object_attributes(object)?.stype != SecretType::Buffer {
+            return Err(Error::UnsupportedSecretType.into());
+        }
+        let mechanism = CK_MECHANISM {
+            mechanism: CKM_SHA256_HMAC,
+            pParameter: ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        self.ctx
+            .sign_init(self.session, &mechanism, object)
+            .map_err(|_| Error::TokenError.into())?;
+        let code = self
+            .ctx
+            .sign(self.session, data)
+            .map_err(|_| Error::TokenError.into())?;
+        code.as_slice()
+            .try_into()
+            .map_err(|_| Error::TokenError.into())
+    }
+}
+
+impl RandomVault for Pkcs11Vault {
+    fn random_bytes_generate(&mut self, buffer: &mut [u8]) -> OckamResult<()> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let random = self
+            .ctx
+            .generate_random(self.session, buffer.len() as CK_ULONG)
+            .map_err(|_| Error::TokenError.into())?;
+        if random.len() != buffer.len() {
+            return Err(Error::TokenError.into());
+        }
+        buffer.copy_from_slice(&random);
+        Ok(())
+    }
+}
+
+impl PersistentVault for Pkcs11Vault {
+    fn get_persistence_id(&self, secret: &Box<dyn Secret>) -> OckamResult<String> {
+        let object = Self::object(secret)?;
+        if !self.attribute_bool(object, CKA_TOKEN)? {
+            return Err(Error::InvalidSecret.into());
+        }
+        Ok(hex::encode(self.attribute(object, CKA_ID)?))
+    }
+
+    fn get_persistent_secret(&self, persistence_id: &str) -> OckamResult<Box<dyn Secret>> {
+        let id = hex::decode(persistence_id).map_err(|_| Error::InvalidPersistenceId.into())?;
+        if id.is_empty() {
+            return Err(Error::InvalidPersistenceId.into());
+        }
+        self.find_key(CKA_ID, &id)
+    }
+
+    fn get_persistent_secret_by_label(&self, label: &str) -> OckamResult<Box<dyn Secret>> {
+        self.find_key(CKA_LABEL, label.as_bytes())
+    }
+
+    fn get_secret_metadata(&self, secret: &Box<dyn Secret>) -> OckamResult<SecretMetadata> {
+        let label = self.attribute(Self::object(secret)?, CKA_LABEL)?;
+        let mut metadata = SecretMetadata::default();
+        if !label.is_empty() {
+            metadata.label =
+                Some(String::from_utf8(label).map_err(|_| Error::InvalidSecret.into())?);
+        }
+        Ok(metadata)
+    }
+
+    fn set_secret_metadata(
+        &mut self,
+        secret: &Box<dyn Secret>,
+        metadata: SecretMetadata,
+    ) -> OckamResult<()> {
+        if !metadata.entries.is_empty() {
+            return Err(Error::MetadataNotSupported.into());
+        }
+        let object = Self::object(secret)?;
+        let label = metadata.label.unwrap_or_default();
+        if !label.is_empty() {
+            if let Ok(existing) = self.get_persistent_secret_by_label(&label) {
+                if Self::object(&existing)? != object {
+                    return Err(Error::LabelAlreadyExists.into());
+                }
+            }
+        }
+
+        let template = [CK_ATTRIBUTE::new(CKA_LABEL).with_bytes(label.as_bytes())];
+        self.ctx
+            .set_attribute_value(self.session, object, &template)
+            .map_err(|_| Error::TokenError.into())?;
+        if let Ok(public) = self.public_key_object(object) {
+            self.ctx
+                .set_attribute_value(self.session, public, &template)
+                .map_err(|_| Error::TokenError.into())?;
+        }
+        Ok(())
+    }
+
+    /// A token has no transactions, the labels already set are restored if one can't be set
+    fn set_secrets_metadata(
+        &mut self,
+        updates: Vec<(&Box<dyn Secret>, SecretMetadata)>,
+    ) -> OckamResult<()> {
+        let mut done = Vec::new();
+        for (secret, metadata) in updates {
+            let previous = self.get_secret_metadata(secret)?;
+            if let Err(e) = self.set_secret_metadata(secret, metadata) {
+                for (secret, previous) in done.into_iter().rev() {
+                    self.set_secret_metadata(secret, previous)?;
+                }
+                return Err(e);
+            }
+            done.push((secret, previous));
+        }
+        Ok(())
+    }
+
+    fn list_persistent_secrets(&mut self) -> OckamResult<Vec<PersistentSecretInfo>> {
+        let mut secrets = Vec::new();
+        for class in &[CKO_PRIVATE_KEY, CKO_SECRET_KEY] {
+            let template = [
+                CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(class),
+                CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_TRUE),
+            ];
+            for object in self.find_objects(&template)? {
+                let id = self.attribute(object, CKA_ID)?;
+                // skip keys of other applications this vault can't address or use
+                let attributes = match self.object_attributes(object) {
+                    Ok(attributes) if !id.is_empty() => attributes,
+                    _ => continue,
+                };
+                let secret: Box<dyn Secret> = Box::new(Pkcs11VaultSecret(object));
+                secrets.push(PersistentSecretInfo {
+                    persistence_id: hex::encode(id),
+                    attributes,
+                    metadata: self.get_secret_metadata(&secret)?,
+                });
+            }
+        }
+        Ok(secrets)
+    }
+}
+
+impl Zeroize for Pkcs11Vault {
+    fn zeroize(&mut self) {
+        self.v.zeroize();
+    }
+}
+
+impl Drop for Pkcs11Vault {
+    fn drop(&mut self) {
+        let _ = self.ctx.logout(self.session);
+        let _ = self.ctx.close_session(self.session);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_vault::types::AES256_SECRET_LENGTH;
+
+    /// The tests need a token, e.g. created with
+    /// `softhsm2-util --init-token --free --label ockam --so-pin 0000 --pin 1234`,
+    /// and the module, the slot it was assigned and the pin set in the environment
+    fn test_vault() -> Pkcs11Vault {
+        let var = |name| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+        let slot = var("OCKAM_PKCS11_SLOT")
+            .parse()
+            .expect("OCKAM_PKCS11_SLOT is not a slot id");
+        Pkcs11Vault::new(
+            var("PKCS11_SOFTHSM2_MODULE"),
+            slot,
+            &var("OCKAM_PKCS11_PIN"),
+        )
+        .unwrap()
+    }
+
+    // a single test, a module can only be initialized once per process.
+    // Run with `cargo test -- --ignored` against a token
+    #[test]
+    #[ignore]
+    fn pkcs11_vault() {
+        let mut vault = test_vault();
+
+        let mut random = [0u8; 32];
+        vault.random_bytes_generate(&mut random).unwrap();
+        assert_ne!(random, [0u8; 32]);
+
+        // signing and ECDH inside the token
+        let attributes = SecretAttributes {
+            stype: SecretType::P256,
+            persistence: SecretPersistence::Ephemeral,
+            length: P256_SECRET_LENGTH,
+        };
+        let sk1 = vault.secret_generate(attributes).unwrap();
+        let sk2 = vault.secret_generate(attributes).unwrap();
+        let pk1 = vault.secret_public_key_get(&sk1).unwrap();
+        let pk2 = vault.secret_public_key_get(&sk2).unwrap();
+        assert_eq!(pk1.as_ref().len(), P256_PUBLIC_LENGTH);
+        assert!(vault.secret_export(&sk1).is_err());
+
+        let signature = vault.sign(&sk1, b"hello").unwrap();
+        vault
+            .verify(signature.as_ref(), pk1.as_ref(), SecretType::P256, b"hello")
+            .unwrap();
+
+        // ECDH and HKDF outputs never leave the token, the keys derived on both
+        // sides are used for AES-GCM inside it
+        let dh1 = vault.ec_diffie_hellman(&sk1, pk2.as_ref()).unwrap();
+        let dh2 = vault.ec_diffie_hellman(&sk2, pk1.as_ref()).unwrap();
+        assert!(vault.secret_export(&dh1).is_err());
+        let aes = SecretAttributes {
+            stype: SecretType::Aes,
+            persistence: SecretPersistence::Ephemeral,
+            length: AES256_SECRET_LENGTH,
+        };
+        let buffer = SecretAttributes {
+            stype: SecretType::Buffer,
+            persistence: SecretPersistence::Ephemeral,
+            length: 32,
+        };
+        let mut keys1 = vault
+            .hkdf_sha256(&dh1, b"test", None, vec![buffer, aes])
+            .unwrap();
+        let mut keys2 = vault
+            .hkdf_sha256(&dh2, b"test", None, vec![buffer, aes])
+            .unwrap();
+        let key1 = keys1.pop().unwrap();
+        let key2 = keys2.pop().unwrap();
+        assert_eq!(vault.secret_attributes_get(&key1).unwrap(), aes);
+        assert!(vault.secret_export(&keys1[0]).is_err());
+        let nonce = [0u8; 12];
+        let mut cipher_text = vault
+            .aead_aes_gcm_encrypt(&key1, b"hello", &nonce, b"aad")
+            .unwrap();
+        let plain_text = vault
+            .aead_aes_gcm_decrypt(&key2, &cipher_text, &nonce, b"aad")
+            .unwrap();
+        assert_eq!(plain_text, b"hello");
+        cipher_text[0] ^= 1;
+        assert!(vault
+            .aead_aes_gcm_decrypt(&key2, &cipher_text, &nonce, b"aad")
+            .is_err());
+
+        // with input key material, as in the next step of a key agreement
+        let key = vault
+            .hkdf_sha256(&keys1[0], b"", Some(&dh1), vec![aes])
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert!(vault
+            .aead_aes_gcm_encrypt(&key, b"hello", &nonce, b"")
+            .is_ok());
+
+        let mut secrets = vec![sk1, sk2, dh1, dh2, key1, key2, key];
+        secrets.extend(keys1);
+        secrets.extend(keys2);
+        for secret in secrets {
+            vault.secret_destroy(secret).unwrap();
+        }
+
+        // persistent secrets are token objects
+        let attributes = SecretAttributes {
+            persistence: SecretPersistence::Persistent,
+            ..attributes
+        };
+        let secret = vault.secret_generate(attributes).unwrap();
+        let public_key = vault.secret_public_key_get(&secret).unwrap();
+        let mut metadata = SecretMetadata::default();
+        metadata.label = Some("pkcs11 test".into());
+        vault
+            .set_secret_metadata(&secret, metadata.clone())
+            .unwrap();
+        metadata.entries.insert("key".into(), "value".into());
+        assert!(vault.set_secret_metadata(&secret, metadata).is_err());
+
+        let id = vault.get_persistence_id(&secret).unwrap();
+        let by_id = vault.get_persistent_secret(&id).unwrap();
+        assert_eq!(vault.secret_public_key_get(&by_id).unwrap(), public_key);
+        let by_label = vault.get_persistent_secret_by_label("pkcs11 test").unwrap();
+        assert_eq!(vault.secret_public_key_get(&by_label).unwrap(), public_key);
+        assert!(vault
+            .list_persistent_secrets()
+            .unwrap()
+            .iter()
+            .any(|info| info.persistence_id == id
+                && info.metadata.label.as_deref() == Some("pkcs11 test")));
+
+        vault.secret_destroy(secret).unwrap();
+        assert!(vault.get_persistent_secret(&id).is_err());
+        assert!(vault.get_persistent_secret_by_label("pkcs11 test").is_err());
+    }
+}
@@ -18,6 +18,12 @@ pub enum Error {
     AeadAesGcmDecrypt,
     InvalidSignature,
     HkdfExpandError,
+    InvalidPrivateKey,
+    AeadChaChaPolyEncrypt,
+    AeadChaChaPolyDecrypt,
+    InvalidNonceLength,
+    RandomBytesGenerate,
+    InvalidKemCiphertext,
 }
 
 impl Error {
@@ -2,22 +2,26 @@ use crate::error::*;
 use crate::xeddsa::*;
 use aead::{generic_array::GenericArray, Aead, NewAead, Payload};
 use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use chacha20poly1305::ChaCha20Poly1305;
+use hmac::{Hmac, Mac, NewMac};
 use ockam_common::error::OckamResult;
 use ockam_vault::{
-    types::*, AsymmetricVault, HashVault, Secret, SecretVault, SignerVault, SymmetricVault,
-    VerifierVault,
+    types::*, AsymmetricVault, HashVault, KemVault, RandomVault, Secret, SecretVault, SignerVault,
+    SymmetricVault, VerifierVault, WrappingVault,
 };
 use p256::{
     elliptic_curve::{sec1::FromEncodedPoint, Group},
     AffinePoint, ProjectivePoint, Scalar,
 };
 use rand::{prelude::*, rngs::OsRng};
-use sha2::{Digest, Sha256};
+use sha2::{Digest, Sha256, Sha512};
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use zeroize::Zeroize;
 
 pub extern crate ockam_vault;
 
+mod mlkem;
 mod xeddsa;
 
 pub mod error;
@@ -45,6 +49,16 @@ impl Zeroize for DefaultVaultSecret {
 
 impl Secret for DefaultVaultSecret {}
 
+/// Length of the chunk of output key material each HKDF output is taken from
+const HKDF_OUTPUT_LENGTH: usize = 32;
+
+/// The hash function a HKDF is built on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HkdfHash {
+    Sha256,
+    Sha512,
+}
+
 /// A pure rust implementation of a vault.
 /// Is not thread-safe i.e. if multiple threads
 /// add values to the vault there may be collisions
@@ -118,26 +132,57 @@ impl DefaultVault {
         }
     }
 
-    fn hkdf_sha256_internal(
+    /// Returns the input key material of a HKDF, which must be a buffer
+    fn hkdf_ikm(&self, ikm: Option<&Box<dyn Secret>>) -> OckamResult<Vec<u8>> {
+        match ikm {
+            Some(ikm) => {
+                let ikm = self.get_entry(ikm)?;
+                if ikm.key_attributes.stype == SecretType::Buffer {
+                    Ok(ikm.key.as_ref().to_vec())
+                } else {
+                    Err(Error::InvalidKeyType.into())
+                }
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Run the HKDF built on `hash` on secrets of this vault. Vaults that keep their
+    /// secrets in a DefaultVault run their HKDFs with it, on the secrets their handles stand for
+    pub fn hkdf(
         &mut self,
+        hash: HkdfHash,
         salt: &Box<dyn Secret>,
         info: &[u8],
-        ikm: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
         output_attributes: Vec<SecretAttributes>,
     ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        let ikm = self.hkdf_ikm(ikm)?;
         let salt = self.get_entry(salt)?;
 
-        // FIXME: Doesn't work for secrets with size more than 32 bytes
-        let okm_len = output_attributes.len() * 32;
-
-        let okm = {
-            let mut okm = vec![0u8; okm_len];
-            let prk = hkdf::Hkdf::<Sha256>::new(Some(salt.key.as_ref()), ikm);
-            prk.expand(info, okm.as_mut_slice())
-                .or(Err(Error::HkdfExpandError.into()))?;
-            okm
+        // every output takes a chunk of the output key material, as Noise expects
+        let mut okm = vec![0u8; output_attributes.len() * HKDF_OUTPUT_LENGTH];
+        let expanded = match hash {
+            HkdfHash::Sha256 => {
+                hkdf::Hkdf::<Sha256>::new(Some(salt.key.as_ref()), &ikm).expand(info, &mut okm)
+            }
+            HkdfHash::Sha512 => {
+                hkdf::Hkdf::<Sha512>::new(Some(salt.key.as_ref()), &ikm).expand(info, &mut okm)
+            }
         };
+        expanded.or(Err(Error::HkdfExpandError.into()))?;
+
+        let secrets = self.hkdf_outputs(&okm, output_attributes);
+        okm.zeroize();
+        secrets
+    }
 
+    /// Import consecutive chunks of the output key material as secrets
+    fn hkdf_outputs(
+        &mut self,
+        okm: &[u8],
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
         let mut secrets = Vec::<Box<dyn Secret>>::new();
         let mut index = 0;
 
@@ -147,19 +192,41 @@ impl DefaultVault {
                 if length != AES256_SECRET_LENGTH && length != AES128_SECRET_LENGTH {
                     return Err(Error::InvalidAesKeyLength.into());
                 }
-            } else if attributes.stype != SecretType::Buffer {
+            } else if attributes.stype == SecretType::ChaCha20Poly1305 {
+                if length != CHACHA20POLY1305_SECRET_LENGTH {
+                    return Err(Error::InvalidKeyType.into());
+                }
+            } else if attributes.stype != SecretType::Buffer || length > HKDF_OUTPUT_LENGTH {
                 return Err(Error::InvalidHkdfOutputType.into());
             }
             let secret = &okm[index..index + length];
             let secret = self.secret_import(&secret, attributes)?;
 
             secrets.push(secret);
-            index += 32;
+            index += HKDF_OUTPUT_LENGTH;
         }
 
         Ok(secrets)
     }
 
+    fn chacha20_poly1305_cipher(
+        entry: &VaultEntry,
+        nonce: &[u8],
+        err: Error,
+    ) -> OckamResult<ChaCha20Poly1305> {
+        if entry.key_attributes.stype != SecretType::ChaCha20Poly1305
+            || entry.key.as_ref().len() != CHACHA20POLY1305_SECRET_LENGTH
+        {
+            return Err(err.into());
+        }
+        if nonce.len() != 12 {
+            return Err(Error::InvalidNonceLength.into());
+        }
+        Ok(ChaCha20Poly1305::new(GenericArray::from_slice(
+            entry.key.as_ref(),
+        )))
+    }
+
     pub fn get_ids(&self) -> Vec<usize> {
         self.entries.keys().map(|i| *i).collect()
     }
@@ -248,11 +315,26 @@ impl SecretVault for DefaultVault {
                 value.copy_from_slice(&key.secret_scalar().to_bytes());
                 SecretKey::new(value.to_vec())
             }
+            SecretType::ChaCha20Poly1305 => {
+                let mut key = vec![0u8; CHACHA20POLY1305_SECRET_LENGTH];
+                rng.fill_bytes(key.as_mut_slice());
+                SecretKey::new(key)
+            }
+            SecretType::Ed25519 => {
+                let mut key = vec![0u8; ED25519_SECRET_LENGTH];
+                rng.fill_bytes(key.as_mut_slice());
+                SecretKey::new(key)
+            }
             SecretType::Buffer => {
                 let mut key = vec![0u8; attributes.length];
                 rng.fill_bytes(key.as_mut_slice());
                 SecretKey::new(key)
             }
+            SecretType::MlKem768 => {
+                let mut key = vec![0u8; ML_KEM_768_SECRET_LENGTH];
+                rng.fill_bytes(key.as_mut_slice());
+                SecretKey::new(key)
+            }
         };
         self.next_id += 1;
         self.entries.insert(
@@ -299,6 +381,9 @@ impl SecretVault for DefaultVault {
     fn secret_public_key_get(&mut self, context: &Box<dyn Secret>) -> OckamResult<PublicKey> {
         let entry = self.get_entry(context)?;
 
+        if entry.key_attributes.stype == SecretType::MlKem768 {
+            return mlkem::public_key(entry.key.as_ref()).map(PublicKey::new);
+        }
         if entry.key.as_ref().len() != CURVE25519_SECRET_LENGTH {
             return Err(Error::InvalidPrivateKeyLen.into());
         }
@@ -320,6 +405,12 @@ impl SecretVault for DefaultVault {
                 let ap = p256::elliptic_curve::sec1::EncodedPoint::from(pp.to_affine());
                 Ok(PublicKey::new(ap.as_bytes().to_vec()))
             }
+            SecretType::Ed25519 => {
+                let sk = ed25519_dalek::SecretKey::from_bytes(entry.key.as_ref())
+                    .map_err(|_| Error::InvalidPrivateKeyLen.into())?;
+                let pk = ed25519_dalek::PublicKey::from(&sk);
+                Ok(PublicKey::new(pk.to_bytes().to_vec()))
+            }
             _ => Err(Error::InvalidKeyType.into()),
         }
     }
@@ -346,19 +437,42 @@ impl HashVault for DefaultVault {
         ikm: Option<&Box<dyn Secret>>,
         output_attributes: Vec<SecretAttributes>,
     ) -> OckamResult<Vec<Box<dyn Secret>>> {
-        let ikm_slice = match ikm {
-            Some(ikm) => {
-                let ikm = self.get_entry(ikm)?;
-                if ikm.key_attributes.stype == SecretType::Buffer {
-                    Ok(ikm.key.as_ref().to_vec())
-                } else {
-                    Err(Error::InvalidKeyType.into())
-                }
-            }
-            None => Ok(Vec::new()),
-        }?;
+        self.hkdf(HkdfHash::Sha256, salt, info, ikm, output_attributes)
+    }
 
-        self.hkdf_sha256_internal(salt, info, &ikm_slice, output_attributes)
+    fn sha512(&self, data: &[u8]) -> OckamResult<[u8; 64]> {
+        let digest = Sha512::digest(data);
+        Ok(*array_ref![digest, 0, 64])
+    }
+
+    fn hkdf_sha512(
+        &mut self,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        self.hkdf(HkdfHash::Sha512, salt, info, ikm, output_attributes)
+    }
+
+    fn hmac_sha256(&mut self, key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<[u8; 32]> {
+        let entry = self.get_entry(key)?;
+        if entry.key_attributes.stype != SecretType::Buffer {
+            return Err(Error::InvalidKeyType.into());
+        }
+        let mut mac = Hmac::<Sha256>::new_varkey(entry.key.as_ref())
+            .map_err(|_| Error::InvalidKeyType.into())?;
+        mac.update(data);
+        let code = mac.finalize().into_bytes();
+        Ok(*array_ref![code, 0, 32])
+    }
+}
+
+impl RandomVault for DefaultVault {
+    fn random_bytes_generate(&mut self, buffer: &mut [u8]) -> OckamResult<()> {
+        OsRng
+            .try_fill_bytes(buffer)
+            .map_err(|_| Error::RandomBytesGenerate.into())
     }
 }
 
@@ -381,6 +495,45 @@ impl AsymmetricVault for DefaultVault {
     }
 }
 
+impl KemVault for DefaultVault {
+    fn kem_encapsulate(&mut self, public_key: &[u8]) -> OckamResult<(Vec<u8>, Box<dyn Secret>)> {
+        let mut m = [0u8; 32];
+        OsRng {}.fill_bytes(&mut m);
+        let (ciphertext, mut shared_secret) = mlkem::encapsulate(public_key, &m)?;
+        m.zeroize();
+
+        let attributes = SecretAttributes {
+            stype: SecretType::Buffer,
+            persistence: SecretPersistence::Ephemeral,
+            length: ML_KEM_SHARED_SECRET_LENGTH,
+        };
+        let secret = self.secret_import(&shared_secret, attributes)?;
+        shared_secret.zeroize();
+        Ok((ciphertext, secret))
+    }
+
+    fn kem_decapsulate(
+        &mut self,
+        context: &Box<dyn Secret>,
+        ciphertext: &[u8],
+    ) -> OckamResult<Box<dyn Secret>> {
+        let entry = self.get_entry(context)?;
+        if entry.key_attributes.stype != SecretType::MlKem768 {
+            return Err(Error::InvalidKeyType.into());
+        }
+        let mut shared_secret = mlkem::decapsulate(entry.key.as_ref(), ciphertext)?;
+
+        let attributes = SecretAttributes {
+            stype: SecretType::Buffer,
+            persistence: SecretPersistence::Ephemeral,
+            length: ML_KEM_SHARED_SECRET_LENGTH,
+        };
+        let secret = self.secret_import(&shared_secret, attributes)?;
+        shared_secret.zeroize();
+        Ok(secret)
+    }
+}
+
 impl SymmetricVault for DefaultVault {
     fn aead_aes_gcm_encrypt(
         &mut self,
@@ -418,10 +571,46 @@ impl SymmetricVault for DefaultVault {
             Error::AeadAesGcmDecrypt
         )
     }
+
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        let entry = self.get_entry(context)?;
+        let cipher = Self::chacha20_poly1305_cipher(entry, nonce, Error::AeadChaChaPolyEncrypt)?;
+        let payload = Payload {
+            aad,
+            msg: plaintext,
+        };
+        cipher
+            .encrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|_| Error::AeadChaChaPolyEncrypt.into())
+    }
+
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        let entry = self.get_entry(context)?;
+        let cipher = Self::chacha20_poly1305_cipher(entry, nonce, Error::AeadChaChaPolyDecrypt)?;
+        let payload = Payload {
+            aad,
+            msg: cipher_text,
+        };
+        cipher
+            .decrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|_| Error::AeadChaChaPolyDecrypt.into())
+    }
 }
 
 impl SignerVault for DefaultVault {
-    fn sign(&mut self, secret_key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<[u8; 64]> {
+    fn sign(&mut self, secret_key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<Signature> {
         let entry = self.get_entry(secret_key)?;
         let key = entry.key.as_ref();
         match entry.key_attributes.stype {
@@ -432,43 +621,96 @@ impl SignerVault for DefaultVault {
                 let sig =
                     x25519_dalek::StaticSecret::from(*array_ref!(key, 0, CURVE25519_SECRET_LENGTH))
                         .sign(data.as_ref(), &nonce);
-                Ok(sig)
+                Ok(Signature::new(sig.to_vec()))
+            }
+            SecretType::Ed25519 if key.len() == ED25519_SECRET_LENGTH => {
+                let sk = ed25519_dalek::SecretKey::from_bytes(key)
+                    .map_err(|_| Error::InvalidPrivateKeyLen.into())?;
+                let pk = ed25519_dalek::PublicKey::from(&sk);
+                let sig = ed25519_dalek::ExpandedSecretKey::from(&sk).sign(data.as_ref(), &pk);
+                Ok(Signature::new(sig.to_bytes().to_vec()))
+            }
+            SecretType::P256 if key.len() == P256_SECRET_LENGTH => {
+                use p256::ecdsa::signature::Signer;
+
+                let sign_key = p256::ecdsa::SigningKey::new(key)
+                    .map_err(|_| Error::InvalidPrivateKey.into())?;
+                let sig: p256::ecdsa::Signature = sign_key.sign(data.as_ref());
+                Ok(Signature::new(sig.as_ref().to_vec()))
             }
-            // SecretKey::P256(k) => {
-            //     let sign_key = SigningKey::new(&k)?;
-            //     let sig = sign_key.sign(data.as_ref());
-            //     Ok(*array_ref![sig.as_ref(), 0, 64])
-            // }
             _ => Err(Error::InvalidKeyType.into()),
         }
     }
 }
 
+/// The raw r || s signatures a P-256 signature may stand for. DER signatures can be
+/// 64 bytes long too, so a signature is read as DER first and then, when it has that
+/// length, as raw r || s
+fn p256_signature_candidates(signature: &[u8]) -> OckamResult<Vec<Signature>> {
+    let mut candidates = vec![];
+    if let Ok(der) = Signature::from_der(signature) {
+        candidates.push(der);
+    }
+    if signature.len() == SIGNATURE_LENGTH {
+        candidates.push(Signature::new(signature.to_vec()));
+    }
+    if candidates.is_empty() {
+        return Err(Error::InvalidSignature.into());
+    }
+    Ok(candidates)
+}
+
 impl VerifierVault for DefaultVault {
-    fn verify(&mut self, signature: &[u8; 64], public_key: &[u8], data: &[u8]) -> OckamResult<()> {
-        // FIXME
-        if public_key.len() == CURVE25519_PUBLIC_LENGTH {
-            if x25519_dalek::PublicKey::from(*array_ref!(public_key, 0, CURVE25519_PUBLIC_LENGTH))
-                .verify(data.as_ref(), &signature)
-            {
-                Ok(())
-            } else {
-                Err(Error::InvalidSignature.into())
+    fn verify(
+        &mut self,
+        signature: &[u8],
+        public_key: &[u8],
+        public_key_type: SecretType,
+        data: &[u8],
+    ) -> OckamResult<()> {
+        let verified = match public_key_type {
+            SecretType::Curve25519 => {
+                if public_key.len() != CURVE25519_PUBLIC_LENGTH {
+                    return Err(Error::InvalidPublicKey.into());
+                }
+                if signature.len() != SIGNATURE_LENGTH {
+                    return Err(Error::InvalidSignature.into());
+                }
+                x25519_dalek::PublicKey::from(*array_ref!(public_key, 0, CURVE25519_PUBLIC_LENGTH))
+                    .verify(data.as_ref(), array_ref!(signature, 0, SIGNATURE_LENGTH))
+            }
+            SecretType::Ed25519 => {
+                let pk = ed25519_dalek::PublicKey::from_bytes(public_key)
+                    .map_err(|_| Error::InvalidPublicKey.into())?;
+                let sig = ed25519_dalek::Signature::try_from(signature)
+                    .map_err(|_| Error::InvalidSignature.into())?;
+                pk.verify_strict(data.as_ref(), &sig).is_ok()
+            }
+            SecretType::P256 => {
+                use p256::ecdsa::signature::Verifier;
+
+                let key = p256::ecdsa::VerifyKey::new(public_key)
+                    .map_err(|_| Error::InvalidPublicKey.into())?;
+                let candidates = p256_signature_candidates(signature)?;
+                candidates.iter().any(|raw| {
+                    p256::ecdsa::Signature::try_from(raw.as_ref())
+                        .map(|sig| key.verify(data.as_ref(), &sig).is_ok())
+                        .unwrap_or(false)
+                })
             }
+            _ => return Err(Error::InvalidKeyType.into()),
+        };
+
+        if verified {
+            Ok(())
         } else {
-            Err(Error::InvalidPublicKey.into())
+            Err(Error::InvalidSignature.into())
         }
-        // match public_key {
-        // PublicKey::P256(k) => {
-        //     let key = VerifyKey::new(&k)?;
-        //     let sig = Signature::try_from(&signature[..])?;
-        //     key.verify(data.as_ref(), &sig)?;
-        //     Ok(())
-        // }
-        // }
     }
 }
 
+impl WrappingVault for DefaultVault {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -553,6 +795,56 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sha512() {
+        let vault = DefaultVault::default();
+        let digest = vault.sha512(b"a").unwrap();
+        assert_eq!(
+            hex::encode(&digest[..]),
+            "1f40fc92da241694750979ee6cf582f2d5d7d28e18335de05abc54d0560e0f53\
+             02860c652bf08d560252aa5e74210546f369fbbbce8c12cfc7957b2652fe9a75"
+        );
+    }
+
+    #[test]
+    fn hmac_sha256() {
+        let mut vault = DefaultVault::default();
+        let key_value = b"Jefe";
+        let attributes = SecretAttributes {
+            stype: SecretType::Buffer,
+            persistence: SecretPersistence::Ephemeral,
+            length: key_value.len(),
+        };
+        let key = vault.secret_import(&key_value[..], attributes).unwrap();
+        let code = vault
+            .hmac_sha256(&key, b"what do ya want for nothing?")
+            .unwrap();
+        assert_eq!(
+            hex::encode(code),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let attributes = SecretAttributes {
+            stype: SecretType::Aes,
+            persistence: SecretPersistence::Ephemeral,
+            length: AES256_SECRET_LENGTH,
+        };
+        let aes = vault.secret_generate(attributes).unwrap();
+        assert!(vault.hmac_sha256(&aes, b"data").is_err());
+    }
+
+    #[test]
+    fn random_bytes_generate() {
+        let mut vault = DefaultVault::default();
+        let mut a = [0u8; 32];
+        let mut b = [0u8; 32];
+        vault.random_bytes_generate(&mut a).unwrap();
+        vault.random_bytes_generate(&mut b).unwrap();
+        assert_ne!(a, [0u8; 32]);
+        assert_ne!(a, b);
+        vault.random_bytes_generate(&mut []).unwrap();
+    }
+
     #[test]
     fn hkdf() {
         let mut vault = DefaultVault::default();
@@ -588,6 +880,22 @@ mod tests {
             hex::encode(digest.as_ref()),
             "921ab9f260544b71941dbac2ca2d42c417aa07b53e055a8f"
         );
+
+        let res = vault.hkdf_sha512(&salt, b"", Some(&ikm), vec![attributes]);
+        let digest = vault.secret_export(&res.unwrap()[0]).unwrap();
+        assert_eq!(
+            hex::encode(digest.as_ref()),
+            "2cb642255c1ef119f72766ab9094f3b484a0fd10b4810126"
+        );
+
+        // outputs are taken from 32 byte chunks of the output key material
+        let attributes = SecretAttributes {
+            length: 33,
+            ..attributes
+        };
+        assert!(vault
+            .hkdf(HkdfHash::Sha512, &salt, b"", Some(&ikm), vec![attributes])
+            .is_err());
     }
 
     #[test]
@@ -648,6 +956,61 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn kem_encapsulate_decapsulate() {
+        let mut vault = DefaultVault::default();
+        let attributes = SecretAttributes {
+            stype: SecretType::MlKem768,
+            persistence: SecretPersistence::Ephemeral,
+            length: ML_KEM_768_SECRET_LENGTH,
+        };
+        let sk_ctx = vault.secret_generate(attributes).unwrap();
+        let pk = vault.secret_public_key_get(&sk_ctx).unwrap();
+        assert_eq!(pk.as_ref().len(), ML_KEM_768_PUBLIC_LENGTH);
+
+        let (mut ciphertext, ss_ctx_1) = vault.kem_encapsulate(pk.as_ref()).unwrap();
+        assert_eq!(ciphertext.len(), ML_KEM_768_CIPHERTEXT_LENGTH);
+        let ss_ctx_2 = vault.kem_decapsulate(&sk_ctx, &ciphertext).unwrap();
+        let ss_1 = vault.secret_export(&ss_ctx_1).unwrap();
+        let ss_2 = vault.secret_export(&ss_ctx_2).unwrap();
+        assert_eq!(ss_1, ss_2);
+
+        ciphertext[0] ^= 1;
+        let ss_ctx_3 = vault.kem_decapsulate(&sk_ctx, &ciphertext).unwrap();
+        assert_ne!(ss_1, vault.secret_export(&ss_ctx_3).unwrap());
+        assert!(vault.kem_decapsulate(&sk_ctx, &ciphertext[1..]).is_err());
+        assert!(vault.kem_encapsulate(&pk.as_ref()[1..]).is_err());
+    }
+
+    #[test]
+    fn ml_kem_768_test_vector() {
+        let seed: Vec<u8> = (0..64).collect();
+        let m: Vec<u8> = (100..132).collect();
+        let ek = mlkem::public_key(&seed).unwrap();
+        assert_eq!(
+            hex::encode(Sha256::digest(&ek)),
+            "0b7934c83125c788995e2ba6bd761e33046b3e40571be53e023309a29f398cc9"
+        );
+
+        let (mut ciphertext, ss) = mlkem::encapsulate(&ek, array_ref!(m, 0, 32)).unwrap();
+        assert_eq!(
+            hex::encode(Sha256::digest(&ciphertext)),
+            "57fe559432dbb3c5547c73f155820622f7efdd532e4330360a36ebf7d2ddec55"
+        );
+        assert_eq!(
+            hex::encode(ss),
+            "c5a74110c158acbaf9c01deb86fa6cc10c14533feda54bec1fdd000d61f07e4e"
+        );
+        assert_eq!(mlkem::decapsulate(&seed, &ciphertext).unwrap(), ss);
+
+        // implicit rejection
+        ciphertext[0] ^= 1;
+        assert_eq!(
+            hex::encode(mlkem::decapsulate(&seed, &ciphertext).unwrap()),
+            "bb28c25ed3222c13ce49d65f663f1c9f148565a664747e142f1abe06f33f4826"
+        );
+    }
+
     #[test]
     fn encryption() {
         let mut vault = DefaultVault::default();
@@ -675,6 +1038,40 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn encryption_chacha20_poly1305() {
+        let mut vault = DefaultVault::default();
+        // RFC 8439 section 2.8.2
+        let key = hex::decode("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f")
+            .unwrap();
+        let nonce = hex::decode("070000004041424344454647").unwrap();
+        let aad = hex::decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let message = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+        let attributes = SecretAttributes {
+            stype: SecretType::ChaCha20Poly1305,
+            persistence: SecretPersistence::Ephemeral,
+            length: CHACHA20POLY1305_SECRET_LENGTH,
+        };
+
+        let ctx = &vault.secret_import(&key, attributes).unwrap();
+        let res = vault.aead_chacha20_poly1305_encrypt(ctx, message.as_ref(), &nonce, &aad);
+        assert!(res.is_ok());
+        let mut ciphertext = res.unwrap();
+        assert_eq!(
+            hex::encode(&ciphertext[ciphertext.len() - 16..]),
+            "1ae10b594f09e26a7e902ecbd0600691"
+        );
+        let res = vault.aead_chacha20_poly1305_decrypt(ctx, &ciphertext, &nonce, &aad);
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), message.to_vec());
+        ciphertext[0] ^= ciphertext[1];
+        let res = vault.aead_chacha20_poly1305_decrypt(ctx, &ciphertext, &nonce, &aad);
+        assert!(res.is_err());
+
+        let res = vault.aead_aes_gcm_encrypt(ctx, message.as_ref(), &nonce, &aad);
+        assert!(res.is_err());
+    }
+
     #[test]
     fn sign() {
         let mut vault = DefaultVault::default();
@@ -689,7 +1086,166 @@ mod tests {
         assert!(res.is_ok());
         let pubkey = vault.secret_public_key_get(&secret).unwrap();
         let signature = res.unwrap();
-        let res = vault.verify(&signature, pubkey.as_ref(), b"hello world!");
+        let res = vault.verify(
+            signature.as_ref(),
+            pubkey.as_ref(),
+            SecretType::Curve25519,
+            b"hello world!",
+        );
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn sign_ed25519() {
+        let mut vault = DefaultVault::default();
+        // RFC 8032 test vector 2
+        let secret = vault
+            .secret_import(
+                &hex::decode("4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb")
+                    .unwrap(),
+                SecretAttributes {
+                    persistence: SecretPersistence::Ephemeral,
+                    stype: SecretType::Ed25519,
+                    length: ED25519_SECRET_LENGTH,
+                },
+            )
+            .unwrap();
+        let pubkey = vault.secret_public_key_get(&secret).unwrap();
+        assert_eq!(
+            hex::encode(pubkey.as_ref()),
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c"
+        );
+        let signature = vault.sign(&secret, &[0x72]).unwrap();
+        assert_eq!(
+            hex::encode(signature.as_ref()),
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00"
+        );
+        let res = vault.verify(
+            signature.as_ref(),
+            pubkey.as_ref(),
+            SecretType::Ed25519,
+            &[0x72],
+        );
+        assert!(res.is_ok());
+        let res = vault.verify(
+            signature.as_ref(),
+            pubkey.as_ref(),
+            SecretType::Curve25519,
+            &[0x72],
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn sign_p256() {
+        let mut vault = DefaultVault::default();
+        let secret = vault
+            .secret_generate(SecretAttributes {
+                persistence: SecretPersistence::Ephemeral,
+                stype: SecretType::P256,
+                length: P256_SECRET_LENGTH,
+            })
+            .unwrap();
+        let pubkey = vault.secret_public_key_get(&secret).unwrap();
+        let signature = vault.sign(&secret, b"hello world!").unwrap();
+        assert_eq!(signature.as_ref().len(), SIGNATURE_LENGTH);
+        let res = vault.verify(
+            signature.as_ref(),
+            pubkey.as_ref(),
+            SecretType::P256,
+            b"hello world!",
+        );
+        assert!(res.is_ok());
+
+        let der = signature.to_der().unwrap();
+        assert_eq!(Signature::from_der(der.as_ref()).unwrap(), signature);
+        let res = vault.verify(
+            der.as_ref(),
+            pubkey.as_ref(),
+            SecretType::P256,
+            b"hello world!",
+        );
         assert!(res.is_ok());
+        let res = vault.verify(der.as_ref(), pubkey.as_ref(), SecretType::P256, b"hello");
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn p256_signature_formats() {
+        // a DER signature of two 29 byte integers is 64 bytes long
+        let mut der = vec![0x30, 0x3e, 0x02, 0x1d];
+        der.extend_from_slice(&[0x11; 29]);
+        der.extend_from_slice(&[0x02, 0x1d]);
+        der.extend_from_slice(&[0x22; 29]);
+        assert_eq!(der.len(), SIGNATURE_LENGTH);
+        let candidates = p256_signature_candidates(&der).unwrap();
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0], Signature::from_der(&der).unwrap());
+        assert_eq!(candidates[1].as_ref(), der.as_slice());
+
+        let raw = [0x7f; SIGNATURE_LENGTH];
+        let candidates = p256_signature_candidates(&raw).unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].as_ref(), &raw[..]);
+
+        assert!(p256_signature_candidates(&[0x30; 10]).is_err());
+    }
+
+    #[test]
+    fn wrapped_export_import() {
+        let mut sender = DefaultVault::default();
+        let mut recipient = DefaultVault::default();
+
+        for stype in &[SecretType::Curve25519, SecretType::P256] {
+            let key_attributes = SecretAttributes {
+                stype: *stype,
+                persistence: SecretPersistence::Ephemeral,
+                length: 32,
+            };
+            let recipient_key = recipient.secret_generate(key_attributes).unwrap();
+            let recipient_public_key = recipient.secret_public_key_get(&recipient_key).unwrap();
+            let signing_key = sender.secret_generate(key_attributes).unwrap();
+            let signing_public_key = sender.secret_public_key_get(&signing_key).unwrap();
+
+            let secret = sender
+                .secret_import(
+                    &[7u8; 32],
+                    SecretAttributes {
+                        stype: SecretType::Aes,
+                        persistence: SecretPersistence::Persistent,
+                        length: AES256_SECRET_LENGTH,
+                    },
+                )
+                .unwrap();
+            let mut bundle = sender
+                .secret_export_wrapped(&secret, recipient_public_key.as_ref(), &signing_key)
+                .unwrap();
+            assert!(!bundle.windows(32).any(|w| w == [7u8; 32]));
+
+            let imported = recipient
+                .secret_import_wrapped(&bundle, &recipient_key, signing_public_key.as_ref(), *stype)
+                .unwrap();
+            assert_eq!(
+                recipient.secret_export(&imported).unwrap().as_ref(),
+                &[7u8; 32]
+            );
+            assert_eq!(
+                recipient.secret_attributes_get(&imported).unwrap(),
+                sender.secret_attributes_get(&secret).unwrap()
+            );
+
+            bundle[20] ^= 1;
+            assert!(
+                recipient
+                    .secret_import_wrapped(
+                        &bundle,
+                        &recipient_key,
+                        signing_public_key.as_ref(),
+                        *stype,
+                    )
+                    .is_err()
+            );
+        }
     }
 }
@@ -0,0 +1,477 @@
+//! ML-KEM-768 key encapsulation according to
+//! <https://csrc.nist.gov/pubs/fips/203/final>
+//!
+//! Decapsulation keys are kept as the 64 byte seed `d || z` the key pair
+//! is derived from, the expanded keys are recomputed when needed.
+
+use crate::error::Error;
+use ockam_common::error::OckamResult;
+use ockam_vault::types::{
+    ML_KEM_768_CIPHERTEXT_LENGTH, ML_KEM_768_PUBLIC_LENGTH, ML_KEM_768_SECRET_LENGTH,
+    ML_KEM_SHARED_SECRET_LENGTH,
+};
+use sha3::digest::{Digest, ExtendableOutput, Update, XofReader};
+use sha3::{Sha3_256, Sha3_512, Shake128, Shake256};
+use zeroize::Zeroize;
+
+const N: usize = 256;
+const Q: u32 = 3329;
+const K: usize = 3;
+const ETA1: usize = 2;
+const ETA2: usize = 2;
+const DU: usize = 10;
+const DV: usize = 4;
+const POLY_BYTES: usize = 384;
+
+type Poly = [u32; N];
+
+/// ceil(2^38 / Q), see `div_q`
+const DIV_Q_MULTIPLIER: u64 = 82570715;
+
+/// `n / Q` for every `n < 2^26`, as a multiplication and a shift. A division of secret
+/// coefficients may be compiled to a `div` instruction, whose timing leaks them (KyberSlash)
+fn div_q(n: u32) -> u32 {
+    ((n as u64 * DIV_Q_MULTIPLIER) >> 38) as u32
+}
+
+/// `n mod Q` for every `n < 2^26`, in constant time
+fn reduce(n: u32) -> u32 {
+    n - div_q(n) * Q
+}
+
+fn zeroize_vector(v: &mut [Poly]) {
+    for p in v.iter_mut() {
+        p[..].zeroize();
+    }
+}
+
+const fn pow_mod(base: u32, exponent: u32) -> u32 {
+    let mut result = 1;
+    let mut base = base;
+    let mut exponent = exponent;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result * base % Q;
+        }
+        base = base * base % Q;
+        exponent >>= 1;
+    }
+    result
+}
+
+const fn bit_rev7(i: u32) -> u32 {
+    let mut reversed = 0;
+    let mut bit = 0;
+    while bit < 7 {
+        reversed |= ((i >> bit) & 1) << (6 - bit);
+        bit += 1;
+    }
+    reversed
+}
+
+/// 17^BitRev7(i) mod q, the twiddle factors of the NTT
+const fn zetas() -> [u32; 128] {
+    let mut zetas = [0; 128];
+    let mut i = 0;
+    while i < 128 {
+        zetas[i] = pow_mod(17, bit_rev7(i as u32));
+        i += 1;
+    }
+    zetas
+}
+
+/// 17^(2 BitRev7(i) + 1) mod q, used to multiply in the NTT domain
+const fn gammas() -> [u32; 128] {
+    let mut gammas = [0; 128];
+    let mut i = 0;
+    while i < 128 {
+        gammas[i] = pow_mod(17, 2 * bit_rev7(i as u32) + 1);
+        i += 1;
+    }
+    gammas
+}
+
+const ZETAS: [u32; 128] = zetas();
+const GAMMAS: [u32; 128] = gammas();
+
+fn ntt(f: &mut Poly) {
+    let mut i = 1;
+    let mut len = 128;
+    while len >= 2 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[i];
+            i += 1;
+            for j in start..start + len {
+                let t = reduce(zeta * f[j + len]);
+                f[j + len] = reduce(f[j] + Q - t);
+                f[j] = reduce(f[j] + t);
+            }
+        }
+        len /= 2;
+    }
+}
+
+fn ntt_inverse(f: &mut Poly) {
+    let mut i = 127;
+    let mut len = 2;
+    while len <= 128 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[i];
+            i -= 1;
+            for j in start..start + len {
+                let t = f[j];
+                f[j] = reduce(t + f[j + len]);
+                f[j + len] = reduce(zeta * reduce(f[j + len] + Q - t));
+            }
+        }
+        len *= 2;
+    }
+    // 3303 = 128^-1 mod q
+    for x in f.iter_mut() {
+        *x = reduce(*x * 3303);
+    }
+}
+
+fn multiply_ntts(f: &Poly, g: &Poly) -> Poly {
+    let mut h = [0; N];
+    for i in 0..N / 2 {
+        let (a0, a1, b0, b1) = (f[2 * i], f[2 * i + 1], g[2 * i], g[2 * i + 1]);
+        h[2 * i] = reduce(reduce(a0 * b0) + reduce(a1 * b1) * GAMMAS[i]);
+        h[2 * i + 1] = reduce(a0 * b1 + a1 * b0);
+    }
+    h
+}
+
+fn add(f: &Poly, g: &Poly) -> Poly {
+    let mut h = [0; N];
+    for i in 0..N {
+        h[i] = reduce(f[i] + g[i]);
+    }
+    h
+}
+
+fn sub(f: &Poly, g: &Poly) -> Poly {
+    let mut h = [0; N];
+    for i in 0..N {
+        h[i] = reduce(f[i] + Q - g[i]);
+    }
+    h
+}
+
+/// Sum of the products of two vectors in the NTT domain
+fn inner_product(f: &[Poly; K], g: &[Poly; K]) -> Poly {
+    let mut h = [0; N];
+    for i in 0..K {
+        h = add(&h, &multiply_ntts(&f[i], &g[i]));
+    }
+    h
+}
+
+/// Uniformly sample a polynomial in the NTT domain from SHAKE128(rho || j || i)
+fn sample_ntt(rho: &[u8], j: u8, i: u8) -> Poly {
+    let mut xof = Shake128::default();
+    xof.update(rho);
+    xof.update([j, i]);
+    let mut reader = xof.finalize_xof();
+
+    let mut a = [0; N];
+    let mut n = 0;
+    let mut c = [0u8; 3];
+    while n < N {
+        reader.read(&mut c);
+        let d1 = c[0] as u32 + 256 * (c[1] as u32 % 16);
+        let d2 = c[1] as u32 / 16 + 16 * c[2] as u32;
+        if d1 < Q {
+            a[n] = d1;
+            n += 1;
+        }
+        if d2 < Q && n < N {
+            a[n] = d2;
+            n += 1;
+        }
+    }
+    a
+}
+
+/// The public matrix A in the NTT domain, transposed when `transpose` is set
+fn sample_matrix(rho: &[u8], transpose: bool) -> [[Poly; K]; K] {
+    let mut a = [[[0; N]; K]; K];
+    for (i, row) in a.iter_mut().enumerate() {
+        for (j, entry) in row.iter_mut().enumerate() {
+            *entry = if transpose {
+                sample_ntt(rho, i as u8, j as u8)
+            } else {
+                sample_ntt(rho, j as u8, i as u8)
+            };
+        }
+    }
+    a
+}
+
+/// Sample a polynomial from the centered binomial distribution of parameter `eta`
+/// with SHAKE256(sigma || nonce) as randomness
+fn sample_cbd(sigma: &[u8], nonce: u8, eta: usize) -> Poly {
+    let mut prf = Shake256::default();
+    prf.update(sigma);
+    prf.update([nonce]);
+    let mut bytes = vec![0u8; 64 * eta];
+    prf.finalize_xof().read(&mut bytes);
+
+    let bit = |k: usize| (bytes[k / 8] as u32 >> (k % 8)) & 1;
+    let mut f = [0; N];
+    for (i, x) in f.iter_mut().enumerate() {
+        let (mut a, mut b) = (0, 0);
+        for j in 0..eta {
+            a += bit(2 * i * eta + j);
+            b += bit(2 * i * eta + eta + j);
+        }
+        *x = reduce(a + Q - b);
+    }
+    bytes.zeroize();
+    f
+}
+
+fn byte_encode(f: &Poly, d: usize, output: &mut Vec<u8>) {
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &x in f.iter() {
+        acc |= x << bits;
+        bits += d;
+        while bits >= 8 {
+            output.push(acc as u8);
+            acc >>= 8;
+            bits -= 8;
+        }
+    }
+}
+
+/// Decode the 32 * `d` bytes of `input`, coefficients are reduced mod q when `d` is 12
+fn byte_decode(input: &[u8], d: usize) -> Poly {
+    let mut f = [0; N];
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut input = input.iter();
+    for x in f.iter_mut() {
+        while bits < d {
+            acc |= (*input.next().unwrap_or(&0) as u32) << bits;
+            bits += 8;
+        }
+        *x = acc & ((1 << d) - 1);
+        acc >>= d;
+        bits -= d;
+        if d == 12 {
+            *x = reduce(*x);
+        }
+    }
+    f
+}
+
+fn compress(f: &Poly, d: usize) -> Poly {
+    let mut h = [0; N];
+    for i in 0..N {
+        h[i] = div_q((f[i] << d) + Q / 2) & ((1 << d) - 1);
+    }
+    h
+}
+
+fn decompress(f: &Poly, d: usize) -> Poly {
+    let mut h = [0; N];
+    for i in 0..N {
+        h[i] = (f[i] * Q + (1 << (d - 1))) >> d;
+    }
+    h
+}
+
+/// K-PKE.KeyGen: returns the encapsulation key and the secret vector in the NTT domain
+fn pke_key_gen(d: &[u8]) -> (Vec<u8>, [Poly; K]) {
+    let mut g = Sha3_512::digest(&[d, &[K as u8]].concat());
+    let (rho, sigma) = g.split_at(32);
+    let a = sample_matrix(rho, false);
+
+    let mut s = [[0; N]; K];
+    let mut e = [[0; N]; K];
+    for i in 0..K {
+        s[i] = sample_cbd(sigma, i as u8, ETA1);
+        e[i] = sample_cbd(sigma, (K + i) as u8, ETA1);
+        ntt(&mut s[i]);
+        ntt(&mut e[i]);
+    }
+
+    let mut ek = Vec::with_capacity(ML_KEM_768_PUBLIC_LENGTH);
+    for i in 0..K {
+        let t = add(&inner_product(&a[i], &s), &e[i]);
+        byte_encode(&t, 12, &mut ek);
+    }
+    ek.extend_from_slice(rho);
+
+    g[..].zeroize();
+    zeroize_vector(&mut e);
+    (ek, s)
+}
+
+/// K-PKE.Encrypt of the 32 byte message `m` with randomness `r`
+fn pke_encrypt(ek: &[u8], m: &[u8], r: &[u8]) -> Vec<u8> {
+    let mut t = [[0; N]; K];
+    for (i, t) in t.iter_mut().enumerate() {
+        *t = byte_decode(&ek[POLY_BYTES * i..POLY_BYTES * (i + 1)], 12);
+    }
+    let a_transposed = sample_matrix(&ek[POLY_BYTES * K..], true);
+
+    let mut y = [[0; N]; K];
+    let mut e1 = [[0; N]; K];
+    for i in 0..K {
+        y[i] = sample_cbd(r, i as u8, ETA1);
+        e1[i] = sample_cbd(r, (K + i) as u8, ETA2);
+        ntt(&mut y[i]);
+    }
+    let e2 = sample_cbd(r, (2 * K) as u8, ETA2);
+
+    let mut ciphertext = Vec::with_capacity(ML_KEM_768_CIPHERTEXT_LENGTH);
+    for i in 0..K {
+        let mut u = inner_product(&a_transposed[i], &y);
+        ntt_inverse(&mut u);
+        let u = add(&u, &e1[i]);
+        byte_encode(&compress(&u, DU), DU, &mut ciphertext);
+    }
+
+    let mu = decompress(&byte_decode(m, 1), 1);
+    let mut v = inner_product(&t, &y);
+    ntt_inverse(&mut v);
+    let v = add(&add(&v, &e2), &mu);
+    byte_encode(&compress(&v, DV), DV, &mut ciphertext);
+
+    zeroize_vector(&mut y);
+    zeroize_vector(&mut e1);
+    ciphertext
+}
+
+/// K-PKE.Decrypt, returns the 32 byte message
+fn pke_decrypt(s: &[Poly; K], ciphertext: &[u8]) -> Vec<u8> {
+    let mut u = [[0; N]; K];
+    for (i, u) in u.iter_mut().enumerate() {
+        *u = decompress(
+            &byte_decode(&ciphertext[32 * DU * i..32 * DU * (i + 1)], DU),
+            DU,
+        );
+        ntt(u);
+    }
+    let v = decompress(&byte_decode(&ciphertext[32 * DU * K..], DV), DV);
+
+    let mut su = inner_product(s, &u);
+    ntt_inverse(&mut su);
+    let mut w = sub(&v, &su);
+
+    let mut m = Vec::with_capacity(32);
+    byte_encode(&compress(&w, 1), 1, &mut m);
+    su[..].zeroize();
+    w[..].zeroize();
+    m
+}
+
+fn check_seed(seed: &[u8]) -> OckamResult<()> {
+    if seed.len() != ML_KEM_768_SECRET_LENGTH {
+        return Err(Error::InvalidPrivateKeyLen.into());
+    }
+    Ok(())
+}
+
+/// Derive the encapsulation key of the decapsulation key seed `d || z`
+pub(crate) fn public_key(seed: &[u8]) -> OckamResult<Vec<u8>> {
+    check_seed(seed)?;
+    let (ek, mut s) = pke_key_gen(&seed[..32]);
+    zeroize_vector(&mut s);
+    Ok(ek)
+}
+
+/// Encapsulate a shared secret to `ek` using the 32 random bytes `m`,
+/// returns the ciphertext and the shared secret
+pub(crate) fn encapsulate(
+    ek: &[u8],
+    m: &[u8; 32],
+) -> OckamResult<(Vec<u8>, [u8; ML_KEM_SHARED_SECRET_LENGTH])> {
+    if ek.len() != ML_KEM_768_PUBLIC_LENGTH {
+        return Err(Error::InvalidPublicKey.into());
+    }
+    // modulus check: every coefficient of the key must already be reduced
+    let mut reencoded = Vec::with_capacity(POLY_BYTES * K);
+    for i in 0..K {
+        let t = byte_decode(&ek[POLY_BYTES * i..POLY_BYTES * (i + 1)], 12);
+        byte_encode(&t, 12, &mut reencoded);
+    }
+    if reencoded[..] != ek[..POLY_BYTES * K] {
+        return Err(Error::InvalidPublicKey.into());
+    }
+
+    let mut g = Sha3_512::digest(&[&m[..], &Sha3_256::digest(ek)[..]].concat());
+    let (shared_secret, r) = g.split_at(ML_KEM_SHARED_SECRET_LENGTH);
+    let ciphertext = pke_encrypt(ek, m, r);
+    let shared_secret = *array_ref!(shared_secret, 0, ML_KEM_SHARED_SECRET_LENGTH);
+    g[..].zeroize();
+    Ok((ciphertext, shared_secret))
+}
+
+/// Recover the shared secret of `ciphertext` with the decapsulation key seed `d || z`.
+/// A ciphertext that doesn't re-encrypt to itself yields an unrelated pseudorandom secret.
+pub(crate) fn decapsulate(
+    seed: &[u8],
+    ciphertext: &[u8],
+) -> OckamResult<[u8; ML_KEM_SHARED_SECRET_LENGTH]> {
+    check_seed(seed)?;
+    if ciphertext.len() != ML_KEM_768_CIPHERTEXT_LENGTH {
+        return Err(Error::InvalidKemCiphertext.into());
+    }
+    let (ek, mut s) = pke_key_gen(&seed[..32]);
+    let z = &seed[32..];
+
+    let mut m = pke_decrypt(&s, ciphertext);
+    let mut g = Sha3_512::digest(&[&m[..], &Sha3_256::digest(&ek)[..]].concat());
+    let (shared_secret, r) = g.split_at(ML_KEM_SHARED_SECRET_LENGTH);
+    let reencrypted = pke_encrypt(&ek, &m, r);
+
+    let mut rejection = [0u8; ML_KEM_SHARED_SECRET_LENGTH];
+    let mut j = Shake256::default();
+    j.update(z);
+    j.update(ciphertext);
+    j.finalize_xof().read(&mut rejection);
+
+    // select in constant time
+    let difference = reencrypted
+        .iter()
+        .zip(ciphertext)
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    let mask = ((difference as u16).wrapping_sub(1) >> 8) as u8;
+    let mut result = [0u8; ML_KEM_SHARED_SECRET_LENGTH];
+    for i in 0..ML_KEM_SHARED_SECRET_LENGTH {
+        result[i] = (shared_secret[i] & mask) | (rejection[i] & !mask);
+    }
+
+    zeroize_vector(&mut s);
+    m.zeroize();
+    g[..].zeroize();
+    rejection.zeroize();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn division_by_q() {
+        for n in 0..1u32 << 26 {
+            assert_eq!(div_q(n), n / Q);
+        }
+        for d in 1..12 {
+            let mut f = [0; N];
+            for start in (0..Q).step_by(N) {
+                for (i, x) in f.iter_mut().enumerate() {
+                    *x = (start + i as u32).min(Q - 1);
+                }
+                let h = compress(&f, d);
+                for i in 0..N {
+                    assert_eq!(h[i], (((f[i] << d) + Q / 2) / Q) & ((1 << d) - 1));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use ockam_common::error::OckamError;
+
+/// Represents the failures that can occur in
+/// an Ockam SQLite Vault
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    None,
+    SecretFromAnotherVault,
+    InvalidSecret,
+    DatabaseError,
+    InvalidPersistenceId,
+    EntryNotFound,
+    UnsupportedSchemaVersion,
+    LabelAlreadyExists,
+}
+
+impl Error {
+    /// Error domain
+    pub const ERROR_DOMAIN: &'static str = "VAULT_SQLITE_ERROR_DOMAIN";
+}
+
+impl Into<OckamError> for Error {
+    fn into(self) -> OckamError {
+        OckamError::new(self as u32, Error::ERROR_DOMAIN)
+    }
+}
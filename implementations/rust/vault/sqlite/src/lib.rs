@@ -0,0 +1,868 @@
+use crate::error::*;
+use ockam_vault::types::{
+    PersistentSecretInfo, PublicKey, SecretAttributes, SecretKey, SecretMetadata,
+    SecretPersistence, SecretType, Signature,
+};
+use ockam_vault::{
+    AsymmetricVault, HashVault, KemVault, PersistentVault, RandomVault, RotationVault, Secret,
+    SecretVault, SignerVault, SymmetricVault, VerifierVault, WrappingVault,
+};
+use ockam_vault_software::{DefaultVault, HkdfHash};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior, NO_PARAMS};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::time::Duration;
+use zeroize::Zeroize;
+
+use ockam_common::error::OckamResult;
+pub use ockam_vault;
+
+pub mod error;
+
+const ATTRS_BYTE_LENGTH: usize = 6;
+/// Schema migrations, the schema version is the number of migrations applied
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE secrets (
+         id INTEGER PRIMARY KEY AUTOINCREMENT,
+         attributes BLOB NOT NULL,
+         secret BLOB NOT NULL
+     );",
+    "ALTER TABLE secrets ADD COLUMN label TEXT;
+     CREATE UNIQUE INDEX secrets_label ON secrets (label);
+     CREATE TABLE metadata (
+         secret_id INTEGER NOT NULL REFERENCES secrets (id),
+         key TEXT NOT NULL,
+         value TEXT NOT NULL,
+         PRIMARY KEY (secret_id, key)
+     );",
+];
+/// How long to wait for another process holding a lock on the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A SqliteVault is an implementation of an Ockam Vault that wraps the software vault and uses
+/// a single SQLite database as a persistent store.
+///
+/// Persistent secrets are stored as rows and loaded on first use, so several processes can
+/// share the same database. Loaded secrets are forgotten whenever another process changes the
+/// database, so a secret destroyed elsewhere is not used anymore. Ephemeral secrets never
+/// leave memory.
+#[derive(Debug)]
+pub struct SqliteVault {
+    v: DefaultVault,
+    conn: Connection,
+    /// The database's `data_version` when the persistent secrets were loaded
+    data_version: i64,
+    persistent: BTreeMap<i64, Box<dyn Secret>>,
+    ephemeral: BTreeMap<usize, Box<dyn Secret>>,
+    next_id: usize,
+}
+
+/// SQLite vault secret
+#[derive(Debug, Copy, Clone)]
+pub enum SqliteVaultSecret {
+    /// A secret stored in the database, identified by its row id
+    Persistent(i64),
+    /// A secret only held in memory
+    Ephemeral(usize),
+}
+
+impl SqliteVaultSecret {
+    pub fn downcast_secret(context: &Box<dyn Secret>) -> OckamResult<&Self> {
+        context
+            .downcast_ref::<SqliteVaultSecret>()
+            .map_err(|_| Error::SecretFromAnotherVault.into())
+    }
+}
+
+impl Zeroize for SqliteVaultSecret {
+    fn zeroize(&mut self) {}
+}
+
+impl Secret for SqliteVaultSecret {}
+
+impl SqliteVault {
+    /// Creates a new SqliteVault storing secrets in the database at `path`, creating it if needed.
+    pub fn new<P: AsRef<Path>>(path: P) -> OckamResult<Self> {
+        let conn = Connection::open(path).map_err(|_| Error::DatabaseError.into())?;
+        Self::with_connection(conn)
+    }
+
+    /// Creates a new SqliteVault backed by an in-memory database.
+    pub fn in_memory() -> OckamResult<Self> {
+        let conn = Connection::open_in_memory().map_err(|_| Error::DatabaseError.into())?;
+        Self::with_connection(conn)
+    }
+
+    fn with_connection(mut conn: Connection) -> OckamResult<Self> {
+        conn.busy_timeout(BUSY_TIMEOUT)
+            .map_err(|_| Error::DatabaseError.into())?;
+        // WAL lets readers in other processes proceed while a secret is being written
+        let _: String = conn
+            .query_row("PRAGMA journal_mode=WAL", NO_PARAMS, |row| row.get(0))
+            .map_err(|_| Error::DatabaseError.into())?;
+
+        for (version, migration) in MIGRATIONS.iter().enumerate() {
+            // take the write lock before checking, another process may be migrating too
+            let tx = conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|_| Error::DatabaseError.into())?;
+            let current: usize =
+                tx.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get::<_, u32>(0))
+                    .map_err(|_| Error::DatabaseError.into())? as usize;
+            if current > MIGRATIONS.len() {
+                return Err(Error::UnsupportedSchemaVersion.into());
+            }
+            if current > version {
+                continue;
+            }
+            tx.execute_batch(migration)
+                .and_then(|_| tx.execute_batch(&format!("PRAGMA user_version = {}", version + 1)))
+                .and_then(|_| tx.commit())
+                .map_err(|_| Error::DatabaseError.into())?;
+        }
+
+        let data_version = Self::data_version(&conn)?;
+        Ok(Self {
+            v: DefaultVault::default(),
+            conn,
+            data_version,
+            persistent: BTreeMap::new(),
+            ephemeral: BTreeMap::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Changes whenever another connection commits to the database
+    fn data_version(conn: &Connection) -> OckamResult<i64> {
+        conn.query_row("PRAGMA data_version", NO_PARAMS, |row| row.get(0))
+            .map_err(|_| Error::DatabaseError.into())
+    }
+
+    /// Forget the loaded persistent secrets if another process changed the database since
+    fn forget_if_changed(&mut self) -> OckamResult<()> {
+        let data_version = Self::data_version(&self.conn)?;
+        if data_version == self.data_version {
+            return Ok(());
+        }
+        self.data_version = data_version;
+        for (_, secret) in std::mem::take(&mut self.persistent) {
+            self.v.secret_destroy(secret)?;
+        }
+        Ok(())
+    }
+
+    fn get_entry<'a>(
+        persistent: &'a BTreeMap<i64, Box<dyn Secret>>,
+        ephemeral: &'a BTreeMap<usize, Box<dyn Secret>>,
+        context: &Box<dyn Secret>,
+    ) -> OckamResult<&'a Box<dyn Secret>> {
+        match SqliteVaultSecret::downcast_secret(context)? {
+            SqliteVaultSecret::Persistent(id) => persistent.get(id),
+            SqliteVaultSecret::Ephemeral(id) => ephemeral.get(id),
+        }
+        .ok_or(Error::InvalidSecret.into())
+    }
+
+    /// Make sure a persistent secret is loaded from the database into the software vault
+    fn load(&mut self, context: &Box<dyn Secret>) -> OckamResult<()> {
+        let id = match SqliteVaultSecret::downcast_secret(context)? {
+            SqliteVaultSecret::Persistent(id) => *id,
+            SqliteVaultSecret::Ephemeral(_) => return Ok(()),
+        };
+        self.forget_if_changed()?;
+        if self.persistent.contains_key(&id) {
+            return Ok(());
+        }
+
+        let (attrs, mut secret): (Vec<u8>, Vec<u8>) = self
+            .conn
+            .query_row(
+                "SELECT attributes, secret FROM secrets WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(|_| Error::DatabaseError.into())?
+            .ok_or(Error::EntryNotFound.into())?;
+
+        if attrs.len() != ATTRS_BYTE_LENGTH {
+            secret.zeroize();
+            return Err(Error::InvalidSecret.into());
+        }
+        let mut attrs_bytes = [0u8; ATTRS_BYTE_LENGTH];
+        attrs_bytes.copy_from_slice(&attrs);
+        let imported = SecretAttributes::try_from(attrs_bytes)
+            .and_then(|attributes| self.v.secret_import(&secret, attributes));
+        secret.zeroize();
+
+        self.persistent.insert(id, imported?);
+        Ok(())
+    }
+
+    /// Hand out handles for secrets created by the software vault, writing the persistent
+    /// ones to the database in a single transaction. The secrets are destroyed if they can't
+    /// be written.
+    fn store(&mut self, secrets: Vec<Box<dyn Secret>>) -> OckamResult<Vec<Box<dyn Secret>>> {
+        let ids = match self.insert(&secrets) {
+            Ok(ids) => ids,
+            Err(e) => {
+                for secret in secrets {
+                    self.v.secret_destroy(secret)?;
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(secrets
+            .into_iter()
+            .zip(ids)
+            .map(|(secret, id)| {
+                let handle = match id {
+                    Some(id) => {
+                        self.persistent.insert(id, secret);
+                        SqliteVaultSecret::Persistent(id)
+                    }
+                    None => {
+                        self.next_id += 1;
+                        self.ephemeral.insert(self.next_id, secret);
+                        SqliteVaultSecret::Ephemeral(self.next_id)
+                    }
+                };
+                let handle: Box<dyn Secret> = Box::new(handle);
+                handle
+            })
+            .collect())
+    }
+
+    /// Write the persistent secrets to the database, returns their row ids
+    fn insert(&mut self, secrets: &[Box<dyn Secret>]) -> OckamResult<Vec<Option<i64>>> {
+        let mut rows = Vec::new();
+        for secret in secrets {
+            let attributes = self.v.secret_attributes_get(secret)?;
+            if matches!(attributes.persistence, SecretPersistence::Persistent) {
+                rows.push(Some((attributes, self.v.secret_export(secret)?)));
+            } else {
+                rows.push(None);
+            }
+        }
+
+        let tx = self
+            .conn
+            .transaction()
+            .map_err(|_| Error::DatabaseError.into())?;
+        let mut ids = Vec::new();
+        for row in &rows {
+            match row {
+                Some((attributes, key)) => {
+                    tx.execute(
+                        "INSERT INTO secrets (attributes, secret) VALUES (?1, ?2)",
+                        params![&attributes.to_bytes()[..], key.as_ref()],
+                    )
+                    .map_err(|_| Error::DatabaseError.into())?;
+                    ids.push(Some(tx.last_insert_rowid()));
+                }
+                None => ids.push(None),
+            }
+        }
+        tx.commit().map_err(|_| Error::DatabaseError.into())?;
+        Ok(ids)
+    }
+
+    fn metadata_entries(&self, id: i64) -> OckamResult<BTreeMap<String, String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT key, value FROM metadata WHERE secret_id = ?1")
+            .map_err(|_| Error::DatabaseError.into())?;
+        let rows = stmt
+            .query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|_| Error::DatabaseError.into())?;
+        rows.collect::<Result<_, _>>()
+            .map_err(|_| Error::DatabaseError.into())
+    }
+
+    fn store_one(&mut self, secret: Box<dyn Secret>) -> OckamResult<Box<dyn Secret>> {
+        self.store(vec![secret])?
+            .pop()
+            .ok_or(Error::InvalidSecret.into())
+    }
+
+    /// Run a HKDF of the software vault, persistent outputs are written together, or not at all
+    fn hkdf(
+        &mut self,
+        hash: HkdfHash,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        self.load(salt)?;
+        if let Some(ikm) = ikm {
+            self.load(ikm)?;
+        }
+        let ikm = match ikm {
+            Some(secret) => Some(Self::get_entry(&self.persistent, &self.ephemeral, secret)?),
+            None => None,
+        };
+        let salt_context = Self::get_entry(&self.persistent, &self.ephemeral, salt)?;
+
+        let secrets = self
+            .v
+            .hkdf(hash, salt_context, info, ikm, output_attributes)?;
+        self.store(secrets)
+    }
+}
+
+impl SecretVault for SqliteVault {
+    /// Create a new secret key
+    fn secret_generate(&mut self, attributes: SecretAttributes) -> OckamResult<Box<dyn Secret>> {
+        let ctx = self.v.secret_generate(attributes)?;
+        self.store_one(ctx)
+    }
+
+    /// Import a secret key into the vault
+    fn secret_import(
+        &mut self,
+        secret: &[u8],
+        attributes: SecretAttributes,
+    ) -> OckamResult<Box<dyn Secret>> {
+        let ctx = self.v.secret_import(secret, attributes)?;
+        self.store_one(ctx)
+    }
+
+    /// Export a secret key from the vault
+    fn secret_export(&mut self, context: &Box<dyn Secret>) -> OckamResult<SecretKey> {
+        self.load(context)?;
+        let context = Self::get_entry(&self.persistent, &self.ephemeral, context)?;
+        self.v.secret_export(context)
+    }
+
+    /// Get the attributes for a secret key
+    fn secret_attributes_get(
+        &mut self,
+        context: &Box<dyn Secret>,
+    ) -> OckamResult<SecretAttributes> {
+        self.load(context)?;
+        let context = Self::get_entry(&self.persistent, &self.ephemeral, context)?;
+        self.v.secret_attributes_get(context)
+    }
+
+    /// Return the associated public key given the secret key
+    fn secret_public_key_get(&mut self, context: &Box<dyn Secret>) -> OckamResult<PublicKey> {
+        self.load(context)?;
+        let context = Self::get_entry(&self.persistent, &self.ephemeral, context)?;
+        self.v.secret_public_key_get(context)
+    }
+
+    /// Remove a secret key from the vault
+    fn secret_destroy(&mut self, context: Box<dyn Secret>) -> OckamResult<()> {
+        let secret = match SqliteVaultSecret::downcast_secret(&context)? {
+            SqliteVaultSecret::Persistent(id) => {
+                let tx = self
+                    .conn
+                    .transaction()
+                    .map_err(|_| Error::DatabaseError.into())?;
+                let deleted = tx
+                    .execute("DELETE FROM metadata WHERE secret_id = ?1", params![id])
+                    .and_then(|_| tx.execute("DELETE FROM secrets WHERE id = ?1", params![id]))
+                    .map_err(|_| Error::DatabaseError.into())?;
+                tx.commit().map_err(|_| Error::DatabaseError.into())?;
+                match self.persistent.remove(id) {
+                    Some(secret) => Some(secret),
+                    None if deleted > 0 => None,
+                    None => return Err(Error::EntryNotFound.into()),
+                }
+            }
+            SqliteVaultSecret::Ephemeral(id) => Some(
+                self.ephemeral
+                    .remove(id)
+                    .ok_or(Error::EntryNotFound.into())?,
+            ),
+        };
+
+        if let Some(secret) = secret {
+            self.v.secret_destroy(secret)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AsymmetricVault for SqliteVault {
+    /// Compute Elliptic-Curve Diffie-Hellman using this secret key
+    ///
+    /// and the specified uncompressed public key
+    fn ec_diffie_hellman(
+        &mut self,
+        context: &Box<dyn Secret>,
+        peer_public_key: &[u8],
+    ) -> OckamResult<Box<dyn Secret>> {
+        self.load(context)?;
+        let context = Self::get_entry(&self.persistent, &self.ephemeral, context)?;
+        let ecdh = self.v.ec_diffie_hellman(context, peer_public_key)?;
+        self.store_one(ecdh)
+    }
+}
+
+impl KemVault for SqliteVault {
+    fn kem_encapsulate(&mut self, public_key: &[u8]) -> OckamResult<(Vec<u8>, Box<dyn Secret>)> {
+        let (ciphertext, shared_secret) = self.v.kem_encapsulate(public_key)?;
+        Ok((ciphertext, self.store_one(shared_secret)?))
+    }
+
+    fn kem_decapsulate(
+        &mut self,
+        context: &Box<dyn Secret>,
+        ciphertext: &[u8],
+    ) -> OckamResult<Box<dyn Secret>> {
+        self.load(context)?;
+        let context = Self::get_entry(&self.persistent, &self.ephemeral, context)?;
+        let shared_secret = self.v.kem_decapsulate(context, ciphertext)?;
+        self.store_one(shared_secret)
+    }
+}
+
+impl SymmetricVault for SqliteVault {
+    /// Encrypt a payload using AES-GCM
+    fn aead_aes_gcm_encrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        self.load(context)?;
+        let context = Self::get_entry(&self.persistent, &self.ephemeral, context)?;
+        self.v.aead_aes_gcm_encrypt(context, plaintext, nonce, aad)
+    }
+
+    /// Decrypt a payload using AES-GCM
+    fn aead_aes_gcm_decrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        self.load(context)?;
+        let context = Self::get_entry(&self.persistent, &self.ephemeral, context)?;
+        self.v
+            .aead_aes_gcm_decrypt(context, cipher_text, nonce, aad)
+    }
+
+    /// Encrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        self.load(context)?;
+        let context = Self::get_entry(&self.persistent, &self.ephemeral, context)?;
+        self.v
+            .aead_chacha20_poly1305_encrypt(context, plaintext, nonce, aad)
+    }
+
+    /// Decrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>> {
+        self.load(context)?;
+        let context = Self::get_entry(&self.persistent, &self.ephemeral, context)?;
+        self.v
+            .aead_chacha20_poly1305_decrypt(context, cipher_text, nonce, aad)
+    }
+}
+
+impl SignerVault for SqliteVault {
+    fn sign(&mut self, secret_key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<Signature> {
+        self.load(secret_key)?;
+        let context = Self::get_entry(&self.persistent, &self.ephemeral, secret_key)?;
+        self.v.sign(context, data)
+    }
+}
+
+impl VerifierVault for SqliteVault {
+    fn verify(
+        &mut self,
+        signature: &[u8],
+        public_key: &[u8],
+        public_key_type: SecretType,
+        data: &[u8],
+    ) -> OckamResult<()> {
+        self.v.verify(signature, public_key, public_key_type, data)
+    }
+}
+
+impl HashVault for SqliteVault {
+    /// Compute the SHA-256 digest given input `data`
+    fn sha256(&self, data: &[u8]) -> OckamResult<[u8; 32]> {
+        self.v.sha256(data)
+    }
+    /// Compute the HKDF-SHA256 using the specified salt and input key material
+    ///
+    /// and return the output key material of the specified length
+    fn hkdf_sha256(
+        &mut self,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        self.hkdf(HkdfHash::Sha256, salt, info, ikm, output_attributes)
+    }
+
+    /// Compute the SHA-512 digest given input `data`
+    fn sha512(&self, data: &[u8]) -> OckamResult<[u8; 64]> {
+        self.v.sha512(data)
+    }
+
+    /// Compute the HKDF-SHA512 using the specified salt and input key material
+    ///
+    /// and return the output key material of the specified length
+    fn hkdf_sha512(
+        &mut self,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>> {
+        self.hkdf(HkdfHash::Sha512, salt, info, ikm, output_attributes)
+    }
+
+    /// Compute the HMAC-SHA256 of `data` keyed with the buffer secret `key`
+    fn hmac_sha256(&mut self, key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<[u8; 32]> {
+        self.load(key)?;
+        let key = Self::get_entry(&self.persistent, &self.ephemeral, key)?;
+        self.v.hmac_sha256(key, data)
+    }
+}
+
+impl RandomVault for SqliteVault {
+    fn random_bytes_generate(&mut self, buffer: &mut [u8]) -> OckamResult<()> {
+        self.v.random_bytes_generate(buffer)
+    }
+}
+
+impl PersistentVault for SqliteVault {
+    fn get_persistence_id(&self, secret: &Box<dyn Secret>) -> OckamResult<String> {
+        match SqliteVaultSecret::downcast_secret(secret)? {
+            SqliteVaultSecret::Persistent(id) => Ok(id.to_string()),
+            SqliteVaultSecret::Ephemeral(_) => Err(Error::InvalidSecret.into()),
+        }
+    }
+
+    fn get_persistent_secret(&self, persistence_id: &str) -> OckamResult<Box<dyn Secret>> {
+        let id: i64 = persistence_id
+            .parse()
+            .map_err(|_| Error::InvalidPersistenceId.into())?;
+
+        // the database is the source of truth, as other processes may add or remove secrets
+        let exists = self
+            .conn
+            .query_row("SELECT 1 FROM secrets WHERE id = ?1", params![id], |_| {
+                Ok(())
+            })
+            .optional()
+            .map_err(|_| Error::DatabaseError.into())?
+            .is_some();
+
+        if exists {
+            Ok(Box::new(SqliteVaultSecret::Persistent(id)))
+        } else {
+            Err(Error::InvalidPersistenceId.into())
+        }
+    }
+
+    fn get_persistent_secret_by_label(&self, label: &str) -> OckamResult<Box<dyn Secret>> {
+        let id: i64 = self
+            .conn
+            .query_row(
+                "SELECT id FROM secrets WHERE label = ?1",
+                params![label],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|_| Error::DatabaseError.into())?
+            .ok_or(Error::EntryNotFound.into())?;
+
+        Ok(Box::new(SqliteVaultSecret::Persistent(id)))
+    }
+
+    fn get_secret_metadata(&self, secret: &Box<dyn Secret>) -> OckamResult<SecretMetadata> {
+        let id = match SqliteVaultSecret::downcast_secret(secret)? {
+            SqliteVaultSecret::Persistent(id) => *id,
+            SqliteVaultSecret::Ephemeral(_) => return Err(Error::InvalidSecret.into()),
+        };
+
+        let label: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT label FROM secrets WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|_| Error::DatabaseError.into())?
+            .ok_or(Error::EntryNotFound.into())?;
+
+        Ok(SecretMetadata {
+            label,
+            entries: self.metadata_entries(id)?,
+        })
+    }
+
+    fn set_secret_metadata(
+        &mut self,
+        secret: &Box<dyn Secret>,
+        metadata: SecretMetadata,
+    ) -> OckamResult<()> {
+        self.set_secrets_metadata(vec![(secret, metadata)])
+    }
+
+    /// Updates all secrets in one transaction
+    fn set_secrets_metadata(
+        &mut self,
+        updates: Vec<(&Box<dyn Secret>, SecretMetadata)>,
+    ) -> OckamResult<()> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|_| Error::DatabaseError.into())?;
+        for (secret, metadata) in updates {
+            let id = match SqliteVaultSecret::downcast_secret(secret)? {
+                SqliteVaultSecret::Persistent(id) => *id,
+                SqliteVaultSecret::Ephemeral(_) => return Err(Error::InvalidSecret.into()),
+            };
+
+            if let Some(label) = &metadata.label {
+                let owner: Option<i64> = tx
+                    .query_row(
+                        "SELECT id FROM secrets WHERE label = ?1",
+                        params![label],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .map_err(|_| Error::DatabaseError.into())?;
+                if owner.map_or(false, |owner| owner != id) {
+                    return Err(Error::LabelAlreadyExists.into());
+                }
+            }
+
+            let updated = tx
+                .execute(
+                    "UPDATE secrets SET label = ?1 WHERE id = ?2",
+                    params![metadata.label, id],
+                )
+                .map_err(|_| Error::DatabaseError.into())?;
+            if updated == 0 {
+                return Err(Error::EntryNotFound.into());
+            }
+            tx.execute("DELETE FROM metadata WHERE secret_id = ?1", params![id])
+                .map_err(|_| Error::DatabaseError.into())?;
+            for (key, value) in &metadata.entries {
+                tx.execute(
+                    "INSERT INTO metadata (secret_id, key, value) VALUES (?1, ?2, ?3)",
+                    params![id, key, value],
+                )
+                .map_err(|_| Error::DatabaseError.into())?;
+            }
+        }
+
+        tx.commit().map_err(|_| Error::DatabaseError.into())
+    }
+
+    fn list_persistent_secrets(&mut self) -> OckamResult<Vec<PersistentSecretInfo>> {
+        let rows: Vec<(i64, Vec<u8>, Option<String>)> = {
+            let mut stmt = self
+                .conn
+                .prepare("SELECT id, attributes, label FROM secrets ORDER BY id")
+                .map_err(|_| Error::DatabaseError.into())?;
+            let rows = stmt
+                .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .map_err(|_| Error::DatabaseError.into())?;
+            rows.collect::<Result<_, _>>()
+                .map_err(|_| Error::DatabaseError.into())?
+        };
+
+        rows.into_iter()
+            .map(|(id, attrs, label)| {
+                if attrs.len() != ATTRS_BYTE_LENGTH {
+                    return Err(Error::InvalidSecret.into());
+                }
+                let mut attrs_bytes = [0u8; ATTRS_BYTE_LENGTH];
+                attrs_bytes.copy_from_slice(&attrs);
+
+                Ok(PersistentSecretInfo {
+                    persistence_id: id.to_string(),
+                    attributes: SecretAttributes::try_from(attrs_bytes)?,
+                    metadata: SecretMetadata {
+                        label,
+                        entries: self.metadata_entries(id)?,
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+impl RotationVault for SqliteVault {}
+
+impl WrappingVault for SqliteVault {}
+
+impl Zeroize for SqliteVault {
+    fn zeroize(&mut self) {
+        self.v.zeroize();
+        self.persistent.clear();
+        self.ephemeral.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_vault::types::{AES256_SECRET_LENGTH, CURVE25519_SECRET_LENGTH};
+
+    fn test_path(name: &str) -> std::path::PathBuf {
+        let path = std::path::PathBuf::from(name);
+        for suffix in &["", "-wal", "-shm"] {
+            let file = std::path::PathBuf::from(format!("{}{}", name, suffix));
+            if file.exists() {
+                std::fs::remove_file(file).unwrap();
+            }
+        }
+        path
+    }
+
+    #[test]
+    fn persistence_test() {
+        let path = test_path("__sqlite_persistence_test.db");
+        let mut vault = SqliteVault::new(&path).unwrap();
+        let atts = SecretAttributes {
+            stype: SecretType::Curve25519,
+            persistence: SecretPersistence::Persistent,
+            length: CURVE25519_SECRET_LENGTH,
+        };
+        let sk1 = vault.secret_generate(atts).unwrap();
+        let sk2 = vault.secret_generate(atts).unwrap();
+        let sk_data1 = vault.secret_export(&sk1).unwrap();
+        let sk_data2 = vault.secret_export(&sk2).unwrap();
+        let sk1_persistence_id = vault.get_persistence_id(&sk1).unwrap();
+        let sk2_persistence_id = vault.get_persistence_id(&sk2).unwrap();
+
+        // a second connection sees the secrets while the first one is still open
+        let mut vault2 = SqliteVault::new(&path).unwrap();
+        let sk1_2 = vault2.get_persistent_secret(&sk1_persistence_id).unwrap();
+        let sk2_2 = vault2.get_persistent_secret(&sk2_persistence_id).unwrap();
+        assert_eq!(sk_data1, vault2.secret_export(&sk1_2).unwrap());
+        assert_eq!(sk_data2, vault2.secret_export(&sk2_2).unwrap());
+
+        // a secret destroyed by the second connection isn't served from the first one's cache
+        vault2.secret_destroy(sk1_2).unwrap();
+        assert!(vault.get_persistent_secret(&sk1_persistence_id).is_err());
+        assert!(vault.secret_export(&sk1).is_err());
+        assert_eq!(sk_data2, vault.secret_export(&sk2).unwrap());
+    }
+
+    #[test]
+    fn ephemeral_secrets_are_not_stored() {
+        let mut vault = SqliteVault::in_memory().unwrap();
+        let atts = SecretAttributes {
+            stype: SecretType::Curve25519,
+            persistence: SecretPersistence::Ephemeral,
+            length: CURVE25519_SECRET_LENGTH,
+        };
+        let sk = vault.secret_generate(atts).unwrap();
+        assert!(vault.get_persistence_id(&sk).is_err());
+
+        let count: i64 = vault
+            .conn
+            .query_row("SELECT COUNT(*) FROM secrets", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn hkdf_persistent_outputs() {
+        let mut vault = SqliteVault::in_memory().unwrap();
+        let salt = vault
+            .secret_import(
+                &[0u8; 32],
+                SecretAttributes {
+                    stype: SecretType::Buffer,
+                    persistence: SecretPersistence::Ephemeral,
+                    length: 32,
+                },
+            )
+            .unwrap();
+        let output = SecretAttributes {
+            stype: SecretType::Aes,
+            persistence: SecretPersistence::Persistent,
+            length: AES256_SECRET_LENGTH,
+        };
+        let keys = vault
+            .hkdf_sha256(&salt, b"", None, vec![output, output])
+            .unwrap();
+        assert_eq!(keys.len(), 2);
+        for key in &keys {
+            let id = vault.get_persistence_id(key).unwrap();
+            assert!(vault.get_persistent_secret(&id).is_ok());
+        }
+    }
+
+    #[test]
+    fn metadata_test() {
+        let mut vault = SqliteVault::in_memory().unwrap();
+        let atts = SecretAttributes {
+            stype: SecretType::Curve25519,
+            persistence: SecretPersistence::Persistent,
+            length: CURVE25519_SECRET_LENGTH,
+        };
+        let sk1 = vault.secret_generate(atts).unwrap();
+        let sk2 = vault.secret_generate(atts).unwrap();
+
+        let mut metadata = SecretMetadata::default();
+        metadata.label = Some("my-sensor-identity".into());
+        metadata.entries.insert("owner".into(), "ops".into());
+        vault.set_secret_metadata(&sk1, metadata.clone()).unwrap();
+        assert!(vault.set_secret_metadata(&sk2, metadata.clone()).is_err());
+
+        let sk = vault
+            .get_persistent_secret_by_label("my-sensor-identity")
+            .unwrap();
+        assert_eq!(
+            vault.get_persistence_id(&sk).unwrap(),
+            vault.get_persistence_id(&sk1).unwrap()
+        );
+        assert_eq!(vault.get_secret_metadata(&sk).unwrap(), metadata);
+
+        let list = vault.list_persistent_secrets().unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].metadata, metadata);
+        assert_eq!(list[1].metadata, SecretMetadata::default());
+
+        // a label moves between secrets in one update, or not at all
+        let sk3 = vault.secret_generate(atts).unwrap();
+        let mut retired = metadata.clone();
+        retired.label = Some("my-sensor-identity@1".into());
+        assert!(vault
+            .set_secrets_metadata(vec![(&sk1, retired.clone()), (&sk3, retired.clone())])
+            .is_err());
+        assert_eq!(vault.get_secret_metadata(&sk1).unwrap(), metadata);
+        vault
+            .set_secrets_metadata(vec![(&sk1, retired.clone()), (&sk3, metadata.clone())])
+            .unwrap();
+        assert_eq!(vault.get_secret_metadata(&sk1).unwrap(), retired);
+        assert_eq!(vault.get_secret_metadata(&sk3).unwrap(), metadata);
+
+        vault.secret_destroy(sk3).unwrap();
+        assert!(vault
+            .get_persistent_secret_by_label("my-sensor-identity")
+            .is_err());
+    }
+}
@@ -10,6 +10,20 @@ pub enum Error {
     UnknownSecretTypeValue,
     /// An unknown secret persistence value was supplied
     UnknownSecretPersistenceValue,
+    /// A signature had an unexpected length
+    InvalidSignatureLength,
+    /// An ASN.1 DER signature could not be decoded
+    InvalidDerSignature,
+    /// A well-known secret metadata entry holds an invalid value
+    InvalidSecretMetadata,
+    /// The system clock is set before the unix epoch
+    SystemTimeError,
+    /// A public key had an unexpected length
+    InvalidPublicKeyLength,
+    /// A wrapped secret bundle could not be decoded
+    InvalidWrappedSecret,
+    /// A wrapped secret bundle has an unknown format version
+    UnsupportedWrappedSecretVersion,
 }
 
 impl Error {
@@ -44,9 +44,17 @@ pub mod error;
 pub mod types;
 
 use ockam_common::error::OckamResult;
+use std::convert::TryFrom;
 use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use types::*;
 
+/// Prefix of a wrapped secret bundle
+const WRAPPED_SECRET_MAGIC: &[u8; 4] = b"OKWS";
+const WRAPPED_SECRET_VERSION: u8 = 1;
+const WRAPPED_SECRET_INFO: &[u8] = b"ockam wrapped secret";
+
 /// Secret
 pub trait Secret: Debug + Sync + Send + 'static + downcast::Any + Zeroize {}
 
@@ -75,14 +83,22 @@ pub trait SecretVault: Zeroize {
 
 /// Trait with sign functionality
 pub trait SignerVault: Zeroize {
-    /// Generate a signature
-    fn sign(&mut self, secret_key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<[u8; 64]>;
+    /// Generate a signature using the algorithm selected by the secret type:
+    /// XEdDSA for Curve25519, Ed25519 for Ed25519 and raw (r || s) ECDSA for P256
+    fn sign(&mut self, secret_key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<Signature>;
 }
 
 /// Trait with verify functionality
 pub trait VerifierVault: Zeroize {
-    /// Verify a signature
-    fn verify(&mut self, signature: &[u8; 64], public_key: &[u8], data: &[u8]) -> OckamResult<()>;
+    /// Verify a signature using the algorithm selected by the public key type.
+    /// ECDSA P256 signatures may be either raw (r || s) or ASN.1 DER encoded
+    fn verify(
+        &mut self,
+        signature: &[u8],
+        public_key: &[u8],
+        public_key_type: SecretType,
+        data: &[u8],
+    ) -> OckamResult<()>;
 }
 
 /// Trait with symmetric encryption
@@ -103,6 +119,22 @@ pub trait SymmetricVault: Zeroize {
         nonce: &[u8],
         aad: &[u8],
     ) -> OckamResult<Vec<u8>>;
+    /// Encrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_encrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        plaintext: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>>;
+    /// Decrypt a payload using ChaCha20-Poly1305
+    fn aead_chacha20_poly1305_decrypt(
+        &mut self,
+        context: &Box<dyn Secret>,
+        cipher_text: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> OckamResult<Vec<u8>>;
 }
 
 /// Vault with asymmetric encryption functionality
@@ -116,6 +148,22 @@ pub trait AsymmetricVault: Zeroize {
     ) -> OckamResult<Box<dyn Secret>>;
 }
 
+/// Vault with key encapsulation functionality
+///
+/// Key pairs are created with `secret_generate` and `secret_public_key_get`
+/// like any other asymmetric secret, e.g. with `SecretType::MlKem768`.
+pub trait KemVault: Zeroize {
+    /// Encapsulate a fresh shared secret to the encapsulation key `public_key`,
+    /// returns the ciphertext for the key owner and the shared secret
+    fn kem_encapsulate(&mut self, public_key: &[u8]) -> OckamResult<(Vec<u8>, Box<dyn Secret>)>;
+    /// Recover the shared secret encapsulated in `ciphertext` using the decapsulation key
+    fn kem_decapsulate(
+        &mut self,
+        context: &Box<dyn Secret>,
+        ciphertext: &[u8],
+    ) -> OckamResult<Box<dyn Secret>>;
+}
+
 /// Vault with hashing functionality
 pub trait HashVault: Zeroize {
     /// Compute the SHA-256 digest given input `data`
@@ -129,6 +177,25 @@ pub trait HashVault: Zeroize {
         ikm: Option<&Box<dyn Secret>>,
         output_attributes: Vec<SecretAttributes>,
     ) -> OckamResult<Vec<Box<dyn Secret>>>;
+    /// Compute the SHA-512 digest given input `data`
+    fn sha512(&self, data: &[u8]) -> OckamResult<[u8; 64]>;
+    /// Compute the HKDF-SHA512 using the specified salt and input key material
+    /// and return the output key material of the specified length
+    fn hkdf_sha512(
+        &mut self,
+        salt: &Box<dyn Secret>,
+        info: &[u8],
+        ikm: Option<&Box<dyn Secret>>,
+        output_attributes: Vec<SecretAttributes>,
+    ) -> OckamResult<Vec<Box<dyn Secret>>>;
+    /// Compute the HMAC-SHA256 of `data` keyed with the buffer secret `key`
+    fn hmac_sha256(&mut self, key: &Box<dyn Secret>, data: &[u8]) -> OckamResult<[u8; 32]>;
+}
+
+/// Vault with a cryptographically secure random number generator
+pub trait RandomVault: Zeroize {
+    /// Fill `buffer` with random bytes
+    fn random_bytes_generate(&mut self, buffer: &mut [u8]) -> OckamResult<()>;
 }
 
 /// Trait for vault with persistence capabilities
@@ -138,4 +205,268 @@ pub trait PersistentVault: Zeroize {
 
     /// Returns persistent secret using id
     fn get_persistent_secret(&self, persistence_id: &str) -> OckamResult<Box<dyn Secret>>;
+
+    /// Returns the persistent secret carrying `label`
+    fn get_persistent_secret_by_label(&self, label: &str) -> OckamResult<Box<dyn Secret>>;
+
+    /// Returns the label and metadata of a persistent secret
+    fn get_secret_metadata(&self, secret: &Box<dyn Secret>) -> OckamResult<SecretMetadata>;
+
+    /// Replaces the label and metadata of a persistent secret
+    fn set_secret_metadata(
+        &mut self,
+        secret: &Box<dyn Secret>,
+        metadata: SecretMetadata,
+    ) -> OckamResult<()>;
+
+    /// Replaces the label and metadata of several persistent secrets, in order, so a label
+    /// can move from one secret to another. Either all secrets are updated or none.
+    fn set_secrets_metadata(
+        &mut self,
+        updates: Vec<(&Box<dyn Secret>, SecretMetadata)>,
+    ) -> OckamResult<()>;
+
+    /// Lists all persistent secrets with their attributes and metadata
+    fn list_persistent_secrets(&mut self) -> OckamResult<Vec<PersistentSecretInfo>>;
+}
+
+/// Trait for vault with key rotation capabilities
+///
+/// The current version of a rotated key keeps its label, while previous versions are
+/// relabeled `<label>@<version>` and destroyed once their grace period has elapsed.
+pub trait RotationVault: SecretVault + PersistentVault {
+    /// Creates a new version of the key labeled `label` and returns it, keeping the
+    /// previous version for `grace_period`
+    fn secret_rotate(
+        &mut self,
+        label: &str,
+        grace_period: Duration,
+    ) -> OckamResult<Box<dyn Secret>> {
+        let current = self.get_persistent_secret_by_label(label)?;
+        let attributes = self.secret_attributes_get(&current)?;
+        let mut metadata = self.get_secret_metadata(&current)?;
+        let version = match metadata.entries.get(METADATA_VERSION) {
+            Some(version) => parse_metadata_number(version)?,
+            None => 1,
+        };
+        let now = unix_time()?;
+
+        let mut retired = metadata.clone();
+        retired.label = Some(format!("{}@{}", label, version));
+        retired.entries.insert(
+            METADATA_EXPIRES_AT.into(),
+            (now + grace_period.as_secs()).to_string(),
+        );
+        metadata
+            .entries
+            .insert(METADATA_VERSION.into(), (version + 1).to_string());
+        metadata
+            .entries
+            .insert(METADATA_CREATED_AT.into(), now.to_string());
+
+        // the label moves to the new version in one update, so it always names a key
+        let new = self.secret_generate(attributes)?;
+        if let Err(e) = self.set_secrets_metadata(vec![(&current, retired), (&new, metadata)]) {
+            self.secret_destroy(new)?;
+            return Err(e);
+        }
+
+        self.purge_expired_secrets()?;
+
+        Ok(new)
+    }
+
+    /// Returns true if the key labeled `label` is older than `max_age`, or its age is unknown
+    fn secret_needs_rotation(&self, label: &str, max_age: Duration) -> OckamResult<bool> {
+        let secret = self.get_persistent_secret_by_label(label)?;
+        let metadata = self.get_secret_metadata(&secret)?;
+        match metadata.entries.get(METADATA_CREATED_AT) {
+            Some(created_at) => {
+                Ok(parse_metadata_number(created_at)? + max_age.as_secs() <= unix_time()?)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Destroys the previous key versions whose grace period has elapsed
+    /// and returns how many were destroyed
+    fn purge_expired_secrets(&mut self) -> OckamResult<usize> {
+        let now = unix_time()?;
+        let mut purged = 0;
+        for info in self.list_persistent_secrets()? {
+            let expires_at = match info.metadata.entries.get(METADATA_EXPIRES_AT) {
+                Some(expires_at) => parse_metadata_number(expires_at)?,
+                None => continue,
+            };
+            if expires_at <= now {
+                let secret = self.get_persistent_secret(&info.persistence_id)?;
+                self.secret_destroy(secret)?;
+                purged += 1;
+            }
+        }
+
+        Ok(purged)
+    }
+}
+
+/// Trait for vault able to move secrets to another vault without exposing them in plaintext
+///
+/// A wrapped secret is encrypted with AES-256-GCM under a key derived with HKDF-SHA256 from an
+/// ephemeral ECDH with the recipient's Curve25519 or P256 key, and signed by the sender.
+pub trait WrappingVault:
+    SecretVault + AsymmetricVault + HashVault + SymmetricVault + SignerVault + VerifierVault
+{
+    /// Wraps the secret for the owner of `recipient_public_key` and signs the bundle with
+    /// `signing_key`
+    fn secret_export_wrapped(
+        &mut self,
+        context: &Box<dyn Secret>,
+        recipient_public_key: &[u8],
+        signing_key: &Box<dyn Secret>,
+    ) -> OckamResult<Vec<u8>> {
+        let attributes = self.secret_attributes_get(context)?;
+        let ephemeral_attributes = match recipient_public_key.len() {
+            CURVE25519_PUBLIC_LENGTH => SecretAttributes {
+                stype: SecretType::Curve25519,
+                persistence: SecretPersistence::Ephemeral,
+                length: CURVE25519_SECRET_LENGTH,
+            },
+            P256_PUBLIC_LENGTH => SecretAttributes {
+                stype: SecretType::P256,
+                persistence: SecretPersistence::Ephemeral,
+                length: P256_SECRET_LENGTH,
+            },
+            _ => return Err(error::Error::InvalidPublicKeyLength.into()),
+        };
+        let ephemeral = self.secret_generate(ephemeral_attributes)?;
+        let ephemeral_public_key = self.secret_public_key_get(&ephemeral)?;
+
+        let mut bundle = WRAPPED_SECRET_MAGIC.to_vec();
+        bundle.push(WRAPPED_SECRET_VERSION);
+        bundle.extend_from_slice(&attributes.to_bytes());
+        bundle.push(ephemeral_public_key.as_ref().len() as u8);
+        bundle.extend_from_slice(ephemeral_public_key.as_ref());
+
+        let key = derive_wrapping_key(
+            self,
+            &ephemeral,
+            recipient_public_key,
+            recipient_public_key,
+            &bundle,
+        );
+        self.secret_destroy(ephemeral)?;
+        let key = key?;
+        let secret = self.secret_export(context)?;
+        let ciphertext = self.aead_aes_gcm_encrypt(&key, secret.as_ref(), &[0u8; 12], &bundle);
+        self.secret_destroy(key)?;
+        bundle.extend_from_slice(&ciphertext?);
+
+        let signature = self.sign(signing_key, &bundle)?;
+        bundle.extend_from_slice(signature.as_ref());
+
+        Ok(bundle)
+    }
+
+    /// Checks the sender's signature on `bundle`, unwraps it with `recipient_secret` and
+    /// imports the secret with the attributes it was exported with
+    fn secret_import_wrapped(
+        &mut self,
+        bundle: &[u8],
+        recipient_secret: &Box<dyn Secret>,
+        sender_public_key: &[u8],
+        sender_public_key_type: SecretType,
+    ) -> OckamResult<Box<dyn Secret>> {
+        // magic + version + attributes + public key length
+        let fixed_length = WRAPPED_SECRET_MAGIC.len() + 1 + 6 + 1;
+        if bundle.len() < fixed_length + SIGNATURE_LENGTH {
+            return Err(error::Error::InvalidWrappedSecret.into());
+        }
+        let (signed, signature) = bundle.split_at(bundle.len() - SIGNATURE_LENGTH);
+        self.verify(signature, sender_public_key, sender_public_key_type, signed)?;
+
+        if &signed[..WRAPPED_SECRET_MAGIC.len()] != WRAPPED_SECRET_MAGIC {
+            return Err(error::Error::InvalidWrappedSecret.into());
+        }
+        if signed[WRAPPED_SECRET_MAGIC.len()] != WRAPPED_SECRET_VERSION {
+            return Err(error::Error::UnsupportedWrappedSecretVersion.into());
+        }
+        let attributes =
+            SecretAttributes::try_from(*array_ref![signed, WRAPPED_SECRET_MAGIC.len() + 1, 6])?;
+        let header_length = fixed_length + signed[fixed_length - 1] as usize;
+        if signed.len() < header_length {
+            return Err(error::Error::InvalidWrappedSecret.into());
+        }
+        let (header, ciphertext) = signed.split_at(header_length);
+        let ephemeral_public_key = &header[fixed_length..];
+
+        let recipient_public_key = self.secret_public_key_get(recipient_secret)?;
+        let key = derive_wrapping_key(
+            self,
+            recipient_secret,
+            ephemeral_public_key,
+            recipient_public_key.as_ref(),
+            header,
+        )?;
+        let plaintext = self.aead_aes_gcm_decrypt(&key, ciphertext, &[0u8; 12], header);
+        self.secret_destroy(key)?;
+        let mut plaintext = plaintext?;
+        let secret = self.secret_import(&plaintext, attributes);
+        plaintext.zeroize();
+
+        secret
+    }
+}
+
+/// Derives the AES key of a wrapped secret, the salt binds it to the bundle header and
+/// the recipient key
+fn derive_wrapping_key<V: WrappingVault + ?Sized>(
+    vault: &mut V,
+    secret: &Box<dyn Secret>,
+    peer_public_key: &[u8],
+    recipient_public_key: &[u8],
+    header: &[u8],
+) -> OckamResult<Box<dyn Secret>> {
+    let mut salt = header.to_vec();
+    salt.extend_from_slice(recipient_public_key);
+    let salt = vault.sha256(&salt)?;
+    let salt = vault.secret_import(
+        &salt,
+        SecretAttributes {
+            stype: SecretType::Buffer,
+            persistence: SecretPersistence::Ephemeral,
+            length: salt.len(),
+        },
+    )?;
+
+    let shared_secret = vault.ec_diffie_hellman(secret, peer_public_key);
+    let keys = match &shared_secret {
+        Ok(shared_secret) => vault.hkdf_sha256(
+            &salt,
+            WRAPPED_SECRET_INFO,
+            Some(shared_secret),
+            vec![SecretAttributes {
+                stype: SecretType::Aes,
+                persistence: SecretPersistence::Ephemeral,
+                length: AES256_SECRET_LENGTH,
+            }],
+        ),
+        Err(_) => Ok(vec![]),
+    };
+    vault.secret_destroy(salt)?;
+    vault.secret_destroy(shared_secret?)?;
+
+    keys?.pop().ok_or(error::Error::InvalidWrappedSecret.into())
+}
+
+fn unix_time() -> OckamResult<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .map_err(|_| error::Error::SystemTimeError.into())
+}
+
+fn parse_metadata_number(value: &str) -> OckamResult<u64> {
+    value
+        .parse()
+        .map_err(|_| error::Error::InvalidSecretMetadata.into())
 }
@@ -1,5 +1,6 @@
 use crate::error::Error;
 use ockam_common::error::{OckamError, OckamResult};
+use std::collections::BTreeMap;
 use zeroize::Zeroize;
 
 /// Curve25519 private key length
@@ -10,10 +11,28 @@ pub const CURVE25519_PUBLIC_LENGTH: usize = 32;
 pub const P256_SECRET_LENGTH: usize = 32;
 /// P256 public key length
 pub const P256_PUBLIC_LENGTH: usize = 65;
+/// Ed25519 private key length
+pub const ED25519_SECRET_LENGTH: usize = 32;
+/// Ed25519 public key length
+pub const ED25519_PUBLIC_LENGTH: usize = 32;
+/// Length of an XEdDSA, Ed25519 or raw (r || s) ECDSA P-256 signature
+pub const SIGNATURE_LENGTH: usize = 64;
+/// Maximum length of an ASN.1 DER encoded ECDSA P-256 signature
+pub const P256_DER_SIGNATURE_MAX_LENGTH: usize = 72;
 /// AES256 private key length
 pub const AES256_SECRET_LENGTH: usize = 32;
 /// AES128 private key length
 pub const AES128_SECRET_LENGTH: usize = 16;
+/// ChaCha20-Poly1305 private key length
+pub const CHACHA20POLY1305_SECRET_LENGTH: usize = 32;
+/// ML-KEM-768 private key length, the seed `d || z` the key pair is derived from
+pub const ML_KEM_768_SECRET_LENGTH: usize = 64;
+/// ML-KEM-768 public (encapsulation) key length
+pub const ML_KEM_768_PUBLIC_LENGTH: usize = 1184;
+/// ML-KEM-768 ciphertext length
+pub const ML_KEM_768_CIPHERTEXT_LENGTH: usize = 1088;
+/// ML-KEM shared secret length
+pub const ML_KEM_SHARED_SECRET_LENGTH: usize = 32;
 
 cfg_if! {
     if #[cfg(feature = "heapless")] {
@@ -22,6 +41,8 @@ cfg_if! {
         pub type SecretKeyVec = heapless::Vec<u8, U32>;
         /// Public Key Vector
         pub type PublicKeyVec = heapless::Vec<u8, U65>;
+        /// Signature Vector
+        pub type SignatureVec = heapless::Vec<u8, U72>;
         /// Bufer for small vectors (e.g. array of attributes). Max size - 4
         pub type SmallBuffer<T> = heapless::Vec<T, U4>;
         /// Buffer for large binaries (e.g. encrypted data). Max size - 512
@@ -32,6 +53,8 @@ cfg_if! {
         pub type SecretKeyVec = Vec<u8>;
         /// Public Key Vector
         pub type PublicKeyVec = Vec<u8>;
+        /// Signature Vector
+        pub type SignatureVec = Vec<u8>;
         /// Bufer for small vectors (e.g. array of attributes)
         pub type SmallBuffer<T> = Vec<T>;
         /// Buffer for large binaries (e.g. encrypted data)
@@ -71,6 +94,96 @@ impl PublicKey {
     }
 }
 
+/// Signature
+#[derive(Clone, Debug, Eq, PartialEq, Zeroize)]
+pub struct Signature(SignatureVec);
+
+impl Signature {
+    /// Constructor
+    pub fn new(data: SignatureVec) -> Self {
+        Self(data)
+    }
+
+    /// Returns slice
+    pub fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Encode a raw (r || s) ECDSA P-256 signature using ASN.1 DER
+    pub fn to_der(&self) -> OckamResult<Signature> {
+        if self.0.len() != SIGNATURE_LENGTH {
+            return Err(Error::InvalidSignatureLength.into());
+        }
+        let (r, s) = self.0.split_at(P256_SECRET_LENGTH);
+        let r = der_integer(r);
+        let s = der_integer(s);
+
+        let mut output = vec![0x30, (r.len() + s.len()) as u8];
+        output.extend_from_slice(&r);
+        output.extend_from_slice(&s);
+        Ok(Self(output))
+    }
+
+    /// Decode an ASN.1 DER ECDSA P-256 signature into its raw (r || s) form
+    pub fn from_der(der: &[u8]) -> OckamResult<Signature> {
+        if der.len() < 2 || der[0] != 0x30 || der[1] as usize != der.len() - 2 {
+            return Err(Error::InvalidDerSignature.into());
+        }
+        let (r, rest) = der_integer_parse(&der[2..])?;
+        let (s, rest) = der_integer_parse(rest)?;
+        if !rest.is_empty() {
+            return Err(Error::InvalidDerSignature.into());
+        }
+
+        let mut output = vec![0u8; SIGNATURE_LENGTH];
+        output[P256_SECRET_LENGTH - r.len()..P256_SECRET_LENGTH].copy_from_slice(r);
+        output[SIGNATURE_LENGTH - s.len()..].copy_from_slice(s);
+        Ok(Self(output))
+    }
+}
+
+/// Encode an unsigned big-endian integer as an ASN.1 DER INTEGER
+fn der_integer(value: &[u8]) -> Vec<u8> {
+    let start = value
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(value.len() - 1);
+    let value = &value[start..];
+    let pad = value[0] & 0x80 != 0;
+
+    let mut output = vec![0x02, (value.len() + pad as usize) as u8];
+    if pad {
+        output.push(0);
+    }
+    output.extend_from_slice(value);
+    output
+}
+
+/// Parse an ASN.1 DER INTEGER, returning the unsigned value and the remaining input
+fn der_integer_parse(data: &[u8]) -> OckamResult<(&[u8], &[u8])> {
+    if data.len() < 3 || data[0] != 0x02 {
+        return Err(Error::InvalidDerSignature.into());
+    }
+    let length = data[1] as usize;
+    if length == 0 || data.len() < length + 2 {
+        return Err(Error::InvalidDerSignature.into());
+    }
+    let (value, rest) = data[2..].split_at(length);
+    // Negative values are invalid
+    if value[0] & 0x80 != 0 {
+        return Err(Error::InvalidDerSignature.into());
+    }
+    let value = if value.len() > 1 && value[0] == 0 {
+        &value[1..]
+    } else {
+        value
+    };
+    if value.len() > P256_SECRET_LENGTH {
+        return Err(Error::InvalidDerSignature.into());
+    }
+    Ok((value, rest))
+}
+
 /// The types of secret keys that the vault supports
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub enum SecretType {
@@ -82,6 +195,12 @@ pub enum SecretType {
     Curve25519,
     /// NIST P-256 (secp256r1, prime256v1) secret key
     P256,
+    /// Ed25519 secret key
+    Ed25519,
+    /// ChaCha20-Poly1305 key
+    ChaCha20Poly1305,
+    /// ML-KEM-768 (Kyber) decapsulation key
+    MlKem768,
 }
 
 impl SecretType {
@@ -92,6 +211,9 @@ impl SecretType {
             SecretType::Aes => 1,
             SecretType::Curve25519 => 2,
             SecretType::P256 => 3,
+            SecretType::Ed25519 => 4,
+            SecretType::ChaCha20Poly1305 => 5,
+            SecretType::MlKem768 => 6,
         }
     }
 
@@ -102,6 +224,9 @@ impl SecretType {
             1 => Ok(SecretType::Aes),
             2 => Ok(SecretType::Curve25519),
             3 => Ok(SecretType::P256),
+            4 => Ok(SecretType::Ed25519),
+            5 => Ok(SecretType::ChaCha20Poly1305),
+            6 => Ok(SecretType::MlKem768),
             _ => Err(Error::UnknownSecretTypeValue.into()),
         }
     }
@@ -177,6 +302,33 @@ try_from_int_impl!(SecretPersistence, u32);
 try_from_int_impl!(SecretPersistence, u64);
 try_from_int_impl!(SecretPersistence, u128);
 
+/// Metadata entry holding the unix time at which a secret was created
+pub const METADATA_CREATED_AT: &str = "created_at";
+/// Metadata entry holding the unix time after which a rotated out secret is destroyed
+pub const METADATA_EXPIRES_AT: &str = "expires_at";
+/// Metadata entry holding the version of a rotated secret
+pub const METADATA_VERSION: &str = "version";
+
+/// Label and metadata attached to a persistent secret
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SecretMetadata {
+    /// Human-readable label, unique within a vault
+    pub label: Option<String>,
+    /// Free-form entries such as creation time, purpose or owner
+    pub entries: BTreeMap<String, String>,
+}
+
+/// A persistent secret as listed by a vault
+#[derive(Clone, Debug)]
+pub struct PersistentSecretInfo {
+    /// Id to retrieve the secret with `get_persistent_secret`
+    pub persistence_id: String,
+    /// The attributes of the secret
+    pub attributes: SecretAttributes,
+    /// The label and metadata of the secret
+    pub metadata: SecretMetadata,
+}
+
 /// Attributes for a specific vault secret
 #[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
 pub struct SecretAttributes {
@@ -216,3 +368,4 @@ impl std::convert::TryFrom<[u8; 6]> for SecretAttributes {
 }
 
 zdrop_impl!(SecretKey);
+zdrop_impl!(Signature);